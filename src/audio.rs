@@ -1,11 +1,168 @@
 use crate::error::{Result, SonicPipeError};
+use crate::modulation::{splitmix64, MFSKDemodulator, WakeUpTracker};
+use crate::{Config, LOG_TARGET};
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use cpal::{Device, SampleFormat, StreamConfig};
-use std::sync::{Arc, Mutex};
+use cpal::{Device, Stream, StreamConfig};
+use rustfft::{num_complex::Complex, FftPlanner};
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+// About 85 ms per window at 48 kHz, fine enough to resolve 100 Hz tone steps.
+const PROBE_FFT_SIZE: usize = 4096;
+
+// An echo is only subtracted when the reference explains at least this share
+// of the energy where it overlaps the recording (correlation of ~0.3).
+const ECHO_MIN_EXPLAINED: f64 = 0.1;
+// A recording cleaned as it arrives has its echo fitted once, over this much
+// of its start: enough to span the output latency and catch the echo.
+const ECHO_FIT_MS: u32 = 500;
+
+// Carrier sense before sending: how long each listen lasts, and how long to
+// wait for a clear channel before giving up.
+pub const CARRIER_SENSE_LISTEN_MS: u32 = 300;
+pub const CARRIER_SENSE_TIMEOUT_MS: u32 = 60_000;
+
+// How often a blocked wait checks whether its stream has failed.
+const STREAM_POLL_MS: u64 = 50;
+
+// Assumed output latency until the device reports its own, roughly what a
+// default-sized buffer costs on common hosts.
+const DEFAULT_OUTPUT_LATENCY_MS: u64 = 50;
+// Extra time left after the device's latency for the last buffer to play.
+const DRAIN_MARGIN_MS: u64 = 20;
+// The least time left for it whatever latency is reported, since some
+// devices report less than they have.
+const MIN_DRAIN_TAIL_MS: u64 = 100;
+
+type SharedError = Arc<Mutex<Option<SonicPipeError>>>;
+
+// Error callback for a stream that keeps the first error it reports. A
+// device that fails mid-stream (unplugged, or the server went away) just
+// stops delivering callbacks, so whoever is waiting on the stream checks
+// here rather than waiting forever or timing out on silence.
+fn capture_errors(errors: &SharedError, direction: &'static str) -> impl FnMut(cpal::StreamError) + Send + 'static {
+    let errors = Arc::clone(errors);
+    move |err| {
+        log::warn!(target: LOG_TARGET, "Audio {} stream error: {}", direction, err);
+        errors
+            .lock()
+            .unwrap()
+            .get_or_insert_with(|| SonicPipeError::AudioDevice(format!("{} stream failed: {}", direction, err)));
+    }
+}
+
+// A running stream that is paused and released as soon as it goes out of
+// scope, so an early return or `?` can't leave the device held open.
+struct StreamGuard(Stream);
+
+impl StreamGuard {
+    fn start(stream: Stream) -> Result<Self> {
+        let guard = Self(stream);
+        guard.0.play().map_err(|e| SonicPipeError::AudioDevice(e.to_string()))?;
+        Ok(guard)
+    }
+}
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        if let Err(e) = self.0.pause() {
+            log::debug!(target: LOG_TARGET, "Could not pause stream on release: {}", e);
+        }
+    }
+}
+
+fn check_stream(errors: &SharedError) -> Result<()> {
+    match errors.lock().unwrap().take() {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+// Sample buffers waiting to be played, drained by the output callback. The
+// running totals let a caller tell when a particular buffer has gone out:
+// it has once `played` reaches the total at the time it was queued.
+// `latency` is how far behind the callback the device last said it was
+// playing.
+#[derive(Debug, Default)]
+struct PlaybackQueue {
+    buffers: VecDeque<Vec<f32>>,
+    position: usize,
+    queued: u64,
+    played: u64,
+    latency: Option<Duration>,
+}
 
+impl PlaybackQueue {
+    // Returns the ticket `played` has to reach for this buffer to be done.
+    fn push(&mut self, samples: Vec<f32>) -> u64 {
+        self.queued += samples.len() as u64;
+        if !samples.is_empty() {
+            self.buffers.push_back(samples);
+        }
+        self.queued
+    }
+
+    // Fills `out` from the queue, padding with silence once it runs dry.
+    fn fill(&mut self, out: &mut [f32]) {
+        let mut written = 0;
+        while written < out.len() {
+            let Some(front) = self.buffers.front() else {
+                break;
+            };
+
+            let n = (front.len() - self.position).min(out.len() - written);
+            out[written..written + n].copy_from_slice(&front[self.position..self.position + n]);
+            written += n;
+            self.position += n;
+            self.played += n as u64;
+
+            if self.position == front.len() {
+                self.buffers.pop_front();
+                self.position = 0;
+            }
+        }
+
+        out[written..].fill(0.0);
+    }
+
+    fn is_played(&self, ticket: u64) -> bool {
+        self.played >= ticket
+    }
+
+    // Drops everything still queued, counting it as played so outstanding
+    // tickets don't wait on a stream that is gone.
+    fn discard(&mut self) {
+        self.buffers.clear();
+        self.position = 0;
+        self.played = self.queued;
+    }
+}
+
+type SharedQueue = Arc<(Mutex<PlaybackQueue>, Condvar)>;
+
+fn wait_played(queue: &SharedQueue, ticket: u64, errors: &SharedError) -> Result<()> {
+    let (queue, drained) = &**queue;
+    let mut guard = queue.lock().unwrap();
+    while !guard.is_played(ticket) {
+        guard = drained.wait_timeout(guard, Duration::from_millis(STREAM_POLL_MS)).unwrap().0;
+        if let Err(e) = check_stream(errors) {
+            guard.discard();
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+// Opens the output stream on first use and keeps it running, playing
+// silence between buffers, so later transmissions start without paying
+// the device-open latency again.
 pub struct AudioOutput {
     device: Device,
     config: StreamConfig,
+    queue: SharedQueue,
+    errors: SharedError,
+    stream: Option<StreamGuard>,
 }
 
 impl AudioOutput {
@@ -15,71 +172,147 @@ impl AudioOutput {
             .default_output_device()
             .ok_or_else(|| SonicPipeError::AudioDevice("No output device found".into()))?;
 
-        let supported_config = device
-            .default_output_config()
-            .map_err(|e| SonicPipeError::AudioDevice(e.to_string()))?;
-
         let config = StreamConfig {
             channels: 1,
             sample_rate: cpal::SampleRate(48000),
             buffer_size: cpal::BufferSize::Default,
         };
 
-        Ok(Self { device, config })
+        Ok(Self {
+            device,
+            config,
+            queue: Arc::new((Mutex::new(PlaybackQueue::default()), Condvar::new())),
+            errors: SharedError::default(),
+            stream: None,
+        })
     }
 
-    pub fn play_samples(&self, samples: Vec<f32>) -> Result<()> {
-        let samples = Arc::new(Mutex::new(samples));
-        let position = Arc::new(Mutex::new(0usize));
-        let finished = Arc::new(Mutex::new(false));
-
-        let samples_clone = Arc::clone(&samples);
-        let position_clone = Arc::clone(&position);
-        let finished_clone = Arc::clone(&finished);
+    fn ensure_stream(&mut self) -> Result<()> {
+        if self.stream.is_some() {
+            return Ok(());
+        }
 
+        let queue = Arc::clone(&self.queue);
         let stream = self
             .device
             .build_output_stream(
                 &self.config,
-                move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
-                    let samples = samples_clone.lock().unwrap();
-                    let mut pos = position_clone.lock().unwrap();
-
-                    for sample in data.iter_mut() {
-                        if *pos < samples.len() {
-                            *sample = samples[*pos];
-                            *pos += 1;
-                        } else {
-                            *sample = 0.0;
-                            *finished_clone.lock().unwrap() = true;
-                        }
+                move |data: &mut [f32], info: &cpal::OutputCallbackInfo| {
+                    let (queue, drained) = &*queue;
+                    let mut queue = queue.lock().unwrap();
+                    queue.fill(data);
+                    let timestamp = info.timestamp();
+                    if let Some(latency) = timestamp.playback.duration_since(&timestamp.callback) {
+                        queue.latency = Some(latency);
                     }
+                    drained.notify_all();
                 },
-                |err| eprintln!("Audio output error: {}", err),
+                capture_errors(&self.errors, "output"),
                 None,
             )
             .map_err(|e| SonicPipeError::AudioDevice(e.to_string()))?;
 
-        stream
-            .play()
-            .map_err(|e| SonicPipeError::AudioDevice(e.to_string()))?;
+        self.stream = Some(StreamGuard::start(stream)?);
+        Ok(())
+    }
 
-        loop {
-            std::thread::sleep(std::time::Duration::from_millis(10));
-            if *finished.lock().unwrap() {
-                break;
-            }
-        }
+    // Queues `samples` behind anything still playing and returns at once.
+    pub fn enqueue(&mut self, samples: Vec<f32>) -> Result<()> {
+        self.ensure_stream()?;
+        self.queue.0.lock().unwrap().push(samples);
+        Ok(())
+    }
 
-        std::thread::sleep(std::time::Duration::from_millis(100));
+    // Blocks until everything queued so far has been handed to the device,
+    // plus the device's latency for its own buffer to play out, but at least
+    // `MIN_DRAIN_TAIL_MS`. If the stream fails first, the rest of the queue
+    // is dropped and the next `enqueue` opens a fresh stream.
+    pub fn wait_drained(&mut self) -> Result<()> {
+        let ticket = self.queue.0.lock().unwrap().queued;
+        if let Err(e) = wait_played(&self.queue, ticket, &self.errors) {
+            self.stream = None;
+            return Err(e);
+        }
 
+        let tail = self.output_latency() + Duration::from_millis(DRAIN_MARGIN_MS);
+        std::thread::sleep(tail.max(Duration::from_millis(MIN_DRAIN_TAIL_MS)));
         Ok(())
     }
+
+    // How long after a sample is handed to the device it leaves the
+    // speaker. Once the stream is running this is what the host last
+    // reported; before then, or on hosts that don't say, it is a guess
+    // from the buffer size.
+    pub fn output_latency(&self) -> Duration {
+        let reported = self.queue.0.lock().unwrap().latency;
+        reported.unwrap_or_else(|| estimated_latency(&self.config))
+    }
+
+    pub fn play_samples(&mut self, samples: Vec<f32>) -> Result<()> {
+        self.enqueue(samples)?;
+        self.wait_drained()
+    }
+}
+
+fn estimated_latency(config: &StreamConfig) -> Duration {
+    match config.buffer_size {
+        cpal::BufferSize::Fixed(frames) => Duration::from_secs_f64(frames as f64 / config.sample_rate.0 as f64),
+        cpal::BufferSize::Default => Duration::from_millis(DEFAULT_OUTPUT_LATENCY_MS),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordStatus {
+    // Nothing heard yet.
+    Waiting,
+    // A transmission has started but isn't complete.
+    Receiving,
+    Complete,
+}
+
+// How long to wait for a transmission to start, and how long a recording may
+// run in total. Once a wake-up tone is heard only the overall cap applies, so
+// a long transfer isn't cut off by the short wait for silence to break.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecordLimits {
+    pub wake_timeout_ms: u64,
+    pub max_record_ms: u64,
+}
+
+impl RecordLimits {
+    pub fn new(wake_timeout_ms: u64, max_record_ms: u64) -> Self {
+        Self {
+            wake_timeout_ms,
+            max_record_ms,
+        }
+    }
+
+    // Whether to stop recording after `elapsed_ms`. Giving up before anything
+    // was heard is a timeout; hitting the cap mid-transmission returns what
+    // was recorded so the caller can still try to decode it.
+    pub fn should_stop(&self, elapsed_ms: u64, status: RecordStatus) -> Result<bool> {
+        match status {
+            RecordStatus::Complete => Ok(true),
+            RecordStatus::Waiting if elapsed_ms > self.wake_timeout_ms => {
+                Err(SonicPipeError::Timeout)
+            }
+            _ if elapsed_ms > self.max_record_ms => {
+                log::warn!(
+                    target: LOG_TARGET,
+                    "Stopped recording at the {} ms cap before the frame ended",
+                    self.max_record_ms
+                );
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
 }
 
 pub struct AudioInput {
     device: Device,
     config: StreamConfig,
+    reference: Option<Vec<f32>>,
 }
 
 impl AudioInput {
@@ -95,84 +328,362 @@ impl AudioInput {
             buffer_size: cpal::BufferSize::Default,
         };
 
-        Ok(Self { device, config })
+        Ok(Self {
+            device,
+            config,
+            reference: None,
+        })
     }
 
-    pub fn record_samples(&self, duration_ms: u32) -> Result<Vec<f32>> {
-        let num_samples = (48000.0 * duration_ms as f32 / 1000.0) as usize;
-        let samples = Arc::new(Mutex::new(Vec::with_capacity(num_samples)));
-        let samples_clone = Arc::clone(&samples);
+    // Subtracts the echo of `reference` (what we are playing) from everything
+    // this input records, until cleared. See `cancel_echo` for its limits.
+    pub fn suppress_reference(&mut self, reference: &[f32]) {
+        self.reference = Some(reference.to_vec());
+    }
+
+    pub fn clear_reference(&mut self) {
+        self.reference = None;
+    }
 
+    fn suppressed(&self, samples: Vec<f32>) -> Vec<f32> {
+        match &self.reference {
+            Some(reference) => cancel_echo(&samples, reference),
+            None => samples,
+        }
+    }
+
+    // Starts recording into `samples`. Recording stops when the returned
+    // guard is dropped, however the caller leaves.
+    fn start_recording(&self, samples: &Arc<Mutex<Vec<f32>>>, errors: &SharedError) -> Result<StreamGuard> {
+        let samples = Arc::clone(samples);
         let stream = self
             .device
             .build_input_stream(
                 &self.config,
                 move |data: &[f32], _: &cpal::InputCallbackInfo| {
-                    let mut samples = samples_clone.lock().unwrap();
-                    samples.extend_from_slice(data);
+                    samples.lock().unwrap().extend_from_slice(data);
                 },
-                |err| eprintln!("Audio input error: {}", err),
+                capture_errors(errors, "input"),
                 None,
             )
             .map_err(|e| SonicPipeError::AudioDevice(e.to_string()))?;
 
-        stream
-            .play()
-            .map_err(|e| SonicPipeError::AudioDevice(e.to_string()))?;
+        StreamGuard::start(stream)
+    }
 
-        std::thread::sleep(std::time::Duration::from_millis(duration_ms as u64));
+    pub fn record_samples(&self, duration_ms: u32) -> Result<Vec<f32>> {
+        let num_samples = (48000.0 * duration_ms as f32 / 1000.0) as usize;
+        let samples = Arc::new(Mutex::new(Vec::with_capacity(num_samples)));
+        let errors = SharedError::default();
+        let stream = self.start_recording(&samples, &errors)?;
+
+        let deadline = Instant::now() + Duration::from_millis(duration_ms as u64);
+        while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+            std::thread::sleep(remaining.min(Duration::from_millis(STREAM_POLL_MS)));
+            check_stream(&errors)?;
+        }
 
         drop(stream);
 
         let result = samples.lock().unwrap().clone();
-        Ok(result)
+        Ok(self.suppressed(result))
     }
 
-    pub fn record_until_complete<F>(&self, mut check_fn: F, timeout_ms: u32) -> Result<Vec<f32>>
-    where
-        F: FnMut(&[f32]) -> bool,
-    {
-        let samples = Arc::new(Mutex::new(Vec::new()));
-        let samples_clone = Arc::clone(&samples);
+    // Listens for `listen_ms` and reports whether the band is free for us to
+    // send: no wake-up tone and no data tones from another sender. See
+    // `MFSKDemodulator::channel_busy`.
+    pub fn is_channel_clear(&self, config: &Config, listen_ms: u32) -> Result<bool> {
+        let samples = self.record_samples(listen_ms)?;
+        Ok(!MFSKDemodulator::new(config.clone()).channel_busy(&samples))
+    }
 
-        let stream = self
-            .device
-            .build_input_stream(
-                &self.config,
-                move |data: &[f32], _: &cpal::InputCallbackInfo| {
-                    let mut samples = samples_clone.lock().unwrap();
-                    samples.extend_from_slice(data);
-                },
-                |err| eprintln!("Audio input error: {}", err),
-                None,
-            )
-            .map_err(|e| SonicPipeError::AudioDevice(e.to_string()))?;
+    // Listens until a whole `listen_ms` goes by clear. After each busy listen
+    // it backs off for a random stretch of up to `listen_ms`, so two senders
+    // waiting on the same transmission don't both start the moment it ends.
+    // Gives up with a timeout once `timeout_ms` has passed.
+    pub fn wait_for_clear_channel(&self, config: &Config, listen_ms: u32, timeout_ms: u32) -> Result<()> {
+        let start = Instant::now();
+        let mut state = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_nanos() as u64);
+
+        while !self.is_channel_clear(config, listen_ms)? {
+            if start.elapsed() > Duration::from_millis(timeout_ms as u64) {
+                return Err(SonicPipeError::Timeout);
+            }
+            state = splitmix64(state);
+            let backoff = state % (listen_ms as u64 + 1);
+            log::info!(target: LOG_TARGET, "Channel busy, listening again in {} ms", backoff);
+            std::thread::sleep(Duration::from_millis(backoff));
+        }
+        Ok(())
+    }
 
-        stream
-            .play()
-            .map_err(|e| SonicPipeError::AudioDevice(e.to_string()))?;
+    // Records the room for `duration_ms` and returns its spectrum, averaged
+    // over half-overlapping windows across the whole recording.
+    pub fn probe_noise(&self, duration_ms: u32) -> Result<Vec<(f32, f32)>> {
+        let samples = self.record_samples(duration_ms)?;
+        let mut demodulator = MFSKDemodulator::new(Config::default());
+        Ok(demodulator.analyze_spectrum_averaged(&samples, PROBE_FFT_SIZE, 0.5))
+    }
+
+    // Records until a whole frame has arrived: a wake-up tone followed by
+    // its end of frame.
+    pub fn record_frame(&self, config: &Config, limits: RecordLimits) -> Result<Vec<f32>> {
+        let mut recorded = Vec::new();
+        self.record_frame_into(config, limits, &mut recorded)?;
+        Ok(recorded)
+    }
 
-        let start = std::time::Instant::now();
-        let timeout = std::time::Duration::from_millis(timeout_ms as u64);
+    // Like `record_frame`, but leaves whatever was captured in `recorded`
+    // even when it fails, so a missed frame can still be saved and looked at.
+    pub fn record_frame_into(
+        &self,
+        config: &Config,
+        limits: RecordLimits,
+        recorded: &mut Vec<f32>,
+    ) -> Result<()> {
+        let mut tracker = WakeUpTracker::new(config.clone());
+        let end_demod = MFSKDemodulator::new(config.clone());
+        let mut scanned = 0;
+
+        self.record_until_complete_into(
+            move |samples| {
+                let wake_end = tracker.feed(&samples[scanned..]);
+                scanned = samples.len();
+
+                match wake_end {
+                    Some(wake_end) if end_demod.detect_end_of_frame_from(samples, wake_end) => RecordStatus::Complete,
+                    Some(_) => RecordStatus::Receiving,
+                    None => RecordStatus::Waiting,
+                }
+            },
+            limits,
+            recorded,
+        )
+    }
 
-        loop {
-            std::thread::sleep(std::time::Duration::from_millis(50));
+    pub fn record_until_complete<F>(&self, check_fn: F, limits: RecordLimits) -> Result<Vec<f32>>
+    where
+        F: FnMut(&[f32]) -> RecordStatus,
+    {
+        let mut recorded = Vec::new();
+        self.record_until_complete_into(check_fn, limits, &mut recorded)?;
+        Ok(recorded)
+    }
 
-            let current_samples = samples.lock().unwrap().clone();
-            if check_fn(&current_samples) {
-                break;
+    // Like `record_until_complete`, but `recorded` ends up holding the
+    // capture however the recording stops, a timeout or stream error included.
+    pub fn record_until_complete_into<F>(
+        &self,
+        mut check_fn: F,
+        limits: RecordLimits,
+        recorded: &mut Vec<f32>,
+    ) -> Result<()>
+    where
+        F: FnMut(&[f32]) -> RecordStatus,
+    {
+        let samples = Arc::new(Mutex::new(Vec::new()));
+        let errors = SharedError::default();
+        let stream = self.start_recording(&samples, &errors)?;
+
+        let start = Instant::now();
+        // Fed only what each poll adds, so the echo isn't refitted over the
+        // whole recording every time.
+        let mut canceller = self
+            .reference
+            .as_deref()
+            .map(|reference| EchoCanceller::new(reference, self.config.sample_rate.0));
+        let mut fed = 0;
+
+        let outcome = loop {
+            std::thread::sleep(Duration::from_millis(STREAM_POLL_MS));
+            if let Err(e) = check_stream(&errors) {
+                break Err(e);
             }
 
-            if start.elapsed() > timeout {
-                return Err(SonicPipeError::Timeout);
+            let status = match &mut canceller {
+                Some(canceller) => {
+                    let recorded = samples.lock().unwrap();
+                    canceller.push(&recorded[fed..]);
+                    fed = recorded.len();
+                    drop(recorded);
+                    check_fn(canceller.cleaned())
+                }
+                None => check_fn(&samples.lock().unwrap().clone()),
+            };
+            match limits.should_stop(start.elapsed().as_millis() as u64, status) {
+                Ok(false) => {}
+                Ok(true) => break Ok(()),
+                Err(e) => break Err(e),
             }
-        }
+        };
 
         drop(stream);
 
         let result = samples.lock().unwrap().clone();
-        Ok(result)
+        *recorded = match canceller {
+            Some(mut canceller) => {
+                canceller.push(&result[fed..]);
+                canceller.finish()
+            }
+            None => result,
+        };
+        outcome
+    }
+}
+
+// Removes a single delayed, scaled copy of `reference` from `recording`. The
+// delay is the peak of their cross-correlation, which may be negative if the
+// recording started after playback, and the gain is the least-squares fit
+// over the overlap.
+//
+// This models the echo as one path with a flat frequency response. Room
+// reverb, the speaker's and microphone's own responses and clock drift
+// between the output and input devices all leave a residual, as does another
+// transmission overlapping the echo, which skews the fitted gain. It reduces
+// a close speaker's echo rather than removing it; a receiver that must not
+// hear itself at all should still keep its input closed while sending.
+pub fn cancel_echo(recording: &[f32], reference: &[f32]) -> Vec<f32> {
+    let mut output = recording.to_vec();
+    if let Some(path) = fit_echo(recording, reference) {
+        path.subtract(&mut output, 0, reference);
+    }
+    output
+}
+
+// `cancel_echo` for a recording that arrives a block at a time. The delay
+// and gain are fitted once, over the first `ECHO_FIT_MS` recorded, and every
+// block after that is cleaned as it comes in rather than refitting the whole
+// recording. Nothing is let through until the fit, so the echo at the start
+// is never seen uncancelled.
+pub struct EchoCanceller {
+    reference: Vec<f32>,
+    samples: Vec<f32>,
+    fit_len: usize,
+    fit: Option<Option<EchoPath>>,
+}
+
+impl EchoCanceller {
+    pub fn new(reference: &[f32], sample_rate: u32) -> Self {
+        Self {
+            reference: reference.to_vec(),
+            samples: Vec::new(),
+            fit_len: (sample_rate as u64 * ECHO_FIT_MS as u64 / 1000) as usize,
+            fit: None,
+        }
     }
+
+    pub fn push(&mut self, block: &[f32]) {
+        let from = self.samples.len();
+        self.samples.extend_from_slice(block);
+        match self.fit {
+            Some(Some(path)) => path.subtract(&mut self.samples, from, &self.reference),
+            Some(None) => {}
+            None if self.samples.len() >= self.fit_len => self.fit_now(),
+            None => {}
+        }
+    }
+
+    // The recording cleaned so far; empty until the echo has been fitted.
+    pub fn cleaned(&self) -> &[f32] {
+        if self.fit.is_some() {
+            &self.samples
+        } else {
+            &[]
+        }
+    }
+
+    // The whole recording cleaned, fitting the echo over what there is if
+    // the recording ended too soon for the fit.
+    pub fn finish(mut self) -> Vec<f32> {
+        if self.fit.is_none() {
+            self.fit_now();
+        }
+        self.samples
+    }
+
+    fn fit_now(&mut self) {
+        let path = fit_echo(&self.samples, &self.reference);
+        if let Some(path) = path {
+            path.subtract(&mut self.samples, 0, &self.reference);
+        }
+        self.fit = Some(path);
+    }
+}
+
+// One echo path: reference sample i comes back `gain` times over as
+// recording sample i + lag.
+#[derive(Debug, Clone, Copy)]
+struct EchoPath {
+    lag: isize,
+    gain: f32,
+}
+
+impl EchoPath {
+    // Subtracts the echo from `recording[from..]`.
+    fn subtract(&self, recording: &mut [f32], from: usize, reference: &[f32]) {
+        let first = (from as isize - self.lag).max(0) as usize;
+        let last = reference
+            .len()
+            .min((recording.len() as isize - self.lag).max(0) as usize);
+        for i in first..last {
+            recording[(i as isize + self.lag) as usize] -= self.gain * reference[i];
+        }
+    }
+}
+
+// The echo path that best explains `recording` as `reference` played back,
+// or None if it explains too little of it; see `cancel_echo`.
+fn fit_echo(recording: &[f32], reference: &[f32]) -> Option<EchoPath> {
+    if recording.is_empty() || reference.is_empty() {
+        return None;
+    }
+
+    let n = (recording.len() + reference.len()).next_power_of_two();
+    let mut planner = FftPlanner::<f64>::new();
+    let forward = planner.plan_fft_forward(n);
+    let inverse = planner.plan_fft_inverse(n);
+
+    let padded = |signal: &[f32]| {
+        let mut buffer: Vec<Complex<f64>> = signal.iter().map(|&s| Complex::new(s as f64, 0.0)).collect();
+        buffer.resize(n, Complex::new(0.0, 0.0));
+        buffer
+    };
+    let mut correlation = padded(recording);
+    let mut reference_spectrum = padded(reference);
+    forward.process(&mut correlation);
+    forward.process(&mut reference_spectrum);
+    for (c, r) in correlation.iter_mut().zip(&reference_spectrum) {
+        *c *= r.conj();
+    }
+    inverse.process(&mut correlation);
+
+    // Entry `k` holds the correlation at lag k, and lag -m wraps to n - m.
+    let lags = (0..recording.len() as isize).chain(-(reference.len() as isize - 1)..0);
+    let (lag, peak) = lags
+        .map(|lag| (lag, correlation[lag.rem_euclid(n as isize) as usize].re / n as f64))
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+        .unwrap();
+
+    // Reference sample i lines up with recording sample i + lag.
+    let first = (-lag).max(0) as usize;
+    let last = reference.len().min((recording.len() as isize - lag) as usize);
+    let energy = |signal: &[f32]| signal.iter().map(|&s| s as f64 * s as f64).sum::<f64>();
+    let reference_energy = energy(&reference[first..last]);
+    let recording_energy = energy(&recording[(first as isize + lag) as usize..(last as isize + lag) as usize]);
+
+    if peak <= 0.0 || reference_energy == 0.0 || recording_energy == 0.0 {
+        return None;
+    }
+    if peak * peak / (reference_energy * recording_energy) < ECHO_MIN_EXPLAINED {
+        return None;
+    }
+
+    Some(EchoPath {
+        lag,
+        gain: (peak / reference_energy) as f32,
+    })
 }
 
 pub fn list_audio_devices() -> Vec<String> {
@@ -197,3 +708,312 @@ pub fn list_audio_devices() -> Vec<String> {
 
     devices
 }
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ResampleQuality {
+    Cubic,
+    Sinc { half_width: usize },
+}
+
+impl Default for ResampleQuality {
+    fn default() -> Self {
+        ResampleQuality::Sinc { half_width: 16 }
+    }
+}
+
+pub fn resample_to(samples: &[f32], from_rate: u32, to_rate: u32) -> Vec<f32> {
+    resample_with_quality(samples, from_rate, to_rate, ResampleQuality::default())
+}
+
+pub fn resample_with_quality(
+    samples: &[f32],
+    from_rate: u32,
+    to_rate: u32,
+    quality: ResampleQuality,
+) -> Vec<f32> {
+    if from_rate == to_rate || samples.is_empty() {
+        return samples.to_vec();
+    }
+
+    let step = from_rate as f64 / to_rate as f64;
+    let out_len = (samples.len() as f64 / step).floor() as usize;
+
+    // Keep the passband a little below the lower Nyquist so the sinc
+    // window has room to roll off before anything can alias.
+    let cutoff = 0.95 * (to_rate as f64 / from_rate as f64).min(1.0);
+
+    (0..out_len)
+        .map(|n| {
+            let t = n as f64 * step;
+            match quality {
+                ResampleQuality::Cubic => cubic_at(samples, t),
+                ResampleQuality::Sinc { half_width } => sinc_at(samples, t, half_width.max(1), cutoff),
+            }
+        })
+        .collect()
+}
+
+fn sample_at(samples: &[f32], index: i64) -> f32 {
+    if index < 0 || index as usize >= samples.len() {
+        0.0
+    } else {
+        samples[index as usize]
+    }
+}
+
+fn cubic_at(samples: &[f32], t: f64) -> f32 {
+    let i = t.floor() as i64;
+    let x = (t - i as f64) as f32;
+
+    let p0 = sample_at(samples, i - 1);
+    let p1 = sample_at(samples, i);
+    let p2 = sample_at(samples, i + 1);
+    let p3 = sample_at(samples, i + 2);
+
+    // Catmull-Rom spline through the four neighbouring samples.
+    p1 + 0.5
+        * x
+        * (p2 - p0 + x * (2.0 * p0 - 5.0 * p1 + 4.0 * p2 - p3 + x * (3.0 * (p1 - p2) + p3 - p0)))
+}
+
+fn sinc_at(samples: &[f32], t: f64, half_width: usize, cutoff: f64) -> f32 {
+    let center = t.floor() as i64;
+    let half_width = half_width as i64;
+
+    let mut acc = 0.0f64;
+    let mut weight_sum = 0.0f64;
+
+    for k in (center - half_width + 1)..=(center + half_width) {
+        let x = t - k as f64;
+        let u = x / half_width as f64;
+        if u.abs() >= 1.0 {
+            continue;
+        }
+
+        let window = 0.42
+            + 0.5 * (std::f64::consts::PI * u).cos()
+            + 0.08 * (2.0 * std::f64::consts::PI * u).cos();
+        let arg = std::f64::consts::PI * cutoff * x;
+        let sinc = if arg.abs() < 1e-9 { 1.0 } else { arg.sin() / arg };
+        let weight = cutoff * sinc * window;
+
+        acc += sample_at(samples, k) as f64 * weight;
+        weight_sum += weight;
+    }
+
+    if weight_sum.abs() < 1e-12 {
+        0.0
+    } else {
+        (acc / weight_sum) as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Config, MFSKDemodulator, MFSKModulator, TransmissionMode};
+
+    #[test]
+    fn test_stream_error_ends_wait() {
+        let queue: SharedQueue = Arc::new((Mutex::new(PlaybackQueue::default()), Condvar::new()));
+        let errors = SharedError::default();
+        let ticket = queue.0.lock().unwrap().push(vec![0.5; 48000]);
+
+        // Nothing drains the queue, as when the device has gone away.
+        let waiter = std::thread::spawn({
+            let (queue, errors) = (Arc::clone(&queue), Arc::clone(&errors));
+            move || wait_played(&queue, ticket, &errors)
+        });
+        std::thread::sleep(Duration::from_millis(20));
+        let mut on_error = capture_errors(&errors, "output");
+        on_error(cpal::StreamError::DeviceNotAvailable);
+        on_error(cpal::StreamError::BackendSpecific {
+            err: cpal::BackendSpecificError {
+                description: "later".into(),
+            },
+        });
+
+        let result = waiter.join().unwrap();
+        assert!(
+            matches!(&result, Err(SonicPipeError::AudioDevice(message)) if message.contains("no longer available")),
+            "{:?}",
+            result
+        );
+        // The first error is the one reported; it is consumed, and the
+        // abandoned buffer no longer holds up later tickets.
+        assert!(check_stream(&errors).is_ok());
+        assert!(queue.0.lock().unwrap().is_played(ticket));
+    }
+
+    #[test]
+    fn test_playback_queue_drains_in_order() {
+        let mut queue = PlaybackQueue::default();
+        let first = queue.push(vec![1.0, 2.0, 3.0]);
+        let second = queue.push(vec![4.0, 5.0]);
+        assert_eq!((first, second), (3, 5));
+
+        // Callback blocks don't line up with buffer boundaries.
+        let mut out = [9.0f32; 2];
+        queue.fill(&mut out);
+        assert_eq!(out, [1.0, 2.0]);
+        assert!(!queue.is_played(first));
+
+        queue.fill(&mut out);
+        assert_eq!(out, [3.0, 4.0]);
+        assert!(queue.is_played(first) && !queue.is_played(second));
+
+        // An underrun pads with silence.
+        let mut out = [9.0f32; 3];
+        queue.fill(&mut out);
+        assert_eq!(out, [5.0, 0.0, 0.0]);
+        assert!(queue.is_played(second));
+
+        // Buffers queued later play straight after, from the same stream.
+        let third = queue.push(vec![6.0]);
+        queue.fill(&mut out);
+        assert_eq!(out, [6.0, 0.0, 0.0]);
+        assert!(queue.is_played(third));
+        assert!(queue.buffers.is_empty());
+    }
+
+    #[test]
+    fn test_estimated_output_latency() {
+        let config = |buffer_size| StreamConfig {
+            channels: 1,
+            sample_rate: cpal::SampleRate(48000),
+            buffer_size,
+        };
+
+        assert_eq!(estimated_latency(&config(cpal::BufferSize::Fixed(960))), Duration::from_millis(20));
+        assert_eq!(
+            estimated_latency(&config(cpal::BufferSize::Default)),
+            Duration::from_millis(DEFAULT_OUTPUT_LATENCY_MS)
+        );
+    }
+
+    #[test]
+    fn test_resample_length_and_identity() {
+        let samples: Vec<f32> = (0..44100).map(|i| (i as f32 * 0.01).sin()).collect();
+        assert_eq!(resample_to(&samples, 48000, 48000), samples);
+        assert_eq!(resample_to(&samples, 44100, 48000).len(), 48000);
+        assert_eq!(resample_with_quality(&samples, 44100, 48000, ResampleQuality::Cubic).len(), 48000);
+    }
+
+    #[test]
+    fn test_resampled_ultrasonic_recording_decodes() {
+        let capture_config = Config {
+            mode: TransmissionMode::Ultrasonic,
+            sample_rate: 44100,
+            ..Default::default()
+        };
+        let modulator = MFSKModulator::new(capture_config);
+        let data = vec![0x0F, 0xF0, 0x5A, 0xA5];
+        let recording = modulator.modulate(&data).unwrap();
+
+        let config = Config {
+            mode: TransmissionMode::Ultrasonic,
+            ..Default::default()
+        };
+        let mut demodulator = MFSKDemodulator::new(config);
+
+        for quality in [ResampleQuality::default(), ResampleQuality::Cubic] {
+            let resampled = resample_with_quality(&recording, 44100, 48000, quality);
+            assert_eq!(demodulator.demodulate(&resampled), Some(data.clone()), "{:?}", quality);
+        }
+    }
+
+    #[test]
+    fn test_cancel_echo_of_own_transmission() {
+        let config = Config::default();
+        let modulator = MFSKModulator::new(config.clone());
+        let own = modulator.modulate(b"our own outgoing frame").unwrap();
+        let remote_data = b"the reply".to_vec();
+        let remote = modulator.modulate(&remote_data).unwrap();
+
+        // Our echo arrives attenuated and late, and its tail runs into the reply.
+        let delay = 2400;
+        let mut recording = vec![0.0f32; delay + own.len() + 4000];
+        for (i, &s) in own.iter().enumerate() {
+            recording[delay + i] += 0.6 * s;
+        }
+        let remote_start = delay + own.len() - 2400;
+        for (i, &s) in remote.iter().enumerate() {
+            if remote_start + i < recording.len() {
+                recording[remote_start + i] += s;
+            } else {
+                recording.push(s);
+            }
+        }
+
+        let mut demodulator = MFSKDemodulator::new(config);
+        assert_ne!(demodulator.demodulate(&recording), Some(remote_data.clone()));
+
+        let cleaned = cancel_echo(&recording, &own);
+        assert_eq!(demodulator.demodulate(&cleaned), Some(remote_data));
+
+        // A reference that isn't in the recording leaves it alone.
+        let unrelated: Vec<f32> = (0..4800).map(|i| ((i * 7919) % 200) as f32 / 100.0 - 1.0).collect();
+        assert_eq!(cancel_echo(&remote, &unrelated), remote);
+    }
+
+    #[test]
+    fn test_echo_canceller_matches_cancel_echo() {
+        let modulator = MFSKModulator::new(Config::default());
+        let own = modulator.modulate(b"our own outgoing frame").unwrap();
+        let mut recording = vec![0.0f32; 2400];
+        recording.extend(own.iter().map(|s| 0.6 * s));
+        recording.extend(modulator.modulate(b"the reply").unwrap());
+
+        let whole = cancel_echo(&recording, &own);
+        let mut canceller = EchoCanceller::new(&own, 48000);
+        for block in recording.chunks(2400) {
+            canceller.push(block);
+            let cleaned = canceller.cleaned();
+            assert!(cleaned.is_empty() || cleaned.len() >= 24000);
+            for (a, b) in cleaned.iter().zip(&whole) {
+                assert!((a - b).abs() < 1e-3, "{} vs {}", a, b);
+            }
+        }
+        assert_eq!(canceller.cleaned().len(), recording.len());
+
+        // A recording too short for the fit is cleaned when it's finished.
+        let mut short = EchoCanceller::new(&own, 48000);
+        short.push(&recording[..12000]);
+        assert!(short.cleaned().is_empty());
+        let finished = short.finish();
+        let expected = cancel_echo(&recording[..12000], &own);
+        assert_eq!(finished, expected);
+    }
+
+    #[test]
+    fn test_cancel_echo_when_recording_starts_mid_playback() {
+        let modulator = MFSKModulator::new(Config::default());
+        let own = modulator.modulate(b"already playing").unwrap();
+
+        let recording: Vec<f32> = own[3000..].iter().map(|s| 0.5 * s).collect();
+        let cleaned = cancel_echo(&recording, &own);
+        let residual = cleaned.iter().fold(0.0f32, |a, s| a.max(s.abs()));
+        assert!(residual < 1e-3, "residual {}", residual);
+    }
+
+    #[test]
+    fn test_wake_timeout_only_applies_before_wake_up() {
+        let limits = RecordLimits::new(1_000, 60_000);
+
+        assert!(!limits.should_stop(500, RecordStatus::Waiting).unwrap());
+        assert!(matches!(limits.should_stop(1_001, RecordStatus::Waiting), Err(SonicPipeError::Timeout)));
+
+        // A long transfer keeps recording past the wake timeout.
+        assert!(!limits.should_stop(30_000, RecordStatus::Receiving).unwrap());
+        assert!(limits.should_stop(30_000, RecordStatus::Complete).unwrap());
+    }
+
+    #[test]
+    fn test_max_record_caps_a_running_transfer() {
+        let limits = RecordLimits::new(1_000, 60_000);
+
+        assert!(limits.should_stop(60_001, RecordStatus::Receiving).unwrap());
+        // Silence past both limits is still reported as a timeout.
+        assert!(limits.should_stop(60_001, RecordStatus::Waiting).is_err());
+    }
+}
@@ -1,23 +1,292 @@
 use crate::error::{Result, SonicPipeError};
+use crate::protocol::FLAG_ECC_REPETITION;
 use lz4_flex::{compress_prepend_size, decompress_size_prepended};
 use reed_solomon_erasure::galois_8::ReedSolomon;
+use serde::{Deserialize, Serialize};
 
 pub const ECC_DATA_SHARDS: usize = 8;
 pub const ECC_PARITY_SHARDS: usize = 4;
+pub const ECC_REPETITIONS: usize = 3;
+// Data length and shard size, big-endian, ahead of the shards.
+pub const ECC_GEOMETRY_LEN: usize = 8;
+
+pub const COMPRESSION_LZ4: u8 = 0;
+pub const COMPRESSION_NONE: u8 = 1;
+pub const COMPRESSION_CUSTOM: u8 = 2;
 
 pub fn compress(data: &[u8]) -> Vec<u8> {
     compress_prepend_size(data)
 }
 
+// The most `compress` can turn `len` bytes into: LZ4's worst-case bound for
+// data it can't shrink, plus the 4-byte size prefix.
+pub fn max_compressed_len(len: usize) -> usize {
+    4 + len + len / 255 + 16
+}
+
 pub fn decompress(data: &[u8]) -> Result<Vec<u8>> {
     decompress_size_prepended(data)
         .map_err(|e| SonicPipeError::Compression(e.to_string()))
 }
 
+pub trait Compressor {
+    fn id(&self) -> u8 {
+        COMPRESSION_CUSTOM
+    }
+
+    fn compress(&self, data: &[u8]) -> Vec<u8>;
+
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>>;
+}
+
+pub struct Lz4Compressor;
+
+impl Compressor for Lz4Compressor {
+    fn id(&self) -> u8 {
+        COMPRESSION_LZ4
+    }
+
+    fn compress(&self, data: &[u8]) -> Vec<u8> {
+        compress(data)
+    }
+
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        decompress(data)
+    }
+}
+
+pub struct NoCompression;
+
+impl Compressor for NoCompression {
+    fn id(&self) -> u8 {
+        COMPRESSION_NONE
+    }
+
+    fn compress(&self, data: &[u8]) -> Vec<u8> {
+        data.to_vec()
+    }
+
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        Ok(data.to_vec())
+    }
+}
+
+pub fn resolve_compressor(id: u8, custom: &dyn Compressor) -> Result<&dyn Compressor> {
+    if id == custom.id() {
+        return Ok(custom);
+    }
+
+    match id {
+        COMPRESSION_LZ4 => Ok(&Lz4Compressor),
+        COMPRESSION_NONE => Ok(&NoCompression),
+        _ => Err(SonicPipeError::Compression(format!("Unknown compressor id {}", id))),
+    }
+}
+
+pub const FRAGMENT_HEADER_SIZE: usize = 4;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fragment {
+    pub index: u16,
+    pub count: u16,
+    pub data: Vec<u8>,
+}
+
+// Splits `data` before compressing, so each fragment is compressed on its
+// own and can be decompressed without the others. Fragments are produced
+// lazily to keep memory bounded for large inputs.
+pub fn compress_fragments<'a>(
+    data: &'a [u8],
+    fragment_size: usize,
+    compressor: &'a dyn Compressor,
+) -> Result<impl Iterator<Item = Vec<u8>> + 'a> {
+    if fragment_size == 0 {
+        return Err(SonicPipeError::Compression("Fragment size must be non-zero".into()));
+    }
+
+    let count = data.len().div_ceil(fragment_size).max(1);
+    if count > u16::MAX as usize {
+        return Err(SonicPipeError::Compression(format!("Too many fragments: {}", count)));
+    }
+
+    let chunks: Box<dyn Iterator<Item = &[u8]>> = if data.is_empty() {
+        Box::new(std::iter::once(data))
+    } else {
+        Box::new(data.chunks(fragment_size))
+    };
+
+    Ok(chunks.enumerate().map(move |(index, chunk)| {
+        let compressed = compressor.compress(chunk);
+        let mut fragment = Vec::with_capacity(FRAGMENT_HEADER_SIZE + compressed.len());
+        fragment.extend_from_slice(&(index as u16).to_be_bytes());
+        fragment.extend_from_slice(&(count as u16).to_be_bytes());
+        fragment.extend_from_slice(&compressed);
+        fragment
+    }))
+}
+
+pub fn decompress_fragment(fragment: &[u8], compressor: &dyn Compressor) -> Result<Fragment> {
+    if fragment.len() < FRAGMENT_HEADER_SIZE {
+        return Err(SonicPipeError::Compression("Fragment too short".into()));
+    }
+
+    let index = u16::from_be_bytes([fragment[0], fragment[1]]);
+    let count = u16::from_be_bytes([fragment[2], fragment[3]]);
+    if index >= count {
+        return Err(SonicPipeError::Compression(format!("Fragment index {} out of {}", index, count)));
+    }
+
+    Ok(Fragment {
+        index,
+        count,
+        data: compressor.decompress(&fragment[FRAGMENT_HEADER_SIZE..])?,
+    })
+}
+
+// Joins fragments received in any order, rejecting duplicates, gaps and
+// fragments that disagree on the total.
+pub fn reassemble_fragments(mut fragments: Vec<Fragment>) -> Result<Vec<u8>> {
+    let count = match fragments.first() {
+        Some(fragment) => fragment.count,
+        None => return Err(SonicPipeError::Decoding("No fragments".into())),
+    };
+
+    fragments.sort_by_key(|f| f.index);
+
+    if fragments.len() != count as usize
+        || fragments.iter().enumerate().any(|(i, f)| f.index as usize != i || f.count != count)
+    {
+        return Err(SonicPipeError::Decoding(format!(
+            "Incomplete fragment set: got {} of {}",
+            fragments.len(),
+            count
+        )));
+    }
+
+    Ok(fragments.into_iter().flat_map(|f| f.data).collect())
+}
+
+// How a frame's payload is protected. Reed-Solomon's 8-byte header and
+// twelve shards swamp a payload of a few bytes; the repetition code instead
+// sends the payload three times and takes a bitwise majority, which repairs
+// any damage confined to one copy for twice the payload in overhead.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EccScheme {
+    #[default]
+    ReedSolomon,
+    Repetition,
+}
+
+impl EccScheme {
+    pub fn from_flags(flags: u8) -> Self {
+        if flags & FLAG_ECC_REPETITION != 0 {
+            EccScheme::Repetition
+        } else {
+            EccScheme::ReedSolomon
+        }
+    }
+
+    pub fn flag(&self) -> u8 {
+        match self {
+            EccScheme::ReedSolomon => 0,
+            EccScheme::Repetition => FLAG_ECC_REPETITION,
+        }
+    }
+
+    // `alignment` only applies to Reed-Solomon; see `with_alignment`.
+    pub fn encoded_len(&self, data_len: usize, alignment: usize) -> usize {
+        self.encoded_len_with(data_len, &ReedSolomonCodec::default().with_alignment(alignment))
+    }
+
+    pub fn encode(&self, data: &[u8], alignment: usize) -> Result<Vec<u8>> {
+        self.encode_with(data, &ReedSolomonCodec::new()?.with_alignment(alignment))
+    }
+
+    pub fn decode(&self, encoded: &[u8]) -> Result<Vec<u8>> {
+        self.decode_with(encoded, &ReedSolomonCodec::new()?)
+    }
+
+    // Like `decode`, but also reports how much of the code's correcting
+    // power the data needed, from 0 (arrived intact) to 1 (at the limit):
+    // the share of locatable Reed-Solomon shards rebuilt, or of bytes whose
+    // copies disagreed.
+    pub fn decode_with_load(&self, encoded: &[u8]) -> Result<(Vec<u8>, f32)> {
+        self.decode_with_load_using(encoded, &ReedSolomonCodec::new()?)
+    }
+
+    // The `_with` and `_using` variants take their Reed-Solomon settings,
+    // such as alignment and protected geometry, from `codec`; the repetition
+    // code ignores it.
+    pub fn encoded_len_with(&self, data_len: usize, codec: &ReedSolomonCodec) -> usize {
+        match self {
+            EccScheme::ReedSolomon => codec.encoded_len(data_len),
+            EccScheme::Repetition => ECC_REPETITIONS * data_len,
+        }
+    }
+
+    pub fn encode_with(&self, data: &[u8], codec: &ReedSolomonCodec) -> Result<Vec<u8>> {
+        match self {
+            EccScheme::ReedSolomon => codec.encode(data),
+            EccScheme::Repetition => Ok(data.repeat(ECC_REPETITIONS)),
+        }
+    }
+
+    pub fn decode_with(&self, encoded: &[u8], codec: &ReedSolomonCodec) -> Result<Vec<u8>> {
+        match self {
+            EccScheme::ReedSolomon => codec.decode(encoded),
+            EccScheme::Repetition => repetition_decode(encoded),
+        }
+    }
+
+    pub fn decode_with_load_using(&self, encoded: &[u8], codec: &ReedSolomonCodec) -> Result<(Vec<u8>, f32)> {
+        match self {
+            EccScheme::ReedSolomon => {
+                let (data, stats) = codec.decode_verbose(encoded)?;
+                if stats.uncorrectable {
+                    return Err(SonicPipeError::ErrorCorrection("Too many corrupted shards".into()));
+                }
+                Ok((data, stats.shards_reconstructed as f32 / (ECC_PARITY_SHARDS / 2) as f32))
+            }
+            EccScheme::Repetition => {
+                let data = repetition_decode(encoded)?;
+                let len = data.len();
+                let disagreed = (0..len)
+                    .filter(|&i| encoded[i] != encoded[len + i] || encoded[i] != encoded[2 * len + i])
+                    .count();
+                Ok((data, disagreed as f32 / len.max(1) as f32))
+            }
+        }
+    }
+}
+
+// Bitwise two-out-of-three vote across the copies.
+fn repetition_decode(encoded: &[u8]) -> Result<Vec<u8>> {
+    if encoded.len() % ECC_REPETITIONS != 0 {
+        return Err(SonicPipeError::ErrorCorrection(format!(
+            "{} bytes is not {} whole copies",
+            encoded.len(),
+            ECC_REPETITIONS
+        )));
+    }
+
+    let len = encoded.len() / ECC_REPETITIONS;
+    let (a, rest) = encoded.split_at(len);
+    let (b, c) = rest.split_at(len);
+    Ok((0..len).map(|i| (a[i] & b[i]) | (a[i] & c[i]) | (b[i] & c[i])).collect())
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EccStats {
+    pub shards_reconstructed: usize,
+    pub uncorrectable: bool,
+}
+
 pub struct ReedSolomonCodec {
     rs: ReedSolomon,
     data_shards: usize,
     parity_shards: usize,
+    alignment: usize,
+    protected_geometry: bool,
 }
 
 impl ReedSolomonCodec {
@@ -29,11 +298,59 @@ impl ReedSolomonCodec {
             rs,
             data_shards: ECC_DATA_SHARDS,
             parity_shards: ECC_PARITY_SHARDS,
+            alignment: 1,
+            protected_geometry: false,
         })
     }
 
+    // Pads the data up to a multiple of `alignment` bytes before splitting
+    // it into shards. A multiple of the data shard count keeps the last data
+    // shard from being mostly padding, and any alignment makes shard sizes
+    // step predictably. The true length is still sent, so the decoder
+    // doesn't need to know the alignment.
+    pub fn with_alignment(mut self, alignment: usize) -> Self {
+        self.alignment = alignment.max(1);
+        self
+    }
+
+    // Sends the geometry header three times and takes a bitwise majority
+    // vote on it. The shards can repair each other, but nothing repairs the
+    // header, and one flipped bit in it loses the whole block. The decoder
+    // has to be told, which the packet's `FLAG_PROTECTED_GEOMETRY` does.
+    pub fn with_protected_geometry(mut self, protected: bool) -> Self {
+        self.protected_geometry = protected;
+        self
+    }
+
+    fn header_len(&self) -> usize {
+        if self.protected_geometry {
+            ECC_REPETITIONS * ECC_GEOMETRY_LEN
+        } else {
+            ECC_GEOMETRY_LEN
+        }
+    }
+
+    pub fn shard_size(&self, data_len: usize) -> usize {
+        data_len.next_multiple_of(self.alignment).div_ceil(self.data_shards)
+    }
+
+    // Zero bytes added to fill out the data shards.
+    pub fn padding_len(&self, data_len: usize) -> usize {
+        self.data_shards * self.shard_size(data_len) - data_len
+    }
+
+    // Everything `encode` adds on top of the data: the length header,
+    // padding and parity shards.
+    pub fn overhead(&self, data_len: usize) -> usize {
+        self.encoded_len(data_len) - data_len
+    }
+
+    pub fn encoded_len(&self, data_len: usize) -> usize {
+        self.header_len() + (self.data_shards + self.parity_shards) * self.shard_size(data_len)
+    }
+
     pub fn encode(&self, data: &[u8]) -> Result<Vec<u8>> {
-        let shard_size = (data.len() + self.data_shards - 1) / self.data_shards;
+        let shard_size = self.shard_size(data.len());
         let total_shards = self.data_shards + self.parity_shards;
 
         let mut shards: Vec<Vec<u8>> = Vec::with_capacity(total_shards);
@@ -58,9 +375,16 @@ impl ReedSolomonCodec {
             .encode(&mut shards)
             .map_err(|e| SonicPipeError::ErrorCorrection(e.to_string()))?;
 
-        let mut result = Vec::with_capacity(4 + data.len() + total_shards * shard_size);
-        result.extend_from_slice(&(data.len() as u32).to_be_bytes());
-        result.extend_from_slice(&(shard_size as u32).to_be_bytes());
+        let mut geometry = Vec::with_capacity(ECC_GEOMETRY_LEN);
+        geometry.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        geometry.extend_from_slice(&(shard_size as u32).to_be_bytes());
+
+        let mut result = Vec::with_capacity(self.header_len() + total_shards * shard_size);
+        if self.protected_geometry {
+            result.extend(geometry.repeat(ECC_REPETITIONS));
+        } else {
+            result.extend(geometry);
+        }
 
         for shard in shards {
             result.extend_from_slice(&shard);
@@ -70,43 +394,115 @@ impl ReedSolomonCodec {
     }
 
     pub fn decode(&self, encoded: &[u8]) -> Result<Vec<u8>> {
-        if encoded.len() < 8 {
+        let (data, stats) = self.decode_verbose(encoded)?;
+        if stats.uncorrectable {
+            return Err(SonicPipeError::ErrorCorrection("Too many corrupted shards".into()));
+        }
+        Ok(data)
+    }
+
+    // Like `decode`, but reports how many shards had to be rebuilt. When the
+    // damage is beyond correction the data shards are returned as received.
+    pub fn decode_verbose(&self, encoded: &[u8]) -> Result<(Vec<u8>, EccStats)> {
+        let header_len = self.header_len();
+        if encoded.len() < header_len {
             return Err(SonicPipeError::ErrorCorrection("Data too short".into()));
         }
 
-        let original_len = u32::from_be_bytes([encoded[0], encoded[1], encoded[2], encoded[3]]) as usize;
-        let shard_size = u32::from_be_bytes([encoded[4], encoded[5], encoded[6], encoded[7]]) as usize;
+        let geometry = if self.protected_geometry {
+            repetition_decode(&encoded[..header_len])?
+        } else {
+            encoded[..header_len].to_vec()
+        };
+        let original_len = u32::from_be_bytes([geometry[0], geometry[1], geometry[2], geometry[3]]) as usize;
+        let shard_size = u32::from_be_bytes([geometry[4], geometry[5], geometry[6], geometry[7]]) as usize;
 
         let total_shards = self.data_shards + self.parity_shards;
-        let expected_len = 8 + total_shards * shard_size;
+        let expected_len = header_len + total_shards * shard_size;
 
         if encoded.len() < expected_len {
             return Err(SonicPipeError::ErrorCorrection("Incomplete data".into()));
         }
 
-        let mut shards: Vec<Option<Vec<u8>>> = Vec::with_capacity(total_shards);
-        for i in 0..total_shards {
-            let start = 8 + i * shard_size;
-            let end = start + shard_size;
-            shards.push(Some(encoded[start..end].to_vec()));
-        }
+        let shards: Vec<Vec<u8>> = (0..total_shards)
+            .map(|i| encoded[header_len + i * shard_size..header_len + (i + 1) * shard_size].to_vec())
+            .collect();
 
-        self.rs
-            .reconstruct(&mut shards)
-            .map_err(|e| SonicPipeError::ErrorCorrection(e.to_string()))?;
+        let (shards, stats) = match self.locate_and_repair(&shards)? {
+            Some((repaired, shards_reconstructed)) => (
+                repaired,
+                EccStats {
+                    shards_reconstructed,
+                    uncorrectable: false,
+                },
+            ),
+            None => (
+                shards,
+                EccStats {
+                    shards_reconstructed: 0,
+                    uncorrectable: true,
+                },
+            ),
+        };
 
         let mut result = Vec::with_capacity(original_len);
-        for shard in shards.iter().take(self.data_shards) {
-            if let Some(data) = shard {
-                result.extend_from_slice(data);
-            }
+        for data in shards.iter().take(self.data_shards) {
+            result.extend_from_slice(data);
         }
 
         result.truncate(original_len);
-        Ok(result)
+        Ok((result, stats))
+    }
+
+    // The shards carry no checksums of their own, so corrupted ones are found
+    // by erasing ever larger sets until the rebuilt codeword verifies. Up to
+    // half the parity shards can be located this way.
+    fn locate_and_repair(&self, shards: &[Vec<u8>]) -> Result<Option<(Vec<Vec<u8>>, usize)>> {
+        let map_err = |e: reed_solomon_erasure::Error| SonicPipeError::ErrorCorrection(e.to_string());
+
+        if self.rs.verify(shards).map_err(map_err)? {
+            return Ok(Some((shards.to_vec(), 0)));
+        }
+
+        for erased in 1..=self.parity_shards / 2 {
+            let mut erasures: Vec<usize> = (0..erased).collect();
+            loop {
+                let mut trial: Vec<Option<Vec<u8>>> = shards
+                    .iter()
+                    .enumerate()
+                    .map(|(i, shard)| (!erasures.contains(&i)).then(|| shard.clone()))
+                    .collect();
+                self.rs.reconstruct(&mut trial).map_err(map_err)?;
+
+                let repaired: Vec<Vec<u8>> = trial.into_iter().flatten().collect();
+                if self.rs.verify(&repaired).map_err(map_err)? {
+                    return Ok(Some((repaired, erased)));
+                }
+
+                if !next_combination(&mut erasures, shards.len()) {
+                    break;
+                }
+            }
+        }
+
+        Ok(None)
     }
 }
 
+// Steps `indices`, an ascending choice from `0..n`, on to the next choice in
+// lexicographic order, or returns false once it was the last.
+fn next_combination(indices: &mut [usize], n: usize) -> bool {
+    let k = indices.len();
+    let Some(i) = (0..k).rev().find(|&i| indices[i] < n - k + i) else {
+        return false;
+    };
+    indices[i] += 1;
+    for j in i + 1..k {
+        indices[j] = indices[j - 1] + 1;
+    }
+    true
+}
+
 impl Default for ReedSolomonCodec {
     fn default() -> Self {
         Self::new().expect("Failed to create Reed-Solomon codec")
@@ -125,6 +521,24 @@ mod tests {
         assert_eq!(decompressed, data);
     }
 
+    #[test]
+    fn test_resolve_compressor() {
+        struct Custom;
+        impl Compressor for Custom {
+            fn compress(&self, data: &[u8]) -> Vec<u8> {
+                data.to_vec()
+            }
+            fn decompress(&self, data: &[u8]) -> Result<Vec<u8>> {
+                Ok(data.to_vec())
+            }
+        }
+
+        assert_eq!(resolve_compressor(COMPRESSION_LZ4, &Custom).unwrap().id(), COMPRESSION_LZ4);
+        assert_eq!(resolve_compressor(COMPRESSION_NONE, &Custom).unwrap().id(), COMPRESSION_NONE);
+        assert_eq!(resolve_compressor(COMPRESSION_CUSTOM, &Custom).unwrap().id(), COMPRESSION_CUSTOM);
+        assert!(resolve_compressor(COMPRESSION_CUSTOM, &Lz4Compressor).is_err());
+    }
+
     #[test]
     fn test_reed_solomon_roundtrip() {
         let codec = ReedSolomonCodec::new().unwrap();
@@ -133,4 +547,176 @@ mod tests {
         let decoded = codec.decode(&encoded).unwrap();
         assert_eq!(decoded, data);
     }
+
+    // Pins the ECC block layout: big-endian data length and shard size, then
+    // the data shards and the parity shards.
+    #[test]
+    fn test_reed_solomon_golden_bytes() {
+        let codec = ReedSolomonCodec::default();
+        assert_eq!(
+            codec.encode(b"ABCDEFGH").unwrap(),
+            [
+                0x00, 0x00, 0x00, 0x08, // data length
+                0x00, 0x00, 0x00, 0x01, // shard size
+                0x41, 0x42, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48, // 8 data shards
+                0x21, 0xC4, 0xBC, 0xED, // 4 parity shards
+            ]
+        );
+    }
+
+    #[test]
+    fn test_protected_geometry_survives_bit_error() {
+        let data: Vec<u8> = (0..40u8).collect();
+        let plain = ReedSolomonCodec::new().unwrap();
+        let protected = ReedSolomonCodec::new().unwrap().with_protected_geometry(true);
+
+        let encoded = protected.encode(&data).unwrap();
+        assert_eq!(encoded.len(), protected.encoded_len(data.len()));
+        assert_eq!(encoded.len(), plain.encoded_len(data.len()) + 2 * ECC_GEOMETRY_LEN);
+
+        // One flipped bit in the shard size throws the plain header's whole
+        // block out of step, but is outvoted in the protected one.
+        for bit in [0, 7] {
+            let mut corrupted = plain.encode(&data).unwrap();
+            corrupted[7] ^= 1 << bit;
+            assert!(plain.decode(&corrupted).map_or(true, |decoded| decoded != data));
+
+            let mut corrupted = encoded.clone();
+            corrupted[ECC_GEOMETRY_LEN + 7] ^= 1 << bit;
+            assert_eq!(protected.decode(&corrupted).unwrap(), data);
+        }
+
+        // Each copy of the length may take a hit, as long as no bit is hit twice.
+        let mut corrupted = encoded.clone();
+        for (copy, bit) in [(0, 0), (1, 3), (2, 6)] {
+            corrupted[copy * ECC_GEOMETRY_LEN + 3] ^= 1 << bit;
+        }
+        assert_eq!(protected.decode(&corrupted).unwrap(), data);
+    }
+
+    #[test]
+    fn test_next_combination_visits_every_choice() {
+        let mut indices = vec![0, 1];
+        let mut seen = vec![indices.clone()];
+        while next_combination(&mut indices, 4) {
+            seen.push(indices.clone());
+        }
+        assert_eq!(
+            seen,
+            [[0, 1], [0, 2], [0, 3], [1, 2], [1, 3], [2, 3]].map(Vec::from)
+        );
+
+        // No 64-bit mask could enumerate these.
+        let mut indices = vec![0, 1, 2];
+        let mut count = 0;
+        while next_combination(&mut indices, 70) {
+            count += 1;
+        }
+        assert_eq!((count + 1, indices), (70 * 69 * 68 / 6, vec![67, 68, 69]));
+    }
+
+    #[test]
+    fn test_repetition_repairs_one_bad_copy() {
+        let data = [0x12, 0x34, 0x56];
+        let scheme = EccScheme::Repetition;
+
+        let mut encoded = scheme.encode(&data, 1).unwrap();
+        assert_eq!(encoded.len(), scheme.encoded_len(data.len(), 1));
+        assert!(encoded.len() < EccScheme::ReedSolomon.encoded_len(data.len(), 1));
+
+        // Whole bytes wiped in different copies still vote out.
+        encoded[0] = 0xFF;
+        encoded[4] ^= 0x0F;
+        encoded[8] = 0x00;
+        assert_eq!(scheme.decode(&encoded).unwrap(), data);
+
+        // The same bit wrong in two copies is beyond it.
+        encoded[3] ^= 0x01;
+        encoded[6] ^= 0x01;
+        assert_ne!(scheme.decode(&encoded).unwrap(), data);
+        assert!(scheme.decode(&encoded[..8]).is_err());
+    }
+
+    #[test]
+    fn test_encoded_len_matches_encode() {
+        let codec = ReedSolomonCodec::new().unwrap();
+        for len in [1, 7, 8, 9, 100, 1000] {
+            let data = vec![0x5Au8; len];
+            assert_eq!(codec.encode(&data).unwrap().len(), codec.encoded_len(len));
+        }
+    }
+
+    #[test]
+    fn test_shard_alignment() {
+        let unaligned = ReedSolomonCodec::new().unwrap();
+        let aligned = ReedSolomonCodec::new().unwrap().with_alignment(32);
+
+        // Unaligned, 9 bytes need 2-byte shards: 7 bytes of padding.
+        let cases = [(1, 1, 4), (8, 1, 4), (9, 2, 4), (32, 4, 4), (33, 5, 8)];
+        for (len, unaligned_shard, aligned_shard) in cases {
+            assert_eq!(unaligned.shard_size(len), unaligned_shard, "{} bytes", len);
+            assert_eq!(aligned.shard_size(len), aligned_shard, "{} bytes", len);
+            assert!(aligned.padding_len(len) < 32);
+
+            let data: Vec<u8> = (0..len as u8).collect();
+            let encoded = aligned.encode(&data).unwrap();
+            assert_eq!(encoded.len(), aligned.encoded_len(len));
+            assert_eq!(aligned.overhead(len), encoded.len() - len);
+
+            // The decoder reads the geometry from the header.
+            assert_eq!(unaligned.decode(&encoded).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn test_decode_reports_reconstructed_shards() {
+        let codec = ReedSolomonCodec::new().unwrap();
+        let data: Vec<u8> = (0..64u8).collect();
+        let encoded = codec.encode(&data).unwrap();
+        let shard_size = codec.shard_size(data.len());
+
+        let (decoded, stats) = codec.decode_verbose(&encoded).unwrap();
+        assert_eq!(decoded, data);
+        assert_eq!(stats, EccStats::default());
+
+        let mut corrupted = encoded.clone();
+        corrupted[8 + shard_size] ^= 0xFF;
+        corrupted[8 + 9 * shard_size + 3] ^= 0x01;
+
+        let (decoded, stats) = codec.decode_verbose(&corrupted).unwrap();
+        assert_eq!(decoded, data);
+        assert_eq!(stats.shards_reconstructed, 2);
+        assert!(!stats.uncorrectable);
+
+        for shard in [0, 4, 10] {
+            corrupted[8 + shard * shard_size] ^= 0x55;
+        }
+        assert!(codec.decode_verbose(&corrupted).unwrap().1.uncorrectable);
+        assert!(codec.decode(&corrupted).is_err());
+    }
+
+    #[test]
+    fn test_fragments_decompress_independently_out_of_order() {
+        let data: Vec<u8> = (0..5000u32).map(|i| (i % 251) as u8 ^ (i / 97) as u8).collect();
+        let mut fragments: Vec<Vec<u8>> = compress_fragments(&data, 1024, &Lz4Compressor).unwrap().collect();
+        assert_eq!(fragments.len(), 5);
+
+        // Each fragment stands alone.
+        let third = decompress_fragment(&fragments[2], &Lz4Compressor).unwrap();
+        assert_eq!((third.index, third.count), (2, 5));
+        assert_eq!(third.data, &data[2048..3072]);
+
+        fragments.reverse();
+        fragments.swap(0, 3);
+        let decoded: Vec<Fragment> = fragments
+            .iter()
+            .map(|f| decompress_fragment(f, &Lz4Compressor).unwrap())
+            .collect();
+        assert_eq!(reassemble_fragments(decoded.clone()).unwrap(), data);
+
+        assert!(reassemble_fragments(decoded[1..].to_vec()).is_err());
+        let mut duplicated = decoded.clone();
+        duplicated[0] = decoded[1].clone();
+        assert!(reassemble_fragments(duplicated).is_err());
+    }
 }
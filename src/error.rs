@@ -5,6 +5,9 @@ pub enum SonicPipeError {
     #[error("Audio device error: {0}")]
     AudioDevice(String),
 
+    #[error("Invalid configuration: {0}")]
+    InvalidConfig(String),
+
     #[error("Encoding error: {0}")]
     Encoding(String),
 
@@ -20,15 +23,48 @@ pub enum SonicPipeError {
     #[error("Invalid packet: {0}")]
     InvalidPacket(String),
 
+    #[error("Unsupported protocol version {0}")]
+    UnsupportedVersion(u8),
+
     #[error("Checksum mismatch")]
     ChecksumMismatch,
 
     #[error("No wake-up tone detected")]
     NoWakeUpTone,
 
+    #[error("No signal detected; check the sender's mode and band match, or move closer")]
+    NoSignal,
+
+    #[error("Signal too weak ({snr_db:.1} dB SNR); move closer, raise the volume or cut background noise")]
+    SignalTooWeak { snr_db: f32 },
+
+    #[error("Frame corrupt ({0}); the signal was heard but damaged, try again or use a longer symbol duration")]
+    FrameCorrupt(String),
+
+    #[error(
+        "Collision ({:.0}% of symbols contested); another sender was transmitting at the same time, wait and retry",
+        .share * 100.0
+    )]
+    Collision { share: f32 },
+
+    #[error("Input clipped ({:.1}% of samples at full scale), lower the input gain", .0 * 100.0)]
+    InputClipped(f32),
+
+    #[error("Sample rate mismatch: expected {expected} Hz but the signal looks like {estimated:.0} Hz")]
+    SampleRateMismatch { expected: u32, estimated: f32 },
+
+    #[error("Symbol duration mismatch: expected {expected} ms but the signal looks like {estimated:.1} ms")]
+    SymbolDurationMismatch { expected: u32, estimated: f32 },
+
     #[error("Timeout waiting for data")]
     Timeout,
 
+    #[error("No acknowledgement after {attempts} attempts")]
+    NotAcknowledged { attempts: u32 },
+
+    #[error("WAV error: {0}")]
+    Wav(String),
+
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
 }
@@ -0,0 +1,188 @@
+use crate::error::{Result, SonicPipeError};
+use crate::modulation::{MFSKDemodulator, MFSKModulator};
+use crate::pipeline::{decode_from_samples, encode_to_samples};
+use crate::{Config, LOG_TARGET};
+
+// Faster symbol durations the probe tries after the mode's default one.
+pub const PROBE_SYMBOL_DURATIONS_MS: [u32; 3] = [30, 20, 10];
+
+// Covers every tone once so a rate only passes if all of them come through.
+const PROBE_PATTERN: [u8; 8] = [0x01, 0x23, 0x45, 0x67, 0x89, 0xAB, 0xCD, 0xEF];
+
+// Each probe is a raw frame, CRC-checked but without ECC, so a rate only
+// counts as clean if it needs no correction.
+fn probe_config(config: &Config, symbol_duration_ms: u32) -> Config {
+    Config {
+        symbol_duration_ms,
+        raw_frame: true,
+        compress: false,
+        ..config.clone()
+    }
+}
+
+// Symbol durations the probe tries, slowest first. The slowest is the
+// mode's default rate, so a channel that can't carry any of the faster ones
+// still lands somewhere that works.
+fn probe_symbol_durations(config: &Config) -> Vec<u32> {
+    let default = config.mode.default_symbol_duration_ms();
+    std::iter::once(default)
+        .chain(PROBE_SYMBOL_DURATIONS_MS.into_iter().filter(|&duration| duration < default))
+        .collect()
+}
+
+fn probe_payload(symbol_duration_ms: u32) -> Vec<u8> {
+    let mut payload = vec![symbol_duration_ms as u8];
+    payload.extend_from_slice(&PROBE_PATTERN);
+    payload
+}
+
+// One probe frame per candidate rate, `inter_frame_gap_ms` apart, along
+// with where each frame starts and how long it is.
+struct Probe {
+    samples: Vec<f32>,
+    spans: Vec<(usize, usize)>,
+}
+
+fn probe_frames(config: &Config) -> Result<Probe> {
+    let gap = vec![0.0f32; config.duration_samples(config.inter_frame_gap_ms)];
+    let mut samples = Vec::new();
+    let durations = probe_symbol_durations(config);
+    let mut spans = Vec::with_capacity(durations.len());
+
+    for (i, &duration) in durations.iter().enumerate() {
+        if i > 0 {
+            samples.extend_from_slice(&gap);
+        }
+        let frame = encode_to_samples(&probe_payload(duration), &probe_config(config, duration))?;
+        spans.push((samples.len(), frame.len()));
+        samples.extend(frame);
+    }
+
+    Ok(Probe { samples, spans })
+}
+
+// The probe a sender plays at the start of a connection.
+pub fn encode_rate_probe(config: &Config) -> Result<Vec<f32>> {
+    probe_frames(config).map(|probe| probe.samples)
+}
+
+// Length of the probe sequence, so a receiver knows how long to listen
+// after the first wake-up tone.
+pub fn rate_probe_samples(config: &Config) -> Result<usize> {
+    probe_frames(config).map(|probe| probe.samples.len())
+}
+
+// The fastest probed rate whose frame decoded intact, or None if even the
+// slowest failed. The frames are sliced out at the offsets the sender laid
+// them at, lined up on the first wake-up tone, since a frame at a rate too
+// fast for the channel can't be trusted to report its own length.
+pub fn evaluate_rate_probe(samples: &[f32], config: &Config) -> Result<Option<u32>> {
+    let probe = probe_frames(config)?;
+    let demodulator = MFSKDemodulator::new(config.clone());
+
+    let Some(reference_wake) = demodulator.detect_wake_up(&probe.samples) else {
+        return Err(SonicPipeError::NoWakeUpTone);
+    };
+    let Some(wake) = demodulator.detect_wake_up(samples) else {
+        return Ok(None);
+    };
+
+    let margin = config.duration_samples(config.inter_frame_gap_ms) / 2;
+    let mut fastest = None;
+
+    for (duration, &(offset, len)) in probe_symbol_durations(config).into_iter().zip(&probe.spans) {
+        let Some(start) = (wake + offset).checked_sub(reference_wake) else {
+            continue;
+        };
+        let from = start.saturating_sub(margin).min(samples.len());
+        let to = (start + len + margin).min(samples.len());
+
+        match decode_from_samples(&samples[from..to], &probe_config(config, duration)) {
+            Ok((payload, _)) if payload == probe_payload(duration) => {
+                fastest = Some(fastest.map_or(duration, |best: u32| best.min(duration)));
+            }
+            Ok(_) => log::debug!(target: LOG_TARGET, "{} ms probe decoded to the wrong pattern", duration),
+            Err(e) => log::debug!(target: LOG_TARGET, "{} ms probe did not decode: {}", duration, e),
+        }
+    }
+
+    Ok(fastest)
+}
+
+// The receiver's answer: the chosen symbol duration carried in an ACK, or a
+// NACK if no rate worked.
+pub fn encode_rate_reply(config: &Config, symbol_duration_ms: Option<u32>) -> Vec<f32> {
+    let modulator = MFSKModulator::new(config.clone());
+    match symbol_duration_ms {
+        Some(duration) => modulator.generate_ack_tone(duration as u16),
+        None => modulator.generate_nack_tone(0),
+    }
+}
+
+// Reads the receiver's answer. Only rates the probe offered are accepted.
+pub fn decode_rate_reply(samples: &[f32], config: &Config) -> Option<u32> {
+    let duration = MFSKDemodulator::new(config.clone()).detect_ack(samples)? as u32;
+    probe_symbol_durations(config).contains(&duration).then_some(duration)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::add_awgn;
+
+    #[test]
+    fn test_clean_channel_picks_fastest_rate() {
+        let config = Config::default();
+        let mut samples = vec![0.0f32; 7000];
+        samples.extend(encode_rate_probe(&config).unwrap());
+        samples.extend(vec![0.0f32; 7000]);
+
+        assert_eq!(evaluate_rate_probe(&samples, &config).unwrap(), Some(10));
+    }
+
+    #[test]
+    fn test_noisy_channel_falls_back_to_slower_rate() {
+        let config = Config::default();
+        let mut samples = vec![0.0f32; 7000];
+        samples.extend(encode_rate_probe(&config).unwrap());
+        samples.extend(vec![0.0f32; 7000]);
+        add_awgn(&mut samples, -9.0, 11);
+
+        let chosen = evaluate_rate_probe(&samples, &config).unwrap().expect("no rate decoded");
+        assert!(chosen > 10, "chose {} ms", chosen);
+
+        // The chosen rate carries real traffic over the same channel.
+        let rate_config = Config {
+            symbol_duration_ms: chosen,
+            ..config.clone()
+        };
+        let message = b"negotiated".to_vec();
+        let mut frame = encode_to_samples(&message, &rate_config).unwrap();
+        add_awgn(&mut frame, -9.0, 12);
+        assert_eq!(
+            decode_from_samples(&frame, &rate_config).unwrap().0,
+            message
+        );
+    }
+
+    #[test]
+    fn test_rate_reply_roundtrip() {
+        let config = Config::default();
+        assert_eq!(decode_rate_reply(&encode_rate_reply(&config, Some(20)), &config), Some(20));
+        assert_eq!(decode_rate_reply(&encode_rate_reply(&config, None), &config), None);
+    }
+
+    #[test]
+    fn test_telephony_probe_starts_at_its_default_rate() {
+        let telephony = Config::builder().telephony().build().unwrap();
+        let audible = Config::default();
+        assert_eq!(probe_symbol_durations(&telephony), vec![80, 30, 20, 10]);
+        assert_eq!(probe_symbol_durations(&audible), vec![50, 30, 20, 10]);
+
+        // A reply is only taken for a rate the probe offered.
+        let reply = encode_rate_reply(&telephony, Some(80));
+        assert_eq!(decode_rate_reply(&reply, &telephony), Some(80));
+        let reply = encode_rate_reply(&audible, Some(80));
+        assert_eq!(decode_rate_reply(&reply, &audible), None);
+    }
+}
@@ -3,6 +3,14 @@ pub mod modulation;
 pub mod audio;
 pub mod error;
 pub mod codec;
+pub mod pipeline;
+pub mod wav;
+pub mod vectors;
+pub mod session;
+pub mod handshake;
+pub mod receiver;
+pub mod timing;
+pub mod testing;
 
 #[cfg(target_arch = "wasm32")]
 pub mod wasm;
@@ -12,17 +20,73 @@ pub use modulation::*;
 pub use audio::*;
 pub use error::*;
 pub use codec::*;
+pub use pipeline::*;
+pub use wav::*;
+pub use vectors::*;
+pub use session::*;
+pub use handshake::*;
+pub use receiver::*;
+pub use timing::*;
+
+use serde::{Deserialize, Serialize};
+
+// Every library log record goes to this target, so an application can turn
+// sonic-pipe's diagnostics up or down on their own, e.g.
+// `RUST_LOG=sonic_pipe=debug`.
+pub const LOG_TARGET: &str = "sonic_pipe";
 
 pub const SAMPLE_RATE: u32 = 48000;
 pub const DEFAULT_SYMBOL_DURATION_MS: u32 = 50;
-pub const NUM_TONES: usize = 16;
+pub const DEFAULT_WAKE_GAP_MS: u32 = 20;
+pub const DEFAULT_BITS_PER_SYMBOL: u32 = 4;
+pub const MAX_BITS_PER_SYMBOL: u32 = 8;
+// Slots searched for tones outside the excluded ranges before giving up.
+const MAX_TONE_SLOTS: usize = 1 << 16;
+pub const NUM_TONES: usize = 1 << DEFAULT_BITS_PER_SYMBOL;
 pub const WAKE_UP_FREQUENCY: f32 = 18500.0;
+pub const ULTRASONIC_WAKE_UP_FREQUENCY: f32 = 19750.0;
+// Above the acoustic band rather than below it, where the second harmonic of
+// a tone under 3.6 kHz would land on one of the data tones.
+pub const ACOUSTIC_WAKE_UP_FREQUENCY: f32 = 7600.0;
+pub const TELEPHONY_WAKE_UP_FREQUENCY: f32 = 3200.0;
+// What `ConfigBuilder::telephony` sets up: symbols long enough that a voice
+// codec's smearing across its 20 ms frames leaves most of each one intact,
+// and cosine fades skipped on receipt.
+pub const TELEPHONY_SYMBOL_DURATION_MS: u32 = 80;
+pub const TELEPHONY_FADE_MS: u32 = 10;
+pub const QUIET_BASE_FREQUENCY: f32 = 18500.0;
+pub const QUIET_FREQUENCY_STEP: f32 = 100.0;
+pub const QUIET_WAKE_UP_FREQUENCY: f32 = 18000.0;
 pub const WAKE_UP_DURATION_MS: u32 = 100;
+pub const WAKE_REPEAT_GAP_MS: u32 = 20;
+pub const SHORT_FRAME_THRESHOLD: usize = 64;
+pub const SYNC_WORD_SYMBOLS: usize = 4;
+pub const DEFAULT_HEADER_SYMBOL_FACTOR: u32 = 2;
+pub const DEFAULT_FADE_MS: u32 = 5;
+pub const DEFAULT_INTER_FRAME_GAP_MS: u32 = 250;
+// Detection windows overlap by three quarters, so a blip of a few
+// milliseconds still lights up four or five of them in a row.
+pub const DEFAULT_WAKE_CONFIRM_WINDOWS: u32 = 6;
+// The packet header and the Reed-Solomon geometry after it (the first copy,
+// when it is protected) decide how everything else is read, and one bad bit
+// in either loses the frame, so these leading bytes go at the slower header
+// rate. The span is the same for every frame: the demodulator has to know it
+// before it has read the flags.
+pub const ROBUST_HEADER_BYTES: usize = protocol::HEADER_SIZE + codec::ECC_GEOMETRY_LEN;
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen::prelude::wasm_bindgen)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum TransmissionMode {
     Audible,
     Ultrasonic,
+    // 4-7 kHz, wake-up tone included under 8 kHz: well inside what ordinary
+    // speakers and microphones reproduce, and within a 16 kHz wideband
+    // voice channel.
+    Acoustic,
+    // 600-2850 Hz with the wake-up tone at 3.2 kHz, inside the 300-3400 Hz
+    // of a narrowband phone call. See `ConfigBuilder::telephony` for the rest
+    // of the setup a voice channel needs.
+    Telephony,
 }
 
 impl TransmissionMode {
@@ -30,6 +94,8 @@ impl TransmissionMode {
         match self {
             TransmissionMode::Audible => 1000.0,
             TransmissionMode::Ultrasonic => 17000.0,
+            TransmissionMode::Acoustic => 4000.0,
+            TransmissionMode::Telephony => 600.0,
         }
     }
 
@@ -37,16 +103,177 @@ impl TransmissionMode {
         match self {
             TransmissionMode::Audible => 100.0,
             TransmissionMode::Ultrasonic => 150.0,
+            TransmissionMode::Acoustic => 200.0,
+            TransmissionMode::Telephony => 150.0,
+        }
+    }
+
+    pub fn wake_up_frequency(&self) -> f32 {
+        match self {
+            TransmissionMode::Audible => WAKE_UP_FREQUENCY,
+            TransmissionMode::Ultrasonic => ULTRASONIC_WAKE_UP_FREQUENCY,
+            TransmissionMode::Acoustic => ACOUSTIC_WAKE_UP_FREQUENCY,
+            TransmissionMode::Telephony => TELEPHONY_WAKE_UP_FREQUENCY,
+        }
+    }
+
+    // The symbol duration the mode is set up with by default; see
+    // `ConfigBuilder::telephony` for why a voice channel needs longer ones.
+    pub fn default_symbol_duration_ms(&self) -> u32 {
+        match self {
+            TransmissionMode::Telephony => TELEPHONY_SYMBOL_DURATION_MS,
+            _ => DEFAULT_SYMBOL_DURATION_MS,
         }
     }
 }
 
-#[derive(Debug, Clone)]
+// Envelope applied at each tone's edges. The raised cosine splatters far less
+// energy into neighbouring tones than a linear ramp of the same length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FadeShape {
+    Linear,
+    Cosine,
+}
+
+impl FadeShape {
+    // Gain at `progress` (0..=1) through the fade-in.
+    pub fn gain(&self, progress: f32) -> f32 {
+        let progress = progress.clamp(0.0, 1.0);
+        match self {
+            FadeShape::Linear => progress,
+            FadeShape::Cosine => 0.5 - 0.5 * (std::f32::consts::PI * progress).cos(),
+        }
+    }
+}
+
+// How the demodulator measures the tones in a symbol window. Both read the
+// DFT bin nearest each tone, so they make the same decisions; the FFT reads
+// every bin in one pass, which pays off as the tone count grows.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SymbolDetector {
+    #[default]
+    Goertzel,
+    Fft,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ToneBand {
+    pub base_frequency: f32,
+    pub frequency_step: f32,
+}
+
+// A steady tone summed under the data at `level` of the volume, outside the
+// data band, that the receiver tracks to follow a drifting frequency offset.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct PilotTone {
+    pub frequency: f32,
+    pub level: f32,
+}
+
+// Payload size `Config::throughput_report` assumes.
+pub const THROUGHPUT_REPORT_PAYLOAD_BYTES: usize = 100;
+
+// What a frame of `payload_bytes` costs on air under a config, assuming
+// the payload doesn't compress. `gross_bps` is the symbol rate times the
+// bits per symbol; `net_bps` is the payload over the whole frame. The
+// overheads are percentages of the frame's airtime, and whatever they leave
+// carries the payload.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ThroughputReport {
+    pub payload_bytes: usize,
+    pub symbol_rate: f32,
+    pub bits_per_symbol: u32,
+    pub gross_bps: f32,
+    pub net_bps: f32,
+    pub frame_ms: f32,
+    // lz4's size prefix.
+    pub compression_overhead_percent: f32,
+    // Parity, padding and the Reed-Solomon header, or the extra copies.
+    pub ecc_overhead_percent: f32,
+    // Everything else: wake-up tones and gaps, sync word, length prefix,
+    // packet header sent at the slower header rate, and checksum.
+    pub framing_overhead_percent: f32,
+}
+
+impl ThroughputReport {
+    pub fn payload_percent(&self) -> f32 {
+        100.0 - self.compression_overhead_percent - self.ecc_overhead_percent - self.framing_overhead_percent
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
     pub mode: TransmissionMode,
     pub symbol_duration_ms: u32,
+    // Skips this much of the start of each symbol window, where the tone is
+    // still fading in, when reading its tones. Receive side only.
+    pub symbol_settle_ms: u32,
+    // Each symbol is one of 2^bits_per_symbol tones. There is deliberately no
+    // separate tone count: a count that isn't a power of two still carries
+    // only floor(log2) bits per symbol, so its extra tones would take up band
+    // without carrying data. `excluded_frequencies` steps around noisy bins.
+    pub bits_per_symbol: u32,
+    pub wake_gap_ms: u32,
+    pub wake_up_repeats: u32,
+    // Consecutive wake windows needed before a wake-up counts as detected.
+    pub wake_confirm_windows: u32,
+    // Silence between back-to-back frames: long enough for output buffers
+    // to drain and room echo to die down. A frame whose trailing tone is
+    // lost ends where this much silence begins.
+    pub inter_frame_gap_ms: u32,
+    // Send every frame the way short frames go: a length prefix right after
+    // the wake-up tone, with no silence gap and no trailing wake-up tone.
+    // Both ends need it set.
+    pub length_prefixed: bool,
+    // Follow the wake-up tone with a fixed run of data symbols that the
+    // receiver checks before trusting the frame, so a stray tone near the
+    // wake-up frequency isn't taken for one. Both ends need it set.
+    pub sync_word: bool,
+    pub header_symbol_factor: u32,
+    pub compress: bool,
+    pub raw_frame: bool,
+    // Compressed payloads are padded to a multiple of this many bytes before
+    // ECC; 1 leaves them unpadded.
+    pub ecc_alignment: usize,
+    pub ecc_scheme: EccScheme,
+    // Sends the Reed-Solomon geometry header three times over; see
+    // `ReedSolomonCodec::with_protected_geometry`. The receiver learns of it
+    // from the packet flags.
+    pub protect_ecc_geometry: bool,
+    pub checksum: ChecksumKind,
     pub sample_rate: u32,
     pub volume: f32,
+    pub fade_ms: u32,
+    pub fade_shape: FadeShape,
+    // Start each tone of a multi-tone signal at a seeded pseudo-random phase
+    // instead of zero, which keeps the tones from peaking together.
+    pub phase_randomization: bool,
+    pub phase_seed: u64,
+    // Whitens frame bytes with `scramble`, its register seeded from this,
+    // before they are split into symbols. Runs of one byte value then still
+    // change tone, giving timing recovery transitions to track, and a
+    // constant payload doesn't sit on one tone. Both ends need the seed.
+    pub scrambler_seed: Option<u64>,
+    pub ultrasonic_quiet: bool,
+    // Overrides the mode's tone layout; the wake-up tone still follows the mode.
+    pub custom_band: Option<ToneBand>,
+    // Inclusive frequency ranges, in Hz, that no tone may land in. Tones that
+    // would are moved up to the next free slots of the band, so the tone
+    // count stays the same. Both ends need the same exclusions.
+    pub excluded_frequencies: Vec<(f32, f32)>,
+    // Both ends need the same pilot, if any.
+    pub pilot: Option<PilotTone>,
+    // Pre-emphasis: one amplitude multiplier per data tone, lowest first,
+    // to make up for a speaker or microphone that rolls off at the band
+    // edges. The wake-up tone is left alone. Send side only.
+    pub tone_gains: Option<Vec<f32>>,
+    pub end_detection_window_ms: u32,
+    pub end_detection_ratio: f32,
+    pub symbol_detector: SymbolDetector,
+    // When set, a data symbol whose strongest tone doesn't beat the runner-up
+    // by this ratio is marked as an erasure instead of decoded, so noise
+    // can't pass for data.
+    pub symbol_confidence_threshold: Option<f32>,
 }
 
 impl Default for Config {
@@ -54,8 +281,703 @@ impl Default for Config {
         Self {
             mode: TransmissionMode::Audible,
             symbol_duration_ms: DEFAULT_SYMBOL_DURATION_MS,
+            symbol_settle_ms: 0,
+            bits_per_symbol: DEFAULT_BITS_PER_SYMBOL,
+            wake_gap_ms: DEFAULT_WAKE_GAP_MS,
+            wake_up_repeats: 1,
+            wake_confirm_windows: DEFAULT_WAKE_CONFIRM_WINDOWS,
+            inter_frame_gap_ms: DEFAULT_INTER_FRAME_GAP_MS,
+            length_prefixed: false,
+            sync_word: false,
+            header_symbol_factor: DEFAULT_HEADER_SYMBOL_FACTOR,
+            compress: true,
+            raw_frame: false,
+            ecc_alignment: 1,
+            ecc_scheme: EccScheme::ReedSolomon,
+            protect_ecc_geometry: false,
+            checksum: ChecksumKind::Crc32,
             sample_rate: SAMPLE_RATE,
             volume: 0.5,
+            fade_ms: DEFAULT_FADE_MS,
+            fade_shape: FadeShape::Linear,
+            phase_randomization: false,
+            phase_seed: 0,
+            scrambler_seed: None,
+            ultrasonic_quiet: false,
+            custom_band: None,
+            excluded_frequencies: Vec::new(),
+            pilot: None,
+            tone_gains: None,
+            end_detection_window_ms: 500,
+            end_detection_ratio: 2.0,
+            symbol_detector: SymbolDetector::Goertzel,
+            symbol_confidence_threshold: None,
+        }
+    }
+}
+
+// Volume scales full-scale tones, so anything above 1.0 clips and a negative
+// value inverts them. Every path into the modulator goes through this.
+pub fn clamp_volume(volume: f32) -> f32 {
+    if volume.is_nan() {
+        0.0
+    } else {
+        volume.clamp(0.0, 1.0)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ConfigBuilder {
+    config: Config,
+}
+
+impl ConfigBuilder {
+    pub fn mode(mut self, mode: TransmissionMode) -> Self {
+        self.config.mode = mode;
+        self
+    }
+
+    // Telephony mode as it's meant to be used, over a phone call or voice
+    // chat: the codec band-limits, requantizes and smears the signal and
+    // drops the odd packet. Symbols are slowed to
+    // `TELEPHONY_SYMBOL_DURATION_MS` and read only once their fade-in is
+    // over, and the Reed-Solomon geometry is sent protected, since a frame
+    // that survives the codec shouldn't be lost to one bad header bit.
+    pub fn telephony(self) -> Self {
+        self.mode(TransmissionMode::Telephony)
+            .symbol_duration_ms(TELEPHONY_SYMBOL_DURATION_MS)
+            .fade(TELEPHONY_FADE_MS, FadeShape::Cosine)
+            .symbol_settle_ms(TELEPHONY_FADE_MS)
+            .protect_ecc_geometry(true)
+    }
+
+    pub fn ultrasonic_quiet(mut self, quiet: bool) -> Self {
+        self.config.ultrasonic_quiet = quiet;
+        self
+    }
+
+    pub fn symbol_duration_ms(mut self, duration_ms: u32) -> Self {
+        self.config.symbol_duration_ms = duration_ms;
+        self
+    }
+
+    pub fn symbol_settle_ms(mut self, settle_ms: u32) -> Self {
+        self.config.symbol_settle_ms = settle_ms;
+        self
+    }
+
+    pub fn bits_per_symbol(mut self, bits: u32) -> Self {
+        self.config.bits_per_symbol = bits;
+        self
+    }
+
+    pub fn wake_gap_ms(mut self, gap_ms: u32) -> Self {
+        self.config.wake_gap_ms = gap_ms;
+        self
+    }
+
+    pub fn wake_up_repeats(mut self, repeats: u32) -> Self {
+        self.config.wake_up_repeats = repeats;
+        self
+    }
+
+    pub fn wake_confirm_windows(mut self, windows: u32) -> Self {
+        self.config.wake_confirm_windows = windows;
+        self
+    }
+
+    pub fn inter_frame_gap_ms(mut self, gap_ms: u32) -> Self {
+        self.config.inter_frame_gap_ms = gap_ms;
+        self
+    }
+
+    pub fn length_prefixed(mut self, enabled: bool) -> Self {
+        self.config.length_prefixed = enabled;
+        self
+    }
+
+    pub fn sync_word(mut self, enabled: bool) -> Self {
+        self.config.sync_word = enabled;
+        self
+    }
+
+    pub fn header_symbol_factor(mut self, factor: u32) -> Self {
+        self.config.header_symbol_factor = factor;
+        self
+    }
+
+    pub fn compress(mut self, compress: bool) -> Self {
+        self.config.compress = compress;
+        self
+    }
+
+    pub fn raw_frame(mut self, raw: bool) -> Self {
+        self.config.raw_frame = raw;
+        self
+    }
+
+    pub fn checksum(mut self, checksum: ChecksumKind) -> Self {
+        self.config.checksum = checksum;
+        self
+    }
+
+    pub fn sample_rate(mut self, sample_rate: u32) -> Self {
+        self.config.sample_rate = sample_rate;
+        self
+    }
+
+    pub fn volume(mut self, volume: f32) -> Self {
+        self.config.set_volume(volume);
+        self
+    }
+
+    pub fn fade(mut self, fade_ms: u32, shape: FadeShape) -> Self {
+        self.config.fade_ms = fade_ms;
+        self.config.fade_shape = shape;
+        self
+    }
+
+    pub fn phase_randomization(mut self, seed: Option<u64>) -> Self {
+        self.config.phase_randomization = seed.is_some();
+        self.config.phase_seed = seed.unwrap_or(0);
+        self
+    }
+
+    pub fn scrambler_seed(mut self, seed: Option<u64>) -> Self {
+        self.config.scrambler_seed = seed;
+        self
+    }
+
+    pub fn ecc_alignment(mut self, alignment: usize) -> Self {
+        self.config.ecc_alignment = alignment;
+        self
+    }
+
+    pub fn ecc_scheme(mut self, scheme: EccScheme) -> Self {
+        self.config.ecc_scheme = scheme;
+        self
+    }
+
+    pub fn protect_ecc_geometry(mut self, enabled: bool) -> Self {
+        self.config.protect_ecc_geometry = enabled;
+        self
+    }
+
+    pub fn symbol_detector(mut self, detector: SymbolDetector) -> Self {
+        self.config.symbol_detector = detector;
+        self
+    }
+
+    pub fn symbol_confidence_threshold(mut self, ratio: Option<f32>) -> Self {
+        self.config.symbol_confidence_threshold = ratio;
+        self
+    }
+
+    pub fn custom_band(mut self, band: ToneBand) -> Self {
+        self.config.custom_band = Some(band);
+        self
+    }
+
+    pub fn excluded_frequencies(mut self, ranges: Vec<(f32, f32)>) -> Self {
+        self.config.excluded_frequencies = ranges;
+        self
+    }
+
+    pub fn pilot(mut self, pilot: Option<PilotTone>) -> Self {
+        self.config.pilot = pilot;
+        self
+    }
+
+    pub fn tone_gains(mut self, gains: Option<Vec<f32>>) -> Self {
+        self.config.tone_gains = gains;
+        self
+    }
+
+    // The band is checked against the final mode and sample rate, so setter
+    // order doesn't matter.
+    pub fn build(self) -> Result<Config> {
+        let mut config = self.config;
+        if let Some(band) = config.custom_band.take() {
+            config.set_custom_band(band)?;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+impl Config {
+    fn is_quiet(&self) -> bool {
+        self.ultrasonic_quiet && self.mode == TransmissionMode::Ultrasonic
+    }
+
+    pub fn base_frequency(&self) -> f32 {
+        if let Some(band) = self.custom_band {
+            band.base_frequency
+        } else if self.is_quiet() {
+            QUIET_BASE_FREQUENCY
+        } else {
+            self.mode.base_frequency()
+        }
+    }
+
+    pub fn frequency_step(&self) -> f32 {
+        if let Some(band) = self.custom_band {
+            band.frequency_step
+        } else if self.is_quiet() {
+            QUIET_FREQUENCY_STEP
+        } else {
+            self.mode.frequency_step()
+        }
+    }
+
+    pub fn wake_up_frequency(&self) -> f32 {
+        if self.is_quiet() {
+            QUIET_WAKE_UP_FREQUENCY
+        } else {
+            self.mode.wake_up_frequency()
+        }
+    }
+
+    pub fn set_custom_band(&mut self, band: ToneBand) -> Result<()> {
+        let top = Config {
+            custom_band: Some(band),
+            ..self.clone()
+        }
+        .tone_frequency(self.num_tones() - 1);
+        let nyquist = self.sample_rate as f32 / 2.0;
+
+        if !(band.base_frequency > 0.0 && band.frequency_step > 0.0) {
+            return Err(SonicPipeError::InvalidConfig(format!("invalid tone band {:?}", band)));
+        }
+        if top >= nyquist {
+            return Err(SonicPipeError::InvalidConfig(format!(
+                "top tone {:.0} Hz is at or above Nyquist ({:.0} Hz)",
+                top, nyquist
+            )));
+        }
+
+        let wake = self.wake_up_frequency();
+        if wake > band.base_frequency - band.frequency_step && wake < top + band.frequency_step {
+            return Err(SonicPipeError::InvalidConfig(format!(
+                "tone band {:.0}-{:.0} Hz overlaps the {:.0} Hz wake-up tone",
+                band.base_frequency, top, wake
+            )));
+        }
+
+        self.custom_band = Some(band);
+        Ok(())
+    }
+
+    pub fn set_volume(&mut self, volume: f32) {
+        self.volume = clamp_volume(volume);
+    }
+
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder::default()
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.symbol_duration_ms == 0 {
+            return Err(SonicPipeError::InvalidConfig("symbol duration must be non-zero".into()));
+        }
+        if self.symbol_settle_ms >= self.symbol_duration_ms {
+            return Err(SonicPipeError::InvalidConfig(format!(
+                "{} ms settling time leaves nothing of a {} ms symbol",
+                self.symbol_settle_ms, self.symbol_duration_ms
+            )));
+        }
+        if self.sample_rate == 0 {
+            return Err(SonicPipeError::InvalidConfig("sample rate must be non-zero".into()));
+        }
+        if !(1..=MAX_BITS_PER_SYMBOL).contains(&self.bits_per_symbol) {
+            return Err(SonicPipeError::InvalidConfig(format!(
+                "{} bits per symbol is outside 1-{}",
+                self.bits_per_symbol, MAX_BITS_PER_SYMBOL
+            )));
+        }
+        for &(low, high) in &self.excluded_frequencies {
+            if !(low.is_finite() && high.is_finite() && low <= high) {
+                return Err(SonicPipeError::InvalidConfig(format!(
+                    "invalid excluded range {}-{} Hz",
+                    low, high
+                )));
+            }
+        }
+        if let Some(ratio) = self.symbol_confidence_threshold {
+            if !(ratio.is_finite() && ratio >= 1.0) {
+                return Err(SonicPipeError::InvalidConfig(format!(
+                    "symbol confidence threshold {} must be a ratio of at least 1.0",
+                    ratio
+                )));
+            }
+        }
+        if !(0.0..=1.0).contains(&self.volume) {
+            return Err(SonicPipeError::InvalidConfig(format!(
+                "volume {} is outside 0.0-1.0",
+                self.volume
+            )));
+        }
+
+        let nyquist = self.sample_rate as f32 / 2.0;
+        let top = self.frequencies().into_iter().fold(self.wake_up_frequency(), f32::max);
+        if top >= nyquist {
+            return Err(SonicPipeError::InvalidConfig(format!(
+                "{:.0} Hz tone is at or above Nyquist ({:.0} Hz) at {} Hz",
+                top, nyquist, self.sample_rate
+            )));
+        }
+
+        // Wider symbols and excluded ranges widen the band, which can run
+        // into the wake-up tone.
+        let (base, step) = (self.base_frequency(), self.frequency_step());
+        let data_top = self.tone_frequency(self.num_tones() - 1);
+        let wake = self.wake_up_frequency();
+        if wake > base - step && wake < data_top + step {
+            return Err(SonicPipeError::InvalidConfig(format!(
+                "{} tones at {:.0}-{:.0} Hz overlap the {:.0} Hz wake-up tone",
+                self.num_tones(), base, data_top, wake
+            )));
+        }
+
+        if let Some(pilot) = self.pilot {
+            if !(pilot.level > 0.0 && pilot.level <= 0.5) {
+                return Err(SonicPipeError::InvalidConfig(format!(
+                    "pilot level {} is outside 0.0-0.5",
+                    pilot.level
+                )));
+            }
+            // A step clear of every other tone, so the pilot can be tracked
+            // across the whole offset the tone detectors can absorb.
+            let clear = |frequency: f32| (pilot.frequency - frequency).abs() >= step;
+            let in_range = pilot.frequency > 0.0 && pilot.frequency < nyquist;
+            if !(in_range && self.frequencies().into_iter().chain([wake]).all(clear)) {
+                return Err(SonicPipeError::InvalidConfig(format!(
+                    "{:.0} Hz pilot must be below Nyquist and a tone step clear of the other tones",
+                    pilot.frequency
+                )));
+            }
+        }
+
+        if let Some(gains) = &self.tone_gains {
+            self.check_tone_gains(gains, &self.frequencies())?;
+        }
+
+        Ok(())
+    }
+
+    // Tone gains need one per tone of `frequencies`, and none may push its
+    // tone to peak above full scale.
+    pub(crate) fn check_tone_gains(&self, gains: &[f32], frequencies: &[f32]) -> Result<()> {
+        if gains.len() != frequencies.len() {
+            return Err(SonicPipeError::InvalidConfig(format!(
+                "expected {} tone gains, got {}",
+                frequencies.len(),
+                gains.len()
+            )));
+        }
+
+        for (&frequency, &gain) in frequencies.iter().zip(gains) {
+            let peak = self.volume * self.tone_gain(frequency) * gain;
+            if !(gain >= 0.0 && peak <= 1.0) {
+                return Err(SonicPipeError::InvalidConfig(format!(
+                    "gain {} on the {:.0} Hz tone gives a peak of {} at volume {}",
+                    gain, frequency, peak, self.volume
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn estimate_duration_ms(&self, payload_len: usize) -> u32 {
+        (self.estimate_frame_samples(payload_len) as u64 * 1000 / self.sample_rate as u64) as u32
+    }
+
+    fn estimate_frame_samples(&self, payload_len: usize) -> usize {
+        let (_, encoded_len) = self.estimate_encoded_len(payload_len);
+        let packet_len = Packet::serialized_len(encoded_len, self.checksum);
+        MFSKModulator::new(self.clone()).frame_samples(packet_len)
+    }
+
+    // Payload length after compression, then after ECC. Compression is
+    // content-dependent, so assume the payload does not shrink and only
+    // pays for lz4's 4-byte size prefix.
+    fn estimate_encoded_len(&self, payload_len: usize) -> (usize, usize) {
+        if self.raw_frame {
+            return (payload_len, payload_len);
+        }
+        let compressed_len = if self.compress { payload_len + 4 } else { payload_len };
+        let encoded_len = self.ecc_scheme.encoded_len_with(compressed_len, &self.reed_solomon_codec());
+        (compressed_len, encoded_len)
+    }
+
+    // The Reed-Solomon codec frames are encoded with, set up for
+    // `ecc_alignment` and `protect_ecc_geometry`.
+    pub fn reed_solomon_codec(&self) -> ReedSolomonCodec {
+        ReedSolomonCodec::default()
+            .with_alignment(self.ecc_alignment)
+            .with_protected_geometry(self.protect_ecc_geometry)
+    }
+
+    // The largest input that fits one frame however badly it compresses.
+    // Compressible input well past this can still fit; see `encode_frame`.
+    pub fn max_payload_len(&self) -> usize {
+        let codec = self.reed_solomon_codec();
+        let worst_case_len = |len: usize| {
+            if self.raw_frame {
+                return len;
+            }
+            let compressed_len = if self.compress { max_compressed_len(len) } else { len };
+            self.ecc_scheme.encoded_len_with(compressed_len, &codec)
+        };
+        (0..=MAX_PAYLOAD_SIZE).rev().find(|&len| worst_case_len(len) <= MAX_PAYLOAD_SIZE).unwrap_or(0)
+    }
+
+    pub fn throughput_report(&self) -> ThroughputReport {
+        self.throughput_report_for(THROUGHPUT_REPORT_PAYLOAD_BYTES)
+    }
+
+    // Like `throughput_report`, for payloads of `payload_len` bytes. Framing
+    // costs the same whatever the payload, so small payloads fare worst.
+    pub fn throughput_report_for(&self, payload_len: usize) -> ThroughputReport {
+        let symbol_rate = 1000.0 / self.symbol_duration_ms.max(1) as f32;
+        let bits_per_symbol = self.bits_per_symbol.clamp(1, MAX_BITS_PER_SYMBOL);
+        let gross_bps = symbol_rate * bits_per_symbol as f32;
+
+        let (compressed_len, encoded_len) = self.estimate_encoded_len(payload_len);
+        let frame_seconds = self.estimate_frame_samples(payload_len) as f32 / self.sample_rate as f32;
+        // Share of the frame spent sending `bytes` at the data rate.
+        let percent = |bytes: usize| bytes as f32 * 8.0 / gross_bps / frame_seconds * 100.0;
+
+        let compression_overhead_percent = percent(compressed_len - payload_len);
+        let ecc_overhead_percent = percent(encoded_len - compressed_len);
+        ThroughputReport {
+            payload_bytes: payload_len,
+            symbol_rate,
+            bits_per_symbol,
+            gross_bps,
+            net_bps: payload_len as f32 * 8.0 / frame_seconds,
+            frame_ms: frame_seconds * 1000.0,
+            compression_overhead_percent,
+            ecc_overhead_percent,
+            framing_overhead_percent: 100.0 - percent(payload_len) - compression_overhead_percent - ecc_overhead_percent,
+        }
+    }
+
+    pub fn wake_gap_samples(&self) -> usize {
+        (self.sample_rate as f32 * self.wake_gap_ms as f32 / 1000.0) as usize
+    }
+
+    pub fn duration_samples(&self, duration_ms: u32) -> usize {
+        (self.sample_rate as f32 * duration_ms as f32 / 1000.0) as usize
+    }
+
+    pub fn header_symbol_duration_ms(&self) -> u32 {
+        self.symbol_duration_ms * self.header_symbol_factor.max(1)
+    }
+
+    // Short frames carry their length in one byte; length-prefixed frames can
+    // be any packet size, so they take two.
+    pub fn length_prefix_bytes(&self) -> usize {
+        if self.length_prefixed {
+            2
+        } else {
+            1
+        }
+    }
+
+    // Whether a frame of `data_len` bytes goes out with a length prefix. Short
+    // frames are told apart by a tone where the silence gap would be, so
+    // they need a non-empty gap to be distinguishable.
+    pub fn uses_length_prefix(&self, data_len: usize) -> bool {
+        self.length_prefixed || (data_len <= SHORT_FRAME_THRESHOLD && self.wake_gap_samples() > 0)
+    }
+
+    // Length of the sync word after the wake-up tone, or 0 without one.
+    pub fn sync_word_samples(&self) -> usize {
+        if self.sync_word {
+            SYNC_WORD_SYMBOLS * self.duration_samples(self.symbol_duration_ms)
+        } else {
+            0
+        }
+    }
+
+    pub fn symbol_samples_exact(&self, duration_ms: u32) -> f64 {
+        self.sample_rate as f64 * duration_ms as f64 / 1000.0
+    }
+
+    pub fn num_tones(&self) -> usize {
+        1 << self.bits_per_symbol.clamp(1, MAX_BITS_PER_SYMBOL)
+    }
+
+    // Symbols needed to carry `len` bytes; the last one is zero-padded.
+    pub fn symbols_per_bytes(&self, len: usize) -> usize {
+        (8 * len).div_ceil(self.bits_per_symbol.clamp(1, MAX_BITS_PER_SYMBOL) as usize)
+    }
+
+    // Offset of data symbol `index` from the start of a frame's data. Symbols
+    // sit on an exact fractional clock and are rounded individually, so
+    // durations that aren't a whole number of samples don't drift. A symbol
+    // straddling the end of the header goes at the header rate.
+    pub fn symbol_offset(&self, index: usize) -> usize {
+        let header_symbols = self.symbols_per_bytes(ROBUST_HEADER_BYTES);
+        let slow = index.min(header_symbols) as f64;
+        let fast = index.saturating_sub(header_symbols) as f64;
+
+        (slow * self.symbol_samples_exact(self.header_symbol_duration_ms())
+            + fast * self.symbol_samples_exact(self.symbol_duration_ms))
+        .round() as usize
+    }
+
+    // Samples taken by the first `data_len` data bytes of a frame.
+    pub fn data_samples(&self, data_len: usize) -> usize {
+        self.symbol_offset(self.symbols_per_bytes(data_len))
+    }
+
+    pub fn frequencies(&self) -> Vec<f32> {
+        let mut frequencies: Vec<f32> = self.tone_slots().take(self.num_tones()).collect();
+        // Out of slots; the missing tones fail validation at Nyquist.
+        frequencies.resize(self.num_tones(), f32::INFINITY);
+        frequencies
+    }
+
+    // Tone `index` of the band. ACK bursts use the first 16 tones whatever
+    // the data symbols use, so this isn't limited to `num_tones`.
+    pub fn tone_frequency(&self, index: usize) -> f32 {
+        self.tone_slots().nth(index).unwrap_or(f32::INFINITY)
+    }
+
+    pub fn is_excluded(&self, frequency: f32) -> bool {
+        self.excluded_frequencies
+            .iter()
+            .any(|&(low, high)| (low..=high).contains(&frequency))
+    }
+
+    // The band's evenly spaced slots, less any that fall in an excluded range.
+    fn tone_slots(&self) -> impl Iterator<Item = f32> + '_ {
+        let (base, step) = (self.base_frequency(), self.frequency_step());
+        (0..MAX_TONE_SLOTS)
+            .map(move |k| base + k as f32 * step)
+            .filter(|&frequency| !self.is_excluded(frequency))
+    }
+
+    // In quiet mode, tones are attenuated by 3 dB per kHz below 20 kHz, since
+    // lower frequencies are the ones most listeners can still hear.
+    pub fn tone_gain(&self, frequency: f32) -> f32 {
+        if self.is_quiet() {
+            let attenuation_db = 3.0 * (20000.0 - frequency).max(0.0) / 1000.0;
+            10f32.powf(-attenuation_db / 20.0)
+        } else {
+            1.0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builder_matches_defaults() {
+        let config = Config::builder()
+            .mode(TransmissionMode::Ultrasonic)
+            .symbol_duration_ms(30)
+            .volume(0.8)
+            .build()
+            .unwrap();
+
+        assert_eq!(config.mode, TransmissionMode::Ultrasonic);
+        assert_eq!(config.symbol_duration_ms, 30);
+        assert_eq!(config.volume, 0.8);
+        assert_eq!(config.wake_gap_ms, Config::default().wake_gap_ms);
+    }
+
+    #[test]
+    fn test_out_of_range_volume_is_clamped() {
+        assert_eq!(Config::builder().volume(5.0).build().unwrap().volume, 1.0);
+        assert_eq!(Config::builder().volume(-0.5).build().unwrap().volume, 0.0);
+        assert_eq!(Config::builder().volume(f32::NAN).build().unwrap().volume, 0.0);
+
+        let mut config = Config::default();
+        config.set_volume(2.0);
+        assert_eq!(config.volume, 1.0);
+
+        // Configs built by hand fail validation, and are still clamped when
+        // they reach the modulator.
+        let overdriven = Config {
+            volume: 5.0,
+            ..Default::default()
+        };
+        assert!(overdriven.validate().is_err());
+        let samples = MFSKModulator::new(overdriven).modulate(b"loud").unwrap();
+        assert!(samples.iter().all(|s| s.abs() <= 1.0));
+    }
+
+    #[test]
+    fn test_builder_rejects_invalid_configs() {
+        let invalid = [
+            Config::builder().symbol_duration_ms(0),
+            Config::builder().symbol_settle_ms(50),
+            // The ultrasonic band doesn't fit under 16 kHz Nyquist.
+            Config::builder().mode(TransmissionMode::Ultrasonic).sample_rate(32000),
+            Config::builder().custom_band(ToneBand {
+                base_frequency: 17000.0,
+                frequency_step: 100.0,
+            }),
+            Config::builder().symbol_confidence_threshold(Some(0.5)),
+            Config::builder().symbol_confidence_threshold(Some(f32::NAN)),
+            Config::builder().pilot(Some(PilotTone {
+                frequency: 1550.0,
+                level: 0.1,
+            })),
+            Config::builder().pilot(Some(PilotTone {
+                frequency: 3200.0,
+                level: 0.9,
+            })),
+        ];
+
+        for builder in invalid {
+            let result = builder.clone().build();
+            assert!(matches!(result, Err(SonicPipeError::InvalidConfig(_))), "{:?}", builder);
+        }
+    }
+
+    #[test]
+    fn test_builder_validates_band_against_final_sample_rate() {
+        let band = ToneBand {
+            base_frequency: 4000.0,
+            frequency_step: 100.0,
+        };
+        assert!(Config::builder().custom_band(band).sample_rate(8000).build().is_err());
+        assert!(Config::builder().sample_rate(8000).custom_band(band).sample_rate(48000).build().is_ok());
+    }
+
+    #[test]
+    fn test_excluded_frequencies_are_skipped() {
+        let config = Config::builder()
+            .excluded_frequencies(vec![(1950.0, 2050.0), (2290.0, 2410.0)])
+            .build()
+            .unwrap();
+
+        let frequencies = config.frequencies();
+        assert_eq!(frequencies.len(), NUM_TONES);
+        assert!(frequencies.iter().all(|&f| !config.is_excluded(f)));
+        // 2000 Hz and 2300-2400 Hz are skipped, so the band runs three slots higher.
+        assert_eq!(frequencies[9], 1900.0);
+        assert_eq!(frequencies[10], 2100.0);
+        assert_eq!(frequencies[12], 2500.0);
+        assert_eq!(frequencies[15], 2800.0);
+        assert_eq!(config.tone_frequency(15), 2800.0);
+
+        let invalid = [vec![(2000.0, 1000.0)], vec![(1000.0, f32::INFINITY)], vec![(f32::NAN, 1000.0)]];
+        for ranges in invalid {
+            assert!(Config::builder().excluded_frequencies(ranges).build().is_err());
         }
+        // Pushed into the ultrasonic wake-up tone.
+        assert!(Config::builder()
+            .mode(TransmissionMode::Ultrasonic)
+            .excluded_frequencies(vec![(17000.0, 17500.0)])
+            .build()
+            .is_err());
     }
 }
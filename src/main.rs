@@ -1,13 +1,22 @@
-use anyhow::Result;
-use clap::{Parser, Subcommand};
+use anyhow::{anyhow, Result};
+use byteorder::{LittleEndian, WriteBytesExt};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use sonic_pipe_core::{
-    audio::{AudioInput, AudioOutput},
-    codec::{compress, decompress, ReedSolomonCodec},
-    modulation::{MFSKDemodulator, MFSKModulator},
-    protocol::Packet,
-    Config, TransmissionMode, WAKE_UP_FREQUENCY,
+    audio::{resample_to, AudioInput, AudioOutput, RecordLimits, CARRIER_SENSE_LISTEN_MS, CARRIER_SENSE_TIMEOUT_MS},
+    modulation::{peak_amplitude, MFSKModulator},
+    pipeline::{decode_from_samples, decode_verbose, encode_to_samples, encode_with_metadata, selftest, DecodeStats},
+    protocol::Metadata,
+    session::{auto_calibrate_volume, Session},
+    wav::{read_wav, write_wav},
+    ChecksumKind, Config, ConfigBuilder, EccScheme, TransmissionMode, TELEPHONY_SYMBOL_DURATION_MS, WAKE_UP_DURATION_MS,
 };
+use std::fmt::Display;
+use std::fs::OpenOptions;
 use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+const DEFAULT_VOLUME: f32 = 0.5;
 
 #[derive(Parser)]
 #[command(name = "sonic-pipe")]
@@ -18,45 +27,260 @@ struct Cli {
     command: Commands,
 }
 
+// Flags left unset fall back to SONIC_PIPE_* environment variables, then to
+// the built-in defaults.
+#[derive(Args)]
+struct ModeArgs {
+    /// Use audible mode even if SONIC_PIPE_MODE says otherwise
+    #[arg(long, conflicts_with_all = ["ultrasonic", "quiet", "acoustic", "telephony"])]
+    audible: bool,
+
+    /// Use ultrasonic mode (17-20kHz, semi-silent)
+    #[arg(long, short)]
+    ultrasonic: bool,
+
+    /// Keep ultrasonic tones at or above 18kHz with audibility weighting (implies --ultrasonic)
+    #[arg(long)]
+    quiet: bool,
+
+    /// Use acoustic mode (4-7.6kHz), for speakers without much treble and wideband voice channels
+    #[arg(long, conflicts_with_all = ["ultrasonic", "quiet"])]
+    acoustic: bool,
+
+    /// Use telephony mode (600-3200Hz, 80ms symbols by default) to send over a phone or voice call
+    #[arg(long, conflicts_with_all = ["ultrasonic", "quiet", "acoustic"])]
+    telephony: bool,
+
+    /// Symbol duration in milliseconds [env: SONIC_PIPE_SYMBOL_DURATION] [default: 50]
+    #[arg(long)]
+    symbol_duration: Option<u32>,
+
+    /// Bits carried by each symbol; uses 2^N tones (must match on both ends)
+    #[arg(long, default_value = "4")]
+    bits_per_symbol: u32,
+
+    /// Whiten frames with the self-synchronizing 1 + x^-18 + x^-23 scrambler, its register seeded
+    /// from this (must match on both ends)
+    #[arg(long)]
+    scrambler_seed: Option<u64>,
+
+    /// Silence between the wake-up tone and data in milliseconds (must match on both ends)
+    #[arg(long, default_value = "20")]
+    wake_gap: u32,
+
+    /// Send every frame's length after the wake-up tone instead of the silence gap (must match on both ends)
+    #[arg(long)]
+    length_prefixed: bool,
+
+    /// Send a fixed sync word after the wake-up tone so stray tones aren't taken for frames (must match on both ends)
+    #[arg(long)]
+    sync_word: bool,
+
+    /// Number of wake-up tones sent before each frame
+    #[arg(long, default_value = "1")]
+    wake_repeats: u32,
+}
+
+impl ModeArgs {
+    fn config(&self) -> Result<Config> {
+        Ok(self.builder()?.build()?)
+    }
+
+    fn builder(&self) -> Result<ConfigBuilder> {
+        self.builder_with_env(&|name| std::env::var(name).ok())
+    }
+
+    #[cfg(test)]
+    fn config_with_env(&self, env: &dyn Fn(&str) -> Option<String>) -> Result<Config> {
+        Ok(self.builder_with_env(env)?.build()?)
+    }
+
+    fn builder_with_env(&self, env: &dyn Fn(&str) -> Option<String>) -> Result<ConfigBuilder> {
+        let (mode, ultrasonic_quiet) = if self.quiet {
+            (TransmissionMode::Ultrasonic, true)
+        } else if self.ultrasonic {
+            (TransmissionMode::Ultrasonic, false)
+        } else if self.acoustic {
+            (TransmissionMode::Acoustic, false)
+        } else if self.telephony {
+            (TransmissionMode::Telephony, false)
+        } else if self.audible {
+            (TransmissionMode::Audible, false)
+        } else {
+            match env("SONIC_PIPE_MODE") {
+                Some(value) => parse_mode(&value)?,
+                None => (TransmissionMode::Audible, false),
+            }
+        };
+
+        let telephony = mode == TransmissionMode::Telephony;
+        let symbol_duration_ms = resolve(
+            self.symbol_duration,
+            env,
+            "SONIC_PIPE_SYMBOL_DURATION",
+            if telephony { TELEPHONY_SYMBOL_DURATION_MS } else { Config::default().symbol_duration_ms },
+        )?;
+
+        let builder = if telephony { Config::builder().telephony() } else { Config::builder().mode(mode) };
+        Ok(builder
+            .ultrasonic_quiet(ultrasonic_quiet)
+            .symbol_duration_ms(symbol_duration_ms)
+            .bits_per_symbol(self.bits_per_symbol)
+            .scrambler_seed(self.scrambler_seed)
+            .wake_gap_ms(self.wake_gap)
+            .length_prefixed(self.length_prefixed)
+            .sync_word(self.sync_word)
+            .wake_up_repeats(self.wake_repeats))
+    }
+}
+
+fn parse_mode(value: &str) -> Result<(TransmissionMode, bool)> {
+    match value.to_ascii_lowercase().as_str() {
+        "audible" => Ok((TransmissionMode::Audible, false)),
+        "ultrasonic" => Ok((TransmissionMode::Ultrasonic, false)),
+        "quiet" => Ok((TransmissionMode::Ultrasonic, true)),
+        "acoustic" => Ok((TransmissionMode::Acoustic, false)),
+        "telephony" => Ok((TransmissionMode::Telephony, false)),
+        _ => Err(anyhow!(
+            "invalid SONIC_PIPE_MODE '{}' (expected audible, ultrasonic, quiet, acoustic or telephony)",
+            value
+        )),
+    }
+}
+
+fn resolve<T>(flag: Option<T>, env: &dyn Fn(&str) -> Option<String>, name: &str, default: T) -> Result<T>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    if let Some(value) = flag {
+        return Ok(value);
+    }
+
+    match env(name) {
+        Some(value) => value
+            .trim()
+            .parse()
+            .map_err(|e| anyhow!("invalid {} '{}': {}", name, value, e)),
+        None => Ok(default),
+    }
+}
+
 #[derive(Subcommand)]
 enum Commands {
     /// Send data via audio
     Send {
-        /// Use ultrasonic mode (17-20kHz, semi-silent)
-        #[arg(long, short)]
-        ultrasonic: bool,
+        #[command(flatten)]
+        mode: ModeArgs,
+
+        /// Volume level (0.0 - 1.0) [env: SONIC_PIPE_VOLUME] [default: 0.5]
+        #[arg(long)]
+        volume: Option<f32>,
+
+        /// Pick the volume by playing test tones and listening for them first (needs a working microphone)
+        #[arg(long, conflicts_with_all = ["volume", "pcm_stdout"])]
+        auto_volume: bool,
+
+        /// Send the data as-is instead of LZ4-compressing it
+        #[arg(long)]
+        no_compress: bool,
 
-        /// Symbol duration in milliseconds
-        #[arg(long, default_value = "50")]
-        symbol_duration: u32,
+        /// Send a debug frame: no compression or ECC, only the CRC
+        #[arg(long)]
+        raw: bool,
 
-        /// Volume level (0.0 - 1.0)
-        #[arg(long, default_value = "0.5")]
-        volume: f32,
+        /// Protect the frame with a CRC64 instead of a CRC32
+        #[arg(long)]
+        crc64: bool,
+
+        /// Use a repetition code instead of Reed-Solomon, which costs less for payloads of a few bytes
+        #[arg(long, conflicts_with = "raw")]
+        repetition_ecc: bool,
+
+        /// Send the Reed-Solomon block's length and shard size three times, so a bit error there can be outvoted
+        #[arg(long, conflicts_with_all = ["raw", "repetition_ecc"])]
+        protect_geometry: bool,
+
+        /// Listen first and wait until no other sender is on the air before transmitting
+        #[arg(long, conflicts_with = "pcm_stdout")]
+        carrier_sense: bool,
 
         /// Data to send (if not provided, reads from stdin)
-        #[arg(short, long)]
+        #[arg(short, long, conflicts_with = "file")]
         data: Option<String>,
+
+        /// Send the contents of this file
+        #[arg(short, long, value_name = "FILE")]
+        file: Option<PathBuf>,
+
+        /// Include the file's name and size so the receiver can save it under that name
+        #[arg(long, requires = "file")]
+        embed_name: bool,
+
+        /// Content type to send along with an embedded name, e.g. image/png
+        #[arg(long, requires = "embed_name")]
+        mime: Option<String>,
+
+        /// Write the signal to stdout as headerless mono PCM (little-endian, 48 kHz) instead of playing it
+        #[arg(long)]
+        pcm_stdout: bool,
+
+        /// Sample format for --pcm-stdout
+        #[arg(long, value_enum, default_value = "f32", requires = "pcm_stdout")]
+        pcm_format: PcmFormat,
     },
 
     /// Receive data via audio
     Receive {
-        /// Use ultrasonic mode (17-20kHz, semi-silent)
-        #[arg(long, short)]
-        ultrasonic: bool,
-
-        /// Symbol duration in milliseconds
-        #[arg(long, default_value = "50")]
-        symbol_duration: u32,
+        #[command(flatten)]
+        mode: ModeArgs,
 
-        /// Timeout in seconds
+        /// Seconds to wait for a transmission to start
         #[arg(long, default_value = "30")]
         timeout: u32,
+
+        /// Longest recording in seconds once a transmission has started
+        #[arg(long, default_value = "300")]
+        max_duration: u32,
+
+        /// Also save the captured audio to this WAV file
+        #[arg(long, value_name = "FILE")]
+        save_recording: Option<PathBuf>,
+
+        /// Write the data to this file instead of stdout; without a path, use
+        /// the name the sender embedded, which never replaces an existing file
+        #[arg(short, long, value_name = "FILE", num_args = 0..=1)]
+        output_file: Option<Option<PathBuf>>,
+    },
+
+    /// Decode a transmission from a WAV recording
+    Decode {
+        #[command(flatten)]
+        mode: ModeArgs,
+
+        /// WAV file to decode
+        input: PathBuf,
+    },
+
+    /// Show the tone table and timing derived from the mode flags
+    Info {
+        #[command(flatten)]
+        mode: ModeArgs,
     },
 
     /// List available audio devices
     Devices,
 
+    /// Measure the speaker-to-microphone round trip, for tuning turnaround and inter-frame gaps
+    Ping {
+        #[command(flatten)]
+        mode: ModeArgs,
+
+        /// Number of pings to send
+        #[arg(short, long, default_value = "3")]
+        count: u32,
+    },
+
     /// Test audio transmission (loopback test)
     Test {
         /// Test message
@@ -72,14 +296,41 @@ fn main() -> Result<()> {
 
     match cli.command {
         Commands::Send {
-            ultrasonic,
-            symbol_duration,
+            mode,
             volume,
+            auto_volume,
+            no_compress,
+            raw,
+            crc64,
+            repetition_ecc,
+            protect_geometry,
+            carrier_sense,
             data,
+            file,
+            embed_name,
+            mime,
+            pcm_stdout,
+            pcm_format,
         } => {
-            let input_data = match data {
-                Some(d) => d.into_bytes(),
-                None => {
+            let mut metadata = None;
+            let input_data = match (data, file) {
+                (Some(d), _) => d.into_bytes(),
+                (None, Some(path)) => {
+                    let contents = std::fs::read(&path)?;
+                    if embed_name {
+                        let name = path
+                            .file_name()
+                            .ok_or_else(|| anyhow!("{} has no file name", path.display()))?
+                            .to_string_lossy();
+                        metadata = Some(Metadata {
+                            filename: Some(name.into_owned()),
+                            mime,
+                            original_len: Some(contents.len() as u64),
+                        });
+                    }
+                    contents
+                }
+                (None, None) => {
                     let mut buffer = Vec::new();
                     io::stdin().read_to_end(&mut buffer)?;
                     buffer
@@ -91,40 +342,96 @@ fn main() -> Result<()> {
                 std::process::exit(1);
             }
 
-            let config = Config {
-                mode: if ultrasonic {
-                    TransmissionMode::Ultrasonic
-                } else {
-                    TransmissionMode::Audible
-                },
-                symbol_duration_ms: symbol_duration,
-                volume,
-                ..Default::default()
-            };
+            let mut builder = mode
+                .builder()?
+                .volume(resolve(volume, &|name| std::env::var(name).ok(), "SONIC_PIPE_VOLUME", DEFAULT_VOLUME)?)
+                .compress(!no_compress)
+                .raw_frame(raw)
+                .checksum(if crc64 { ChecksumKind::Crc64 } else { ChecksumKind::Crc32 })
+                .ecc_scheme(if repetition_ecc { EccScheme::Repetition } else { EccScheme::ReedSolomon });
+            // Telephony mode turns it on by itself.
+            if protect_geometry {
+                builder = builder.protect_ecc_geometry(true);
+            }
+            let mut config = builder.build()?;
+
+            if auto_volume {
+                let volume = auto_calibrate_volume(&config)?;
+                eprintln!("Calibrated volume: {:.2}", volume);
+                config.set_volume(volume);
+            }
 
-            send_data(&input_data, &config)?;
+            if pcm_stdout {
+                let samples = encode_with_metadata(&input_data, metadata.as_ref(), &config)?;
+                let mut stdout = io::stdout().lock();
+                write_pcm(&mut stdout, &samples, pcm_format)?;
+                stdout.flush()?;
+                eprintln!("Wrote {} samples ({:?}, {} Hz) to stdout", samples.len(), pcm_format, config.sample_rate);
+            } else {
+                if carrier_sense {
+                    eprintln!("Waiting for a clear channel...");
+                    let input = AudioInput::new()?;
+                    input.wait_for_clear_channel(&config, CARRIER_SENSE_LISTEN_MS, CARRIER_SENSE_TIMEOUT_MS)?;
+                }
+                send_data(&input_data, metadata.as_ref(), &config)?;
+            }
         }
 
         Commands::Receive {
-            ultrasonic,
-            symbol_duration,
+            mode,
             timeout,
+            max_duration,
+            save_recording,
+            output_file,
         } => {
-            let config = Config {
-                mode: if ultrasonic {
-                    TransmissionMode::Ultrasonic
-                } else {
-                    TransmissionMode::Audible
-                },
-                symbol_duration_ms: symbol_duration,
-                ..Default::default()
-            };
+            let config = mode.config()?;
+            let limits = RecordLimits::new(timeout as u64 * 1000, max_duration as u64 * 1000);
+
+            let (data, metadata) = receive_data(&config, limits, save_recording.as_deref())?;
+            if let Some(metadata) = &metadata {
+                eprintln!(
+                    "File: {} ({})",
+                    metadata.filename.as_deref().unwrap_or("unnamed"),
+                    metadata.mime.as_deref().unwrap_or("unknown type")
+                );
+            }
+            let name = metadata.and_then(|m| m.filename).and_then(|name| safe_file_name(&name));
+
+            match output_file {
+                Some(Some(path)) => {
+                    std::fs::write(&path, &data)?;
+                    eprintln!("Wrote {} bytes to {}", data.len(), path.display());
+                }
+                // The sender picked this name, so it's only ever a new file.
+                Some(None) => {
+                    let Some(name) = name else {
+                        return Err(anyhow!(
+                            "the sender embedded no file name; pass --output-file <FILE>"
+                        ));
+                    };
+                    let path = PathBuf::from(name);
+                    write_new_file(&path, &data)?;
+                    eprintln!("Wrote {} bytes to {}", data.len(), path.display());
+                }
+                None => {
+                    io::stdout().write_all(&data)?;
+                    io::stdout().flush()?;
+                }
+            }
+        }
 
-            let data = receive_data(&config, timeout)?;
+        Commands::Decode { mode, input } => {
+            let config = mode.config()?;
+
+            let data = decode_wav(&config, &input)?;
             io::stdout().write_all(&data)?;
             io::stdout().flush()?;
         }
 
+        Commands::Info { mode } => {
+            print_info(&mode.config()?);
+        }
+
         Commands::Devices => {
             let devices = sonic_pipe_core::audio::list_audio_devices();
             println!("Available audio devices:");
@@ -133,6 +440,10 @@ fn main() -> Result<()> {
             }
         }
 
+        Commands::Ping { mode, count } => {
+            run_ping(&mode.config()?, count)?;
+        }
+
         Commands::Test { message } => {
             println!("Running loopback test with message: {}", message);
             run_test(&message)?;
@@ -142,26 +453,65 @@ fn main() -> Result<()> {
     Ok(())
 }
 
-fn send_data(data: &[u8], config: &Config) -> Result<()> {
-    eprintln!("Preparing to send {} bytes...", data.len());
+// Only the last path component of an embedded name is used, so a sender
+// can't direct the write elsewhere.
+fn safe_file_name(name: &str) -> Option<String> {
+    Path::new(name).file_name()?.to_str().map(str::to_string)
+}
 
-    let compressed = compress(data);
-    eprintln!("Compressed to {} bytes", compressed.len());
+// Writes `data` to a file that mustn't exist yet, for names the user didn't
+// choose.
+fn write_new_file(path: &Path, data: &[u8]) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::AlreadyExists => anyhow!(
+                "{} already exists; pass --output-file <FILE> to write elsewhere or to replace it",
+                path.display()
+            ),
+            _ => e.into(),
+        })?;
+    file.write_all(data)?;
+    Ok(())
+}
 
-    let ecc = ReedSolomonCodec::new()?;
-    let encoded = ecc.encode(&compressed)?;
-    eprintln!("ECC encoded to {} bytes", encoded.len());
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+enum PcmFormat {
+    /// 32-bit float in -1.0..1.0
+    F32,
+    /// Signed 16-bit integer
+    S16,
+}
 
-    let packet = Packet::new(encoded)?;
-    let packet_data = packet.serialize();
-    eprintln!("Packet size: {} bytes", packet_data.len());
+// Raw samples with no header, so the output can be streamed straight into
+// tools like `aplay -f S16_LE -r 48000` or `sox -t f32 -r 48000 -c 1`.
+fn write_pcm<W: Write>(writer: &mut W, samples: &[f32], format: PcmFormat) -> io::Result<()> {
+    let mut writer = io::BufWriter::new(writer);
+    for &sample in samples {
+        match format {
+            PcmFormat::F32 => writer.write_f32::<LittleEndian>(sample)?,
+            PcmFormat::S16 => writer.write_i16::<LittleEndian>((sample.clamp(-1.0, 1.0) * 32767.0).round() as i16)?,
+        }
+    }
+    writer.flush()
+}
 
-    let modulator = MFSKModulator::new(config.clone());
-    let samples = modulator.modulate(&packet_data);
+fn send_data(data: &[u8], metadata: Option<&Metadata>, config: &Config) -> Result<()> {
+    eprintln!("Preparing to send {} bytes...", data.len());
+    eprintln!("Estimated duration: {} ms", config.estimate_duration_ms(data.len()));
+
+    let samples = encode_with_metadata(data, metadata, config)?;
     let duration_ms = samples.len() as f32 / 48.0;
     eprintln!("Audio duration: {:.1} ms", duration_ms);
 
-    let audio_output = AudioOutput::new()?;
+    let peak = peak_amplitude(&samples);
+    if peak > 1.0 {
+        eprintln!("Warning: signal peaks at {:.2} and will clip; lower the volume", peak);
+    }
+
+    let mut audio_output = AudioOutput::new()?;
 
     eprintln!("Transmitting...");
     audio_output.play_samples(samples)?;
@@ -170,102 +520,150 @@ fn send_data(data: &[u8], config: &Config) -> Result<()> {
     Ok(())
 }
 
-fn receive_data(config: &Config, timeout_secs: u32) -> Result<Vec<u8>> {
+fn receive_data(
+    config: &Config,
+    limits: RecordLimits,
+    save_recording: Option<&Path>,
+) -> Result<(Vec<u8>, Option<Metadata>)> {
     eprintln!("Listening for transmission...");
     eprintln!("Mode: {:?}", config.mode);
-    eprintln!("Timeout: {} seconds", timeout_secs);
+    eprintln!("Timeout: {} seconds (recording up to {} seconds)", limits.wake_timeout_ms / 1000, limits.max_record_ms / 1000);
 
     let audio_input = AudioInput::new()?;
-    let mut demodulator = MFSKDemodulator::new(config.clone());
+    let mut samples = Vec::new();
+    let recorded = audio_input.record_frame_into(config, limits, &mut samples);
 
-    let wake_detected = std::sync::Arc::new(std::sync::Mutex::new(false));
-    let wake_detected_clone = wake_detected.clone();
+    eprintln!("Recorded {} samples", samples.len());
 
-    let samples = audio_input.record_until_complete(
-        move |samples| {
-            if samples.len() < 48000 {
-                return false;
-            }
+    // Saved before anything can fail, a missed wake-up tone included, since
+    // a failed receive is when the recording is needed.
+    if let Some(path) = save_recording {
+        write_wav(path, &samples, config.sample_rate)?;
+        eprintln!("Saved recording to {}", path.display());
+    }
+    recorded?;
 
-            let mut temp_demod = MFSKDemodulator::new(config.clone());
+    eprintln!("Decoding...");
 
-            if temp_demod.detect_wake_up(samples).is_some() {
-                *wake_detected_clone.lock().unwrap() = true;
+    let (decoded, metadata, stats) = decode_verbose(&samples, config)?;
+    print_stats(&stats);
 
-                let end_check_start = samples.len().saturating_sub(24000);
-                let end_samples = &samples[end_check_start..];
+    Ok((decoded, metadata))
+}
 
-                let wake_mag = temp_demod.goertzel(end_samples, WAKE_UP_FREQUENCY);
-                let noise: f32 = temp_demod
-                    .get_frequencies()
-                    .iter()
-                    .map(|&f| temp_demod.goertzel(end_samples, f))
-                    .sum::<f32>()
-                    / 16.0;
+fn print_stats(stats: &DecodeStats) {
+    eprintln!(
+        "Decoded {} bytes ({} compressed, {} ECC) from {} samples in {} ms: {:.1} bit/s",
+        stats.payload_bytes,
+        stats.compressed_bytes,
+        stats.ecc_bytes,
+        stats.samples,
+        stats.duration_ms,
+        stats.effective_bps
+    );
+    eprintln!("Reception quality: {:.2}", stats.quality);
+}
 
-                return wake_mag > noise * 2.0 && samples.len() > 96000;
-            }
+fn decode_wav(config: &Config, path: &Path) -> Result<Vec<u8>> {
+    let (samples, sample_rate) = read_wav(path)?;
+    eprintln!("Read {} samples at {} Hz from {}", samples.len(), sample_rate, path.display());
 
-            false
-        },
-        timeout_secs * 1000,
-    )?;
+    let samples = if sample_rate != config.sample_rate {
+        eprintln!("Resampling to {} Hz", config.sample_rate);
+        resample_to(&samples, sample_rate, config.sample_rate)
+    } else {
+        samples
+    };
 
-    eprintln!("Recorded {} samples, demodulating...", samples.len());
+    let (decoded, _, stats) = decode_verbose(&samples, config)?;
+    print_stats(&stats);
 
-    let raw_data = demodulator
-        .demodulate(&samples)
-        .ok_or_else(|| anyhow::anyhow!("Failed to demodulate signal"))?;
+    Ok(decoded)
+}
 
-    eprintln!("Demodulated {} bytes", raw_data.len());
+fn print_info(config: &Config) {
+    let modulator = MFSKModulator::new(config.clone());
+    let nyquist = config.sample_rate as f32 / 2.0;
+    let wake_frequency = config.wake_up_frequency();
+
+    println!("Mode:            {:?}{}", config.mode, if config.ultrasonic_quiet { " (quiet)" } else { "" });
+    println!("Sample rate:     {} Hz", config.sample_rate);
+    println!(
+        "Symbol:          {} ms ({} samples), header {} ms",
+        config.symbol_duration_ms,
+        config.duration_samples(config.symbol_duration_ms),
+        config.header_symbol_duration_ms()
+    );
+    println!("Wake-up tone:    {:.0} Hz, {} ms", wake_frequency, WAKE_UP_DURATION_MS);
+    println!("Wake gap:        {} ms", config.wake_gap_ms);
+
+    println!();
+    println!("Tone  Frequency (Hz)  Gain (dB)");
+    for (i, &frequency) in modulator.get_frequencies().iter().enumerate() {
+        println!("{:>4}  {:>14.0}  {:>9.1}", format!("{:X}", i), frequency, 20.0 * config.tone_gain(frequency).log10());
+    }
 
-    let packet = Packet::deserialize(&raw_data)?;
-    eprintln!("Packet payload: {} bytes", packet.payload.len());
+    let highest = modulator.get_frequencies().iter().copied().fold(wake_frequency, f32::max);
+    let report = config.throughput_report();
+
+    println!();
+    println!("Nyquist:         {:.0} Hz ({:.0} Hz above the highest tone)", nyquist, nyquist - highest);
+    println!(
+        "Raw bitrate:     {:.1} bit/s ({:.1} symbols/s x {} bits)",
+        report.gross_bps, report.symbol_rate, report.bits_per_symbol
+    );
+    println!(
+        "Effective rate:  {:.1} bit/s ({} bytes in {:.0} ms, incl. framing and ECC)",
+        report.net_bps, report.payload_bytes, report.frame_ms
+    );
+    println!(
+        "Airtime:         {:.1}% payload, {:.1}% ECC, {:.1}% framing, {:.1}% compression",
+        report.payload_percent(),
+        report.ecc_overhead_percent,
+        report.framing_overhead_percent,
+        report.compression_overhead_percent
+    );
+}
+
+fn run_ping(config: &Config, count: u32) -> Result<()> {
+    let mut session = Session::new(config.clone())?;
+    let mut latencies = Vec::new();
 
-    let ecc = ReedSolomonCodec::new()?;
-    let decoded = ecc.decode(&packet.payload)?;
-    eprintln!("ECC decoded: {} bytes", decoded.len());
+    for i in 1..=count {
+        match session.ping() {
+            Ok(latency_ms) => {
+                println!("ping {}: {:.1} ms", i, latency_ms);
+                latencies.push(latency_ms);
+            }
+            Err(e) => println!("ping {}: {}", i, e),
+        }
+    }
 
-    let decompressed = decompress(&decoded)?;
-    eprintln!("Decompressed: {} bytes", decompressed.len());
+    if latencies.is_empty() {
+        return Err(anyhow!("no ping was heard; check the volume and that the microphone can hear the speaker"));
+    }
 
-    Ok(decompressed)
+    let min = latencies.iter().copied().fold(f32::INFINITY, f32::min);
+    let max = latencies.iter().copied().fold(0.0f32, f32::max);
+    let avg = latencies.iter().sum::<f32>() / latencies.len() as f32;
+    println!("{}/{} heard, min/avg/max = {:.1}/{:.1}/{:.1} ms", latencies.len(), count, min, avg, max);
+    Ok(())
 }
 
 fn run_test(message: &str) -> Result<()> {
     let config = Config::default();
     let data = message.as_bytes();
 
-    let compressed = compress(data);
-    let ecc = ReedSolomonCodec::new()?;
-    let encoded = ecc.encode(&compressed)?;
-    let encoded_len = encoded.len();
-    let packet = Packet::new(encoded)?;
-    let packet_data = packet.serialize();
-
-    let modulator = MFSKModulator::new(config.clone());
-    let samples = modulator.modulate(&packet_data);
+    let samples = encode_to_samples(data, &config)?;
 
     println!("Original: {} bytes", data.len());
-    println!("Compressed: {} bytes", compressed.len());
-    println!("ECC encoded: {} bytes", encoded_len);
-    println!("Packet: {} bytes", packet_data.len());
     println!("Audio samples: {}", samples.len());
     println!("Duration: {:.1} ms", samples.len() as f32 / 48.0);
 
-    let mut demodulator = MFSKDemodulator::new(config);
-    let decoded_data = demodulator
-        .demodulate(&samples)
-        .ok_or_else(|| anyhow::anyhow!("Demodulation failed"))?;
-
-    let decoded_packet = Packet::deserialize(&decoded_data)?;
-    let ecc_decoded = ecc.decode(&decoded_packet.payload)?;
-    let decompressed = decompress(&ecc_decoded)?;
-
-    let result = String::from_utf8_lossy(&decompressed);
-    println!("\nDecoded message: {}", result);
+    let (decoded, _) = decode_from_samples(&samples, &config)?;
+    println!("\nDecoded message: {}", String::from_utf8_lossy(&decoded));
 
-    if decompressed == data {
+    if selftest(data, &config)? {
         println!("\n✓ Test PASSED: Messages match!");
     } else {
         println!("\n✗ Test FAILED: Messages don't match!");
@@ -275,15 +673,131 @@ fn run_test(message: &str) -> Result<()> {
     Ok(())
 }
 
-trait DemodulatorExt {
-    fn get_frequencies(&self) -> Vec<f32>;
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mode_args() -> ModeArgs {
+        ModeArgs {
+            audible: false,
+            ultrasonic: false,
+            quiet: false,
+            acoustic: false,
+            telephony: false,
+            symbol_duration: None,
+            bits_per_symbol: 4,
+            scrambler_seed: None,
+            wake_gap: 20,
+            length_prefixed: false,
+            sync_word: false,
+            wake_repeats: 1,
+        }
+    }
+
+    fn env_from(vars: &'static [(&'static str, &'static str)]) -> impl Fn(&str) -> Option<String> {
+        move |name| vars.iter().find(|(k, _)| *k == name).map(|(_, v)| v.to_string())
+    }
+
+    #[test]
+    fn test_mode_resolution() {
+        let no_env = env_from(&[]);
+        let quiet_env = env_from(&[("SONIC_PIPE_MODE", "quiet")]);
+
+        let config = mode_args().config_with_env(&no_env).unwrap();
+        assert_eq!(config.mode, TransmissionMode::Audible);
+
+        let config = mode_args().config_with_env(&quiet_env).unwrap();
+        assert_eq!(config.mode, TransmissionMode::Ultrasonic);
+        assert!(config.ultrasonic_quiet);
+
+        let args = ModeArgs {
+            audible: true,
+            ..mode_args()
+        };
+        let config = args.config_with_env(&quiet_env).unwrap();
+        assert_eq!(config.mode, TransmissionMode::Audible);
+        assert!(!config.ultrasonic_quiet);
+
+        let args = ModeArgs {
+            ultrasonic: true,
+            ..mode_args()
+        };
+        let config = args.config_with_env(&quiet_env).unwrap();
+        assert_eq!(config.mode, TransmissionMode::Ultrasonic);
+        assert!(!config.ultrasonic_quiet);
+
+        let config = mode_args().config_with_env(&env_from(&[("SONIC_PIPE_MODE", "Acoustic")])).unwrap();
+        assert_eq!(config.mode, TransmissionMode::Acoustic);
+
+        let args = ModeArgs {
+            telephony: true,
+            ..mode_args()
+        };
+        let config = args.config_with_env(&no_env).unwrap();
+        assert_eq!(config.mode, TransmissionMode::Telephony);
+        assert_eq!(config.symbol_duration_ms, TELEPHONY_SYMBOL_DURATION_MS);
+        assert!(config.protect_ecc_geometry);
+        let config = args.config_with_env(&env_from(&[("SONIC_PIPE_SYMBOL_DURATION", "60")])).unwrap();
+        assert_eq!(config.symbol_duration_ms, 60);
+
+        assert!(mode_args().config_with_env(&env_from(&[("SONIC_PIPE_MODE", "loud")])).is_err());
+    }
+
+    #[test]
+    fn test_symbol_duration_resolution() {
+        let env = env_from(&[("SONIC_PIPE_SYMBOL_DURATION", "40")]);
+
+        assert_eq!(mode_args().config_with_env(&env_from(&[])).unwrap().symbol_duration_ms, 50);
+        assert_eq!(mode_args().config_with_env(&env).unwrap().symbol_duration_ms, 40);
+
+        let args = ModeArgs {
+            symbol_duration: Some(30),
+            ..mode_args()
+        };
+        assert_eq!(args.config_with_env(&env).unwrap().symbol_duration_ms, 30);
+    }
+
+    #[test]
+    fn test_volume_resolution() {
+        let env = env_from(&[("SONIC_PIPE_VOLUME", "0.7")]);
+
+        assert_eq!(resolve(None, &env_from(&[]), "SONIC_PIPE_VOLUME", DEFAULT_VOLUME).unwrap(), 0.5);
+        assert_eq!(resolve(None, &env, "SONIC_PIPE_VOLUME", DEFAULT_VOLUME).unwrap(), 0.7);
+        assert_eq!(resolve(Some(0.2), &env, "SONIC_PIPE_VOLUME", DEFAULT_VOLUME).unwrap(), 0.2);
+
+        let bad = env_from(&[("SONIC_PIPE_VOLUME", "loud")]);
+        assert!(resolve(None, &bad, "SONIC_PIPE_VOLUME", DEFAULT_VOLUME).is_err());
+    }
+
+    #[test]
+    fn test_embedded_name_cannot_escape_directory() {
+        assert_eq!(safe_file_name("report.pdf").as_deref(), Some("report.pdf"));
+        assert_eq!(safe_file_name("../../etc/passwd").as_deref(), Some("passwd"));
+        assert_eq!(safe_file_name(".."), None);
+    }
+
+    #[test]
+    fn test_embedded_name_never_replaces_a_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("received.txt");
+
+        write_new_file(&path, b"first").unwrap();
+        assert!(write_new_file(&path, b"second").is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), b"first");
+    }
+
+    #[test]
+    fn test_pcm_output_formats() {
+        let samples = [0.0f32, 0.5, -1.0, 2.0];
+
+        let mut f32_out = Vec::new();
+        write_pcm(&mut f32_out, &samples, PcmFormat::F32).unwrap();
+        assert_eq!(f32_out.len(), 16);
+        assert_eq!(&f32_out[4..8], &0.5f32.to_le_bytes());
 
-impl DemodulatorExt for MFSKDemodulator {
-    fn get_frequencies(&self) -> Vec<f32> {
-        let config = Config::default();
-        let base_freq = config.mode.base_frequency();
-        let step = config.mode.frequency_step();
-        (0..16).map(|i| base_freq + (i as f32) * step).collect()
+        let mut s16_out = Vec::new();
+        write_pcm(&mut s16_out, &samples, PcmFormat::S16).unwrap();
+        let values: Vec<i16> = s16_out.chunks(2).map(|b| i16::from_le_bytes([b[0], b[1]])).collect();
+        assert_eq!(values, [0, 16384, -32767, 32767]);
     }
 }
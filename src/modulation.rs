@@ -1,110 +1,773 @@
-use crate::{Config, TransmissionMode, SAMPLE_RATE, WAKE_UP_DURATION_MS, WAKE_UP_FREQUENCY};
+use crate::error::{Result, SonicPipeError};
+use crate::timing::{elapsed_between, Clock, DemodTiming, SystemClock};
+use crate::{
+    Config, SymbolDetector, ToneBand, LOG_TARGET, SHORT_FRAME_THRESHOLD, SYNC_WORD_SYMBOLS, WAKE_REPEAT_GAP_MS,
+    WAKE_UP_DURATION_MS,
+};
 use rustfft::{num_complex::Complex, FftPlanner};
+use std::cell::RefCell;
 use std::f32::consts::PI;
 
+pub const ACK_DIGIT_DURATION_MS: u32 = 40;
+pub const ACK_DIGIT_GAP_MS: u32 = 10;
+const ACK_SEQ_DIGITS: usize = 4;
+const ACK_LOW_TONES: [usize; 4] = [0, 2, 4, 6];
+const ACK_HIGH_TONES: [usize; 4] = [9, 11, 13, 15];
+// A data window below a tenth of the wake-up tone's RMS (-20 dB), or within
+// 2x the profiled noise, is treated as a dropout.
+const DROPOUT_RATIO: f32 = 0.1;
+const DROPOUT_NOISE_MARGIN: f32 = 2.0;
+
+// The sync word is looked for up to a quarter symbol either side of where
+// the wake-up tone ended, in eighths of that span.
+const SYNC_SEARCH_DIVISOR: usize = 4;
+const SYNC_SEARCH_STEPS: usize = 8;
+
+const BAND_STEP_CANDIDATES: [f32; 3] = [100.0, 150.0, 200.0];
+const BAND_SEARCH_RESOLUTION_HZ: f32 = 50.0;
+const BAND_MIN_FREQUENCY: f32 = 500.0;
+
+// A sample counts as clipped when it sits at the rails and hasn't moved
+// over three samples; a clean full-scale sine only grazes the peak.
+const CLIP_LEVEL: f32 = 0.98;
+pub const CLIP_FRACTION_THRESHOLD: f32 = 0.01;
+
+pub const DEFAULT_SPECTRUM_FFT_SIZE: usize = 4096;
+
+// The wake-up tone is looked for this far either side of where it should
+// be; a 44.1 kHz device read as 48 kHz moves it up by about 9%.
+const RATE_SEARCH_SPAN: f32 = 0.15;
+const RATE_FFT_SIZE: usize = 4096;
+// Deviations under this are left to the tone detectors, which tolerate them.
+const RATE_MISMATCH_TOLERANCE: f32 = 0.02;
+
+// Symbol periods the duration estimator will consider, and how far a
+// period's autocorrelation may fall below the strongest and still count;
+// every multiple of the period correlates about as well as the period itself.
+const SYMBOL_PERIOD_MAX_MS: u32 = 1000;
+const SYMBOL_PERIOD_PEAK_RATIO: f32 = 0.6;
+const SYMBOL_DURATION_MISMATCH_TOLERANCE: f32 = 0.1;
+
+// Wake-up, trailing and ack tones reading weaker than this (-100 dBFS) are
+// taken for digital silence rather than a tone. It is about what the 0.01
+// these checks used before magnitudes were normalized came to over a 50 ms
+// window at 48 kHz; the ratio tests beside it do the real work.
+const MIN_TONE_LEVEL: f32 = 1e-5;
+
+// Self-synchronizing scrambler with polynomial 1 + x^-18 + x^-23, as in
+// V.34: each output bit is the input bit XOR the outputs 18 and 23 bits
+// before it. The descrambler only looks at received bits, so it falls back
+// into step 23 bits after a slip, at the cost of turning each bit error on
+// air into three.
+const SCRAMBLER_TAPS: (u32, u32) = (18, 23);
+const SCRAMBLER_MASK: u32 = (1 << SCRAMBLER_TAPS.1) - 1;
+
+// A window whose pilot reads weaker than this carries no pilot, so the last
+// offset tracked is kept.
+const PILOT_MIN_LEVEL: f32 = 0.002;
+
+// Onset detection, for frames whose wake-up tone never arrived: data-band
+// energy in windows of ONSET_WINDOW_MS has to climb ONSET_RATIO over the
+// median of the ONSET_FLOOR_MS before it, and above ONSET_MIN_LEVEL, and
+// stay there for ONSET_SUSTAIN_MS, so a knock or a word doesn't pass for a
+// frame. At least ONSET_MIN_FLOOR_MS of the recording has to come first to
+// judge the floor by.
+const ONSET_WINDOW_MS: u32 = 10;
+const ONSET_RATIO: f32 = 4.0;
+const ONSET_MIN_LEVEL: f32 = 0.005;
+const ONSET_SUSTAIN_MS: u32 = 150;
+const ONSET_FLOOR_MS: u32 = 500;
+const ONSET_MIN_FLOOR_MS: u32 = 100;
+
+// Collision detection: a data window is contested when a second tone reaches
+// COLLISION_RIVAL_RATIO of the winner's magnitude and both stand
+// COLLISION_FLOOR_RATIO over the median of the other tones, and a frame collided
+// when at least COLLISION_MIN_SHARE of its windows are.
+const COLLISION_RIVAL_RATIO: f32 = 0.5;
+const COLLISION_FLOOR_RATIO: f32 = 4.0;
+pub const COLLISION_MIN_SHARE: f32 = 0.25;
+
+// Carrier sense: the channel is busy when the strongest data tone reaches
+// CHANNEL_BUSY_LEVEL in at least CHANNEL_BUSY_SHARE of the ONSET_WINDOW_MS
+// windows listened to. A sender's symbols keep some tone up all the time,
+// while a knock or a word only lifts a few windows.
+const CHANNEL_BUSY_LEVEL: f32 = 0.02;
+const CHANNEL_BUSY_SHARE: f32 = 0.5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AckKind {
+    Ack,
+    Nack,
+}
+
+impl AckKind {
+    fn digit(&self) -> u8 {
+        match self {
+            AckKind::Ack => 0xA,
+            AckKind::Nack => 0x5,
+        }
+    }
+
+    fn from_digit(digit: u8) -> Option<Self> {
+        match digit {
+            0xA => Some(AckKind::Ack),
+            0x5 => Some(AckKind::Nack),
+            _ => None,
+        }
+    }
+}
+
 pub struct MFSKModulator {
     config: Config,
     frequencies: Vec<f32>,
 }
 
 impl MFSKModulator {
-    pub fn new(config: Config) -> Self {
-        let base_freq = config.mode.base_frequency();
-        let step = config.mode.frequency_step();
+    pub fn new(mut config: Config) -> Self {
+        config.set_volume(config.volume);
+        let frequencies = config.frequencies();
+
+        Self {
+            config,
+            frequencies,
+        }
+    }
+
+    // Sends symbol `i` on `frequencies[i]` instead of the config's band, for
+    // tone sets that aren't evenly spaced. The receiver needs the same table.
+    pub fn with_frequencies(config: Config, frequencies: Vec<f32>) -> Result<Self> {
+        check_frequency_table(&config, &frequencies)?;
+        if let Some(gains) = &config.tone_gains {
+            config.check_tone_gains(gains, &frequencies)?;
+        }
+        Ok(Self {
+            frequencies,
+            ..Self::new(config)
+        })
+    }
+
+    // Replaces the config's `tone_gains`, rejected if any tone would then
+    // peak above full scale.
+    pub fn set_tone_gains(&mut self, gains: Vec<f32>) -> Result<()> {
+        self.config.check_tone_gains(&gains, &self.frequencies)?;
+        self.config.tone_gains = Some(gains);
+        Ok(())
+    }
 
-        let frequencies: Vec<f32> = (0..16).map(|i| base_freq + (i as f32) * step).collect();
+    pub fn tone_gains(&self) -> Option<&[f32]> {
+        self.config.tone_gains.as_deref()
+    }
 
-        Self { config, frequencies }
+    fn emphasis(&self, frequency: f32) -> f32 {
+        let Some(gains) = &self.config.tone_gains else {
+            return 1.0;
+        };
+        self.frequencies
+            .iter()
+            .position(|&f| f == frequency)
+            .and_then(|index| gains.get(index).copied())
+            .unwrap_or(1.0)
     }
 
     pub fn generate_tone(&self, frequency: f32, duration_ms: u32) -> Vec<f32> {
-        let num_samples = (self.config.sample_rate as f32 * duration_ms as f32 / 1000.0) as usize;
-        let mut samples = Vec::with_capacity(num_samples);
-
-        for i in 0..num_samples {
-            let t = i as f32 / self.config.sample_rate as f32;
-            let sample = (2.0 * PI * frequency * t).sin() * self.config.volume;
-
-            let fade_samples = (self.config.sample_rate as f32 * 0.005) as usize;
-            let fade = if i < fade_samples {
-                i as f32 / fade_samples as f32
-            } else if i > num_samples - fade_samples {
-                (num_samples - i) as f32 / fade_samples as f32
-            } else {
-                1.0
-            };
+        self.generate_tone_samples(frequency, self.config.duration_samples(duration_ms))
+    }
+
+    fn generate_tone_samples(&self, frequency: f32, num_samples: usize) -> Vec<f32> {
+        self.generate_tone_with_phase(frequency, num_samples, 0.0)
+    }
+
+    fn generate_tone_with_phase(&self, frequency: f32, num_samples: usize, phase: f32) -> Vec<f32> {
+        let amplitude = self.config.volume * self.config.tone_gain(frequency) * self.emphasis(frequency);
+
+        self.envelope(num_samples)
+            .into_iter()
+            .enumerate()
+            .map(|(i, gain)| {
+                let t = i as f32 / self.config.sample_rate as f32;
+                (2.0 * PI * frequency * t + phase).sin() * amplitude * gain
+            })
+            .collect()
+    }
 
-            samples.push(sample * fade);
+    // Starting phase of tone `index` in a multi-tone signal. It depends only
+    // on the seed and the tone, so a given subcarrier always starts the same.
+    fn tone_phase(&self, index: usize) -> f32 {
+        if !self.config.phase_randomization {
+            return 0.0;
+        }
+        let bits = splitmix64(self.config.phase_seed ^ (index as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15));
+        (bits >> 40) as f32 / (1u64 << 24) as f32 * 2.0 * PI
+    }
+
+    // Sums the given tones, scaled so the result never exceeds the volume.
+    pub fn generate_multitone(&self, tones: &[usize], duration_ms: u32) -> Vec<f32> {
+        let num_samples = self.config.duration_samples(duration_ms);
+        let scale = 1.0 / tones.len().max(1) as f32;
+        let mut samples = vec![0.0f32; num_samples];
+
+        for &index in tones {
+            let frequency = self.config.tone_frequency(index);
+            let tone = self.generate_tone_with_phase(frequency, num_samples, self.tone_phase(index));
+            for (sample, t) in samples.iter_mut().zip(tone) {
+                *sample += t * scale;
+            }
         }
 
         samples
     }
 
+    // Gain for each sample of a tone. Fades are measured from the nearer edge
+    // and capped at half the tone, so short symbols rise and fall without the
+    // two ramps overlapping.
+    fn envelope(&self, num_samples: usize) -> Vec<f32> {
+        let fade_samples = self.config.duration_samples(self.config.fade_ms).min(num_samples / 2);
+
+        (0..num_samples)
+            .map(|i| {
+                let edge = i.min(num_samples - 1 - i);
+                if edge < fade_samples {
+                    self.config.fade_shape.gain(edge as f32 / fade_samples as f32)
+                } else {
+                    1.0
+                }
+            })
+            .collect()
+    }
+
     pub fn generate_wake_up_tone(&self) -> Vec<f32> {
-        self.generate_tone(WAKE_UP_FREQUENCY, WAKE_UP_DURATION_MS)
+        self.generate_tone(self.config.wake_up_frequency(), WAKE_UP_DURATION_MS)
     }
 
-    pub fn modulate(&self, data: &[u8]) -> Vec<f32> {
+    pub fn modulate(&self, data: &[u8]) -> Result<Vec<f32>> {
         let mut samples = Vec::new();
 
-        samples.extend(self.generate_wake_up_tone());
+        for repeat in 0..self.config.wake_up_repeats.max(1) {
+            if repeat > 0 {
+                samples.extend(vec![0.0f32; self.config.duration_samples(WAKE_REPEAT_GAP_MS)]);
+            }
+            samples.extend(self.generate_wake_up_tone());
+        }
+        if self.config.sync_word {
+            self.push_sync_word(&mut samples);
+        }
+
+        let short_frame = self.config.uses_length_prefix(data.len());
+        if !short_frame {
+            samples.extend(vec![0.0f32; self.config.wake_gap_samples()]);
+        }
+        let pilot_start = samples.len();
+        if short_frame {
+            self.push_length(&mut samples, data.len())?;
+        }
+
+        let data = match self.config.scrambler_seed {
+            Some(seed) => scramble(data, seed),
+            None => data.to_vec(),
+        };
+        for (i, symbol) in bytes_to_symbols(&data, self.frequencies.len()).into_iter().enumerate() {
+            let len = self.config.symbol_offset(i + 1) - self.config.symbol_offset(i);
+            samples.extend(self.generate_tone_samples(self.frequencies[symbol as usize], len));
+        }
+        self.add_pilot(&mut samples[pilot_start..]);
+
+        if !short_frame {
+            samples.extend(self.generate_wake_up_tone());
+        }
 
-        let silence_samples = (self.config.sample_rate as f32 * 0.02) as usize;
-        samples.extend(vec![0.0f32; silence_samples]);
+        Ok(samples)
+    }
 
-        for byte in data {
-            let high_nibble = (byte >> 4) & 0x0F;
-            let low_nibble = byte & 0x0F;
+    // `modulate`, along with the peak it reaches; see `peak_amplitude`.
+    pub fn modulate_with_peak(&self, data: &[u8]) -> Result<(Vec<f32>, f32)> {
+        let samples = self.modulate(data)?;
+        let peak = peak_amplitude(&samples);
+        Ok((samples, peak))
+    }
 
-            let high_freq = self.frequencies[high_nibble as usize];
-            let low_freq = self.frequencies[low_nibble as usize];
+    // Sums the pilot under the data, which is turned down by the pilot's
+    // share so the peak stays where the volume puts it.
+    fn add_pilot(&self, samples: &mut [f32]) {
+        let Some(pilot) = self.config.pilot else {
+            return;
+        };
 
-            samples.extend(self.generate_tone(high_freq, self.config.symbol_duration_ms));
-            samples.extend(self.generate_tone(low_freq, self.config.symbol_duration_ms));
+        let tone = self.generate_tone_samples(pilot.frequency, samples.len());
+        for (sample, pilot_sample) in samples.iter_mut().zip(tone) {
+            *sample = *sample * (1.0 - pilot.level) + pilot_sample * pilot.level;
         }
+    }
 
-        samples.extend(self.generate_wake_up_tone());
+    // Renders the transmission for a device running at `output_rate`,
+    // generating every tone at that rate rather than resampling, so the high
+    // tones come out clean. The receiver has to be configured for the same
+    // rate.
+    pub fn modulate_at_rate(&self, data: &[u8], output_rate: u32) -> Result<Vec<f32>> {
+        let config = Config {
+            sample_rate: output_rate,
+            ..self.config.clone()
+        };
+        config.validate()?;
 
-        samples
+        let modulator = MFSKModulator {
+            frequencies: self.frequencies.clone(),
+            ..MFSKModulator::new(config)
+        };
+        modulator.modulate(data)
+    }
+
+    // Back-to-back frames with `inter_frame_gap_ms` of silence between them.
+    pub fn modulate_frames(&self, frames: &[&[u8]]) -> Result<Vec<f32>> {
+        let gap = vec![0.0f32; self.config.duration_samples(self.config.inter_frame_gap_ms)];
+        let mut samples = Vec::new();
+
+        for (i, frame) in frames.iter().enumerate() {
+            if i > 0 {
+                samples.extend_from_slice(&gap);
+            }
+            samples.extend(self.modulate(frame)?);
+        }
+
+        Ok(samples)
+    }
+
+    pub fn frame_samples(&self, data_len: usize) -> usize {
+        let wake_samples = self.config.duration_samples(WAKE_UP_DURATION_MS);
+        let gap_samples = self.config.wake_gap_samples();
+        let data_samples = self.config.data_samples(data_len);
+        let extra_repeats = self.config.wake_up_repeats.max(1) as usize - 1;
+        let lead_samples = wake_samples
+            + extra_repeats * (self.config.duration_samples(WAKE_REPEAT_GAP_MS) + wake_samples)
+            + self.config.sync_word_samples();
+
+        if self.config.uses_length_prefix(data_len) {
+            let length_samples = self.config.symbols_per_bytes(self.config.length_prefix_bytes())
+                * self.config.duration_samples(self.config.header_symbol_duration_ms());
+            lead_samples + length_samples + data_samples
+        } else {
+            lead_samples + wake_samples + gap_samples + data_samples
+        }
+    }
+
+    fn push_sync_word(&self, samples: &mut Vec<f32>) {
+        let symbol_samples = self.config.duration_samples(self.config.symbol_duration_ms);
+        for tone in sync_tones(self.frequencies.len()) {
+            samples.extend(self.generate_tone_samples(self.frequencies[tone], symbol_samples));
+        }
+    }
+
+    // The frame length, big-endian, at the header rate. A length the prefix
+    // can't hold would come out truncated, so it's refused.
+    fn push_length(&self, samples: &mut Vec<f32>, len: usize) -> Result<()> {
+        let prefix_bytes = self.config.length_prefix_bytes();
+        if len >= 1 << (8 * prefix_bytes) {
+            return Err(SonicPipeError::Encoding(format!(
+                "frame length {} doesn't fit a {}-byte length prefix",
+                len, prefix_bytes
+            )));
+        }
+        let bytes = (len as u16).to_be_bytes();
+        let prefix = &bytes[bytes.len() - prefix_bytes..];
+        for symbol in bytes_to_symbols(prefix, self.frequencies.len()) {
+            samples.extend(self.generate_tone(self.frequencies[symbol as usize], self.config.header_symbol_duration_ms()));
+        }
+        Ok(())
     }
 
     pub fn get_frequencies(&self) -> &[f32] {
         &self.frequencies
     }
+
+    // Picks the tone layout whose tones see the least total noise in a
+    // spectrum such as `AudioInput::probe_noise` returns, keeping clear of
+    // the wake-up tone and of Nyquist.
+    pub fn recommend_band(&self, noise: &[(f32, f32)]) -> Option<ToneBand> {
+        if noise.is_empty() {
+            return None;
+        }
+
+        let noise_at = |frequency: f32| {
+            let i = noise.partition_point(|&(f, _)| f < frequency);
+            match (i.checked_sub(1).map(|j| noise[j]), noise.get(i)) {
+                (Some(below), Some(above)) if frequency - below.0 < above.0 - frequency => below.1,
+                (_, Some(above)) => above.1,
+                (Some(below), None) => below.1,
+                (None, None) => 0.0,
+            }
+        };
+
+        let nyquist = self.config.sample_rate as f32 / 2.0;
+        let wake = self.config.wake_up_frequency();
+        let num_tones = self.frequencies.len();
+        let mut best: Option<(f32, ToneBand)> = None;
+
+        for step in BAND_STEP_CANDIDATES {
+            let mut base = BAND_MIN_FREQUENCY;
+            while base + (num_tones - 1) as f32 * step < nyquist - step {
+                let tones = (0..num_tones).map(|i| base + i as f32 * step);
+                if tones.clone().all(|f| (f - wake).abs() >= 2.0 * step) {
+                    let score: f32 = tones.map(noise_at).sum();
+                    if best.is_none_or(|(best_score, _)| score < best_score) {
+                        best = Some((
+                            score,
+                            ToneBand {
+                                base_frequency: base,
+                                frequency_step: step,
+                            },
+                        ));
+                    }
+                }
+                base += BAND_SEARCH_RESOLUTION_HZ;
+            }
+        }
+
+        best.map(|(_, band)| band)
+    }
+
+    pub fn generate_ack_tone(&self, seq: u16) -> Vec<f32> {
+        self.generate_ack_burst(AckKind::Ack, seq)
+    }
+
+    pub fn generate_nack_tone(&self, seq: u16) -> Vec<f32> {
+        self.generate_ack_burst(AckKind::Nack, seq)
+    }
+
+    fn generate_ack_burst(&self, kind: AckKind, seq: u16) -> Vec<f32> {
+        let gap_samples = (self.config.sample_rate as f32 * ACK_DIGIT_GAP_MS as f32 / 1000.0) as usize;
+
+        let mut digits = vec![kind.digit()];
+        digits.extend((0..ACK_SEQ_DIGITS).rev().map(|i| ((seq >> (i * 4)) & 0x0F) as u8));
+
+        let mut samples = Vec::new();
+        for digit in digits {
+            let tones = [ACK_LOW_TONES[(digit >> 2) as usize], ACK_HIGH_TONES[(digit & 0x03) as usize]];
+            samples.extend(self.generate_multitone(&tones, ACK_DIGIT_DURATION_MS));
+            samples.extend(vec![0.0f32; gap_samples]);
+        }
+
+        samples
+    }
+}
+
+// Largest absolute sample in `samples`. Anything above 1.0 will clip on a
+// float output device, and sooner on one converting to integer samples.
+pub fn peak_amplitude(samples: &[f32]) -> f32 {
+    samples.iter().fold(0.0f32, |peak, &s| peak.max(s.abs()))
+}
+
+// The tones of the sync word, as shares of the band so that any tone count
+// has them: neighbours sit far apart, so a window that straddles two still
+// reads one of them clearly.
+fn sync_tones(num_tones: usize) -> [usize; SYNC_WORD_SYMBOLS] {
+    [num_tones / 4, num_tones - 1, 0, num_tones / 2]
+}
+
+// Bits each symbol carries with `num_tones` tones. A count that isn't a
+// power of two rounds down and leaves its top tones unused. Panics outside
+// 2-256 tones, which a validated `Config` never has.
+fn bits_per_symbol(num_tones: usize) -> usize {
+    assert!((2..=256).contains(&num_tones), "tone count {} is outside 2-256", num_tones);
+    num_tones.ilog2() as usize
+}
+
+// The tone indices `data` is sent as, most significant bits first: with 16
+// tones, each byte's high nibble and then its low nibble. Panics unless
+// `num_tones` is 2-256.
+pub fn bytes_to_symbols(data: &[u8], num_tones: usize) -> Vec<u8> {
+    pack_bits(data, bits_per_symbol(num_tones))
+}
+
+// Inverse of `bytes_to_symbols`; bits left over after the last whole byte
+// are dropped. Panics unless `num_tones` is 2-256.
+pub fn symbols_to_bytes(symbols: &[u8], num_tones: usize) -> Vec<u8> {
+    let bits = bits_per_symbol(num_tones);
+    unpack_bits(symbols, bits, symbols.len() * bits / 8)
+}
+
+// Splits `data` into `bits`-wide symbols, most significant bits first. A
+// final symbol that only partly overlaps the data is padded with zero bits,
+// so the caller has to keep the byte length to undo it. Panics unless
+// `bits` is 1-8.
+pub fn pack_bits(data: &[u8], bits: usize) -> Vec<u8> {
+    assert!((1..=8).contains(&bits), "{} bits per symbol is outside 1-8", bits);
+    let mask = (1u32 << bits) - 1;
+    let mut symbols = Vec::with_capacity((data.len() * 8).div_ceil(bits));
+
+    let mut acc = 0u32;
+    let mut acc_bits = 0;
+    for &byte in data {
+        acc = (acc << 8) | byte as u32;
+        acc_bits += 8;
+        while acc_bits >= bits {
+            acc_bits -= bits;
+            symbols.push(((acc >> acc_bits) & mask) as u8);
+        }
+    }
+    if acc_bits > 0 {
+        symbols.push(((acc << (bits - acc_bits)) & mask) as u8);
+    }
+
+    symbols
+}
+
+// Inverse of `pack_bits` for `len` original bytes. The padding in the last
+// symbol is dropped, as is anything past `len`; too few symbols give only
+// the bytes they fully cover. Panics unless `bits` is 1-8.
+pub fn unpack_bits(symbols: &[u8], bits: usize, len: usize) -> Vec<u8> {
+    assert!((1..=8).contains(&bits), "{} bits per symbol is outside 1-8", bits);
+    let mask = (1u32 << bits) - 1;
+    let mut bytes = Vec::with_capacity(len.min(symbols.len() * bits / 8));
+
+    let mut acc = 0u32;
+    let mut acc_bits = 0;
+    for &symbol in symbols {
+        if bytes.len() == len {
+            break;
+        }
+        acc = (acc << bits) | (symbol as u32 & mask);
+        acc_bits += bits;
+        if acc_bits >= 8 {
+            acc_bits -= 8;
+            bytes.push((acc >> acc_bits) as u8);
+        }
+    }
+
+    bytes
+}
+
+// The scrambler register's starting state for `seed`. From zero a run of
+// zero bytes would go out unchanged, so the low bit is always set.
+fn scrambler_state(seed: u64) -> u32 {
+    (splitmix64(seed) as u32 & SCRAMBLER_MASK) | 1
+}
+
+fn scrambler_feedback(state: u32) -> u8 {
+    ((state >> (SCRAMBLER_TAPS.0 - 1)) ^ (state >> (SCRAMBLER_TAPS.1 - 1))) as u8 & 1
+}
+
+// Applies `f` to every bit of `data`, most significant first.
+fn map_bits(data: &[u8], mut f: impl FnMut(u8) -> u8) -> Vec<u8> {
+    data.iter()
+        .map(|&byte| {
+            (0..8)
+                .rev()
+                .fold(0u8, |out, i| (out << 1) | f((byte >> i) & 1))
+        })
+        .collect()
+}
+
+// Whitens `data` with the self-synchronizing scrambler, its register seeded
+// from `seed`, so even constant data is sent as a varied tone sequence that
+// spreads its energy over every tone.
+pub fn scramble(data: &[u8], seed: u64) -> Vec<u8> {
+    let mut state = scrambler_state(seed);
+    map_bits(data, |bit| {
+        let out = bit ^ scrambler_feedback(state);
+        state = ((state << 1) | out as u32) & SCRAMBLER_MASK;
+        out
+    })
+}
+
+// Only the first 23 bits depend on the seed; after that the register holds
+// received bits alone.
+pub fn descramble(data: &[u8], seed: u64) -> Vec<u8> {
+    let mut state = scrambler_state(seed);
+    map_bits(data, |bit| {
+        let out = bit ^ scrambler_feedback(state);
+        state = ((state << 1) | bit as u32) & SCRAMBLER_MASK;
+        out
+    })
+}
+
+// An explicit tone table needs one distinct tone per symbol, each below
+// Nyquist.
+fn check_frequency_table(config: &Config, frequencies: &[f32]) -> Result<()> {
+    if frequencies.len() != config.num_tones() {
+        return Err(SonicPipeError::InvalidConfig(format!(
+            "{} bits per symbol needs {} tones, got {}",
+            config.bits_per_symbol,
+            config.num_tones(),
+            frequencies.len()
+        )));
+    }
+
+    let nyquist = config.sample_rate as f32 / 2.0;
+    for (i, &frequency) in frequencies.iter().enumerate() {
+        if !(frequency > 0.0 && frequency < nyquist) {
+            return Err(SonicPipeError::InvalidConfig(format!(
+                "tone {} at {} Hz is outside 0-{:.0} Hz",
+                i, frequency, nyquist
+            )));
+        }
+        if frequencies[..i].contains(&frequency) {
+            return Err(SonicPipeError::InvalidConfig(format!("{} Hz appears twice in the tone table", frequency)));
+        }
+    }
+
+    Ok(())
+}
+
+pub(crate) fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+// What a window's pilot says about the channel: how far it sits off its
+// nominal frequency, and its amplitude.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PilotReading {
+    pub offset_hz: f32,
+    pub level: f32,
+}
+
+// The dropout threshold from `signal_floor`. With a pilot, the part taken
+// from the wake-up tone follows the pilot's level through the frame, so a
+// slow fade of the whole channel isn't mistaken for lost signal.
+#[derive(Debug, Clone, Copy)]
+struct SignalFloor {
+    signal: f32,
+    noise: f32,
+    reference_pilot: Option<f32>,
+    gain: f32,
+}
+
+impl SignalFloor {
+    fn follow_pilot(&mut self, reading: PilotReading) {
+        let reference = *self.reference_pilot.get_or_insert(reading.level);
+        self.gain = reading.level / reference;
+    }
+
+    fn level(&self) -> f32 {
+        (self.signal * self.gain).max(self.noise)
+    }
+}
+
+#[derive(Default)]
+struct FrameSymbols {
+    symbols: Vec<u8>,
+    erased: Vec<usize>,
+    trailing_start: Option<usize>,
+}
+
+impl FrameSymbols {
+    fn clear(&mut self) {
+        self.symbols.clear();
+        self.erased.clear();
+        self.trailing_start = None;
+    }
+
+    // A window with no trusted symbol goes in as zero bits, noted as erased.
+    fn push(&mut self, symbol: Option<u8>) {
+        if symbol.is_none() {
+            self.erased.push(self.symbols.len());
+        }
+        self.symbols.push(symbol.unwrap_or(0));
+    }
+}
+
+// Buffers kept across calls so that decoding frame after frame doesn't
+// allocate for every symbol window.
+#[derive(Default)]
+struct Scratch {
+    frame: FrameSymbols,
+    fft_buffer: Vec<Complex<f32>>,
+    fft_scratch: Vec<Complex<f32>>,
+    magnitudes: Vec<f32>,
 }
 
 pub struct MFSKDemodulator {
     config: Config,
     frequencies: Vec<f32>,
-    fft_planner: FftPlanner<f32>,
+    // Behind a RefCell so symbol detection can reuse cached plans from the
+    // `&self` demodulation paths.
+    fft_planner: RefCell<FftPlanner<f32>>,
+    noise_profile: Option<Vec<f32>>,
+    smoothed_spectrum: Vec<f32>,
+    scratch: RefCell<Scratch>,
+    // Rough operation count of the tone transforms run so far: a Goertzel
+    // filter costs its window length, an FFT `n log2 n`. Lets tests compare
+    // the two detectors without timing them.
+    #[cfg(test)]
+    transform_work: std::cell::Cell<u64>,
 }
 
 impl MFSKDemodulator {
     pub fn new(config: Config) -> Self {
-        let base_freq = config.mode.base_frequency();
-        let step = config.mode.frequency_step();
-
-        let frequencies: Vec<f32> = (0..16).map(|i| base_freq + (i as f32) * step).collect();
+        let frequencies = config.frequencies();
 
         Self {
             config,
             frequencies,
-            fft_planner: FftPlanner::new(),
+            fft_planner: RefCell::new(FftPlanner::new()),
+            noise_profile: None,
+            smoothed_spectrum: Vec::new(),
+            scratch: RefCell::new(Scratch::default()),
+            #[cfg(test)]
+            transform_work: Default::default(),
+        }
+    }
+
+    // Detects symbols against an explicit tone table, such as one from
+    // another encoder; the wake-up tone still comes from the config.
+    pub fn with_frequencies(config: Config, frequencies: Vec<f32>) -> Result<Self> {
+        check_frequency_table(&config, &frequencies)?;
+        Ok(Self {
+            frequencies,
+            ..Self::new(config)
+        })
+    }
+
+    // Per-tone mean noise magnitude, subtracted from each tone before the
+    // symbol decision so louder low-frequency room noise doesn't bias it.
+    pub fn set_noise_profile(&mut self, profile: Vec<f32>) {
+        self.noise_profile = Some(profile);
+    }
+
+    pub fn clear_noise_profile(&mut self) {
+        self.noise_profile = None;
+    }
+
+    pub fn noise_profile(&self) -> Option<&[f32]> {
+        self.noise_profile.as_deref()
+    }
+
+    pub fn estimate_noise_profile(&self, noise: &[f32]) -> Vec<f32> {
+        let symbol_samples = self.symbol_samples();
+        let mut profile = vec![0.0f32; self.frequencies.len()];
+        let mut windows = 0;
+
+        for window in noise.chunks_exact(symbol_samples) {
+            for (p, &freq) in profile.iter_mut().zip(self.frequencies.iter()) {
+                *p += self.goertzel_normalized(window, freq);
+            }
+            windows += 1;
+        }
+
+        if windows > 0 {
+            for p in profile.iter_mut() {
+                *p /= windows as f32;
+            }
         }
+
+        profile
     }
 
     pub fn goertzel(&self, samples: &[f32], target_freq: f32) -> f32 {
         let n = samples.len();
+        #[cfg(test)]
+        self.transform_work
+            .set(self.transform_work.get() + n as u64);
         let k = (target_freq * n as f32 / self.config.sample_rate as f32).round() as usize;
         let omega = 2.0 * PI * k as f32 / n as f32;
         let coeff = 2.0 * omega.cos();
 
-        let mut s0 = 0.0f32;
         let mut s1 = 0.0f32;
         let mut s2 = 0.0f32;
 
         for &sample in samples {
-            s0 = sample + coeff * s1 - s2;
+            let s0 = sample + coeff * s1 - s2;
             s2 = s1;
             s1 = s0;
         }
@@ -113,130 +776,3371 @@ impl MFSKDemodulator {
         power.sqrt()
     }
 
-    pub fn detect_wake_up(&self, samples: &[f32]) -> Option<usize> {
-        let window_size = (self.config.sample_rate as f32 * WAKE_UP_DURATION_MS as f32 / 1000.0 / 2.0) as usize;
-        let step = window_size / 4;
+    // Scales the Goertzel magnitude by 2/N so a pure tone reads as its
+    // amplitude, independent of the window length.
+    pub fn goertzel_normalized(&self, samples: &[f32], target_freq: f32) -> f32 {
+        if samples.is_empty() {
+            return 0.0;
+        }
+        self.goertzel(samples, target_freq) * 2.0 / samples.len() as f32
+    }
 
-        for i in (0..samples.len().saturating_sub(window_size)).step_by(step) {
-            let window = &samples[i..i + window_size];
-            let wake_mag = self.goertzel(window, WAKE_UP_FREQUENCY);
+    pub fn clipped_fraction(&self, samples: &[f32]) -> f32 {
+        if samples.len() < 3 {
+            return 0.0;
+        }
 
-            let data_mag: f32 = self.frequencies.iter()
-                .map(|&f| self.goertzel(window, f))
-                .fold(0.0f32, |a, b| a.max(b));
+        let clipped = samples
+            .windows(3)
+            .filter(|w| w[1].abs() >= CLIP_LEVEL && (w[1] - w[0]).abs() < 1e-4 && (w[2] - w[1]).abs() < 1e-4)
+            .count();
 
-            if wake_mag > 0.01 && wake_mag > data_mag * 1.5 {
-                let wake_end = i + (self.config.sample_rate as f32 * WAKE_UP_DURATION_MS as f32 / 1000.0) as usize;
-                return Some(wake_end);
-            }
-        }
+        clipped as f32 / (samples.len() - 2) as f32
+    }
 
-        None
+    pub fn is_clipped(&self, samples: &[f32]) -> bool {
+        self.clipped_fraction(samples) > CLIP_FRACTION_THRESHOLD
     }
 
-    pub fn detect_symbol(&self, samples: &[f32]) -> u8 {
-        let mut max_magnitude = 0.0f32;
-        let mut detected_index = 0u8;
+    fn wake_window_size(&self) -> usize {
+        (self.config.sample_rate as f32 * WAKE_UP_DURATION_MS as f32 / 1000.0 / 2.0) as usize
+    }
 
-        for (i, &freq) in self.frequencies.iter().enumerate() {
-            let magnitude = self.goertzel(samples, freq);
-            if magnitude > max_magnitude {
-                max_magnitude = magnitude;
-                detected_index = i as u8;
-            }
-        }
+    fn wake_samples(&self) -> usize {
+        (self.config.sample_rate as f32 * WAKE_UP_DURATION_MS as f32 / 1000.0) as usize
+    }
 
-        detected_index
+    fn is_wake_window(&self, window: &[f32]) -> bool {
+        self.wake_dominance(window) > 0.5
     }
 
-    pub fn demodulate(&mut self, samples: &[f32]) -> Option<Vec<u8>> {
-        let start_pos = self.detect_wake_up(samples)?;
+    // How far the wake tone stands out in a window, from 0 to 1. The window
+    // passes the detection ratio at 0.5, and windows too quiet to detect
+    // score 0.
+    fn wake_dominance(&self, window: &[f32]) -> f32 {
+        let wake_mag = self.goertzel_normalized(window, self.config.wake_up_frequency());
+        if wake_mag <= MIN_TONE_LEVEL {
+            return 0.0;
+        }
+
+        let data_mag: f32 = self.frequencies.iter()
+            .map(|&f| self.goertzel_normalized(window, f))
+            .fold(0.0f32, |a, b| a.max(b));
 
-        let symbol_samples = (self.config.sample_rate as f32 * self.config.symbol_duration_ms as f32 / 1000.0) as usize;
-        let mut pos = start_pos + (self.config.sample_rate as f32 * 0.02) as usize;
+        wake_mag / (wake_mag + data_mag * 1.5)
+    }
 
-        let mut data = Vec::new();
-        let mut nibbles = Vec::new();
+    // Quick check for a wake-up tone, without locating its end or reading
+    // any data.
+    pub fn has_signal(&self, samples: &[f32]) -> bool {
+        self.find_wake_window(samples, 0, samples.len()).is_some()
+    }
 
-        while pos + symbol_samples <= samples.len() {
-            let window = &samples[pos..pos + symbol_samples];
+    // Whether another sender is on the air in `samples`: a wake-up tone, or
+    // data tones sounding through most of the recording. Meant for a short
+    // listen before transmitting, so unlike `detect_onset` there's no noise
+    // floor to compare against and the level is absolute.
+    pub fn channel_busy(&self, samples: &[f32]) -> bool {
+        if self.has_signal(samples) {
+            return true;
+        }
 
-            let wake_mag = self.goertzel(window, WAKE_UP_FREQUENCY);
-            let data_mag: f32 = self.frequencies.iter()
-                .map(|&f| self.goertzel(window, f))
-                .fold(0.0f32, |a, b| a.max(b));
+        let window = self.config.duration_samples(ONSET_WINDOW_MS).max(1);
+        let windows = samples.len() / window;
+        let loud = samples
+            .chunks_exact(window)
+            .filter(|chunk| self.data_magnitude(chunk) >= CHANNEL_BUSY_LEVEL)
+            .count();
+        windows > 0 && loud as f32 >= windows as f32 * CHANNEL_BUSY_SHARE
+    }
 
-            if wake_mag > data_mag * 1.5 && wake_mag > 0.01 {
-                break;
-            }
+    // The strongest wake-tone dominance over any window in `samples`; above
+    // 0.5 the tone is strong enough for `has_signal`.
+    pub fn signal_confidence(&self, samples: &[f32]) -> f32 {
+        let window_size = self.wake_window_size();
+        let step = window_size / 4;
 
-            let symbol = self.detect_symbol(window);
-            nibbles.push(symbol);
+        (0..samples.len().saturating_sub(window_size))
+            .step_by(step)
+            .map(|i| self.wake_dominance(&samples[i..i + window_size]))
+            .fold(0.0f32, f32::max)
+    }
 
-            pos += symbol_samples;
+    // The rate the samples were actually captured at, judged from where the
+    // first wake-up tone lands: a device running at a different rate than
+    // configured shifts every tone by the ratio of the two.
+    pub fn estimate_sample_rate(&self, samples: &[f32]) -> Option<f32> {
+        let sample_rate = self.config.sample_rate as f32;
+        let expected = self.config.wake_up_frequency();
+        let bin_hz = sample_rate / RATE_FFT_SIZE as f32;
+        let low = (expected * (1.0 - RATE_SEARCH_SPAN) / bin_hz) as usize;
+        let high = ((expected * (1.0 + RATE_SEARCH_SPAN) / bin_hz) as usize).min(RATE_FFT_SIZE / 2 - 1);
+        if low + 2 > high {
+            return None;
         }
 
-        for chunk in nibbles.chunks(2) {
-            if chunk.len() == 2 {
-                let byte = (chunk[0] << 4) | (chunk[1] & 0x0F);
-                data.push(byte);
+        for start in (0..samples.len().saturating_sub(RATE_FFT_SIZE)).step_by(RATE_FFT_SIZE / 4) {
+            let spectrum = self.symbol_spectrum(&samples[start..start + RATE_FFT_SIZE]);
+            let range = &spectrum[low..=high];
+            let (offset, &peak) = range.iter().enumerate().max_by(|a, b| a.1.total_cmp(b.1))?;
+            let mean = range.iter().sum::<f32>() / range.len() as f32;
+            if peak <= MIN_TONE_LEVEL || peak < mean * 4.0 {
+                continue;
             }
-        }
 
-        if data.is_empty() {
-            None
-        } else {
-            Some(data)
+            // Parabolic interpolation between the neighbouring bins.
+            let bin = low + offset;
+            let (left, right) = (spectrum[bin - 1], spectrum[bin + 1]);
+            let curvature = left - 2.0 * peak + right;
+            let shift = if curvature < 0.0 { 0.5 * (left - right) / curvature } else { 0.0 };
+            let measured = (bin as f32 + shift) * bin_hz;
+
+            return Some(sample_rate * expected / measured);
         }
+
+        None
     }
 
-    pub fn analyze_spectrum(&mut self, samples: &[f32]) -> Vec<(f32, f32)> {
-        let fft_size = 4096;
-        let fft = self.fft_planner.plan_fft_forward(fft_size);
+    // The estimated capture rate, if it is far enough off the configured one
+    // to explain a failed decode.
+    pub fn sample_rate_mismatch(&self, samples: &[f32]) -> Option<f32> {
+        let sample_rate = self.config.sample_rate as f32;
+        self.estimate_sample_rate(samples)
+            .filter(|estimated| (estimated / sample_rate - 1.0).abs() > RATE_MISMATCH_TOLERANCE)
+    }
 
-        let mut input: Vec<Complex<f32>> = samples
-            .iter()
-            .take(fft_size)
-            .map(|&s| Complex::new(s, 0.0))
-            .collect();
+    // The sender's symbol duration in milliseconds, judged from the signal
+    // alone. The dominant tone is tracked after the wake-up tone and every
+    // change of tone lands on a whole number of symbols from the start of
+    // the data, header symbols included, so the transitions' autocorrelation
+    // peaks at the symbol period. The first clear peak is then refined by a
+    // least-squares fit over every transition.
+    pub fn estimate_symbol_duration(&self, samples: &[f32]) -> Option<f32> {
+        let start = self.detect_wake_up(samples)?;
+        let data = &samples[start..];
 
-        while input.len() < fft_size {
-            input.push(Complex::new(0.0, 0.0));
+        // One tone spacing per bin, so each window separates neighbouring tones.
+        let mut sorted = self.frequencies.clone();
+        sorted.sort_by(f32::total_cmp);
+        let spacing = sorted.windows(2).map(|w| w[1] - w[0]).fold(f32::INFINITY, f32::min);
+        let window = (self.config.sample_rate as f32 / spacing).round() as usize;
+        let hop = self.config.duration_samples(1).max(1);
+        if window == 0 || data.len() < window + hop {
+            return None;
         }
 
-        fft.process(&mut input);
+        let track: Vec<(usize, f32)> = (0..=(data.len() - window) / hop)
+            .map(|j| {
+                let frame = &data[j * hop..j * hop + window];
+                self.frequencies
+                    .iter()
+                    .map(|&f| self.tone_magnitude(frame, f))
+                    .enumerate()
+                    .fold((0, f32::NEG_INFINITY), |best, (i, m)| if m > best.1 { (i, m) } else { best })
+            })
+            .collect();
 
-        let freq_resolution = self.config.sample_rate as f32 / fft_size as f32;
+        let mut peaks: Vec<f32> = track.iter().map(|&(_, m)| m).collect();
+        peaks.sort_by(f32::total_cmp);
+        let floor = peaks[peaks.len() * 9 / 10] * 0.25;
 
-        input
+        // A change of tone can flicker while the window straddles it, so
+        // changes within a window of each other are one transition.
+        let window_hops = window.div_ceil(hop);
+        let mut clusters: Vec<Vec<usize>> = Vec::new();
+        for j in 1..track.len() {
+            let (prev, cur) = (track[j - 1], track[j]);
+            if prev.1 < floor || cur.1 < floor || prev.0 == cur.0 {
+                continue;
+            }
+            match clusters.last_mut() {
+                Some(cluster) if j - cluster[cluster.len() - 1] <= window_hops => cluster.push(j),
+                _ => clusters.push(vec![j]),
+            }
+        }
+        let transitions: Vec<f32> = clusters
             .iter()
-            .take(fft_size / 2)
-            .enumerate()
-            .map(|(i, c)| {
-                let freq = i as f32 * freq_resolution;
-                let magnitude = (c.re * c.re + c.im * c.im).sqrt() / fft_size as f32;
-                (freq, magnitude)
-            })
-            .collect()
-    }
-}
+            .map(|c| c.iter().sum::<usize>() as f32 / c.len() as f32)
+            .collect();
+        if transitions.len() < 4 {
+            return None;
+        }
 
-#[cfg(test)]
+        let span = transitions[transitions.len() - 1].ceil() as usize + 2;
+        let mut train = vec![0.0f32; span + 1];
+        for &t in &transitions {
+            let i = t.round() as usize;
+            train[i] += 1.0;
+            train[i - 1] += 0.5;
+            train[i + 1] += 0.5;
+        }
+
+        let min_lag = window_hops + 1;
+        let max_lag = (span / 2).min(self.config.duration_samples(SYMBOL_PERIOD_MAX_MS) / hop);
+        if min_lag + 1 >= max_lag {
+            return None;
+        }
+        let correlation: Vec<f32> = (0..=max_lag + 1)
+            .map(|lag| train.iter().zip(&train[lag.min(span)..]).map(|(a, b)| a * b).sum())
+            .collect();
+        let strongest = correlation[min_lag..=max_lag].iter().copied().fold(0.0f32, f32::max);
+        let period = (min_lag..=max_lag).find(|&lag| {
+            let r = correlation[lag];
+            r >= strongest * SYMBOL_PERIOD_PEAK_RATIO && r >= correlation[lag - 1] && r >= correlation[lag + 1]
+        })? as f32;
+
+        let first = transitions[0];
+        let (num, den) = transitions[1..].iter().fold((0.0f32, 0.0f32), |(num, den), &t| {
+            let k = ((t - first) / period).round();
+            (num + k * (t - first), den + k * k)
+        });
+        if den == 0.0 {
+            return None;
+        }
+
+        let samples_per_symbol = num / den * hop as f32;
+        Some(samples_per_symbol * 1000.0 / self.config.sample_rate as f32)
+    }
+
+    // The estimated symbol duration, if it is far enough off the configured
+    // one to explain a failed decode. A short frame is mostly header, so its
+    // estimate can come out at the header rate instead.
+    pub fn symbol_duration_mismatch(&self, samples: &[f32]) -> Option<f32> {
+        let expected = [
+            self.config.symbol_duration_ms,
+            self.config.header_symbol_duration_ms(),
+        ];
+        self.estimate_symbol_duration(samples).filter(|estimated| {
+            expected
+                .iter()
+                .all(|&ms| (estimated / ms as f32 - 1.0).abs() > SYMBOL_DURATION_MISMATCH_TOLERANCE)
+        })
+    }
+
+    // Normalized magnitude at exactly `frequency` rather than the nearest
+    // bin, so a partial window's reading isn't skewed by bin mismatch.
+    fn tone_magnitude(&self, samples: &[f32], frequency: f32) -> f32 {
+        if samples.is_empty() {
+            return 0.0;
+        }
+
+        let omega = 2.0 * PI * frequency / self.config.sample_rate as f32;
+        let coeff = 2.0 * omega.cos();
+
+        let mut s1 = 0.0f32;
+        let mut s2 = 0.0f32;
+        for &sample in samples {
+            let s0 = sample + coeff * s1 - s2;
+            s2 = s1;
+            s1 = s0;
+        }
+
+        (s1 * s1 + s2 * s2 - s1 * s2 * coeff).max(0.0).sqrt() * 2.0 / samples.len() as f32
+    }
+
+    fn wake_hysteresis(&self) -> WakeHysteresis {
+        WakeHysteresis::new(self.config.wake_confirm_windows)
+    }
+
+    // Start of the first run of confirmed wake windows beginning in
+    // `from..to`; the run itself may extend past `to`. Gives the same answer
+    // as checking every window in turn, but only checks every few until one
+    // lights up: a run long enough to confirm can't fit between two coarse
+    // windows, so the fine scan is only needed around the hits.
+    fn find_wake_window(&self, samples: &[f32], from: usize, to: usize) -> Option<usize> {
+        let window_size = self.wake_window_size();
+        self.find_wake_window_by(samples.len(), from, to, |i| {
+            self.is_wake_window(&samples[i..i + window_size])
+        })
+    }
+
+    // `find_wake_window` over `len` samples, with `is_wake` checking the
+    // window starting at a given sample.
+    fn find_wake_window_by(
+        &self,
+        len: usize,
+        from: usize,
+        to: usize,
+        mut is_wake: impl FnMut(usize) -> bool,
+    ) -> Option<usize> {
+        let window_size = self.wake_window_size();
+        let step = window_size / 4;
+        let stride = self.config.wake_confirm_windows.clamp(1, 4) as usize * step;
+        // First position a scan of every window would stop at, unless a run
+        // that started before `to` carries it further.
+        let last_start = from + to.saturating_sub(from).div_ceil(step) * step;
+
+        let mut coarse = from;
+        while coarse + window_size <= len && coarse <= last_start + stride {
+            if !is_wake(coarse) {
+                coarse += stride;
+                continue;
+            }
+
+            let mut i = coarse;
+            while i >= from + step && is_wake(i - step) {
+                i -= step;
+            }
+            if i > last_start {
+                return None;
+            }
+
+            let mut hysteresis = self.wake_hysteresis();
+            if i == 0 {
+                hysteresis = hysteresis.at_recording_start();
+            }
+            while i + window_size <= len {
+                let wake = is_wake(i);
+                if hysteresis.update(wake) {
+                    return Some(i - hysteresis.run_span(step));
+                }
+                if !wake {
+                    break;
+                }
+                if i - hysteresis.run_span(step) >= to {
+                    return None;
+                }
+                i += step;
+            }
+
+            // The run fell short; carry on from the next coarse window.
+            coarse = from + ((i - from) / stride + 1) * stride;
+        }
+
+        None
+    }
+
+    // Locates the falling edge of the wake tone that a window starting at
+    // `start` landed in: the wake magnitude of a sliding window drops to half
+    // its plateau when the window is half past the (fade-centred) tone end.
+    fn wake_tone_end(&self, samples: &[f32], start: usize) -> usize {
+        let window_size = self.wake_window_size();
+        let fine_step = (window_size / 96).max(1);
+        let fade_samples = self.config.duration_samples(self.config.fade_ms);
+        let limit = (start + self.wake_samples() + window_size).min(samples.len().saturating_sub(window_size));
+
+        let mut peak = 0.0f32;
+        let mut pos = start;
+        while pos <= limit {
+            let magnitude = self.tone_magnitude(&samples[pos..pos + window_size], self.config.wake_up_frequency());
+            if magnitude > peak {
+                peak = magnitude;
+            } else if magnitude < peak / 2.0 {
+                return (pos + (window_size + fade_samples) / 2).saturating_sub(fine_step);
+            }
+            pos += fine_step;
+        }
+
+        // The tone runs past the samples we have.
+        start + self.wake_samples()
+    }
+
+    // Locates the rising edge of the wake tone near the confirmed run at
+    // `start`. Windows that only partly overlap the tone can already pass
+    // detection, so the edge may lie either side of `start`: find the first
+    // window reaching half the plateau and read the edge off how much of it
+    // the tone fills. A tone that was already sounding when the recording
+    // began starts at 0.
+    fn wake_tone_start(&self, samples: &[f32], start: usize) -> usize {
+        let window_size = self.wake_window_size();
+        let fine_step = (window_size / 96).max(1);
+        let fade_samples = self.config.duration_samples(self.config.fade_ms);
+        let frequency = self.config.wake_up_frequency();
+        let magnitude_at = |pos: usize| {
+            let from = pos.min(samples.len());
+            self.tone_magnitude(&samples[from..(pos + window_size).min(samples.len())], frequency)
+        };
+
+        let plateau = (0..=4).map(|k| magnitude_at(start + k * window_size / 4)).fold(0.0f32, f32::max);
+        if plateau == 0.0 {
+            return start;
+        }
+
+        let mut pos = start.saturating_sub(window_size);
+        let mut magnitude = magnitude_at(pos);
+        while magnitude < plateau / 2.0 && pos < start + window_size {
+            pos += fine_step;
+            magnitude = magnitude_at(pos);
+        }
+
+        let overlap = (magnitude / plateau).min(1.0) * window_size as f32;
+        (pos as f32 + window_size as f32 - overlap - fade_samples as f32 / 2.0).max(0.0) as usize
+    }
+
+    // Samples a wake-up group may span from its first detected window until
+    // its end is known, including the look-ahead for a following repeat and
+    // any sync word after it.
+    fn wake_group_lookahead(&self) -> usize {
+        let repeats = self.config.wake_up_repeats.max(1) as usize;
+        let repeat_gap = self.config.duration_samples(WAKE_REPEAT_GAP_MS);
+        let sync_samples = self.config.sync_word_samples();
+        let sync_search = if sync_samples > 0 { self.symbol_samples() / SYNC_SEARCH_DIVISOR } else { 0 };
+        repeats * (self.wake_samples() + repeat_gap + 2 * self.wake_window_size()) + sync_samples + sync_search
+    }
+
+    // Follows repeated wake-up tones and returns the end of the last one, so
+    // a frame still aligns if the first of the group was missed.
+    fn wake_group_end(&self, samples: &[f32], start: usize) -> usize {
+        let repeat_gap = self.config.duration_samples(WAKE_REPEAT_GAP_MS);
+        let mut wake_end = self.wake_tone_end(samples, start);
+
+        while let Some(next) = self.find_wake_window(samples, wake_end, wake_end + repeat_gap + self.wake_window_size() / 4) {
+            wake_end = self.wake_tone_end(samples, next);
+        }
+
+        wake_end
+    }
+
+    // Where the sync word after a wake-up tone ending at `wake_end` ends, or
+    // None if it isn't there. Its tones are known, so the offset within a
+    // quarter symbol either way that reads them most clearly pins down the
+    // symbol timing better than the tone's falling edge can. Without a sync
+    // word configured the frame goes on straight from the tone.
+    fn sync_word_end(&self, samples: &[f32], wake_end: usize) -> Option<usize> {
+        if !self.config.sync_word {
+            return Some(wake_end);
+        }
+
+        let symbol_samples = self.symbol_samples();
+        let tones = sync_tones(self.frequencies.len());
+        let span = symbol_samples / SYNC_SEARCH_DIVISOR;
+        let step = (span / SYNC_SEARCH_STEPS).max(1);
+
+        let mut best: Option<(f32, usize)> = None;
+        for start in (wake_end.saturating_sub(span)..=wake_end + span).step_by(step) {
+            let mut score = 0.0;
+            let matched = tones.iter().enumerate().all(|(i, &tone)| {
+                let Some(window) = samples.get(start + i * symbol_samples..start + (i + 1) * symbol_samples) else {
+                    return false;
+                };
+                let mags: Vec<f32> = self.frequencies.iter().map(|&f| self.goertzel_normalized(window, f)).collect();
+                score += mags[tone] / mags.iter().sum::<f32>().max(1e-9);
+                self.strongest_tone(mags.iter().copied()) as usize == tone
+            });
+            if matched && best.is_none_or(|(best_score, _)| score > best_score) {
+                best = Some((score, start));
+            }
+        }
+
+        best.map(|(_, start)| start + tones.len() * symbol_samples)
+    }
+
+    // The first wake-up group from `from` on that is followed by a sync word,
+    // if one is configured: where its first wake window starts, and where the
+    // frame carries on after the tone and sync word. A group without one was
+    // something else at the wake-up frequency and is passed over.
+    fn find_frame_start(&self, samples: &[f32], mut from: usize) -> Option<(usize, usize)> {
+        loop {
+            let start = self.find_wake_window(samples, from, samples.len())?;
+            let wake_end = self.wake_group_end(samples, start);
+            if let Some(end) = self.sync_word_end(samples, wake_end) {
+                return Some((start, end));
+            }
+            log::debug!(target: LOG_TARGET, "No sync word after the wake-up tone at sample {}; skipping it", start);
+            from = wake_end.max(start + 1);
+        }
+    }
+
+    pub fn detect_wake_up(&self, samples: &[f32]) -> Option<usize> {
+        let (start, end) = self.find_frame_start(samples, 0)?;
+        log::trace!(target: LOG_TARGET, "Wake-up tone from sample {} to {}", start, end);
+        Some(end)
+    }
+
+    // Where the first wake-up tone starts and the last of its group ends,
+    // both read off the signal rather than assumed from the nominal tone
+    // length, so a tone whose start was clipped still lines up.
+    pub fn detect_wake_bounds(&self, samples: &[f32]) -> Option<(usize, usize)> {
+        let start = self.find_wake_window(samples, 0, samples.len())?;
+        Some((self.wake_tone_start(samples, start), self.wake_group_end(samples, start)))
+    }
+
+    // Where data-band energy first rises well clear of the noise floor and
+    // stays up, standing in for the end of the wake-up tone when that tone
+    // can't be heard. That lands on the length prefix of a short frame, or
+    // the first data symbol of a long one.
+    pub fn detect_onset(&self, samples: &[f32]) -> Option<usize> {
+        self.find_onset(samples).map(|(onset, _)| onset)
+    }
+
+    // The onset, and where the energy falls back to the floor again, to the
+    // nearest window.
+    fn find_onset(&self, samples: &[f32]) -> Option<(usize, usize)> {
+        let window = self.config.duration_samples(ONSET_WINDOW_MS);
+        if window == 0 {
+            return None;
+        }
+
+        let levels: Vec<f32> = samples.chunks_exact(window).map(|chunk| self.data_magnitude(chunk)).collect();
+        let sustain = (ONSET_SUSTAIN_MS / ONSET_WINDOW_MS) as usize;
+        let floor_windows = (ONSET_FLOOR_MS / ONSET_WINDOW_MS) as usize;
+        let threshold = |i: usize| {
+            let mut before = levels[i.saturating_sub(floor_windows)..i].to_vec();
+            before.sort_by(f32::total_cmp);
+            (before[before.len() / 2] * ONSET_RATIO).max(ONSET_MIN_LEVEL)
+        };
+
+        let min_floor_windows = (ONSET_MIN_FLOOR_MS / ONSET_WINDOW_MS) as usize;
+        let (first, threshold) = (min_floor_windows..levels.len().saturating_sub(sustain))
+            .map(|i| (i, threshold(i)))
+            .find(|&(i, threshold)| levels[i..i + sustain].iter().all(|&level| level >= threshold))?;
+        let end = (first..levels.len()).find(|&i| levels[i] < threshold).map_or(samples.len(), |i| i * window);
+
+        // A tone that fills a fraction of a window reads as that fraction of
+        // its level, so the onset is half a window on from the first
+        // position that reads half the level of a full window, less the half
+        // of the fade-in that the ramp delays that point by.
+        let full = levels[first + 1];
+        let hop = window / ONSET_WINDOW_MS as usize;
+        let from = first.saturating_sub(1) * window;
+        let lag = window / 2 - self.config.duration_samples(self.config.fade_ms).min(window) / 2;
+        let onset = (from..=(first + 1) * window)
+            .step_by(hop.max(1))
+            .find(|&pos| self.data_magnitude(&samples[pos..pos + window]) >= full / 2.0)?;
+
+        Some((onset + lag, end))
+    }
+
+    // Only tone magnitudes are compared, so an audio path that inverts the
+    // polarity or shifts the phase of some frequencies more than others
+    // doesn't change the decision. A phase-keyed mode would have to recover
+    // the polarity from a known preamble first.
+    pub fn detect_symbol(&self, samples: &[f32]) -> u8 {
+        match self.config.symbol_detector {
+            SymbolDetector::Goertzel => {
+                self.strongest_tone(self.frequencies.iter().map(|&freq| self.goertzel_normalized(samples, freq)))
+            }
+            SymbolDetector::Fft => self.detect_symbol_fft(samples),
+        }
+    }
+
+    // `detect_symbol` for frame data, with every tone moved by the offset the
+    // pilot tracked, or None when the configured confidence threshold isn't
+    // met and the window should be treated as an erasure.
+    fn detect_data_symbol(&self, samples: &[f32], offset_hz: f32) -> Option<u8> {
+        let threshold = self.config.symbol_confidence_threshold;
+        if threshold.is_none() && offset_hz == 0.0 {
+            return Some(self.detect_symbol(samples));
+        }
+
+        let mut scratch = self.scratch.borrow_mut();
+        match self.config.symbol_detector {
+            SymbolDetector::Goertzel => {
+                scratch.magnitudes.clear();
+                scratch.magnitudes.extend(
+                    self.frequencies.iter().map(|&freq| self.goertzel_normalized(samples, freq + offset_hz)),
+                );
+            }
+            SymbolDetector::Fft => self.fft_tone_magnitudes(samples, offset_hz, &mut scratch),
+        }
+        let symbol = self.strongest_tone(scratch.magnitudes.iter().copied());
+        let Some(threshold) = threshold else {
+            return Some(symbol);
+        };
+
+        let magnitudes = &mut scratch.magnitudes;
+        if let Some(profile) = &self.noise_profile {
+            for (magnitude, floor) in magnitudes.iter_mut().zip(profile) {
+                *magnitude -= floor;
+            }
+        }
+
+        let top = magnitudes[symbol as usize];
+        let runner_up = magnitudes.iter()
+            .enumerate()
+            .filter(|&(i, _)| i != symbol as usize)
+            .fold(0.0f32, |a, (_, &m)| a.max(m));
+
+        (top > 0.0 && top >= runner_up * threshold).then_some(symbol)
+    }
+
+    // Reads every tone from a single FFT of the window. Like `goertzel`, it
+    // takes the bin nearest each tone, so a tone that falls between bins
+    // (the window isn't a whole number of tone-step periods) loses up to a
+    // third of its magnitude either way.
+    pub fn detect_symbol_fft(&self, samples: &[f32]) -> u8 {
+        if samples.is_empty() {
+            return 0;
+        }
+
+        let mut scratch = self.scratch.borrow_mut();
+        self.fft_tone_magnitudes(samples, 0.0, &mut scratch);
+        self.strongest_tone(scratch.magnitudes.iter().copied())
+    }
+
+    // Fills `scratch.magnitudes` with each tone's bin of the window's
+    // spectrum, as `symbol_spectrum` would read it, without allocating.
+    fn fft_tone_magnitudes(&self, samples: &[f32], offset_hz: f32, scratch: &mut Scratch) {
+        let Scratch { fft_buffer, fft_scratch, magnitudes, .. } = scratch;
+        magnitudes.clear();
+        let n = samples.len();
+        if n == 0 {
+            magnitudes.resize(self.frequencies.len(), 0.0);
+            return;
+        }
+
+        let fft = self.fft_planner.borrow_mut().plan_fft_forward(n);
+        #[cfg(test)]
+        self.transform_work
+            .set(self.transform_work.get() + n as u64 * n.ilog2() as u64);
+        fft_buffer.clear();
+        fft_buffer.extend(samples.iter().map(|&s| Complex::new(s, 0.0)));
+        fft_scratch.resize(fft.get_inplace_scratch_len(), Complex::new(0.0, 0.0));
+        fft.process_with_scratch(fft_buffer, fft_scratch);
+
+        let sample_rate = self.config.sample_rate as f32;
+        magnitudes.extend(self.frequencies.iter().map(|&freq| {
+            let bin = ((freq + offset_hz) * n as f32 / sample_rate).round() as usize;
+            if bin <= n / 2 {
+                fft_buffer[bin].norm() * 2.0 / n as f32
+            } else {
+                0.0
+            }
+        }));
+    }
+
+    // Normalized magnitude of every DFT bin of the window up to Nyquist,
+    // with bin `k` at `k * sample_rate / samples.len()` Hz.
+    pub fn symbol_spectrum(&self, samples: &[f32]) -> Vec<f32> {
+        let n = samples.len();
+        let fft = self.fft_planner.borrow_mut().plan_fft_forward(n);
+
+        let mut buffer: Vec<Complex<f32>> = samples.iter().map(|&s| Complex::new(s, 0.0)).collect();
+        fft.process(&mut buffer);
+
+        buffer[..n / 2 + 1].iter().map(|c| c.norm() * 2.0 / n as f32).collect()
+    }
+
+    fn strongest_tone(&self, magnitudes: impl Iterator<Item = f32>) -> u8 {
+        let mut max_magnitude = f32::NEG_INFINITY;
+        let mut detected_index = 0u8;
+
+        for (i, mut magnitude) in magnitudes.enumerate() {
+            if let Some(profile) = &self.noise_profile {
+                magnitude -= profile.get(i).copied().unwrap_or(0.0);
+            }
+            if magnitude > max_magnitude {
+                max_magnitude = magnitude;
+                detected_index = i as u8;
+            }
+        }
+
+        detected_index
+    }
+
+    fn detect_ack_digit(&self, window: &[f32]) -> Option<u8> {
+        let group_peak = |tones: &[usize; 4]| {
+            let mags: Vec<f32> = tones
+                .iter()
+                .map(|&t| self.goertzel_normalized(window, self.config.tone_frequency(t)))
+                .collect();
+            let (index, &peak) = mags
+                .iter()
+                .enumerate()
+                .max_by(|a, b| a.1.total_cmp(b.1))
+                .unwrap();
+            let runner_up = mags
+                .iter()
+                .enumerate()
+                .filter(|&(i, _)| i != index)
+                .fold(0.0f32, |a, (_, &m)| a.max(m));
+            (index as u8, peak, runner_up)
+        };
+
+        let (low_index, low_peak, low_runner_up) = group_peak(&ACK_LOW_TONES);
+        let (high_index, high_peak, high_runner_up) = group_peak(&ACK_HIGH_TONES);
+
+        let dual_tone = low_peak.min(high_peak) > low_peak.max(high_peak) * 0.25;
+        let dominant = low_peak > low_runner_up * 2.0 && high_peak > high_runner_up * 2.0;
+
+        if low_peak > MIN_TONE_LEVEL && high_peak > MIN_TONE_LEVEL && dual_tone && dominant {
+            Some((low_index << 2) | high_index)
+        } else {
+            None
+        }
+    }
+
+    fn ack_digit_score(&self, window: &[f32], digit: u8) -> f32 {
+        self.goertzel_normalized(window, self.config.tone_frequency(ACK_LOW_TONES[(digit >> 2) as usize]))
+            + self.goertzel_normalized(window, self.config.tone_frequency(ACK_HIGH_TONES[(digit & 0x03) as usize]))
+    }
+
+    pub fn detect_ack_signal(&self, samples: &[f32]) -> Option<(AckKind, u16)> {
+        let digit_samples = (self.config.sample_rate as f32 * ACK_DIGIT_DURATION_MS as f32 / 1000.0) as usize;
+        let gap_samples = (self.config.sample_rate as f32 * ACK_DIGIT_GAP_MS as f32 / 1000.0) as usize;
+        let stride = digit_samples + gap_samples;
+        let burst_len = stride * ACK_SEQ_DIGITS + digit_samples;
+
+        if samples.len() < burst_len {
+            return None;
+        }
+
+        let step = digit_samples / 4;
+        let fine_step = (digit_samples / 16).max(1);
+
+        for i in (0..=samples.len() - burst_len).step_by(step) {
+            let kind_digit = match self.detect_ack_digit(&samples[i..i + digit_samples]) {
+                Some(digit) => digit,
+                None => continue,
+            };
+            let kind = match AckKind::from_digit(kind_digit) {
+                Some(kind) => kind,
+                None => continue,
+            };
+
+            let search_start = i.saturating_sub(digit_samples / 4);
+            let search_end = (i + digit_samples / 2).min(samples.len() - burst_len);
+            let start = (search_start..=search_end)
+                .step_by(fine_step)
+                .max_by(|&a, &b| {
+                    let score_a = self.ack_digit_score(&samples[a..a + digit_samples], kind_digit);
+                    let score_b = self.ack_digit_score(&samples[b..b + digit_samples], kind_digit);
+                    score_a.total_cmp(&score_b)
+                })
+                .unwrap_or(i);
+
+            let seq = (1..=ACK_SEQ_DIGITS).try_fold(0u16, |seq, d| {
+                let pos = start + d * stride;
+                self.detect_ack_digit(&samples[pos..pos + digit_samples])
+                    .map(|digit| (seq << 4) | digit as u16)
+            });
+
+            if let Some(seq) = seq {
+                return Some((kind, seq));
+            }
+        }
+
+        None
+    }
+
+    pub fn detect_ack(&self, samples: &[f32]) -> Option<u16> {
+        match self.detect_ack_signal(samples)? {
+            (AckKind::Ack, seq) => Some(seq),
+            (AckKind::Nack, _) => None,
+        }
+    }
+
+    fn symbol_samples(&self) -> usize {
+        self.config.duration_samples(self.config.symbol_duration_ms)
+    }
+
+    fn length_symbol_samples(&self) -> usize {
+        self.config.duration_samples(self.config.header_symbol_duration_ms())
+    }
+
+    fn length_prefix_samples(&self) -> usize {
+        self.config.symbols_per_bytes(self.config.length_prefix_bytes()) * self.length_symbol_samples()
+    }
+
+    fn is_short_frame(&self, samples: &[f32], wake_end: usize) -> bool {
+        if self.config.length_prefixed {
+            return true;
+        }
+
+        let gap_samples = self.config.wake_gap_samples();
+        if gap_samples == 0 || wake_end < gap_samples || wake_end + gap_samples > samples.len() {
+            return false;
+        }
+
+        // Weighed against whatever came just before: the wake-up tone, or
+        // the end of the sync word.
+        let before = &samples[wake_end - gap_samples..wake_end];
+        let reference = if self.config.sync_word {
+            self.data_magnitude(before)
+        } else {
+            self.goertzel_normalized(before, self.config.wake_up_frequency())
+        };
+        self.data_magnitude(&samples[wake_end..wake_end + gap_samples]) > reference * 0.25
+    }
+
+    // Like `is_short_frame`, for a frame whose wake-up tone is missing. The
+    // gap is weighed against the stretch right after it instead, which
+    // carries data either way.
+    fn is_short_frame_without_wake(&self, samples: &[f32], start: usize) -> bool {
+        if self.config.length_prefixed {
+            return true;
+        }
+
+        let gap_samples = self.config.wake_gap_samples();
+        if gap_samples == 0 || start + 2 * gap_samples > samples.len() {
+            return false;
+        }
+
+        let after_mag = self.data_magnitude(&samples[start + gap_samples..start + 2 * gap_samples]);
+        self.data_magnitude(&samples[start..start + gap_samples]) > after_mag * 0.25
+    }
+
+    fn data_magnitude(&self, window: &[f32]) -> f32 {
+        self.data_magnitude_at(window, 0.0)
+    }
+
+    fn data_magnitude_at(&self, window: &[f32], offset_hz: f32) -> f32 {
+        self.frequencies.iter()
+            .map(|&f| self.goertzel_normalized(window, f + offset_hz))
+            .fold(0.0f32, |a, b| a.max(b))
+    }
+
+    // Measures the pilot in `window`, looking for it `offset_hz` off its
+    // nominal frequency, where the previous window left it. The phase it
+    // gains between the two halves of the window gives the remaining offset,
+    // which is unambiguous up to the window's bin width either way. None
+    // without a pilot, or when the window doesn't carry one.
+    pub fn read_pilot(&self, window: &[f32], offset_hz: f32) -> Option<PilotReading> {
+        let pilot = self.config.pilot?;
+        let half = window.len() / 2;
+        if half == 0 {
+            return None;
+        }
+
+        let sample_rate = self.config.sample_rate as f64;
+        let omega = 2.0 * std::f64::consts::PI * (pilot.frequency + offset_hz) as f64 / sample_rate;
+        let correlate = |range: std::ops::Range<usize>| {
+            range.fold(Complex::new(0.0f64, 0.0), |acc, n| {
+                acc + Complex::from_polar(window[n] as f64, -omega * n as f64)
+            })
+        };
+        let first = correlate(0..half);
+        let second = correlate(half..2 * half);
+
+        let level = ((first.norm() + second.norm()) / half as f64) as f32;
+        if level < PILOT_MIN_LEVEL {
+            return None;
+        }
+
+        let residual = (second * first.conj()).arg() * sample_rate / (2.0 * std::f64::consts::PI * half as f64);
+        Some(PilotReading {
+            offset_hz: offset_hz + residual as f32,
+            level,
+        })
+    }
+
+    fn symbol_window<'a>(&self, samples: &'a [f32], data_start: usize, index: usize) -> Option<&'a [f32]> {
+        let start = data_start + self.config.symbol_offset(index);
+        let mut end = data_start + self.config.symbol_offset(index + 1);
+
+        // A recording that stops right at the end of the frame can cut the
+        // last window slightly short when the wake-up estimate runs late.
+        if end > samples.len() && (end - samples.len()) * 4 <= end - start {
+            end = samples.len();
+        }
+        samples.get(start..end).map(|window| self.settled(window))
+    }
+
+    // The part of a symbol window past `symbol_settle_ms`, once the tone has
+    // finished fading in.
+    fn settled<'a>(&self, window: &'a [f32]) -> &'a [f32] {
+        let settle = self.config.duration_samples(self.config.symbol_settle_ms);
+        &window[settle.min(window.len())..]
+    }
+
+    fn read_length(&self, samples: &[f32], pos: usize) -> Option<usize> {
+        let symbol_samples = self.length_symbol_samples();
+        let end = pos + self.length_prefix_samples();
+        if symbol_samples == 0 || end > samples.len() {
+            return None;
+        }
+
+        // The length's bits, high first as `pack_bits` lays them out, less the
+        // padding at the end of the last symbol.
+        let bits = self.config.bits_per_symbol as usize;
+        let prefix_bytes = self.config.length_prefix_bytes();
+        let packed = samples[pos..end]
+            .chunks_exact(symbol_samples)
+            .fold(0usize, |acc, window| (acc << bits) | self.detect_symbol(self.settled(window)) as usize);
+        Some(packed >> (self.config.symbols_per_bytes(prefix_bytes) * bits - prefix_bytes * 8))
+    }
+
+    pub fn short_frame_end(&self, samples: &[f32]) -> Option<usize> {
+        let start_pos = self.detect_wake_up(samples)?;
+        self.short_frame_end_from(samples, start_pos)
+    }
+
+    pub fn short_frame_end_from(&self, samples: &[f32], start_pos: usize) -> Option<usize> {
+        if !self.is_short_frame(samples, start_pos) {
+            return None;
+        }
+
+        let len = self.read_length(samples, start_pos)?;
+        Some(start_pos + self.length_prefix_samples() + self.config.data_samples(len))
+    }
+
+    pub fn detect_end_of_frame(&self, samples: &[f32]) -> bool {
+        match self.detect_wake_up(samples) {
+            Some(start_pos) => self.detect_end_of_frame_from(samples, start_pos),
+            None => false,
+        }
+    }
+
+    pub fn detect_end_of_frame_from(&self, samples: &[f32], start_pos: usize) -> bool {
+        if let Some(frame_end) = self.short_frame_end_from(samples, start_pos) {
+            return samples.len() >= frame_end;
+        }
+
+        let window_samples = (self.config.sample_rate as f32 * self.config.end_detection_window_ms as f32 / 1000.0) as usize;
+        if samples.len() < start_pos + window_samples {
+            return false;
+        }
+
+        let end_samples = &samples[samples.len() - window_samples..];
+        let wake_mag = self.goertzel_normalized(end_samples, self.config.wake_up_frequency());
+        let noise = self.frequencies.iter()
+            .map(|&f| self.goertzel_normalized(end_samples, f))
+            .sum::<f32>()
+            / self.frequencies.len() as f32;
+
+        wake_mag > noise * self.config.end_detection_ratio
+    }
+
+    // None if the samples run out before the length says the frame ends.
+    fn read_short_frame(&self, samples: &[f32], start_pos: usize, frame: &mut FrameSymbols) -> Option<()> {
+        frame.clear();
+        let len = self.read_length(samples, start_pos)?;
+
+        let data_start = start_pos + self.length_prefix_samples();
+        let mut floor = self.signal_floor(samples, start_pos);
+        let mut pilot_offset = 0.0;
+        for i in 0..self.config.symbols_per_bytes(len) {
+            let window = self.symbol_window(samples, data_start, i)?;
+            if let Some(reading) = self.read_pilot(window, pilot_offset) {
+                pilot_offset = reading.offset_hz;
+                floor.follow_pilot(reading);
+            }
+            frame.push(if self.rms(window) < floor.level() {
+                None
+            } else {
+                self.detect_data_symbol(window, pilot_offset)
+            });
+        }
+
+        Some(())
+    }
+
+    pub fn demodulate(&mut self, samples: &[f32]) -> Option<Vec<u8>> {
+        self.demodulate_with_erasures(samples).map(|(data, _)| data)
+    }
+
+    // Like `demodulate`, but also returns the indices of bytes that overlap a
+    // signal dropout. Those bytes read as zero bits rather than whatever
+    // tone happened to win in the silence, ready for erasure decoding.
+    pub fn demodulate_with_erasures(&mut self, samples: &[f32]) -> Option<(Vec<u8>, Vec<usize>)> {
+        let (start_pos, short, end) = self.locate_frame(samples)?;
+        self.demodulate_frame(&samples[..end], start_pos, short)
+    }
+
+    // Like `demodulate`, but also reports where the time went, for tracking
+    // down slow decodes on constrained hardware.
+    pub fn demodulate_timed(&mut self, samples: &[f32]) -> (Option<Vec<u8>>, DemodTiming) {
+        self.demodulate_timed_with(samples, &SystemClock::new())
+    }
+
+    pub fn demodulate_timed_with(&mut self, samples: &[f32], clock: &impl Clock) -> (Option<Vec<u8>>, DemodTiming) {
+        let mut timing = DemodTiming::default();
+        let started = clock.now_ms();
+
+        let located = self.locate_frame(samples);
+        let located_at = clock.now_ms();
+        timing.wake_detection = elapsed_between(started, located_at);
+        let Some((start_pos, short, end)) = located else {
+            timing.total = timing.wake_detection;
+            return (None, timing);
+        };
+
+        let mut frame = std::mem::take(&mut self.scratch.borrow_mut().frame);
+        let read = self.read_frame(&samples[..end], start_pos, short, &mut frame);
+        let read_at = clock.now_ms();
+        let result = read.and_then(|_| self.pack_frame(&frame.symbols, &frame.erased));
+        let packed_at = clock.now_ms();
+
+        timing.symbol_detection = elapsed_between(located_at, read_at);
+        timing.packing = elapsed_between(read_at, packed_at);
+        timing.symbols = frame.symbols.len();
+        self.scratch.borrow_mut().frame = frame;
+
+        timing.total = elapsed_between(started, clock.now_ms());
+        (result.map(|(data, _)| data), timing)
+    }
+
+    // Where the first frame's data starts, whether it's a short frame, and
+    // how much of `samples` is worth reading for it.
+    fn locate_frame(&self, samples: &[f32]) -> Option<(usize, bool, usize)> {
+        let Some(start_pos) = self.detect_wake_up(samples) else {
+            return self.locate_from_onset(samples);
+        };
+        Some((start_pos, self.is_short_frame(samples, start_pos), samples.len()))
+    }
+
+    // The fallback when no wake-up tone was heard. Without the tone or its
+    // gap to go by, a frame counts as short when the length it would start
+    // with accounts for all of its energy; otherwise its data began at the
+    // onset and ran on. The recording is cut where the energy stops, since
+    // any trailing tone was likely lost along with the leading one.
+    fn locate_from_onset(&self, samples: &[f32]) -> Option<(usize, bool, usize)> {
+        let (onset, end) = self.find_onset(samples)?;
+        // With a sync word on, the energy starts with it rather than the data.
+        let onset = self.sync_word_end(samples, onset)?;
+        let window = self.config.duration_samples(ONSET_WINDOW_MS);
+        log::debug!(target: LOG_TARGET, "No wake-up tone; decoding from energy onset at sample {} to {}", onset, end);
+        let cut = (end + window).min(samples.len());
+        let samples = &samples[..cut];
+
+        let short = self.config.length_prefixed
+            || self.read_length(samples, onset).is_some_and(|len| {
+                let frame_end = onset + self.length_prefix_samples() + self.config.data_samples(len);
+                len <= SHORT_FRAME_THRESHOLD && frame_end.abs_diff(end) <= self.symbol_samples() + window
+            });
+        if short {
+            return Some((onset, true, cut));
+        }
+        Some((onset.checked_sub(self.config.wake_gap_samples())?, false, cut))
+    }
+
+    // Decodes a frame without looking for its wake-up tone, for recordings
+    // that lost it to trimming or clipping. `start_offset` is where the tone
+    // would have ended: the start of the silence gap, or of the length prefix
+    // for a short frame.
+    pub fn demodulate_from(&mut self, samples: &[f32], start_offset: usize) -> Option<Vec<u8>> {
+        let start_offset = self.sync_word_end(samples, start_offset)?;
+        let short = self.is_short_frame_without_wake(samples, start_offset);
+        self.demodulate_frame(samples, start_offset, short).map(|(data, _)| data)
+    }
+
+    fn demodulate_frame(&self, samples: &[f32], start_pos: usize, short: bool) -> Option<(Vec<u8>, Vec<usize>)> {
+        // Taken out for the read, since detecting each symbol borrows the
+        // scratch space too.
+        let mut frame = std::mem::take(&mut self.scratch.borrow_mut().frame);
+        let read = self.read_frame(samples, start_pos, short, &mut frame);
+        let result = read.and_then(|_| self.pack_frame(&frame.symbols, &frame.erased));
+        self.scratch.borrow_mut().frame = frame;
+        result
+    }
+
+    fn read_frame(&self, samples: &[f32], start_pos: usize, short: bool, frame: &mut FrameSymbols) -> Option<()> {
+        if short {
+            self.read_short_frame(samples, start_pos, frame)
+        } else {
+            let data_start = start_pos + self.config.wake_gap_samples();
+            self.read_long_frame_into(samples, start_pos, data_start, frame);
+            Some(())
+        }
+    }
+
+    // Per-tone SNR in dB over the data of the first frame: each tone's mean
+    // magnitude in the windows where it was the detected symbol, over its
+    // mean magnitude where it wasn't. A tone that never won a window is
+    // scored on its strongest reading instead, so it comes out near 0 dB
+    // rather than missing. Empty if no frame is found.
+    pub fn estimate_snr_per_tone(&self, samples: &[f32]) -> Vec<f32> {
+        let Some((data_start, count)) = self.frame_data_span(samples) else {
+            return Vec::new();
+        };
+
+        let num_tones = self.frequencies.len();
+        let mut signal = vec![(0.0f32, 0usize); num_tones];
+        let mut noise = vec![(0.0f32, 0usize); num_tones];
+        let mut strongest = vec![0.0f32; num_tones];
+
+        for window in (0..count).map_while(|i| self.symbol_window(samples, data_start, i)) {
+            let mags: Vec<f32> = self.frequencies.iter().map(|&f| self.goertzel_normalized(window, f)).collect();
+            let detected = self.strongest_tone(mags.iter().copied()) as usize;
+
+            for (tone, &mag) in mags.iter().enumerate() {
+                let bucket = if tone == detected { &mut signal[tone] } else { &mut noise[tone] };
+                bucket.0 += mag;
+                bucket.1 += 1;
+                strongest[tone] = strongest[tone].max(mag);
+            }
+        }
+
+        let mean = |(sum, n): (f32, usize)| if n > 0 { Some(sum / n as f32) } else { None };
+        (0..num_tones)
+            .map(|tone| {
+                let level = mean(signal[tone]).unwrap_or(strongest[tone]);
+                let floor = mean(noise[tone]).unwrap_or(0.0).max(1e-9);
+                20.0 * (level.max(1e-9) / floor).log10()
+            })
+            .collect()
+    }
+
+    // SNR in dB over the data of the first frame: the detected tone's mean
+    // magnitude over the mean magnitude of the rest. None if no frame is
+    // found.
+    pub fn estimate_snr(&self, samples: &[f32]) -> Option<f32> {
+        let (data_start, count) = self.frame_data_span(samples)?;
+        let (mut signal, mut noise, mut windows) = (0.0f32, 0.0f32, 0usize);
+
+        for window in (0..count).map_while(|i| self.symbol_window(samples, data_start, i)) {
+            let mags: Vec<f32> = self.frequencies.iter().map(|&f| self.goertzel_normalized(window, f)).collect();
+            let detected = mags[self.strongest_tone(mags.iter().copied()) as usize];
+            signal += detected;
+            noise += (mags.iter().sum::<f32>() - detected) / (mags.len() - 1).max(1) as f32;
+            windows += 1;
+        }
+
+        (windows > 0).then(|| 20.0 * (signal.max(1e-9) / noise.max(1e-9)).log10())
+    }
+
+    // How clearly the first frame's symbols stood out, from 0 to 1: the
+    // winning tone's lead over the runner-up, as a share of the winner,
+    // averaged over the data windows. None if no frame is found, with or
+    // without its wake-up tone.
+    pub fn symbol_confidence(&self, samples: &[f32]) -> Option<f32> {
+        let (data_start, count) = self.frame_data_span(samples)?;
+        let (mut total, mut windows) = (0.0f32, 0usize);
+
+        for window in (0..count).map_while(|i| self.symbol_window(samples, data_start, i)) {
+            let mags: Vec<f32> = self.frequencies.iter().map(|&f| self.goertzel_normalized(window, f)).collect();
+            let detected = self.strongest_tone(mags.iter().copied()) as usize;
+            let runner_up = mags
+                .iter()
+                .enumerate()
+                .filter(|&(i, _)| i != detected)
+                .fold(0.0f32, |a, (_, &m)| a.max(m));
+            if mags[detected] > 0.0 {
+                total += (mags[detected] - runner_up) / mags[detected];
+            }
+            windows += 1;
+        }
+
+        (windows > 0).then(|| total / windows as f32)
+    }
+
+    // Share of the first frame's data windows that are contested: another
+    // tone rivals the winner while both stand clear of the rest. One sender
+    // leaves a single clear winner and noise lifts every tone alike, so two
+    // or more standouts are the mark of a second transmitter sending over
+    // the first. None if no frame is found, or with fewer than three tones,
+    // where nothing is left over to tell standouts from noise by.
+    pub fn collision_share(&self, samples: &[f32]) -> Option<f32> {
+        if self.frequencies.len() < 3 {
+            return None;
+        }
+        let (data_start, count) = self.frame_data_span(samples)?;
+        let (mut contested, mut windows) = (0usize, 0usize);
+
+        for window in (0..count).map_while(|i| self.symbol_window(samples, data_start, i)) {
+            let mut mags: Vec<f32> = self.frequencies.iter().map(|&f| self.goertzel_normalized(window, f)).collect();
+            mags.sort_by(|a, b| b.total_cmp(a));
+            let (top, rival, rest) = (mags[0], mags[1], &mags[2..]);
+            let floor = rest[rest.len() / 2].max(1e-9);
+            if rival >= top * COLLISION_RIVAL_RATIO && rival >= floor * COLLISION_FLOOR_RATIO {
+                contested += 1;
+            }
+            windows += 1;
+        }
+
+        (windows > 0).then(|| contested as f32 / windows as f32)
+    }
+
+    pub fn collision_detected(&self, samples: &[f32]) -> bool {
+        self.collision_share(samples).is_some_and(|share| share >= COLLISION_MIN_SHARE)
+    }
+
+    // Where the first frame's data starts and how many symbols it runs to,
+    // found as `demodulate` finds it: by its wake-up tone, or failing that by
+    // its onset.
+    fn frame_data_span(&self, samples: &[f32]) -> Option<(usize, usize)> {
+        let (start_pos, short, end) = self.locate_frame(samples)?;
+        let samples = &samples[..end];
+        if short {
+            let len = self.read_length(samples, start_pos)?;
+            Some((start_pos + self.length_prefix_samples(), self.config.symbols_per_bytes(len)))
+        } else {
+            let data_start = start_pos + self.config.wake_gap_samples();
+            Some((data_start, self.read_long_frame(samples, start_pos, data_start).symbols.len()))
+        }
+    }
+
+    // Symbol `i` carries bits `i * bits..(i + 1) * bits`, so an erased symbol
+    // can spoil the bytes on both sides of a byte boundary. The descrambler
+    // feeds each received bit back in up to 23 bits later, which carries the
+    // damage that much further.
+    fn pack_frame(&self, symbols: &[u8], erased: &[usize]) -> Option<(Vec<u8>, Vec<usize>)> {
+        let mut data = symbols_to_bytes(symbols, self.frequencies.len());
+        let mut reach = 0;
+        if let Some(seed) = self.config.scrambler_seed {
+            data = descramble(&data, seed);
+            reach = SCRAMBLER_TAPS.1 as usize;
+        }
+        let bits = self.config.bits_per_symbol as usize;
+
+        let mut erased_bytes: Vec<usize> = erased
+            .iter()
+            .flat_map(|&i| i * bits / 8..=((i + 1) * bits - 1 + reach) / 8)
+            .filter(|&i| i < data.len())
+            .collect();
+        erased_bytes.sort_unstable();
+        erased_bytes.dedup();
+
+        if data.is_empty() {
+            None
+        } else {
+            Some((data, erased_bytes))
+        }
+    }
+
+    fn rms(&self, window: &[f32]) -> f32 {
+        (window.iter().map(|s| s * s).sum::<f32>() / window.len().max(1) as f32).sqrt()
+    }
+
+    // Windows quieter than this are dropouts. It is set well under the level
+    // of the frame's own wake-up tone, and above the noise floor when a noise
+    // profile is known, so ordinary fading doesn't trip it.
+    fn signal_floor(&self, samples: &[f32], wake_end: usize) -> SignalFloor {
+        let wake_samples = self.wake_samples();
+        let from = wake_end.saturating_sub(wake_samples * 3 / 4);
+        let to = wake_end.saturating_sub(wake_samples / 4).min(samples.len());
+        let wake_rms = if to > from { self.rms(&samples[from..to]) } else { 0.0 };
+
+        // A sine's Goertzel magnitude is its amplitude, √2 times its RMS.
+        let noise_rms = self.noise_profile
+            .as_ref()
+            .map(|profile| profile.iter().sum::<f32>() / profile.len().max(1) as f32 / std::f32::consts::SQRT_2)
+            .unwrap_or(0.0);
+
+        SignalFloor {
+            signal: wake_rms * DROPOUT_RATIO,
+            noise: noise_rms * DROPOUT_NOISE_MARGIN,
+            reference_pilot: None,
+            gain: 1.0,
+        }
+    }
+
+    // Reads symbols until the trailing wake-up tone, noting which windows fell
+    // below the signal floor and where that tone starts, or None if the samples
+    // ran out first.
+    fn read_long_frame(&self, samples: &[f32], wake_end: usize, data_start: usize) -> FrameSymbols {
+        let mut frame = FrameSymbols::default();
+        self.read_long_frame_into(samples, wake_end, data_start, &mut frame);
+        frame
+    }
+
+    fn read_long_frame_into(&self, samples: &[f32], wake_end: usize, data_start: usize, frame: &mut FrameSymbols) {
+        frame.clear();
+        let mut floor = self.signal_floor(samples, wake_end);
+        let mut pilot_offset = 0.0;
+
+        while let Some(window) = self.symbol_window(samples, data_start, frame.symbols.len()) {
+            // The trailing tone carries no pilot, so it is looked for where
+            // the last data symbol's offset left it.
+            let wake_mag =
+                self.goertzel_normalized(window, self.config.wake_up_frequency() + pilot_offset);
+            if wake_mag > self.data_magnitude_at(window, pilot_offset) * 1.5
+                && wake_mag > MIN_TONE_LEVEL
+            {
+                frame.trailing_start =
+                    Some(data_start + self.config.symbol_offset(frame.symbols.len()));
+                return;
+            }
+
+            if let Some(reading) = self.read_pilot(window, pilot_offset) {
+                pilot_offset = reading.offset_hz;
+                floor.follow_pilot(reading);
+            }
+            frame.push(if self.rms(window) < floor.level() {
+                None
+            } else {
+                self.detect_data_symbol(window, pilot_offset)
+            });
+        }
+    }
+
+    fn frame_end_from(&self, samples: &[f32], wake_end: usize) -> usize {
+        if let Some(end) = self.short_frame_end_from(samples, wake_end) {
+            return end.min(samples.len());
+        }
+
+        let data_start = wake_end + self.config.wake_gap_samples();
+        let frame = self.read_long_frame(samples, wake_end, data_start);
+        let silence = self.inter_frame_silence(
+            samples,
+            data_start,
+            self.signal_floor(samples, wake_end).level(),
+        );
+
+        match (frame.trailing_start, silence) {
+            // A lost trailing tone would otherwise be mistaken for the next
+            // frame's wake-up tone.
+            (Some(trailing_start), Some(silence)) if silence < trailing_start => silence,
+            // Bounded to one tone, so a next frame's wake-up tone that follows
+            // straight on isn't swallowed into this frame.
+            (Some(trailing_start), _) => self.wake_tone_end(samples, trailing_start).min(samples.len()),
+            (None, silence) => silence.unwrap_or(samples.len()),
+        }
+    }
+
+    // Start of the first stretch after `from` that stays below `floor` for
+    // a whole inter-frame gap, measured in symbol-length windows.
+    fn inter_frame_silence(&self, samples: &[f32], from: usize, floor: f32) -> Option<usize> {
+        let gap_samples = self.config.duration_samples(self.config.inter_frame_gap_ms);
+        let window_size = self.symbol_samples();
+        if gap_samples == 0 || window_size == 0 {
+            return None;
+        }
+
+        let mut silence_start = None;
+        for start in (from..samples.len().saturating_sub(window_size)).step_by(window_size) {
+            if self.rms(&samples[start..start + window_size]) >= floor {
+                silence_start = None;
+                continue;
+            }
+
+            let silence_start = *silence_start.get_or_insert(start);
+            if start + window_size - silence_start >= gap_samples {
+                return Some(silence_start);
+            }
+        }
+
+        None
+    }
+
+    // Sample ranges of every frame in a continuous recording, each running
+    // from its first wake-up window to the end of its data or trailing tone.
+    pub fn find_frames(&self, samples: &[f32]) -> Vec<(usize, usize)> {
+        let mut frames = Vec::new();
+        let mut from = 0;
+
+        while let Some((start, wake_end)) = self.find_frame_start(samples, from) {
+            let end = self.frame_end_from(samples, wake_end).max(wake_end);
+            frames.push((start, end));
+            from = end;
+        }
+
+        frames
+    }
+
+    pub fn demodulate_all(&mut self, samples: &[f32]) -> Vec<Option<Vec<u8>>> {
+        self.find_frames(samples)
+            .into_iter()
+            .map(|(start, end)| self.demodulate(&samples[start..end]))
+            .collect()
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn get_frequencies(&self) -> &[f32] {
+        &self.frequencies
+    }
+
+    // Magnitude spectrum of the first `fft_size` samples, zero-padded if the
+    // buffer is shorter. A full-scale sine reads as 0.5 in its bin.
+    pub fn analyze_spectrum(&mut self, samples: &[f32], fft_size: usize) -> Vec<(f32, f32)> {
+        let fft = self.fft_planner.borrow_mut().plan_fft_forward(fft_size);
+        let scratch = self.scratch.get_mut();
+        let input = &mut scratch.fft_buffer;
+
+        input.clear();
+        input.extend(samples.iter().take(fft_size).map(|&s| Complex::new(s, 0.0)));
+        input.resize(fft_size, Complex::new(0.0, 0.0));
+
+        scratch.fft_scratch.resize(fft.get_inplace_scratch_len(), Complex::new(0.0, 0.0));
+        fft.process_with_scratch(input, &mut scratch.fft_scratch);
+
+        let freq_resolution = self.config.sample_rate as f32 / fft_size as f32;
+
+        input
+            .iter()
+            .take(fft_size / 2)
+            .enumerate()
+            .map(|(i, c)| {
+                let freq = i as f32 * freq_resolution;
+                let magnitude = (c.re * c.re + c.im * c.im).sqrt() / fft_size as f32;
+                (freq, magnitude)
+            })
+            .collect()
+    }
+
+    // Welch's method: Hann-windowed FFTs of `fft_size` samples slid across
+    // the whole buffer, consecutive windows overlapping by `overlap` (0..1),
+    // with their power averaged. Scaled to match `analyze_spectrum`, so a
+    // steady tone reads the same in both. A buffer shorter than one window is
+    // zero-padded into a single one.
+    pub fn analyze_spectrum_averaged(&mut self, samples: &[f32], fft_size: usize, overlap: f32) -> Vec<(f32, f32)> {
+        let fft = self.fft_planner.borrow_mut().plan_fft_forward(fft_size);
+        let window: Vec<f32> = (0..fft_size)
+            .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / fft_size as f32).cos())
+            .collect();
+        let window_sum: f32 = window.iter().sum();
+
+        let hop = ((fft_size as f32 * (1.0 - overlap.clamp(0.0, 0.95))) as usize).max(1);
+        let starts: Vec<usize> = if samples.len() > fft_size {
+            (0..=samples.len() - fft_size).step_by(hop).collect()
+        } else {
+            vec![0]
+        };
+
+        let mut power = vec![0.0f32; fft_size / 2];
+        let mut input = vec![Complex::new(0.0, 0.0); fft_size];
+        for &start in &starts {
+            let segment = &samples[start..(start + fft_size).min(samples.len())];
+            for (i, slot) in input.iter_mut().enumerate() {
+                let sample = segment.get(i).copied().unwrap_or(0.0);
+                *slot = Complex::new(sample * window[i], 0.0);
+            }
+            fft.process(&mut input);
+            for (bin, c) in power.iter_mut().zip(&input) {
+                *bin += c.norm_sqr();
+            }
+        }
+
+        let freq_resolution = self.config.sample_rate as f32 / fft_size as f32;
+        let count = starts.len() as f32;
+
+        power
+            .iter()
+            .enumerate()
+            .map(|(i, &p)| (i as f32 * freq_resolution, (p / count).sqrt() / window_sum))
+            .collect()
+    }
+
+    // Exponential moving average over successive spectra, for displays that
+    // would otherwise flicker. `alpha` is the weight of the newest spectrum;
+    // the first call after a reset seeds the average.
+    pub fn analyze_spectrum_smoothed(&mut self, samples: &[f32], alpha: f32) -> Vec<(f32, f32)> {
+        let alpha = alpha.clamp(0.0, 1.0);
+        let spectrum = self.analyze_spectrum(samples, DEFAULT_SPECTRUM_FFT_SIZE);
+
+        if self.smoothed_spectrum.len() != spectrum.len() {
+            self.smoothed_spectrum = spectrum.iter().map(|&(_, m)| m).collect();
+        } else {
+            for (smoothed, &(_, magnitude)) in self.smoothed_spectrum.iter_mut().zip(spectrum.iter()) {
+                *smoothed += alpha * (magnitude - *smoothed);
+            }
+        }
+
+        spectrum
+            .iter()
+            .zip(self.smoothed_spectrum.iter())
+            .map(|(&(freq, _), &magnitude)| (freq, magnitude))
+            .collect()
+    }
+
+    pub fn reset_spectrum_smoothing(&mut self) {
+        self.smoothed_spectrum.clear();
+    }
+}
+
+// Debounces wake detection: the condition has to hold for `required`
+// consecutive windows before it counts, and once it has, it has to drop
+// again before another detection can fire.
+#[derive(Debug, Clone)]
+struct WakeHysteresis {
+    required: u32,
+    run: u32,
+    armed: bool,
+    leading: bool,
+}
+
+impl WakeHysteresis {
+    fn new(required: u32) -> Self {
+        Self {
+            required: required.max(1),
+            run: 0,
+            armed: true,
+            leading: false,
+        }
+    }
+
+    // For scans from the start of a recording. A tone already sounding
+    // there had its beginning clipped, so a run from the very first window
+    // only needs half the usual length to count.
+    fn at_recording_start(mut self) -> Self {
+        self.leading = true;
+        self
+    }
+
+    fn update(&mut self, is_wake: bool) -> bool {
+        if !is_wake {
+            self.run = 0;
+            self.armed = true;
+            self.leading = false;
+            return false;
+        }
+
+        self.run += 1;
+        let required = if self.leading { self.required.div_ceil(2) } else { self.required };
+        if self.armed && self.run >= required {
+            self.armed = false;
+            return true;
+        }
+
+        false
+    }
+
+    // Distance from the first window of the current run to the latest one.
+    fn run_span(&self, step: usize) -> usize {
+        self.run.saturating_sub(1) as usize * step
+    }
+
+    // Holds off the next detection until the condition has dropped.
+    fn disarm(&mut self) {
+        self.run = 0;
+        self.armed = false;
+        self.leading = false;
+    }
+}
+
+pub struct WakeUpTracker {
+    demodulator: MFSKDemodulator,
+    hysteresis: WakeHysteresis,
+    pending: Vec<f32>,
+    pending_offset: usize,
+    scan_pos: usize,
+    wake_start_found: bool,
+    wake_end: Option<usize>,
+}
+
+impl WakeUpTracker {
+    pub fn new(config: Config) -> Self {
+        let demodulator = MFSKDemodulator::new(config);
+        Self {
+            hysteresis: demodulator.wake_hysteresis().at_recording_start(),
+            demodulator,
+            pending: Vec::new(),
+            pending_offset: 0,
+            scan_pos: 0,
+            wake_start_found: false,
+            wake_end: None,
+        }
+    }
+
+    pub fn feed(&mut self, new_samples: &[f32]) -> Option<usize> {
+        if self.wake_end.is_some() {
+            return self.wake_end;
+        }
+
+        self.pending.extend_from_slice(new_samples);
+
+        if !self.wake_start_found {
+            let window_size = self.demodulator.wake_window_size();
+            let step = window_size / 4;
+
+            // The run counter carries across calls, so keep the samples of
+            // a run in progress and resume scanning after its last window.
+            let mut i = self.scan_pos;
+            while i + window_size < self.pending.len() {
+                let is_wake = self.demodulator.is_wake_window(&self.pending[i..i + window_size]);
+                if self.hysteresis.update(is_wake) {
+                    self.wake_start_found = true;
+                    break;
+                }
+                i += step;
+            }
+
+            let run_start = if self.wake_start_found {
+                i - self.hysteresis.run_span(step)
+            } else {
+                i - self.hysteresis.run as usize * step
+            };
+            self.pending.drain(..run_start);
+            self.pending_offset += run_start;
+            self.scan_pos = i - run_start;
+        }
+
+        // Once the group has started, hold on to samples until its end (and
+        // any repeats) can be located the same way a full scan would.
+        if self.wake_start_found && self.pending.len() >= self.demodulator.wake_group_lookahead() {
+            let group_end = self.demodulator.wake_group_end(&self.pending, 0);
+            match self.demodulator.sync_word_end(&self.pending, group_end) {
+                Some(end) => {
+                    let wake_end = self.pending_offset + end;
+                    log::debug!(target: LOG_TARGET, "Wake-up tone detected, ending at sample {}", wake_end);
+                    self.wake_end = Some(wake_end);
+                    self.pending.clear();
+                }
+                // Not one of our frames; the next call scans on from where
+                // the tone ended.
+                None => {
+                    log::debug!(target: LOG_TARGET, "No sync word after wake-up at sample {}", self.pending_offset);
+                    let group_end = group_end.min(self.pending.len());
+                    self.pending.drain(..group_end);
+                    self.pending_offset += group_end;
+                    self.scan_pos = 0;
+                    self.wake_start_found = false;
+                    self.hysteresis.disarm();
+                }
+            }
+        }
+
+        self.wake_end
+    }
+
+    pub fn wake_end(&self) -> Option<usize> {
+        self.wake_end
+    }
+
+    // A tone still sounding when the tracker is reset won't be reported
+    // again; the next detection needs the wake condition to drop first.
+    pub fn reset(&mut self) {
+        self.pending.clear();
+        self.pending_offset = 0;
+        self.scan_pos = 0;
+        self.wake_start_found = false;
+        self.wake_end = None;
+        self.hysteresis.disarm();
+    }
+}
+
+#[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{FadeShape, NUM_TONES, ROBUST_HEADER_BYTES, SAMPLE_RATE};
+
+    #[test]
+    fn test_modulation_roundtrip() {
+        let config = Config::default();
+        let modulator = MFSKModulator::new(config.clone());
+        let mut demodulator = MFSKDemodulator::new(config);
+
+        let data = vec![0xAB, 0xCD, 0x12, 0x34];
+        let samples = modulator.modulate(&data).unwrap();
+        let decoded = demodulator.demodulate(&samples);
+
+        assert!(decoded.is_some());
+        assert_eq!(decoded.unwrap(), data);
+    }
+
+    #[test]
+    fn test_ultrasonic_roundtrip() {
+        for ultrasonic_quiet in [false, true] {
+            let config = Config {
+                mode: crate::TransmissionMode::Ultrasonic,
+                ultrasonic_quiet,
+                ..Default::default()
+            };
+            let modulator = MFSKModulator::new(config.clone());
+            let mut demodulator = MFSKDemodulator::new(config);
+
+            for data in [vec![0xAA, 0x5A, 0xA5], (0..100).collect::<Vec<u8>>()] {
+                let samples = modulator.modulate(&data).unwrap();
+                assert_eq!(demodulator.demodulate(&samples), Some(data));
+            }
+        }
+    }
+
+    #[test]
+    fn test_acoustic_roundtrip() {
+        let config = Config {
+            mode: crate::TransmissionMode::Acoustic,
+            ..Default::default()
+        };
+        let frequencies = config.frequencies();
+        assert_eq!((frequencies[0], frequencies[NUM_TONES - 1]), (4000.0, 7000.0));
+
+        // Fits under Nyquist for a 16 kHz wideband voice channel too.
+        for sample_rate in [SAMPLE_RATE, 16000] {
+            let config = Config::builder()
+                .mode(crate::TransmissionMode::Acoustic)
+                .sample_rate(sample_rate)
+                .build()
+                .unwrap();
+            let modulator = MFSKModulator::new(config.clone());
+            let mut demodulator = MFSKDemodulator::new(config);
+
+            for data in [vec![0xAA, 0x5A, 0xA5], (0..100).collect::<Vec<u8>>()] {
+                let samples = modulator.modulate(&data).unwrap();
+                assert_eq!(demodulator.demodulate(&samples), Some(data), "{} Hz", sample_rate);
+            }
+        }
+    }
+
+    #[test]
+    fn test_ultrasonic_quiet_stays_above_18khz() {
+        let config = Config {
+            mode: crate::TransmissionMode::Ultrasonic,
+            ultrasonic_quiet: true,
+            ..Default::default()
+        };
+        assert!(config.wake_up_frequency() >= 18000.0);
+        assert!(config.frequencies().iter().all(|&f| f >= 18000.0));
+
+        let modulator = MFSKModulator::new(config.clone());
+        let samples = modulator.modulate(&(0..16).map(|i| i * 0x11).collect::<Vec<u8>>()).unwrap();
+
+        let mut planner = FftPlanner::<f32>::new();
+        let fft = planner.plan_fft_forward(samples.len());
+        let mut spectrum: Vec<Complex<f32>> = samples.iter().map(|&s| Complex::new(s, 0.0)).collect();
+        fft.process(&mut spectrum);
+
+        let bin_hz = config.sample_rate as f32 / samples.len() as f32;
+        let (audible, total) = spectrum[..samples.len() / 2]
+            .iter()
+            .enumerate()
+            .fold((0.0f32, 0.0f32), |(audible, total), (i, c)| {
+                let power = c.norm_sqr();
+                if (i as f32) * bin_hz < 17500.0 {
+                    (audible + power, total + power)
+                } else {
+                    (audible, total + power)
+                }
+            });
+        assert!(audible / total < 1e-3, "audible energy fraction {}", audible / total);
+
+        let low = modulator.generate_tone(config.frequencies()[0], 50);
+        let high = modulator.generate_tone(config.frequencies()[15], 50);
+        let peak = |s: &[f32]| s.iter().fold(0.0f32, |a, &b| a.max(b.abs()));
+        assert!(peak(&low) < peak(&high) * 0.8);
+    }
+
+    #[test]
+    fn test_short_frame_roundtrip() {
+        let config = Config::default();
+        let modulator = MFSKModulator::new(config.clone());
+        let mut demodulator = MFSKDemodulator::new(config);
+
+        let data = vec![0xDE, 0xAD, 0xBE, 0xEF];
+        let samples = modulator.modulate(&data).unwrap();
+
+        let wake_samples = modulator.generate_wake_up_tone().len();
+        let symbol_samples = demodulator.symbol_samples();
+        // A 4-byte frame is all length byte and header, so every symbol is slow.
+        let header_samples = demodulator.length_symbol_samples();
+        assert_eq!(samples.len(), wake_samples + (data.len() + 1) * 2 * header_samples);
+
+        // Wake alignment errs a few samples early, never late.
+        let frame_end = demodulator.short_frame_end(&samples).unwrap();
+        assert!(frame_end <= samples.len() && frame_end + 50 > samples.len());
+        assert_eq!(demodulator.demodulate(&samples), Some(data));
+        assert_eq!(demodulator.demodulate(&samples[..samples.len() - symbol_samples]), None);
+    }
+
+    #[test]
+    fn test_long_frame_roundtrip() {
+        let config = Config::default();
+        let modulator = MFSKModulator::new(config.clone());
+        let mut demodulator = MFSKDemodulator::new(config);
+
+        let data: Vec<u8> = (0..=SHORT_FRAME_THRESHOLD as u8).collect();
+        let samples = modulator.modulate(&data).unwrap();
+
+        assert_eq!(demodulator.short_frame_end(&samples), None);
+        assert_eq!(demodulator.demodulate(&samples), Some(data));
+    }
+
+    #[test]
+    fn test_custom_wake_gap_roundtrip() {
+        let long_data: Vec<u8> = (0..80).collect();
+        let short_data = vec![0x12, 0x34, 0x56];
+        let default_len = MFSKModulator::new(Config::default()).modulate(&long_data).unwrap().len();
+
+        for wake_gap_ms in [0, 60] {
+            let config = Config {
+                wake_gap_ms,
+                ..Default::default()
+            };
+            let modulator = MFSKModulator::new(config.clone());
+            let mut demodulator = MFSKDemodulator::new(config.clone());
+
+            let samples = modulator.modulate(&long_data).unwrap();
+            let expected_len = default_len + config.wake_gap_samples() - Config::default().wake_gap_samples();
+            assert_eq!(samples.len(), expected_len);
+            assert_eq!(demodulator.demodulate(&samples), Some(long_data.clone()));
+
+            let samples = modulator.modulate(&short_data).unwrap();
+            assert_eq!(demodulator.demodulate(&samples), Some(short_data.clone()));
+        }
+    }
+
+    #[test]
+    fn test_sync_word_rejects_stray_wake_tone() {
+        let config = Config::builder().sync_word(true).build().unwrap();
+        let modulator = MFSKModulator::new(config.clone());
+        let mut demodulator = MFSKDemodulator::new(config.clone());
+
+        for len in [8, 200] {
+            let data: Vec<u8> = (0..len).map(|i| (i * 37 + 11) as u8).collect();
+            let frame = modulator.modulate(&data).unwrap();
+            assert_eq!(frame.len(), modulator.frame_samples(len));
+
+            // A wake-up tone followed by other symbols, as from a sender
+            // without the sync word, isn't taken for a frame.
+            let stray = MFSKModulator::new(Config::default()).modulate(&data).unwrap();
+            assert_eq!(demodulator.detect_wake_up(&stray), None);
+            assert_eq!(demodulator.demodulate(&stray), None);
+
+            let mut samples = vec![0.0f32; 5000];
+            samples.extend(&stray);
+            samples.extend(vec![0.0f32; config.duration_samples(config.inter_frame_gap_ms)]);
+            let frame_start = samples.len();
+            samples.extend(&frame);
+            samples.extend(vec![0.0f32; 5000]);
+
+            assert_eq!(demodulator.demodulate(&samples), Some(data.clone()), "{} bytes", len);
+            assert_eq!(demodulator.demodulate_all(&samples).into_iter().flatten().collect::<Vec<_>>(), vec![data]);
+
+            let mut tracker = WakeUpTracker::new(config.clone());
+            let tracked = samples.chunks(2048).find_map(|chunk| tracker.feed(chunk));
+            assert_eq!(tracked, demodulator.detect_wake_up(&samples));
+            assert!(tracked.unwrap() > frame_start);
+        }
+
+        // A late start to the sync word is picked up from the symbols
+        // themselves rather than the end of the tone.
+        let skew = config.duration_samples(5);
+        let wake_samples = config.duration_samples(WAKE_UP_DURATION_MS);
+        let frame = modulator.modulate(&[0xA5; 8]).unwrap();
+        let mut samples = frame[..wake_samples].to_vec();
+        samples.extend(vec![0.0f32; skew]);
+        samples.extend(&frame[wake_samples..]);
+        let sync_end = wake_samples + skew + config.sync_word_samples();
+
+        let detected = demodulator.detect_wake_up(&samples).unwrap();
+        let tolerance = config.duration_samples(config.symbol_duration_ms) / SYNC_SEARCH_DIVISOR / SYNC_SEARCH_STEPS;
+        assert!(detected.abs_diff(sync_end) <= tolerance, "sync ends at {} but found {}", sync_end, detected);
+        assert_eq!(demodulator.demodulate(&samples), Some(vec![0xA5; 8]));
+    }
+
+    #[test]
+    fn test_oversized_length_prefix_is_refused() {
+        let config = Config {
+            length_prefixed: true,
+            ..Default::default()
+        };
+        let modulator = MFSKModulator::new(config);
+        assert!(modulator.modulate(&vec![0; (1 << 16) - 1]).is_ok());
+        let result = modulator.modulate(&vec![0; 1 << 16]);
+        assert!(matches!(result, Err(SonicPipeError::Encoding(_))), "{:?}", result);
+    }
+
+    #[test]
+    fn test_length_prefixed_framing() {
+        let plain = Config::default();
+        let prefixed = Config {
+            length_prefixed: true,
+            ..Default::default()
+        };
+
+        for config in [plain.clone(), prefixed.clone()] {
+            let modulator = MFSKModulator::new(config.clone());
+            let mut demodulator = MFSKDemodulator::new(config.clone());
+
+            let mut stream = Vec::new();
+            let mut sent = Vec::new();
+            for len in [1, SHORT_FRAME_THRESHOLD, SHORT_FRAME_THRESHOLD + 1, 300] {
+                let data: Vec<u8> = (0..len).map(|i| (i * 37 + 11) as u8).collect();
+                let mut samples = vec![0.0f32; 500];
+                samples.extend(modulator.modulate(&data).unwrap());
+                samples.extend(vec![0.0f32; 500]);
+
+                assert_eq!(demodulator.demodulate(&samples), Some(data.clone()), "{} bytes", len);
+                assert!(demodulator.detect_end_of_frame(&samples));
+                stream.push(data.clone());
+                sent.push(data);
+            }
+
+            let frames: Vec<&[u8]> = stream.iter().map(Vec::as_slice).collect();
+            let samples = modulator.modulate_frames(&frames).unwrap();
+            let decoded: Vec<Vec<u8>> = demodulator.demodulate_all(&samples).into_iter().flatten().collect();
+            assert_eq!(decoded, sent);
+        }
+
+        // A long frame only goes silent after the wake-up tone without the
+        // prefix. The middle of the gap is checked since wake alignment errs
+        // a few samples early.
+        let data = vec![0x5Au8; 300];
+        for (config, silent) in [(plain, true), (prefixed, false)] {
+            let samples = MFSKModulator::new(config.clone()).modulate(&data).unwrap();
+            let wake_end = MFSKDemodulator::new(config.clone()).detect_wake_up(&samples).unwrap();
+            let gap_samples = config.wake_gap_samples();
+            let gap = &samples[wake_end + gap_samples / 4..wake_end + gap_samples * 3 / 4];
+            assert_eq!(gap.iter().all(|&s| s.abs() < 1e-3), silent);
+        }
+    }
+
+    #[test]
+    fn test_demodulate_from_without_wake_tone() {
+        let config = Config::default();
+        let modulator = MFSKModulator::new(config.clone());
+        let mut demodulator = MFSKDemodulator::new(config.clone());
+        let wake_samples = demodulator.wake_samples();
+
+        for len in [4, 200] {
+            let data: Vec<u8> = (0..len).map(|i| (i * 29 + 3) as u8).collect();
+            let samples = modulator.modulate(&data).unwrap();
+            let trimmed = &samples[wake_samples..];
+
+            assert_eq!(demodulator.demodulate(trimmed), None, "{} bytes", len);
+            assert_eq!(demodulator.demodulate_from(trimmed, 0), Some(data.clone()), "{} bytes", len);
+
+            let mut padded = vec![0.0f32; 1000];
+            padded.extend_from_slice(trimmed);
+            assert_eq!(demodulator.demodulate_from(&padded, 1000), Some(data), "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn test_onset_fallback_without_wake_tone() {
+        let config = Config::default();
+        let modulator = MFSKModulator::new(config.clone());
+        let mut demodulator = MFSKDemodulator::new(config.clone());
+
+        // Five passes of an 8-tap moving average: 2 to 13 dB down across the
+        // data band, and well over 100 dB down at the wake-up tone, which
+        // leaves nothing of it above the noise.
+        let low_pass = |samples: &[f32]| {
+            let mut out = samples.to_vec();
+            for _ in 0..5 {
+                out = (0..out.len())
+                    .map(|i| out[i.saturating_sub(7)..=i].iter().sum::<f32>() / 8.0)
+                    .collect();
+            }
+            out
+        };
+        let lead = 15000;
+        let mut quiet = vec![0.01f32; lead];
+        crate::testing::add_awgn(&mut quiet, 0.0, 5);
+        quiet.iter_mut().for_each(|s| *s -= 0.01);
+
+        for data in [vec![0x42, 0x17, 0xE9], (0..120u8).map(|i| i.wrapping_mul(13)).collect()] {
+            let frame = modulator.modulate(&data).unwrap();
+            let mut samples = quiet.clone();
+            // A short in-band blip ahead of the frame isn't an onset.
+            for (i, s) in samples[3000..4000].iter_mut().enumerate() {
+                *s += 0.3 * (2.0 * PI * 1500.0 * i as f32 / config.sample_rate as f32).sin();
+            }
+            samples.extend(frame);
+            samples.extend_from_slice(&quiet);
+            let filtered = low_pass(&samples);
+
+            assert_eq!(demodulator.detect_wake_up(&filtered), None);
+            let data_start = if data.len() <= SHORT_FRAME_THRESHOLD {
+                lead + demodulator.wake_samples()
+            } else {
+                lead + demodulator.wake_samples() + config.wake_gap_samples()
+            };
+            let onset = demodulator.detect_onset(&filtered).unwrap();
+            assert!(onset.abs_diff(data_start) < config.duration_samples(3), "onset {} vs {}", onset, data_start);
+            assert_eq!(demodulator.demodulate(&filtered), Some(data), "onset {}", onset);
+        }
+
+        assert_eq!(demodulator.detect_onset(&quiet), None);
+    }
+
+    #[test]
+    fn test_frame_samples_matches_modulate() {
+        for length_prefixed in [false, true] {
+            let modulator = MFSKModulator::new(Config {
+                length_prefixed,
+                ..Default::default()
+            });
+            for len in [1, SHORT_FRAME_THRESHOLD, SHORT_FRAME_THRESHOLD + 1, 200] {
+                let data = vec![0x3Cu8; len];
+                assert_eq!(modulator.modulate(&data).unwrap().len(), modulator.frame_samples(len));
+            }
+        }
+    }
+
+    #[test]
+    fn test_detect_end_of_frame() {
+        let config = Config::default();
+        let modulator = MFSKModulator::new(config.clone());
+        let demodulator = MFSKDemodulator::new(config);
+
+        let long = modulator.modulate(&(0..100).collect::<Vec<u8>>()).unwrap();
+        assert!(demodulator.detect_end_of_frame(&long));
+        assert!(!demodulator.detect_end_of_frame(&long[..long.len() / 2]));
+        assert!(!demodulator.detect_end_of_frame(&long[..long.len() - demodulator.wake_samples()]));
+
+        let short = modulator.modulate(&[1, 2, 3, 4]).unwrap();
+        assert!(demodulator.detect_end_of_frame(&short));
+        assert!(!demodulator.detect_end_of_frame(&short[..short.len() - 10]));
+
+        assert!(!demodulator.detect_end_of_frame(&vec![0.0f32; 48000]));
+    }
+
+    #[test]
+    fn test_cosine_fade_roundtrip() {
+        let config = Config {
+            fade_ms: 10,
+            fade_shape: FadeShape::Cosine,
+            ..Default::default()
+        };
+        let modulator = MFSKModulator::new(config.clone());
+        let mut demodulator = MFSKDemodulator::new(config.clone());
+
+        let tone = modulator.generate_tone(1000.0, 50);
+        let fade_samples = config.duration_samples(10);
+        // A raised cosine starts flatter than a linear ramp of the same length.
+        let early = tone[..fade_samples / 4].iter().fold(0.0f32, |a, &s| a.max(s.abs()));
+        assert!(early < 0.2 * config.volume, "early fade peak {}", early);
+
+        let data: Vec<u8> = (0..80u8).collect();
+        let samples = modulator.modulate(&data).unwrap();
+        assert_eq!(demodulator.demodulate(&samples), Some(data));
+    }
+
+    #[test]
+    fn test_short_tone_envelope() {
+        for shape in [FadeShape::Linear, FadeShape::Cosine] {
+            for duration_ms in [1, 2, 5] {
+                let config = Config {
+                    fade_shape: shape,
+                    ..Default::default()
+                };
+                let modulator = MFSKModulator::new(config.clone());
+                let num_samples = config.duration_samples(duration_ms);
+
+                let envelope = modulator.envelope(num_samples);
+                let peak = envelope.iter().position(|&g| g == 1.0).unwrap_or(num_samples / 2);
+                assert!(envelope.iter().all(|&g| (0.0..=1.0).contains(&g)));
+                assert!(envelope[..=peak].windows(2).all(|w| w[0] <= w[1]), "{:?} {} ms rises", shape, duration_ms);
+                assert!(envelope[peak..].windows(2).all(|w| w[0] >= w[1]), "{:?} {} ms falls", shape, duration_ms);
+
+                let tone = modulator.generate_tone(1000.0, duration_ms);
+                assert_eq!(tone.len(), num_samples);
+                assert!(tone.iter().all(|s| s.abs() <= config.volume));
+            }
+        }
+    }
+
+    #[test]
+    fn test_generated_tone_frequency_and_amplitude() {
+        let config = Config::builder().volume(0.6).build().unwrap();
+        let modulator = MFSKModulator::new(config.clone());
+        let mut demodulator = MFSKDemodulator::new(config.clone());
+        let fft_size = 8192;
+        let resolution = config.sample_rate as f32 / fft_size as f32;
+
+        // Audible data tones, the ultrasonic band, and one just under Nyquist,
+        // where a badly wrapped phase would alias back down.
+        for frequency in [1000.0, 1700.0, 2500.0, 18500.0, 19750.0, 23500.0] {
+            let tone = modulator.generate_tone(frequency, 100);
+            assert_eq!(tone.len(), config.duration_samples(100));
+
+            let (peak, _) = demodulator
+                .analyze_spectrum(&tone, fft_size)
+                .into_iter()
+                .fold((0.0f32, 0.0f32), |best, bin| if bin.1 > best.1 { bin } else { best });
+            assert!((peak - frequency).abs() <= resolution, "{} Hz peaked at {} Hz", frequency, peak);
+
+            // Correlating against the exact frequency recovers the amplitude
+            // once the energy lost to the fade ramps is accounted for.
+            let envelope = modulator.envelope(tone.len());
+            let (re, im) = tone.iter().enumerate().fold((0.0f64, 0.0f64), |(re, im), (i, &s)| {
+                let phase = 2.0 * std::f64::consts::PI * frequency as f64 * i as f64 / config.sample_rate as f64;
+                (re + s as f64 * phase.cos(), im + s as f64 * phase.sin())
+            });
+            let gain: f64 = envelope.iter().map(|&g| g as f64).sum();
+            let amplitude = (2.0 * re.hypot(im) / gain) as f32;
+            assert!((amplitude - config.volume).abs() < 0.01 * config.volume, "{} Hz at {}", frequency, amplitude);
+
+            let sustained = &tone[envelope.iter().position(|&g| g == 1.0).unwrap()..];
+            let peak_sample = sustained.iter().fold(0.0f32, |a, &s| a.max(s.abs()));
+            assert!(peak_sample <= config.volume + 1e-6, "{} Hz peaks at {}", frequency, peak_sample);
+        }
+    }
+
+    #[test]
+    fn test_modulate_reports_peak() {
+        let config = Config::builder().volume(0.8).build().unwrap();
+        let modulator = MFSKModulator::new(config.clone());
+
+        let (samples, peak) = modulator.modulate_with_peak(b"peak").unwrap();
+        assert_eq!(samples, modulator.modulate(b"peak").unwrap());
+        assert!(peak <= config.volume && peak > 0.99 * config.volume, "peak {}", peak);
+
+        assert_eq!(peak_amplitude(&[0.2, -1.4, 0.9]), 1.4);
+        assert_eq!(peak_amplitude(&[]), 0.0);
+    }
+
+    #[test]
+    fn test_mid_frame_dropout_is_erased() {
+        let config = Config::default();
+        let modulator = MFSKModulator::new(config.clone());
+        let mut demodulator = MFSKDemodulator::new(config.clone());
+
+        let data: Vec<u8> = (0..100u8).map(|i| i.wrapping_mul(37)).collect();
+        let mut samples = modulator.modulate(&data).unwrap();
+
+        // Silence bytes 40..50 of the data.
+        let data_start = config.duration_samples(WAKE_UP_DURATION_MS) + config.wake_gap_samples();
+        let from = data_start + config.data_samples(40);
+        let to = data_start + config.data_samples(50);
+        samples[from..to].fill(0.0);
+
+        let (decoded, erased) = demodulator.demodulate_with_erasures(&samples).unwrap();
+        assert_eq!(decoded.len(), data.len());
+        assert!((40..50).all(|i| erased.contains(&i)), "erased {:?}", erased);
+        assert!(erased.iter().all(|&i| (39..=50).contains(&i)), "erased {:?}", erased);
+        for i in (0..data.len()).filter(|i| !erased.contains(i)) {
+            assert_eq!(decoded[i], data[i], "byte {}", i);
+        }
+    }
+
+    #[test]
+    fn test_ambiguous_symbol_is_erased() {
+        let config = Config {
+            symbol_confidence_threshold: Some(2.0),
+            ..Default::default()
+        };
+        let modulator = MFSKModulator::new(config.clone());
+        let mut demodulator = MFSKDemodulator::new(config.clone());
+        let plain = MFSKDemodulator::new(Config::default());
+
+        // White noise at a signal-like level, so the dropout floor passes it.
+        let noise = |len: usize, seed: u64| {
+            let mut noise = vec![0.3f32; len];
+            crate::testing::add_awgn(&mut noise, 0.0, seed);
+            noise.iter().map(|s| s - 0.3).collect::<Vec<f32>>()
+        };
+
+        let symbol_samples = config.duration_samples(config.symbol_duration_ms);
+        for seed in 0..20 {
+            let window = noise(symbol_samples, seed);
+            assert_eq!(demodulator.detect_data_symbol(&window, 0.0), None, "seed {}", seed);
+            assert!(plain.detect_data_symbol(&window, 0.0).is_some());
+        }
+
+        let data: Vec<u8> = (0..100u8).map(|i| i.wrapping_mul(37)).collect();
+        let mut samples = modulator.modulate(&data).unwrap();
+        let data_start = config.duration_samples(WAKE_UP_DURATION_MS) + config.wake_gap_samples();
+        let from = data_start + config.data_samples(40);
+        let to = data_start + config.data_samples(50);
+        samples[from..to].copy_from_slice(&noise(to - from, 99));
+
+        let (decoded, erased) = demodulator.demodulate_with_erasures(&samples).unwrap();
+        assert!((40..50).all(|i| erased.contains(&i)), "erased {:?}", erased);
+        assert!(erased.iter().all(|&i| (39..=50).contains(&i)), "erased {:?}", erased);
+        for i in (0..data.len()).filter(|i| !erased.contains(i)) {
+            assert_eq!(decoded[i], data[i], "byte {}", i);
+        }
+    }
+
+    // Moves every frequency in `samples` by `offset_hz(t)` at `t` seconds in,
+    // by rotating the analytic signal.
+    fn shift_frequency(samples: &[f32], sample_rate: u32, offset_hz: impl Fn(f32) -> f32) -> Vec<f32> {
+        let n = samples.len();
+        let mut planner = FftPlanner::new();
+        let mut buffer: Vec<Complex<f32>> = samples.iter().map(|&s| Complex::new(s, 0.0)).collect();
+        planner.plan_fft_forward(n).process(&mut buffer);
+        for (k, bin) in buffer.iter_mut().enumerate() {
+            if k > 0 && k < n.div_ceil(2) {
+                *bin *= 2.0;
+            } else if k > n / 2 {
+                *bin = Complex::new(0.0, 0.0);
+            }
+        }
+        planner.plan_fft_inverse(n).process(&mut buffer);
+
+        let mut phase = 0.0f64;
+        buffer
+            .iter()
+            .enumerate()
+            .map(|(i, c)| {
+                let shifted = c / n as f32 * Complex::from_polar(1.0, phase as f32);
+                let t = i as f32 / sample_rate as f32;
+                phase = (phase + 2.0 * std::f64::consts::PI * offset_hz(t) as f64 / sample_rate as f64)
+                    % (2.0 * std::f64::consts::PI);
+                shifted.re
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_pilot_tracks_drifting_offset() {
+        let pilot = crate::PilotTone {
+            frequency: 3200.0,
+            level: 0.1,
+        };
+        let config = Config {
+            pilot: Some(pilot),
+            ..Default::default()
+        };
+        let modulator = MFSKModulator::new(config.clone());
+        let mut demodulator = MFSKDemodulator::new(config.clone());
+
+        for data in [(0..100u8).map(|i| i.wrapping_mul(71)).collect::<Vec<u8>>(), vec![0x0F, 0xF0, 0x5A]] {
+            let samples = modulator.modulate(&data).unwrap();
+            assert_eq!(samples.len(), modulator.frame_samples(data.len()));
+            assert!(samples.iter().all(|s| s.abs() <= config.volume + 1e-6));
+            assert_eq!(demodulator.demodulate(&samples), Some(data.clone()));
+        }
+
+        // Drifts off to 70 Hz, most of a tone step, over the frame.
+        let data: Vec<u8> = (0..100u8).map(|i| i.wrapping_mul(71)).collect();
+        let samples = modulator.modulate(&data).unwrap();
+        let duration = samples.len() as f32 / config.sample_rate as f32;
+        let drifted = shift_frequency(&samples, config.sample_rate, |t| 70.0 * t / duration);
+
+        let symbol_samples = config.duration_samples(config.symbol_duration_ms);
+        let from = drifted.len() - demodulator.wake_samples() - symbol_samples;
+        let window = &drifted[from..from + symbol_samples];
+        let expected = 70.0 * (from + symbol_samples / 2) as f32 / config.sample_rate as f32 / duration;
+        let coarse = demodulator.read_pilot(window, 60.0).unwrap();
+        assert!((coarse.offset_hz - expected).abs() < 0.5, "{:?}, expected {} Hz", coarse, expected);
+        let reading = demodulator.read_pilot(window, coarse.offset_hz).unwrap();
+        assert!((reading.level - pilot.level * config.volume).abs() < 0.005, "{:?}", reading);
+
+        assert_eq!(demodulator.demodulate(&drifted), Some(data.clone()));
+
+        // The same frame without the pilot to follow falls apart.
+        let unpiloted = MFSKModulator::new(Config::default()).modulate(&data).unwrap();
+        let drifted = shift_frequency(&unpiloted, config.sample_rate, |t| 70.0 * t / duration);
+        let decoded = MFSKDemodulator::new(Config::default()).demodulate(&drifted);
+        assert_ne!(decoded, Some(data));
+    }
+
+    #[test]
+    fn test_pilot_follows_slow_fade() {
+        let pilot = crate::PilotTone {
+            frequency: 3200.0,
+            level: 0.1,
+        };
+        let config = Config {
+            pilot: Some(pilot),
+            ..Default::default()
+        };
+        let data: Vec<u8> = (0..100u8).map(|i| i.wrapping_mul(71)).collect();
+
+        // Fades to a twentieth over the data, well under the dropout floor.
+        let data_start = config.duration_samples(WAKE_UP_DURATION_MS) + config.wake_gap_samples();
+        let fade = |mut samples: Vec<f32>| {
+            let len = (samples.len() - data_start) as f32;
+            for (i, sample) in samples[data_start..].iter_mut().enumerate() {
+                *sample *= 1.0 - 0.95 * i as f32 / len;
+            }
+            samples
+        };
+
+        let faded = fade(MFSKModulator::new(config.clone()).modulate(&data).unwrap());
+        let (decoded, erased) = MFSKDemodulator::new(config)
+            .demodulate_with_erasures(&faded)
+            .unwrap();
+        assert!(erased.is_empty(), "erased {:?}", erased);
+        assert_eq!(decoded, data);
+
+        // Without the pilot, the quiet end of the frame reads as a dropout.
+        let faded = fade(MFSKModulator::new(Config::default()).modulate(&data).unwrap());
+        let (_, erased) = MFSKDemodulator::new(Config::default())
+            .demodulate_with_erasures(&faded)
+            .unwrap();
+        assert!(!erased.is_empty());
+    }
+
+    #[test]
+    fn test_tone_gains_scale_each_tone() {
+        let config = Config::default();
+        let mut modulator = MFSKModulator::new(config.clone());
+        // Sampled peaks miss the crest by a varying amount, so compare RMS.
+        let rms = |samples: Vec<f32>| (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt();
+        let frequencies = config.frequencies();
+
+        // Rising toward the top of the band, as for a tweeter that rolls off.
+        let gains: Vec<f32> = (0..NUM_TONES).map(|i| 1.0 + i as f32 / 15.0).collect();
+        modulator.set_tone_gains(gains.clone()).unwrap();
+
+        let base = rms(MFSKModulator::new(config.clone()).generate_tone(frequencies[0], 50));
+        for (i, &gain) in gains.iter().enumerate() {
+            let level = rms(modulator.generate_tone(frequencies[i], 50));
+            assert!((level / base - gain).abs() < 0.01, "tone {}: {} vs {}", i, level / base, gain);
+        }
+        let wake = MFSKModulator::new(config.clone()).generate_wake_up_tone();
+        assert_eq!(modulator.generate_wake_up_tone(), wake);
+
+        // At volume 0.5 a gain of 2.0 just reaches full scale; more clips.
+        let mut too_loud = gains.clone();
+        too_loud[15] = 2.5;
+        assert!(modulator.set_tone_gains(too_loud).is_err());
+        assert!(modulator.set_tone_gains(vec![1.0; 4]).is_err());
+        assert!(modulator.set_tone_gains(vec![f32::NAN; NUM_TONES]).is_err());
+        assert_eq!(modulator.tone_gains(), Some(&gains[..]));
+
+        // The same gains set through the config, and kept through serde.
+        let emphasized = Config::builder()
+            .tone_gains(Some(gains.clone()))
+            .build()
+            .unwrap();
+        let json = serde_json::to_string(&emphasized).unwrap();
+        let restored: Config = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.tone_gains, Some(gains));
+        let from_config = MFSKModulator::new(restored);
+        assert_eq!(
+            from_config.generate_tone(frequencies[15], 50),
+            modulator.generate_tone(frequencies[15], 50)
+        );
+        assert!(Config::builder()
+            .tone_gains(Some(vec![1.0; 4]))
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn test_modulate_at_rate() {
+        let config = Config::default();
+        let modulator = MFSKModulator::new(config.clone());
+        let data = b"44.1 kHz".to_vec();
+
+        let samples = modulator.modulate_at_rate(&data, 44100).unwrap();
+        let native = Config {
+            sample_rate: 44100,
+            ..config.clone()
+        };
+        assert_eq!(samples.len(), MFSKModulator::new(native.clone()).frame_samples(data.len()));
+
+        // The wake-up tone and a data symbol land on their frequencies when
+        // played at 44.1 kHz.
+        let mut demodulator = MFSKDemodulator::new(native.clone());
+        let peak = |spectrum: Vec<(f32, f32)>| {
+            spectrum.into_iter().fold((0.0, 0.0), |a, b| if b.1 > a.1 { b } else { a }).0
+        };
+        let resolution = 44100.0 / 2048.0;
+        let wake = peak(demodulator.analyze_spectrum(&samples[..2048], 2048));
+        assert!((wake - config.wake_up_frequency()).abs() <= resolution, "wake at {} Hz", wake);
+
+        let first_symbol = native.duration_samples(WAKE_UP_DURATION_MS) + native.wake_gap_samples();
+        let tone = peak(demodulator.analyze_spectrum(&samples[first_symbol..first_symbol + 2048], 2048));
+        // A short frame opens with its length byte, high nibble first.
+        let expected = config.frequencies()[data.len() >> 4];
+        assert!((tone - expected).abs() <= resolution, "{} Hz, expected {}", tone, expected);
+
+        assert_eq!(demodulator.demodulate(&samples), Some(data.clone()));
+        assert!(modulator.modulate_at_rate(&data, 8000).is_err());
+    }
+
+    #[test]
+    fn test_phase_randomization_lowers_peak() {
+        let all_tones: Vec<usize> = (0..NUM_TONES).collect();
+        let peak = |config: Config| {
+            MFSKModulator::new(config)
+                .generate_multitone(&all_tones, 50)
+                .iter()
+                .fold(0.0f32, |a, s| a.max(s.abs()))
+        };
+
+        let aligned = peak(Config::default());
+        let randomized = peak(Config {
+            phase_randomization: true,
+            phase_seed: 7,
+            ..Default::default()
+        });
+        assert!(randomized < aligned * 0.7, "randomized peak {} vs aligned {}", randomized, aligned);
+
+        // Same seed, same phases.
+        let again = peak(Config {
+            phase_randomization: true,
+            phase_seed: 7,
+            ..Default::default()
+        });
+        assert_eq!(randomized, again);
+    }
+
+    #[test]
+    fn test_ack_roundtrip_with_random_phases() {
+        let config = Config {
+            phase_randomization: true,
+            phase_seed: 42,
+            ..Default::default()
+        };
+        let modulator = MFSKModulator::new(config.clone());
+        let demodulator = MFSKDemodulator::new(config);
+
+        let samples = modulator.generate_ack_tone(0x1234);
+        assert_eq!(demodulator.detect_ack(&samples), Some(0x1234));
+    }
+
+    #[test]
+    fn test_signal_presence() {
+        let config = Config::default();
+        let modulator = MFSKModulator::new(config.clone());
+        let demodulator = MFSKDemodulator::new(config);
+
+        let noise: Vec<f32> = (0..SAMPLE_RATE as u64)
+            .map(|i| ((splitmix64(i) >> 40) as f32 / (1u64 << 24) as f32 * 2.0 - 1.0) * 0.3)
+            .collect();
+        assert!(!demodulator.has_signal(&noise));
+        assert!(demodulator.signal_confidence(&noise) < 0.5, "{}", demodulator.signal_confidence(&noise));
+
+        let mut recording = noise.clone();
+        for (r, s) in recording[10_000..].iter_mut().zip(modulator.modulate(b"is anyone there").unwrap()) {
+            *r += s;
+        }
+        assert!(demodulator.has_signal(&recording));
+        assert!(demodulator.signal_confidence(&recording) > 0.9, "{}", demodulator.signal_confidence(&recording));
+
+        assert!(!demodulator.has_signal(&[]));
+        assert_eq!(demodulator.signal_confidence(&[]), 0.0);
+    }
+
+    #[test]
+    fn test_ack_tone_roundtrip() {
+        for config in [
+            Config::default(),
+            Config {
+                mode: crate::TransmissionMode::Ultrasonic,
+                ..Default::default()
+            },
+        ] {
+            let modulator = MFSKModulator::new(config.clone());
+            let demodulator = MFSKDemodulator::new(config);
+
+            let mut samples = vec![0.0f32; 1234];
+            samples.extend(modulator.generate_ack_tone(0xBEEF));
+            samples.extend(vec![0.0f32; 4800]);
+
+            assert_eq!(demodulator.detect_ack(&samples), Some(0xBEEF));
+            assert_eq!(demodulator.detect_ack_signal(&samples), Some((AckKind::Ack, 0xBEEF)));
+
+            let nack = modulator.generate_nack_tone(7);
+            assert_eq!(demodulator.detect_ack_signal(&nack), Some((AckKind::Nack, 7)));
+            assert_eq!(demodulator.detect_ack(&nack), None);
+        }
+    }
+
+    #[test]
+    fn test_ack_not_detected_in_data_frame() {
+        let config = Config::default();
+        let modulator = MFSKModulator::new(config.clone());
+        let demodulator = MFSKDemodulator::new(config);
+
+        let samples = modulator.modulate(&[0x0A, 0xA0, 0x5F, 0x12, 0x34]).unwrap();
+        assert_eq!(demodulator.detect_ack_signal(&samples), None);
+    }
+
+    #[test]
+    fn test_wake_alignment_with_leading_silence() {
+        let config = Config::default();
+        let modulator = MFSKModulator::new(config.clone());
+        let demodulator = MFSKDemodulator::new(config.clone());
+        let wake_samples = modulator.generate_wake_up_tone().len();
+
+        for offset in [0, 7, 1000, 12345] {
+            let mut samples = vec![0.0f32; offset];
+            samples.extend(modulator.modulate(&[1, 2, 3, 4]).unwrap());
+
+            let wake_end = demodulator.detect_wake_up(&samples).unwrap();
+            assert!(wake_end <= offset + wake_samples && wake_end + 50 > offset + wake_samples);
+        }
+    }
+
+    #[test]
+    fn test_clipped_leading_wake_tone() {
+        let config = Config::default();
+        let modulator = MFSKModulator::new(config.clone());
+        let mut demodulator = MFSKDemodulator::new(config.clone());
+        let data = b"clipped start".to_vec();
+        let frame = modulator.modulate(&data).unwrap();
+        let wake_samples = modulator.generate_wake_up_tone().len();
+
+        // A whole tone: both edges within a millisecond.
+        let mut samples = vec![0.0f32; 5000];
+        samples.extend_from_slice(&frame);
+        let (start, end) = demodulator.detect_wake_bounds(&samples).unwrap();
+        assert!(start.abs_diff(5000) < 48, "start {}", start);
+        assert!(end.abs_diff(5000 + wake_samples) < 48, "end {}", end);
+
+        // The recording starts 40 ms into the wake-up tone.
+        let cut = config.duration_samples(40);
+        let samples = &frame[cut..];
+        let (start, end) = demodulator.detect_wake_bounds(samples).expect("no wake-up");
+        assert_eq!(start, 0);
+        assert!(end.abs_diff(wake_samples - cut) < 48, "end {}", end);
+        assert_eq!(demodulator.demodulate(samples), Some(data.clone()));
+
+        let mut tracker = WakeUpTracker::new(config.clone());
+        let detected = samples.chunks(2400).find_map(|chunk| tracker.feed(chunk));
+        assert_eq!(detected, Some(end));
+    }
+
+    #[test]
+    fn test_wake_up_repeats_align_to_last_tone() {
+        let config = Config {
+            wake_up_repeats: 3,
+            ..Default::default()
+        };
+        let modulator = MFSKModulator::new(config.clone());
+        let mut demodulator = MFSKDemodulator::new(config.clone());
+
+        let wake_samples = modulator.generate_wake_up_tone().len();
+        let lead_samples = 3 * wake_samples + 2 * config.duration_samples(WAKE_REPEAT_GAP_MS);
+
+        for data in [vec![0xC0, 0xFF, 0xEE], (0..100u8).collect::<Vec<u8>>()] {
+            let mut samples = vec![0.0f32; 3333];
+            samples.extend(modulator.modulate(&data).unwrap());
+            assert_eq!(samples.len() - 3333, modulator.frame_samples(data.len()));
+
+            let wake_end = demodulator.detect_wake_up(&samples).unwrap();
+            assert!(wake_end <= 3333 + lead_samples && wake_end + 50 > 3333 + lead_samples);
+            assert_eq!(demodulator.demodulate(&samples), Some(data.clone()));
+
+            // Losing the first tone of the group still lands on the last one.
+            samples[3333..3333 + wake_samples].fill(0.0);
+            assert_eq!(demodulator.demodulate(&samples), Some(data));
+        }
+    }
+
+    #[test]
+    fn test_wake_up_tracker_matches_full_scan() {
+        let config = Config::default();
+        let modulator = MFSKModulator::new(config.clone());
+        let demodulator = MFSKDemodulator::new(config.clone());
+
+        let mut samples = vec![0.0f32; 30000];
+        samples.extend(modulator.modulate(&[1, 2, 3, 4]).unwrap());
+        let expected = demodulator.detect_wake_up(&samples);
+        assert!(expected.is_some());
+
+        for chunk_size in [480, 2400, 7777] {
+            let mut tracker = WakeUpTracker::new(config.clone());
+            let mut detected = None;
+            for chunk in samples.chunks(chunk_size) {
+                detected = tracker.feed(chunk);
+                assert!(tracker.pending.len() <= demodulator.wake_group_lookahead() + chunk_size);
+            }
+            assert_eq!(detected, expected);
+            assert_eq!(tracker.wake_end(), expected);
+
+            tracker.reset();
+            assert_eq!(tracker.feed(&vec![0.0f32; 48000]), None);
+        }
+    }
+
+    #[test]
+    fn test_wake_detection_needs_sustained_tone() {
+        let config = Config::default();
+        let modulator = MFSKModulator::new(config.clone());
+        let demodulator = MFSKDemodulator::new(config.clone());
+
+        // A 5 ms click fits inside a single window, though the overlapping
+        // windows all see it.
+        let mut spike = vec![0.0f32; 4800];
+        spike.extend(modulator.generate_tone(config.wake_up_frequency(), 5));
+        spike.extend(vec![0.0f32; 9600]);
+        assert!(demodulator.detect_wake_up(&spike).is_none());
+        assert!(!demodulator.has_signal(&spike));
+
+        let single = MFSKDemodulator::new(Config::builder().wake_confirm_windows(1).build().unwrap());
+        assert!(single.has_signal(&spike));
+
+        let mut sustained = vec![0.0f32; 4800];
+        sustained.extend(modulator.generate_wake_up_tone());
+        sustained.extend(vec![0.0f32; 9600]);
+        assert!(demodulator.detect_wake_up(&sustained).is_some());
+    }
+
+    #[test]
+    fn test_wake_hysteresis_rearms_after_drop() {
+        let mut hysteresis = WakeHysteresis::new(3);
+        let fired: Vec<bool> = [true, false, true, true, true, true, true, false, true, true, true]
+            .iter()
+            .map(|&is_wake| hysteresis.update(is_wake))
+            .collect();
+        assert_eq!(
+            fired,
+            [false, false, false, false, true, false, false, false, false, false, true]
+        );
+
+        // A tracker reset mid-tone waits for the tone to end.
+        let config = Config::default();
+        let modulator = MFSKModulator::new(config.clone());
+        let tone = modulator.generate_tone(config.wake_up_frequency(), 400);
+        let mut tracker = WakeUpTracker::new(config);
+        let _ = tracker.feed(&tone[..9600]);
+        assert!(tracker.wake_start_found);
+
+        tracker.reset();
+        assert_eq!(tracker.feed(&tone[9600..]), None);
+        assert!(!tracker.wake_start_found);
+    }
+
+    // The straightforward scan the coarse-to-fine search has to agree with.
+    fn linear_wake_search(demodulator: &MFSKDemodulator, samples: &[f32], from: usize, to: usize) -> Option<usize> {
+        let window_size = demodulator.wake_window_size();
+        let step = window_size / 4;
+        let mut hysteresis = demodulator.wake_hysteresis();
+        if from == 0 {
+            hysteresis = hysteresis.at_recording_start();
+        }
+
+        let mut i = from;
+        while i + window_size <= samples.len() {
+            if hysteresis.update(demodulator.is_wake_window(&samples[i..i + window_size])) {
+                return Some(i - hysteresis.run_span(step));
+            }
+            if i - hysteresis.run_span(step) >= to {
+                break;
+            }
+            i += step;
+        }
+
+        None
+    }
 
     #[test]
-    fn test_modulation_roundtrip() {
+    fn test_coarse_wake_search_matches_linear_scan() {
+        let config = Config::default();
+        let modulator = MFSKModulator::new(config.clone());
+        let frame = modulator.modulate(b"needle").unwrap();
+        let wake = modulator.generate_wake_up_tone();
+
+        // 30 s of noise with a short blip, a frame, and a tone cut off at the
+        // start of the buffer.
+        let mut samples = wake[wake.len() / 2..].to_vec();
+        samples.extend(vec![0.0f32; 48000 * 20]);
+        samples.extend_from_slice(&wake[..1500]);
+        samples.extend(vec![0.0f32; 48000 * 5]);
+        let frame_at = samples.len();
+        samples.extend_from_slice(&frame);
+        samples.extend(vec![0.0f32; 48000 * 30 - samples.len()]);
+        crate::testing::add_awgn(&mut samples, 5.0, 3);
+
+        for confirm in [1, 2, 3, 6, 9] {
+            let demodulator = MFSKDemodulator::new(Config {
+                wake_confirm_windows: confirm,
+                ..config.clone()
+            });
+            for (from, to) in [(0, samples.len()), (1, samples.len()), (12345, frame_at), (frame_at, frame_at + 100)] {
+                assert_eq!(
+                    demodulator.find_wake_window(&samples, from, to),
+                    linear_wake_search(&demodulator, &samples, from, to),
+                    "confirm {} from {} to {}",
+                    confirm,
+                    from,
+                    to
+                );
+            }
+        }
+
+        // A scan of every window checks at least one per step on the way to
+        // the frame; the coarse search has to get there in under half that.
+        let demodulator = MFSKDemodulator::new(config);
+        let window_size = demodulator.wake_window_size();
+        let mut checked = 0;
+        let coarse = demodulator.find_wake_window_by(samples.len(), 1, samples.len(), |i| {
+            checked += 1;
+            demodulator.is_wake_window(&samples[i..i + window_size])
+        });
+        let linear = linear_wake_search(&demodulator, &samples, 1, samples.len());
+
+        assert!(coarse.is_some());
+        assert_eq!(coarse, linear);
+        let linear_checks = (linear.unwrap() - 1) / (window_size / 4);
+        assert!(
+            checked * 2 < linear_checks,
+            "coarse checked {} windows vs at least {} for a linear scan",
+            checked,
+            linear_checks
+        );
+    }
+
+    #[test]
+    fn test_fft_detector_matches_goertzel() {
+        let goertzel_config = Config::default();
+        let fft_config = Config::builder().symbol_detector(SymbolDetector::Fft).build().unwrap();
+        let modulator = MFSKModulator::new(goertzel_config.clone());
+        let goertzel = MFSKDemodulator::new(goertzel_config.clone());
+        let fft = MFSKDemodulator::new(fft_config.clone());
+
+        let frequencies = goertzel_config.frequencies();
+        let windows: Vec<(usize, Vec<f32>)> = (0..200)
+            .map(|i| {
+                let tone = (splitmix64(i) % NUM_TONES as u64) as usize;
+                let mut window = modulator.generate_tone(frequencies[tone], goertzel_config.symbol_duration_ms);
+                crate::testing::add_awgn(&mut window, -22.0, i);
+                (tone, window)
+            })
+            .collect();
+
+        let by_goertzel: Vec<u8> = windows
+            .iter()
+            .map(|(_, w)| goertzel.detect_symbol(w))
+            .collect();
+        let by_fft: Vec<u8> = windows.iter().map(|(_, w)| fft.detect_symbol(w)).collect();
+
+        // Both read the same bins, so they agree even on the windows the
+        // noise gets wrong.
+        assert_eq!(by_fft, by_goertzel);
+        let errors = windows.iter().zip(&by_fft).filter(|((tone, _), &d)| *tone != d as usize).count();
+        assert!(errors > 0 && errors < windows.len() / 2, "{} errors", errors);
+
+        // One transform per window beats a filter per tone at 16 tones.
+        let (fft_work, goertzel_work) = (fft.transform_work.get(), goertzel.transform_work.get());
+        assert!(
+            fft_work < goertzel_work,
+            "fft {} vs goertzel {}",
+            fft_work,
+            goertzel_work
+        );
+
+        let data = b"FFT detector".to_vec();
+        let mut demodulator = MFSKDemodulator::new(fft_config.clone());
+        assert_eq!(demodulator.demodulate(&MFSKModulator::new(fft_config).modulate(&data).unwrap()), Some(data));
+    }
+
+    #[test]
+    fn test_goertzel_normalized_is_window_independent() {
+        let config = Config::default();
+        let demodulator = MFSKDemodulator::new(config);
+
+        let freq = 1000.0;
+        let amplitude = 0.5;
+        let samples: Vec<f32> = (0..2400)
+            .map(|i| amplitude * (2.0 * PI * freq * i as f32 / SAMPLE_RATE as f32).sin())
+            .collect();
+
+        let short = demodulator.goertzel_normalized(&samples[..480], freq);
+        let long = demodulator.goertzel_normalized(&samples, freq);
+
+        assert!((short - amplitude).abs() < amplitude * 0.05, "10ms: {}", short);
+        assert!((long - amplitude).abs() < amplitude * 0.05, "50ms: {}", long);
+        assert!(demodulator.goertzel(&samples, freq) > demodulator.goertzel(&samples[..480], freq) * 4.0);
+        assert_eq!(demodulator.goertzel_normalized(&[], freq), 0.0);
+    }
+
+    #[test]
+    fn test_noise_profile_whitens_colored_noise() {
         let config = Config::default();
         let modulator = MFSKModulator::new(config.clone());
         let mut demodulator = MFSKDemodulator::new(config);
+        let symbol_samples = demodulator.symbol_samples();
 
-        let data = vec![0xAB, 0xCD, 0x12, 0x34];
-        let samples = modulator.modulate(&data);
-        let decoded = demodulator.demodulate(&samples);
+        let mut state = 0x2545_F491_4F6C_DD1Du64;
+        let mut uniform = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            ((state >> 11) as f64 / (1u64 << 53) as f64) as f32
+        };
+        // Leaky-integrated white noise: power falls off with frequency, so
+        // the low tones sit under a much higher noise floor than the high ones.
+        let mut level = 0.0f32;
+        let mut red_noise = |len: usize| -> Vec<f32> {
+            (0..len)
+                .map(|_| {
+                    level = 0.98 * level + (uniform() - 0.5) * 0.1;
+                    level
+                })
+                .collect()
+        };
 
-        assert!(decoded.is_some());
-        assert_eq!(decoded.unwrap(), data);
+        let profile = demodulator.estimate_noise_profile(&red_noise(symbol_samples * 200));
+        assert!(profile[0] > profile[15] * 1.5);
+
+        let trials = 320;
+        let mut errors_raw = 0;
+        let mut errors_whitened = 0;
+        for trial in 0..trials {
+            let tone = trial % NUM_TONES;
+            let noise = red_noise(symbol_samples);
+            let window: Vec<f32> = modulator
+                .generate_tone(demodulator.get_frequencies()[tone], 50)
+                .iter()
+                .zip(noise.iter())
+                .map(|(s, n)| s * 0.03 + n)
+                .collect();
+
+            demodulator.clear_noise_profile();
+            errors_raw += (demodulator.detect_symbol(&window) as usize != tone) as usize;
+            demodulator.set_noise_profile(profile.clone());
+            errors_whitened += (demodulator.detect_symbol(&window) as usize != tone) as usize;
+        }
+
+        assert!(errors_raw > 0);
+        assert!(errors_whitened < errors_raw, "whitened {} vs raw {}", errors_whitened, errors_raw);
+    }
+
+    #[test]
+    fn test_clipping_detection() {
+        let config = Config {
+            volume: 1.0,
+            ..Default::default()
+        };
+        let modulator = MFSKModulator::new(config.clone());
+        let demodulator = MFSKDemodulator::new(config);
+
+        let samples = modulator.modulate(b"clip").unwrap();
+        assert!(!demodulator.is_clipped(&samples));
+
+        let overdriven: Vec<f32> = samples.iter().map(|s| (s * 10.0).clamp(-1.0, 1.0)).collect();
+        assert!(demodulator.is_clipped(&overdriven));
+        assert!(demodulator.clipped_fraction(&overdriven) > 0.3);
+    }
+
+    #[test]
+    fn test_faint_wake_tone_is_detected() {
+        // -50 dBFS: quiet, but clean.
+        let config = Config {
+            volume: 0.003,
+            ..Default::default()
+        };
+        let modulator = MFSKModulator::new(config.clone());
+        let mut demodulator = MFSKDemodulator::new(config.clone());
+
+        for data in [b"faint".to_vec(), vec![0x5A; 100]] {
+            let samples = modulator.modulate(&data).unwrap();
+            assert!(peak_amplitude(&samples) < 0.01);
+            assert!(demodulator.has_signal(&samples));
+            assert_eq!(demodulator.demodulate(&samples), Some(data));
+        }
+        assert_eq!(
+            demodulator.detect_ack(&modulator.generate_ack_tone(0x1234)),
+            Some(0x1234)
+        );
+    }
+
+    #[test]
+    fn test_header_survives_noise_that_corrupts_payload() {
+        let config = Config {
+            symbol_duration_ms: 10,
+            wake_gap_ms: 0,
+            ..Default::default()
+        };
+        let modulator = MFSKModulator::new(config.clone());
+        let mut demodulator = MFSKDemodulator::new(config.clone());
+
+        let data: Vec<u8> = (0..96u32).map(|i| (i * 37 + 11) as u8).collect();
+        let mut samples = modulator.modulate(&data).unwrap();
+
+        let mut state = 0x9E37_79B9_7F4A_7C15u64;
+        let mut uniform = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            ((state >> 11) as f64 / (1u64 << 53) as f64) as f32
+        };
+        let wake_samples = modulator.generate_wake_up_tone().len();
+        let data_end = wake_samples + config.data_samples(data.len());
+        for s in &mut samples[wake_samples..data_end] {
+            *s += (uniform() - 0.5) * 3.5;
+        }
+
+        let decoded = demodulator.demodulate(&samples).unwrap();
+        assert_eq!(decoded.len(), data.len());
+        assert_eq!(decoded[..ROBUST_HEADER_BYTES], data[..ROBUST_HEADER_BYTES]);
+
+        let payload_errors = (ROBUST_HEADER_BYTES..data.len()).filter(|&i| decoded[i] != data[i]).count();
+        assert!(payload_errors > 0, "noise should corrupt the payload rate");
+    }
+
+    #[test]
+    fn test_recommend_band_finds_quiet_region() {
+        let modulator = MFSKModulator::new(Config::default());
+
+        let resolution = SAMPLE_RATE as f32 / 4096.0;
+        let noise: Vec<(f32, f32)> = (0..2048)
+            .map(|i| {
+                let f = i as f32 * resolution;
+                (f, if (5000.0..7000.0).contains(&f) { 0.001 } else { 0.05 })
+            })
+            .collect();
+
+        let band = modulator.recommend_band(&noise).unwrap();
+        let top = band.base_frequency + 15.0 * band.frequency_step;
+        assert!(band.base_frequency >= 5000.0 && top < 7000.0, "{:?}", band);
+
+        assert_eq!(modulator.recommend_band(&[]), None);
+    }
+
+    #[test]
+    fn test_custom_band_roundtrip() {
+        let mut config = Config::default();
+        let band = ToneBand {
+            base_frequency: 5000.0,
+            frequency_step: 120.0,
+        };
+        config.set_custom_band(band).unwrap();
+        assert_eq!(config.frequencies()[0], 5000.0);
+        assert_eq!(config.frequencies()[15], 5000.0 + 15.0 * 120.0);
+
+        let modulator = MFSKModulator::new(config.clone());
+        let mut demodulator = MFSKDemodulator::new(config.clone());
+        let data = vec![0x13, 0x37, 0xC0, 0xDE];
+        assert_eq!(demodulator.demodulate(&modulator.modulate(&data).unwrap()), Some(data));
+
+        // Over Nyquist, and straddling the 18.5 kHz audible wake-up tone.
+        let mut config = Config::default();
+        for band in [
+            ToneBand { base_frequency: 22000.0, frequency_step: 200.0 },
+            ToneBand { base_frequency: 17500.0, frequency_step: 100.0 },
+            ToneBand { base_frequency: 1000.0, frequency_step: 0.0 },
+        ] {
+            assert!(config.set_custom_band(band).is_err(), "{:?}", band);
+        }
+        assert_eq!(config.custom_band, None);
+    }
+
+    #[test]
+    fn test_smoothed_spectrum_converges() {
+        let config = Config::default();
+        let modulator = MFSKModulator::new(config.clone());
+        let mut demodulator = MFSKDemodulator::new(config);
+
+        let tone = modulator.generate_tone(3000.0, 100);
+        let target = demodulator.analyze_spectrum(&tone, DEFAULT_SPECTRUM_FFT_SIZE);
+
+        let burst = modulator.generate_tone(9000.0, 100);
+        demodulator.analyze_spectrum_smoothed(&burst, 0.3);
+
+        let error = |spectrum: &[(f32, f32)]| {
+            spectrum.iter().zip(target.iter()).map(|(a, b)| (a.1 - b.1).abs()).fold(0.0f32, f32::max)
+        };
+
+        let first = demodulator.analyze_spectrum_smoothed(&tone, 0.3);
+        let mut latest = first.clone();
+        for _ in 0..40 {
+            latest = demodulator.analyze_spectrum_smoothed(&tone, 0.3);
+        }
+        assert!(error(&first) > 0.01);
+        assert!(error(&latest) < 1e-5);
+
+        demodulator.reset_spectrum_smoothing();
+        assert_eq!(
+            demodulator.analyze_spectrum_smoothed(&burst, 0.3),
+            demodulator.analyze_spectrum(&burst, DEFAULT_SPECTRUM_FFT_SIZE)
+        );
+    }
+
+    #[test]
+    fn test_averaged_spectrum_covers_whole_buffer() {
+        let config = Config::default();
+        let modulator = MFSKModulator::new(config.clone());
+        let mut demodulator = MFSKDemodulator::new(config);
+
+        // Half a second of silence, then half a second of 3 kHz.
+        let mut samples = vec![0.0f32; 24000];
+        samples.extend(modulator.generate_tone(3000.0, 500));
+
+        let peak = |spectrum: &[(f32, f32)]| {
+            spectrum.iter().copied().fold((0.0f32, 0.0f32), |best, bin| if bin.1 > best.1 { bin } else { best })
+        };
+
+        // A single FFT only sees the silent start.
+        assert!(peak(&demodulator.analyze_spectrum(&samples, 2048)).1 < 1e-6);
+
+        for (fft_size, overlap) in [(2048, 0.5), (4096, 0.75), (8192, 0.0)] {
+            let spectrum = demodulator.analyze_spectrum_averaged(&samples, fft_size, overlap);
+            assert_eq!(spectrum.len(), fft_size / 2);
+
+            let resolution = crate::SAMPLE_RATE as f32 / fft_size as f32;
+            let (frequency, magnitude) = peak(&spectrum);
+            assert!((frequency - 3000.0).abs() <= resolution, "{} Hz at {}", frequency, fft_size);
+            assert!(magnitude > 0.05, "{} at {}", magnitude, fft_size);
+        }
+    }
+
+    #[test]
+    fn test_fractional_symbol_length_does_not_drift() {
+        // 15 ms at 44.1 kHz is 661.5 samples per symbol.
+        let config = Config {
+            sample_rate: 44100,
+            symbol_duration_ms: 15,
+            ..Default::default()
+        };
+        assert_eq!(config.symbol_samples_exact(15).fract(), 0.5);
+
+        let modulator = MFSKModulator::new(config.clone());
+        let mut demodulator = MFSKDemodulator::new(config);
+
+        let data: Vec<u8> = (0..400u32).map(|i| (i * 73 + 5) as u8).collect();
+        let samples = modulator.modulate(&data).unwrap();
+        assert_eq!(samples.len(), modulator.frame_samples(data.len()));
+        assert_eq!(demodulator.demodulate(&samples), Some(data));
+    }
+
+    #[test]
+    fn test_find_frames_in_continuous_recording() {
+        let config = Config::default();
+        let modulator = MFSKModulator::new(config.clone());
+        let mut demodulator = MFSKDemodulator::new(config);
+
+        let first = vec![1, 2, 3];
+        let second: Vec<u8> = (0..100u8).map(|i| i.wrapping_mul(29)).collect();
+        let third = vec![9, 8, 7, 6];
+
+        let mut samples = vec![0.0f32; 5000];
+        samples.extend(modulator.modulate(&first).unwrap());
+        samples.extend(vec![0.0f32; 3000]);
+        let second_start = samples.len();
+        samples.extend(modulator.modulate(&second).unwrap());
+        // The next frame's wake-up tone follows the trailing one directly.
+        let third_start = samples.len();
+        samples.extend(modulator.modulate(&third).unwrap());
+        samples.extend(vec![0.0f32; 2000]);
+
+        let frames = demodulator.find_frames(&samples);
+        assert_eq!(frames.len(), 3, "{:?}", frames);
+        assert!(frames[1].0 <= second_start + 600 && frames[1].1 <= third_start + 50);
+        assert!(frames[2].0 + 600 >= third_start);
+
+        assert_eq!(demodulator.demodulate_all(&samples), vec![Some(first), Some(second), Some(third)]);
+    }
+
+    #[test]
+    fn test_frames_split_on_inter_frame_gap() {
+        let config = Config::builder().inter_frame_gap_ms(300).build().unwrap();
+        let modulator = MFSKModulator::new(config.clone());
+        let mut demodulator = MFSKDemodulator::new(config.clone());
+
+        let first: Vec<u8> = (0..80u8).collect();
+        let second: Vec<u8> = (0..90u8).map(|i| i.wrapping_mul(7)).collect();
+
+        let mut samples = vec![0.0f32; 4000];
+        samples.extend(modulator.modulate_frames(&[&first, &second]).unwrap());
+        samples.extend(vec![0.0f32; 4000]);
+
+        let frames = demodulator.find_frames(&samples);
+        assert_eq!(frames.len(), 2, "{:?}", frames);
+        assert_eq!(demodulator.demodulate_all(&samples), vec![Some(first.clone()), Some(second.clone())]);
+
+        // Without its trailing tone the first frame still ends at the gap
+        // rather than running into the second.
+        let first_end = 4000 + modulator.frame_samples(first.len());
+        let wake_samples = config.duration_samples(WAKE_UP_DURATION_MS);
+        samples[first_end - wake_samples..first_end].fill(0.0);
+
+        let frames = demodulator.find_frames(&samples);
+        assert_eq!(frames.len(), 2, "{:?}", frames);
+        assert!(frames[0].1 <= first_end && frames[0].1 + wake_samples + 2400 >= first_end, "{:?}", frames);
+        assert_eq!(demodulator.demodulate_all(&samples), vec![Some(first), Some(second)]);
+    }
+
+    #[test]
+    fn test_symbol_packing_roundtrip() {
+        let all_bytes: Vec<u8> = (0..=255).collect();
+
+        let nibbles = bytes_to_symbols(&all_bytes, 16);
+        assert_eq!(&nibbles[..6], &[0x0, 0x0, 0x0, 0x1, 0x0, 0x2]);
+        assert_eq!(&nibbles[nibbles.len() - 2..], &[0xF, 0xF]);
+
+        for num_tones in [2, 4, 8, 16, 32, 64, 256] {
+            let symbols = bytes_to_symbols(&all_bytes, num_tones);
+            let bits = num_tones.trailing_zeros() as usize;
+            assert_eq!(symbols.len(), (all_bytes.len() * 8).div_ceil(bits));
+            assert!(symbols.iter().all(|&s| (s as usize) < num_tones));
+            assert_eq!(symbols_to_bytes(&symbols, num_tones), all_bytes, "{} tones", num_tones);
+        }
+
+        // Each symbol is the tone the modulator actually sends.
+        let config = Config::default();
+        let modulator = MFSKModulator::new(config.clone());
+        let demodulator = MFSKDemodulator::new(config.clone());
+        let samples = modulator.modulate(&[0xA7; 70]).unwrap();
+        let data_start = config.duration_samples(WAKE_UP_DURATION_MS) + config.wake_gap_samples();
+        let window = demodulator.symbol_window(&samples, data_start, 1).unwrap();
+        assert_eq!(demodulator.detect_symbol(window), bytes_to_symbols(&[0xA7], NUM_TONES)[1]);
+    }
+
+    #[test]
+    fn test_settling_skip_reads_faded_tones_stronger() {
+        let config = Config {
+            symbol_duration_ms: 20,
+            ..Default::default()
+        };
+        let settled_config = Config {
+            symbol_settle_ms: config.fade_ms,
+            ..config.clone()
+        };
+        let data: Vec<u8> = (0..40u8).map(|i| i.wrapping_mul(37)).collect();
+        let samples = MFSKModulator::new(config.clone()).modulate(&data).unwrap();
+        let data_start = config.duration_samples(WAKE_UP_DURATION_MS) + config.wake_gap_samples();
+
+        // Each window's magnitude on the tone that was sent, and the sent
+        // tone's lead over the runner-up.
+        let readings = |config: &Config| {
+            let demodulator = MFSKDemodulator::new(config.clone());
+            let symbols = bytes_to_symbols(&data, NUM_TONES);
+            let (mut level, mut lead) = (0.0f32, 0.0f32);
+            for (i, &symbol) in symbols.iter().enumerate() {
+                let window = demodulator.symbol_window(&samples, data_start, i).unwrap();
+                let mags: Vec<f32> =
+                    demodulator.frequencies.iter().map(|&f| demodulator.goertzel_normalized(window, f)).collect();
+                let sent = mags[symbol as usize];
+                let runner_up = mags
+                    .iter()
+                    .enumerate()
+                    .filter(|&(tone, _)| tone != symbol as usize)
+                    .fold(0.0f32, |a, (_, &m)| a.max(m));
+                level += sent;
+                lead += sent - runner_up;
+            }
+            (level / symbols.len() as f32, lead / symbols.len() as f32)
+        };
+
+        let (level, lead) = readings(&config);
+        let (settled_level, settled_lead) = readings(&settled_config);
+        assert!(settled_level > level * 1.05, "{} vs {}", settled_level, level);
+        assert!(settled_lead > lead, "{} vs {}", settled_lead, lead);
+
+        let mut demodulator = MFSKDemodulator::new(settled_config);
+        assert_eq!(demodulator.demodulate(&samples).unwrap(), data);
+    }
+
+    #[test]
+    fn test_pack_bits() {
+        let data = [0xA7, 0x3C, 0x01, 0xFF, 0x80];
+
+        // Four bits is the nibble layout: high nibble, then low nibble.
+        let nibbles: Vec<u8> = data.iter().flat_map(|&b| [b >> 4, b & 0x0F]).collect();
+        assert_eq!(pack_bits(&data, 4), nibbles);
+        assert_eq!(unpack_bits(&nibbles, 4, data.len()), data);
+
+        // 0xA7 0x3C in 3-bit groups: 101 001 110 011 110 0(00).
+        assert_eq!(&pack_bits(&data[..2], 3), &[5, 1, 6, 3, 6, 0]);
+
+        for bits in 1..=8 {
+            for len in 0..=data.len() {
+                let symbols = pack_bits(&data[..len], bits);
+                assert_eq!(symbols.len(), (8 * len).div_ceil(bits));
+                assert!(symbols.iter().all(|&s| (s as u32) < 1 << bits));
+                assert_eq!(unpack_bits(&symbols, bits, len), &data[..len], "{} bits, {} bytes", bits, len);
+            }
+        }
+
+        // The recorded length stops a padding-only symbol reading as a byte.
+        let symbols = pack_bits(&[0xAB], 3);
+        assert_eq!(symbols.len(), 3);
+        assert_eq!(unpack_bits(&symbols, 3, 1), vec![0xAB]);
+        assert_eq!(unpack_bits(&symbols[..2], 3, 1), Vec::<u8>::new());
+
+        // Tone counts that aren't powers of two round down.
+        assert_eq!(bytes_to_symbols(&data, 6), pack_bits(&data, 2));
+        assert_eq!(bytes_to_symbols(&data, 12), pack_bits(&data, 3));
+        assert_eq!(symbols_to_bytes(&bytes_to_symbols(&data, 12), 12), data);
+    }
+
+    #[test]
+    fn test_symbol_width_outside_range_panics() {
+        let panics = |f: &dyn Fn()| std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)).is_err();
+
+        for bits in [0, 9] {
+            assert!(panics(&|| drop(pack_bits(&[0xAB], bits))), "{} bits", bits);
+            assert!(panics(&|| drop(unpack_bits(&[1, 2], bits, 1))), "{} bits", bits);
+        }
+        for num_tones in [0, 1, 257] {
+            assert!(panics(&|| drop(bytes_to_symbols(&[0xAB], num_tones))), "{} tones", num_tones);
+            assert!(panics(&|| drop(symbols_to_bytes(&[1, 2], num_tones))), "{} tones", num_tones);
+        }
+        assert!(!panics(&|| drop(symbols_to_bytes(&bytes_to_symbols(&[0xAB], 256), 2))));
+    }
+
+    #[test]
+    fn test_snr_per_tone_finds_attenuated_tone() {
+        let config = Config::default();
+        let mut modulator = MFSKModulator::new(config.clone());
+        let mut gains = vec![1.0; NUM_TONES];
+        gains[5] = 0.03;
+        modulator.set_tone_gains(gains).unwrap();
+
+        let data: Vec<u8> = (0..=255).collect();
+        let mut samples = modulator.modulate(&data).unwrap();
+        crate::testing::add_awgn(&mut samples, 15.0, 21);
+
+        let demodulator = MFSKDemodulator::new(config);
+        let snr = demodulator.estimate_snr_per_tone(&samples);
+        assert_eq!(snr.len(), NUM_TONES);
+
+        let healthy = snr.iter().enumerate().filter(|&(i, _)| i != 5).map(|(_, &s)| s);
+        let weakest_healthy = healthy.fold(f32::INFINITY, f32::min);
+        assert!(weakest_healthy > 20.0, "{:?}", snr);
+        assert!(snr[5] < weakest_healthy - 15.0, "{:?}", snr);
+
+        assert!(demodulator.estimate_snr_per_tone(&vec![0.0f32; 48000]).is_empty());
+    }
+
+    #[test]
+    fn test_estimate_symbol_duration() {
+        let data: Vec<u8> = (0..90).map(|i| (i * 71 + 3) as u8).collect();
+        // The receiver's own setting plays no part in the estimate.
+        let demodulator = MFSKDemodulator::new(Config::default());
+
+        for duration in [12, 20, 37, 50, 85] {
+            let config = Config {
+                symbol_duration_ms: duration,
+                ..Config::default()
+            };
+            let mut samples = vec![0.0f32; 6000];
+            samples.extend(MFSKModulator::new(config).modulate(&data).unwrap());
+            samples.extend(vec![0.0f32; 6000]);
+            crate::testing::add_awgn(&mut samples, 10.0, duration as u64);
+
+            let estimated = demodulator.estimate_symbol_duration(&samples).unwrap();
+            assert!((estimated - duration as f32).abs() < 0.5, "{} ms read as {} ms", duration, estimated);
+        }
+
+        // Short frames have fewer transitions but the same grid. The header
+        // goes at twice the period, so there need to be more data symbols
+        // than header symbols for the period to stand out.
+        let config = Config {
+            symbol_duration_ms: 25,
+            ..Config::default()
+        };
+        let samples = MFSKModulator::new(config).modulate(&data[..60]).unwrap();
+        let estimated = demodulator.estimate_symbol_duration(&samples).unwrap();
+        assert!((estimated - 25.0).abs() < 1.0, "read as {} ms", estimated);
+
+        assert_eq!(demodulator.estimate_symbol_duration(&vec![0.0f32; 48000]), None);
+    }
+
+    #[test]
+    fn test_scrambler_varies_constant_payload() {
+        let zeros = vec![0u8; 100];
+        let scrambled = scramble(&zeros, 7);
+        assert_eq!(descramble(&scrambled, 7), zeros);
+        assert_ne!(descramble(&scrambled, 8), zeros);
+
+        // Whatever the fill, every tone is used about equally.
+        for fill in [0x00, 0xFF, 0x55] {
+            let data = vec![fill; 500];
+            let scrambled = scramble(&data, 7);
+            assert_eq!(descramble(&scrambled, 7), data);
+
+            let mut histogram = [0usize; NUM_TONES];
+            for symbol in bytes_to_symbols(&scrambled, NUM_TONES) {
+                histogram[symbol as usize] += 1;
+            }
+            let expected = 2 * data.len() / NUM_TONES;
+            assert!(
+                histogram
+                    .iter()
+                    .all(|&count| count > expected / 2 && count < expected * 3 / 2),
+                "0x{:02X}: {:?}",
+                fill,
+                histogram
+            );
+        }
+
+        // A bit error on air spoils that bit and the two taps after it.
+        let data: Vec<u8> = (0..40).collect();
+        let mut scrambled = scramble(&data, 7);
+        scrambled[10] ^= 0x80;
+        let wrong_bits: u32 = descramble(&scrambled, 7)
+            .iter()
+            .zip(&data)
+            .map(|(a, b)| (a ^ b).count_ones())
+            .sum();
+        assert_eq!(wrong_bits, 3);
+
+        let symbols = bytes_to_symbols(&scrambled, NUM_TONES);
+        let mut used = [false; NUM_TONES];
+        symbols.iter().for_each(|&s| used[s as usize] = true);
+        assert!(used.iter().all(|&u| u), "not every tone was used");
+        let longest_run = symbols
+            .chunk_by(|a, b| a == b)
+            .map(|run| run.len())
+            .max()
+            .unwrap();
+        assert!(longest_run <= 6, "{} symbols in a row on one tone", longest_run);
+
+        let config = Config::builder().scrambler_seed(Some(7)).build().unwrap();
+        let modulator = MFSKModulator::new(config.clone());
+        let mut demodulator = MFSKDemodulator::new(config.clone());
+        for data in [vec![0u8; 10], zeros] {
+            let samples = modulator.modulate(&data).unwrap();
+            assert_eq!(demodulator.demodulate(&samples), Some(data.clone()));
+            // Without the seed the receiver only sees the scrambled bytes.
+            assert_ne!(
+                MFSKDemodulator::new(Config::default()).demodulate(&samples),
+                Some(data)
+            );
+        }
+    }
+
+    #[test]
+    fn test_custom_frequency_table_roundtrip() {
+        let config = Config::default();
+        // Uneven spacing, out of order, nothing like the mode's own band.
+        let table: Vec<f32> = (0..16)
+            .map(|i| 600.0 + [0.0, 130.0, 270.0, 460.0][i % 4] + (i / 4) as f32 * 900.0)
+            .rev()
+            .collect();
+
+        let modulator = MFSKModulator::with_frequencies(config.clone(), table.clone()).unwrap();
+        let samples = modulator.modulate(b"non-uniform tone table").unwrap();
+
+        let mut demodulator = MFSKDemodulator::with_frequencies(config.clone(), table.clone()).unwrap();
+        assert_eq!(demodulator.get_frequencies(), &table[..]);
+        assert_eq!(demodulator.demodulate(&samples).unwrap(), b"non-uniform tone table");
+        assert_ne!(
+            MFSKDemodulator::new(config.clone()).demodulate(&samples),
+            Some(b"non-uniform tone table".to_vec())
+        );
+
+        assert!(MFSKDemodulator::with_frequencies(config.clone(), table[..8].to_vec()).is_err());
+        let mut duplicate = table.clone();
+        duplicate[3] = duplicate[0];
+        assert!(MFSKDemodulator::with_frequencies(config.clone(), duplicate).is_err());
+        let mut too_high = table;
+        too_high[0] = 24000.0;
+        assert!(MFSKModulator::with_frequencies(config, too_high).is_err());
+    }
+
+    #[test]
+    fn test_excluded_frequency_is_never_sent() {
+        let config = Config::builder().excluded_frequencies(vec![(1990.0, 2010.0)]).build().unwrap();
+        let modulator = MFSKModulator::new(config.clone());
+        let mut demodulator = MFSKDemodulator::new(config.clone());
+        assert_eq!(demodulator.get_frequencies(), modulator.get_frequencies());
+
+        let data: Vec<u8> = (0..=255).collect();
+        let samples = modulator.modulate(&data).unwrap();
+        assert_eq!(demodulator.demodulate(&samples), Some(data));
+
+        // Only the neighbours' leakage is left at 2000 Hz.
+        let excluded = demodulator.goertzel_normalized(&samples, 2000.0);
+        let default_samples = MFSKModulator::new(Config::default()).modulate(&(0..=255).collect::<Vec<u8>>()).unwrap();
+        let used = demodulator.goertzel_normalized(&default_samples, 2000.0);
+        assert!(excluded < used * 0.25, "{} at 2000 Hz, {} when it is used", excluded, used);
+    }
+
+    #[test]
+    fn test_bits_per_symbol_roundtrip() {
+        let short: Vec<u8> = (0..20).map(|i| (i * 37 + 5) as u8).collect();
+        let long: Vec<u8> = (0..90).map(|i| (i * 53 + 11) as u8).collect();
+
+        for bits in [1, 3, 4, 5] {
+            let config = Config::builder().bits_per_symbol(bits).symbol_duration_ms(20).build().unwrap();
+            assert_eq!(config.num_tones(), 1 << bits);
+
+            let modulator = MFSKModulator::new(config.clone());
+            let mut demodulator = MFSKDemodulator::new(config.clone());
+            assert_eq!(modulator.get_frequencies().len(), 1 << bits);
+
+            for data in [&short, &long] {
+                let samples = modulator.modulate(data).unwrap();
+                assert_eq!(samples.len(), modulator.frame_samples(data.len()), "{} bits", bits);
+                assert_eq!(demodulator.demodulate(&samples).as_ref(), Some(data), "{} bits", bits);
+            }
+        }
+
+        assert!(Config::builder().bits_per_symbol(0).build().is_err());
+        assert!(Config::builder().bits_per_symbol(9).build().is_err());
+        // 32 ultrasonic tones run into the wake-up tone.
+        assert!(Config::builder()
+            .mode(crate::TransmissionMode::Ultrasonic)
+            .bits_per_symbol(5)
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn test_erased_symbol_spans_byte_boundary() {
+        let config = Config::builder().bits_per_symbol(3).build().unwrap();
+        let demodulator = MFSKDemodulator::new(config);
+
+        // 8 symbols of 3 bits are 3 bytes; symbol 2 holds bits 6-8.
+        let (data, erased) = demodulator.pack_frame(&[0; 8], &[2]).unwrap();
+        assert_eq!(data.len(), 3);
+        assert_eq!(erased, vec![0, 1]);
+    }
+
+    #[test]
+    fn test_erased_symbol_covers_descrambled_spread() {
+        let config = Config {
+            scrambler_seed: Some(7),
+            ..Default::default()
+        };
+        let demodulator = MFSKDemodulator::new(config);
+        let data: Vec<u8> = (0..16).map(|i| i * 13).collect();
+        let symbols = bytes_to_symbols(&scramble(&data, 7), demodulator.get_frequencies().len());
+
+        for i in 0..symbols.len() {
+            let mut damaged = symbols.clone();
+            damaged[i] ^= 0b1010;
+            let (decoded, erased) = demodulator.pack_frame(&damaged, &[i]).unwrap();
+            for byte in (0..data.len()).filter(|&byte| decoded[byte] != data[byte]) {
+                assert!(erased.contains(&byte), "symbol {}: byte {} not in {:?}", i, byte, erased);
+            }
+        }
     }
 
     #[test]
@@ -255,4 +4159,75 @@ mod tests {
         let other_magnitude = demodulator.goertzel(&samples, 2000.0);
         assert!(magnitude > other_magnitude * 5.0);
     }
+
+    #[test]
+    fn test_overlapping_transmissions_flag_collision() {
+        let config = Config::default();
+        let modulator = MFSKModulator::new(config.clone());
+        let demodulator = MFSKDemodulator::new(config.clone());
+        let first = modulator.modulate(b"first sender's message here").unwrap();
+        let second = modulator.modulate(b"and another one talking over").unwrap();
+
+        let lead = config.duration_samples(100);
+        let mut alone = vec![0.0f32; lead];
+        alone.extend(&first);
+        alone.extend(vec![0.0f32; lead]);
+        let mut noisy = alone.clone();
+        crate::testing::add_awgn(&mut alone, 30.0, 4);
+        crate::testing::add_awgn(&mut noisy, 0.0, 4);
+        assert_eq!(demodulator.collision_share(&alone), Some(0.0));
+        assert!(!demodulator.collision_detected(&noisy), "{:?}", demodulator.collision_share(&noisy));
+
+        // The second sender starts 1.2 s in, a little quieter, off the
+        // first's symbol boundaries.
+        let mut collided = alone.clone();
+        collided.extend(vec![0.0f32; second.len()]);
+        let offset = lead + config.duration_samples(1234);
+        for (sample, s) in collided[offset..].iter_mut().zip(&second) {
+            *sample += s * 0.8;
+        }
+        assert!(
+            demodulator.collision_detected(&collided),
+            "{:?}",
+            demodulator.collision_share(&collided)
+        );
+
+        // Two tones leave no others to measure the floor against.
+        let binary = Config {
+            bits_per_symbol: 1,
+            ..Default::default()
+        };
+        let frame = MFSKModulator::new(binary.clone()).modulate(b"first").unwrap();
+        let mut demodulator = MFSKDemodulator::new(binary);
+        assert_eq!(demodulator.demodulate(&frame), Some(b"first".to_vec()));
+        assert_eq!(demodulator.collision_share(&frame), None);
+    }
+
+    #[test]
+    fn test_channel_busy_with_another_sender() {
+        let config = Config::default();
+        let demodulator = MFSKDemodulator::new(config.clone());
+        let listen = config.duration_samples(300);
+        let frame = MFSKModulator::new(config.clone()).modulate(b"someone else is talking").unwrap();
+        let at = |offset_ms: u32, gain: f32| {
+            let from = config.duration_samples(offset_ms);
+            let mut samples: Vec<f32> = frame[from..from + listen].iter().map(|s| s * gain).collect();
+            crate::testing::add_awgn(&mut samples, 20.0, offset_ms as u64);
+            samples
+        };
+
+        // Caught during the wake-up tone, and in the middle of the data, from
+        // a sender across the room.
+        assert!(demodulator.channel_busy(&at(0, 0.1)));
+        assert!(demodulator.channel_busy(&at(1500, 0.1)));
+
+        // A room with some hiss, about 0.05 RMS, and one knock in it.
+        let mut quiet = vec![0.05f32; listen];
+        crate::testing::add_awgn(&mut quiet, 0.0, 7);
+        quiet.iter_mut().for_each(|s| *s -= 0.05);
+        assert!(!demodulator.channel_busy(&quiet));
+        let knock = config.duration_samples(20);
+        quiet[listen / 2..listen / 2 + knock].iter_mut().for_each(|s| *s += 0.5);
+        assert!(!demodulator.channel_busy(&quiet));
+    }
 }
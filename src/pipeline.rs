@@ -0,0 +1,1242 @@
+use crate::codec::{
+    compress_fragments, decompress_fragment, reassemble_fragments, resolve_compressor, Compressor,
+    EccScheme, Fragment, Lz4Compressor, NoCompression, ReedSolomonCodec, COMPRESSION_NONE,
+};
+use crate::error::{Result, SonicPipeError};
+use crate::modulation::{MFSKDemodulator, MFSKModulator, CLIP_FRACTION_THRESHOLD, COLLISION_MIN_SHARE};
+use crate::protocol::{
+    Metadata, Packet, FLAG_COMPRESSION_MASK, FLAG_FRAGMENT, FLAG_METADATA, FLAG_PROTECTED_GEOMETRY,
+    FLAG_RAW, MAX_PAYLOAD_SIZE,
+};
+use crate::{Config, LOG_TARGET};
+
+// Below this, as `estimate_snr` reads it, frames stop decoding reliably; it
+// sits about 30 dB above the wideband SNR of white noise at the default rate.
+const MIN_DECODE_SNR_DB: f32 = 20.0;
+
+// How far using up all of the ECC's correcting power pulls the reception
+// quality down; a frame ECC only just saved still decoded correctly.
+const ECC_LOAD_WEIGHT: f32 = 0.5;
+
+// Frame payload as decoded, before decompression.
+struct DecodedFrame {
+    compression_id: u8,
+    has_metadata: bool,
+    is_fragment: bool,
+    payload: Vec<u8>,
+    ecc_bytes: usize,
+    ecc_load: f32,
+    checksum_ok: bool,
+}
+
+// What a decoded transmission cost on air. `samples` and `duration_ms` span
+// the frame itself rather than the whole recording, so silence before the
+// wake-up tone doesn't drag the rate down. `quality` is the frame's
+// `reception_quality`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct DecodeStats {
+    pub payload_bytes: usize,
+    pub compressed_bytes: usize,
+    pub ecc_bytes: usize,
+    pub samples: usize,
+    pub duration_ms: u32,
+    pub effective_bps: f32,
+    pub quality: f32,
+}
+
+// One number, 0 to 1, for how trustworthy a reception was: how clearly the
+// symbols stood out (see `MFSKDemodulator::symbol_confidence`), discounted
+// by how much of the ECC's correcting power was used, and 0 if the checksum
+// failed. Near 1 is a clean reception; a low score on a frame that still
+// checked out decoded, but only just, and is worth asking to resend.
+pub fn reception_quality(symbol_confidence: f32, ecc_load: f32, checksum_ok: bool) -> f32 {
+    if !checksum_ok {
+        return 0.0;
+    }
+    symbol_confidence.clamp(0.0, 1.0) * (1.0 - ECC_LOAD_WEIGHT * ecc_load.clamp(0.0, 1.0))
+}
+
+pub fn encode_to_samples(data: &[u8], config: &Config) -> Result<Vec<f32>> {
+    encode_with_metadata(data, None, config)
+}
+
+// Prepends `metadata` to the payload, before compression, and flags it so
+// the receiver knows to strip it off.
+pub fn encode_with_metadata(data: &[u8], metadata: Option<&Metadata>, config: &Config) -> Result<Vec<f32>> {
+    let (body, flags) = match metadata {
+        Some(metadata) => {
+            let mut body = metadata.serialize()?;
+            body.extend_from_slice(data);
+            (body, FLAG_METADATA)
+        }
+        None => (data.to_vec(), 0),
+    };
+
+    if config.raw_frame {
+        encode_raw_frame(&body, flags, config)
+    } else if config.compress {
+        encode_compressed(&body, flags, config, &Lz4Compressor)
+    } else {
+        encode_compressed(&body, flags, config, &NoCompression)
+    }
+}
+
+pub fn encode_to_samples_with(data: &[u8], config: &Config, compressor: &dyn Compressor) -> Result<Vec<f32>> {
+    encode_compressed(data, 0, config, compressor)
+}
+
+fn encode_compressed(data: &[u8], flags: u8, config: &Config, compressor: &dyn Compressor) -> Result<Vec<f32>> {
+    let compressed = compressor.compress(data);
+    log::debug!(target: LOG_TARGET, "Compressed {} bytes to {} bytes", data.len(), compressed.len());
+
+    encode_frame(&compressed, (compressor.id() & FLAG_COMPRESSION_MASK) | flags, config)
+}
+
+// Compresses after splitting, one frame per fragment, so every frame can be
+// decoded on its own and the frames may arrive in any order. Frames are
+// rendered as they're taken, so only the one being sent is held in memory.
+pub fn encode_fragments_to_samples<'a>(
+    data: &'a [u8],
+    config: &'a Config,
+    fragment_size: usize,
+) -> Result<impl Iterator<Item = Result<Vec<f32>>> + 'a> {
+    let compressor: &dyn Compressor = if config.compress {
+        &Lz4Compressor
+    } else {
+        &NoCompression
+    };
+    let flags = (compressor.id() & FLAG_COMPRESSION_MASK) | FLAG_FRAGMENT;
+
+    Ok(compress_fragments(data, fragment_size, compressor)?
+        .map(move |fragment| encode_frame(&fragment, flags, config)))
+}
+
+fn encode_raw_frame(data: &[u8], flags: u8, config: &Config) -> Result<Vec<f32>> {
+    let packet = Packet::with_flags(data.to_vec(), FLAG_RAW | COMPRESSION_NONE | flags | config.checksum.flag())?;
+    log::debug!(target: LOG_TARGET, "Raw debug frame: {} bytes", data.len());
+
+    let modulator = MFSKModulator::new(config.clone());
+    modulator.modulate(&packet.serialize())
+}
+
+fn encode_frame(payload: &[u8], flags: u8, config: &Config) -> Result<Vec<f32>> {
+    let encoded = config.ecc_scheme.encode_with(payload, &config.reed_solomon_codec())?;
+    log::debug!(target: LOG_TARGET, "{:?} ECC encoded to {} bytes", config.ecc_scheme, encoded.len());
+    // Named here rather than left to `Packet`, whose limit is on the
+    // ECC-expanded bytes rather than on anything the caller passed in.
+    if encoded.len() > MAX_PAYLOAD_SIZE {
+        return Err(SonicPipeError::Encoding(format!(
+            "{} bytes after compression take {} with ECC, over the {}-byte frame limit; \
+             split the data or send it as fragments (up to {} bytes always fit)",
+            payload.len(),
+            encoded.len(),
+            MAX_PAYLOAD_SIZE,
+            config.max_payload_len()
+        )));
+    }
+
+    let geometry_flag = if config.protect_ecc_geometry { FLAG_PROTECTED_GEOMETRY } else { 0 };
+    let flags = flags | config.checksum.flag() | config.ecc_scheme.flag() | geometry_flag;
+    let packet = Packet::with_checksum_over(encoded, flags, payload)?;
+    let packet_data = packet.serialize();
+    log::debug!(target: LOG_TARGET, "Packet size: {} bytes", packet_data.len());
+
+    let modulator = MFSKModulator::new(config.clone());
+    modulator.modulate(&packet_data)
+}
+
+// The decoded data, along with its `reception_quality`, for deciding
+// whether to trust it or ask for it again.
+pub fn decode_from_samples(samples: &[f32], config: &Config) -> Result<(Vec<u8>, f32)> {
+    decode_from_samples_with(samples, config, &Lz4Compressor)
+}
+
+pub fn decode_from_samples_with(
+    samples: &[f32],
+    config: &Config,
+    compressor: &dyn Compressor,
+) -> Result<(Vec<u8>, f32)> {
+    decode_verbose_using(samples, config, compressor).map(|(data, _, stats)| (data, stats.quality))
+}
+
+pub fn decode_with_metadata(samples: &[f32], config: &Config) -> Result<(Vec<u8>, Option<Metadata>)> {
+    decode_verbose_using(samples, config, &Lz4Compressor).map(|(data, metadata, _)| (data, metadata))
+}
+
+// Like `decode_with_metadata`, but also reports sizes, timing and quality.
+pub fn decode_verbose(samples: &[f32], config: &Config) -> Result<(Vec<u8>, Option<Metadata>, DecodeStats)> {
+    decode_verbose_using(samples, config, &Lz4Compressor)
+}
+
+fn decode_verbose_using(
+    samples: &[f32],
+    config: &Config,
+    compressor: &dyn Compressor,
+) -> Result<(Vec<u8>, Option<Metadata>, DecodeStats)> {
+    decode_reporting_cause(samples, config, |demodulator| {
+        let frame = decode_frame(demodulator, samples)?;
+
+        let decompressed = decompress_frame(&frame, compressor)?;
+        log::debug!(target: LOG_TARGET, "Decompressed: {} bytes", decompressed.len());
+
+        let (data, metadata) = if frame.has_metadata {
+            let (metadata, data) = Metadata::deserialize(&decompressed)?;
+            (data.to_vec(), Some(metadata))
+        } else {
+            (decompressed, None)
+        };
+
+        let frame_samples = demodulator
+            .find_frames(samples)
+            .first()
+            .map_or(samples.len(), |&(start, end)| end - start);
+        let confidence = demodulator.symbol_confidence(samples).unwrap_or(0.0);
+        let quality = reception_quality(confidence, frame.ecc_load, frame.checksum_ok);
+        let stats = decode_stats(&data, &frame, frame_samples, quality, config);
+
+        Ok((data, metadata, stats))
+    })
+}
+
+// A transfer sent in one fragment is complete; any other fragment fails
+// here with the count it needs the rest of.
+fn decompress_frame(frame: &DecodedFrame, compressor: &dyn Compressor) -> Result<Vec<u8>> {
+    let compressor = resolve_compressor(frame.compression_id, compressor)?;
+    if frame.is_fragment {
+        reassemble_fragments(vec![decompress_fragment(&frame.payload, compressor)?])
+    } else {
+        compressor.decompress(&frame.payload)
+    }
+}
+
+fn decode_stats(
+    data: &[u8],
+    frame: &DecodedFrame,
+    samples: usize,
+    quality: f32,
+    config: &Config,
+) -> DecodeStats {
+    let seconds = samples as f32 / config.sample_rate as f32;
+
+    DecodeStats {
+        payload_bytes: data.len(),
+        compressed_bytes: frame.payload.len(),
+        ecc_bytes: frame.ecc_bytes,
+        samples,
+        duration_ms: (seconds * 1000.0).round() as u32,
+        effective_bps: if seconds > 0.0 { data.len() as f32 * 8.0 / seconds } else { 0.0 },
+        quality,
+    }
+}
+
+pub fn decode_fragments_from_samples(frames: &[Vec<f32>], config: &Config) -> Result<Vec<u8>> {
+    let fragments = frames
+        .iter()
+        .map(|samples| {
+            decode_reporting_cause(samples, config, |demodulator| {
+                decode_fragment(demodulator, samples)
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    reassemble_fragments(fragments)
+}
+
+fn decode_fragment(demodulator: &mut MFSKDemodulator, samples: &[f32]) -> Result<Fragment> {
+    let frame = decode_frame(demodulator, samples)?;
+    if !frame.is_fragment {
+        return Err(SonicPipeError::Decoding(
+            "Frame is a complete message, not a fragment".into(),
+        ));
+    }
+    decompress_fragment(
+        &frame.payload,
+        resolve_compressor(frame.compression_id, &Lz4Compressor)?,
+    )
+}
+
+// Decodes whatever fragments arrived intact and lists the indices still
+// missing, so only those need to be sent again. The total comes from the
+// fragments themselves, so if none decode nothing can be reported missing.
+pub fn decode_partial(frames: &[Vec<f32>], config: &Config) -> (Vec<(usize, Vec<u8>)>, Vec<usize>) {
+    let mut received: Vec<Fragment> = Vec::new();
+    for (i, samples) in frames.iter().enumerate() {
+        let fragment = decode_reporting_cause(samples, config, |demodulator| {
+            decode_fragment(demodulator, samples)
+        });
+
+        match fragment {
+            Ok(fragment) if !received.iter().any(|f| f.index == fragment.index) => received.push(fragment),
+            Ok(_) => {}
+            Err(e) => log::debug!(target: LOG_TARGET, "Frame {} did not decode: {}", i, e),
+        }
+    }
+
+    // Fragments that disagree on the total can't be from the same transfer;
+    // the first one decoded wins.
+    let count = received.first().map_or(0, |f| f.count);
+    received.retain(|f| f.count == count);
+    received.sort_by_key(|f| f.index);
+
+    let missing = (0..count)
+        .filter(|&i| !received.iter().any(|f| f.index == i))
+        .map(usize::from)
+        .collect();
+    let fragments = received.into_iter().map(|f| (f.index as usize, f.data)).collect();
+
+    (fragments, missing)
+}
+
+fn decode_reporting_cause<T>(
+    samples: &[f32],
+    config: &Config,
+    decode: impl FnOnce(&mut MFSKDemodulator) -> Result<T>,
+) -> Result<T> {
+    let mut demodulator = MFSKDemodulator::new(config.clone());
+
+    // Overdriven input often still decodes, so clipping is only reported
+    // as the cause once the decode has actually failed.
+    let clipped = demodulator.clipped_fraction(samples);
+    if clipped > CLIP_FRACTION_THRESHOLD {
+        log::warn!(target: LOG_TARGET, "Input is clipping: {:.1}% of samples at full scale", clipped * 100.0);
+    }
+
+    match decode(&mut demodulator) {
+        // The version byte came through, so the signal itself was fine,
+        // unless it was read at the wrong sample rate or symbol duration.
+        Err(e @ SonicPipeError::UnsupportedVersion(_))
+            if demodulator.sample_rate_mismatch(samples).is_none()
+                && demodulator.symbol_duration_mismatch(samples).is_none() =>
+        {
+            Err(e)
+        }
+        Err(e) if clipped > CLIP_FRACTION_THRESHOLD => {
+            log::debug!(target: LOG_TARGET, "Decode of clipped input failed: {}", e);
+            Err(SonicPipeError::InputClipped(clipped))
+        }
+        // A device running at the wrong rate shifts every tone, and a sender
+        // at another symbol duration puts every window out of step, so
+        // nothing decodes; say so rather than report a generic failure. A
+        // second sender talking over the first spoils the symbol duration
+        // estimate too, so it's checked for before that.
+        Err(e) => {
+            if let Some(estimated) = demodulator.sample_rate_mismatch(samples) {
+                log::debug!(target: LOG_TARGET, "Decode failed: {}", e);
+                Err(SonicPipeError::SampleRateMismatch {
+                    expected: config.sample_rate,
+                    estimated,
+                })
+            } else if let Some(share) = collision(&demodulator, samples, config) {
+                log::debug!(target: LOG_TARGET, "Decode failed: {}", e);
+                Err(SonicPipeError::Collision { share })
+            } else if let Some(estimated) = demodulator.symbol_duration_mismatch(samples) {
+                log::debug!(target: LOG_TARGET, "Decode failed: {}", e);
+                Err(SonicPipeError::SymbolDurationMismatch {
+                    expected: config.symbol_duration_ms,
+                    estimated,
+                })
+            } else {
+                Err(signal_failure(e, &demodulator, samples))
+            }
+        }
+        result => result,
+    }
+}
+
+// Share of contested symbol windows when they point to a second sender.
+// Reading a sender at the wrong symbol duration contests windows too, as
+// each one straddles two symbols, but those clear up when read at the
+// duration estimated from the signal; a second sender's tones don't.
+fn collision(demodulator: &MFSKDemodulator, samples: &[f32], config: &Config) -> Option<f32> {
+    let share = demodulator.collision_share(samples).filter(|&share| share >= COLLISION_MIN_SHARE)?;
+    match demodulator.symbol_duration_mismatch(samples) {
+        Some(estimated) => {
+            let retimed = Config {
+                symbol_duration_ms: estimated.round().max(1.0) as u32,
+                ..config.clone()
+            };
+            MFSKDemodulator::new(retimed).collision_detected(samples).then_some(share)
+        }
+        None => Some(share),
+    }
+}
+
+// Sorts a frame that didn't demodulate or check out by what was heard:
+// nothing, a frame too faint to read, or one that came through damaged.
+// Those call for different fixes, so a bare checksum error isn't enough.
+fn signal_failure(error: SonicPipeError, demodulator: &MFSKDemodulator, samples: &[f32]) -> SonicPipeError {
+    match error {
+        SonicPipeError::Decoding(_)
+        | SonicPipeError::ChecksumMismatch
+        | SonicPipeError::ErrorCorrection(_)
+        | SonicPipeError::InvalidPacket(_) => {}
+        other => return other,
+    }
+    log::debug!(target: LOG_TARGET, "Decode failed: {}", error);
+
+    if demodulator.detect_wake_up(samples).is_none() && demodulator.detect_onset(samples).is_none() {
+        return SonicPipeError::NoSignal;
+    }
+    match demodulator.estimate_snr(samples) {
+        Some(snr_db) if snr_db < MIN_DECODE_SNR_DB => SonicPipeError::SignalTooWeak { snr_db },
+        _ => SonicPipeError::FrameCorrupt(error.to_string()),
+    }
+}
+
+// Demodulates one frame down to its ECC-decoded payload and compressor id.
+fn decode_frame(demodulator: &mut MFSKDemodulator, samples: &[f32]) -> Result<DecodedFrame> {
+    let raw_data = demodulate_packet(demodulator, samples)?;
+
+    let packet = Packet::deserialize_unchecked(&raw_data)?;
+    log::debug!(target: LOG_TARGET, "Packet payload: {} bytes", packet.payload.len());
+
+    let frame = decode_packet(packet)?;
+    if !frame.checksum_ok {
+        return Err(SonicPipeError::ChecksumMismatch);
+    }
+    Ok(frame)
+}
+
+fn demodulate_packet(demodulator: &mut MFSKDemodulator, samples: &[f32]) -> Result<Vec<u8>> {
+    let raw_data = demodulator
+        .demodulate(samples)
+        .ok_or_else(|| SonicPipeError::Decoding("Failed to demodulate signal".into()))?;
+    log::debug!(target: LOG_TARGET, "Demodulated {} bytes", raw_data.len());
+
+    Ok(raw_data)
+}
+
+// ECC-decodes the payload and checks the checksum against what came out,
+// which for an ECC frame covers the decoded bytes; see
+// `Packet::with_checksum_over`. A raw frame's covers the payload as sent.
+fn decode_packet(packet: Packet) -> Result<DecodedFrame> {
+    let has_metadata = packet.flags & FLAG_METADATA != 0;
+    let is_fragment = packet.flags & FLAG_FRAGMENT != 0;
+    if packet.is_raw() {
+        return Ok(DecodedFrame {
+            compression_id: COMPRESSION_NONE,
+            has_metadata,
+            is_fragment,
+            checksum_ok: packet.checksum_matches(),
+            payload: packet.payload,
+            ecc_bytes: 0,
+            ecc_load: 0.0,
+        });
+    }
+
+    let scheme = EccScheme::from_flags(packet.flags);
+    let codec = ReedSolomonCodec::new()?.with_protected_geometry(packet.flags & FLAG_PROTECTED_GEOMETRY != 0);
+    let (decoded, ecc_load) = scheme.decode_with_load_using(&packet.payload, &codec)?;
+    log::debug!(target: LOG_TARGET, "ECC decoded: {} bytes, load {:.2}", decoded.len(), ecc_load);
+
+    Ok(DecodedFrame {
+        compression_id: packet.compression_id(),
+        has_metadata,
+        is_fragment,
+        checksum_ok: packet.checksum_covers(&decoded),
+        ecc_bytes: packet.payload.len() - decoded.len(),
+        payload: decoded,
+        ecc_load,
+    })
+}
+
+// Runs each receive stage in turn for as long as it can and returns what the
+// furthest one produced, along with every error met on the way: the message
+// if all went well, else the decompressed, ECC-decoded or raw packet bytes.
+// A failed checksum is noted but not fatal, so damaged bytes can still be
+// looked at. Meant for salvaging a damaged transmission, not everyday use.
+pub fn decode_best_effort(samples: &[f32], config: &Config) -> (Option<Vec<u8>>, Vec<SonicPipeError>) {
+    let mut errors = Vec::new();
+
+    let demodulated = decode_reporting_cause(samples, config, |demodulator| demodulate_packet(demodulator, samples));
+    let raw_data = match demodulated {
+        Ok(raw_data) => raw_data,
+        Err(e) => {
+            errors.push(e);
+            return (None, errors);
+        }
+    };
+
+    let packet = match Packet::deserialize_unchecked(&raw_data) {
+        Ok(packet) => packet,
+        Err(e) => {
+            errors.push(e);
+            return (Some(raw_data), errors);
+        }
+    };
+
+    let payload = packet.payload.clone();
+    let frame = match decode_packet(packet) {
+        Ok(frame) => frame,
+        Err(e) => {
+            errors.push(e);
+            return (Some(payload), errors);
+        }
+    };
+    if !frame.checksum_ok {
+        errors.push(SonicPipeError::ChecksumMismatch);
+    }
+
+    let decompressed = match decompress_frame(&frame, &Lz4Compressor) {
+        Ok(decompressed) => decompressed,
+        Err(e) => {
+            errors.push(e);
+            return (Some(frame.payload), errors);
+        }
+    };
+
+    if !frame.has_metadata {
+        return (Some(decompressed), errors);
+    }
+    match Metadata::deserialize(&decompressed) {
+        Ok((_, data)) => (Some(data.to_vec()), errors),
+        Err(e) => {
+            errors.push(e);
+            (Some(decompressed), errors)
+        }
+    }
+}
+
+pub fn selftest(message: &[u8], config: &Config) -> Result<bool> {
+    let samples = encode_to_samples(message, config)?;
+
+    // A frame that encodes but cannot be decoded is exactly the kind of
+    // inconsistency this check exists to report, so it is not an error.
+    match decode_from_samples(&samples, config) {
+        Ok((decoded, _)) => Ok(decoded == message),
+        Err(e) => {
+            log::debug!(target: LOG_TARGET, "Self-test decode failed: {}", e);
+            Ok(false)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::{COMPRESSION_LZ4, ECC_GEOMETRY_LEN};
+    use crate::modulation::peak_amplitude;
+    use crate::protocol::HEADER_SIZE;
+    use crate::testing::add_awgn;
+    use crate::{ChecksumKind, TransmissionMode, THROUGHPUT_REPORT_PAYLOAD_BYTES, WAKE_UP_DURATION_MS};
+
+    #[test]
+    fn test_selftest_matrix() {
+        let configs = [
+            Config::default(),
+            Config {
+                symbol_duration_ms: 30,
+                ..Default::default()
+            },
+            Config {
+                mode: TransmissionMode::Ultrasonic,
+                ..Default::default()
+            },
+            Config {
+                mode: TransmissionMode::Ultrasonic,
+                ultrasonic_quiet: true,
+                volume: 0.9,
+                ..Default::default()
+            },
+        ];
+        let payloads: [&[u8]; 3] = [
+            b"Hello, Sonic-Pipe!",
+            &[0x00, 0xFF, 0x80, 0x7F, 0x01],
+            &[0xA5; 64],
+        ];
+
+        for config in &configs {
+            for payload in payloads {
+                assert!(selftest(payload, config).unwrap(), "{:?} {:?}", config, payload);
+            }
+        }
+    }
+
+    #[test]
+    fn test_estimate_duration_matches_encoded_length() {
+        let mut state = 0x1234_5678u32;
+        let payload: Vec<u8> = (0..200)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect();
+
+        for config in [
+            Config::default(),
+            Config {
+                symbol_duration_ms: 20,
+                ..Default::default()
+            },
+            Config {
+                ecc_alignment: 64,
+                ..Default::default()
+            },
+        ] {
+            let samples = encode_to_samples(&payload, &config).unwrap();
+            let actual_ms = samples.len() as f32 * 1000.0 / config.sample_rate as f32;
+            let estimate_ms = config.estimate_duration_ms(payload.len()) as f32;
+            assert!(
+                (estimate_ms - actual_ms).abs() < actual_ms * 0.05,
+                "estimate {} vs actual {}",
+                estimate_ms,
+                actual_ms
+            );
+        }
+    }
+
+    #[test]
+    fn test_throughput_report_matches_transmission() {
+        let config = Config {
+            compress: false,
+            ..Default::default()
+        };
+        let report = config.throughput_report();
+        assert_eq!((report.symbol_rate, report.bits_per_symbol, report.gross_bps), (20.0, 4, 80.0));
+
+        let payload: Vec<u8> = (0..THROUGHPUT_REPORT_PAYLOAD_BYTES).map(|i| (i * 37) as u8).collect();
+        let samples = encode_to_samples(&payload, &config).unwrap();
+        let seconds = samples.len() as f32 / config.sample_rate as f32;
+        assert!((report.frame_ms - seconds * 1000.0).abs() < 1.0, "{:?}", report);
+        assert!((report.net_bps - payload.len() as f32 * 8.0 / seconds).abs() < 0.1, "{:?}", report);
+
+        // 100 bytes go out as eight 13-byte data shards and four parity
+        // shards behind an 8-byte header.
+        let ecc_bytes = EccScheme::ReedSolomon.encoded_len(payload.len(), 1) - payload.len();
+        assert_eq!(ecc_bytes, 64);
+        let ecc_seconds = ecc_bytes as f32 * 8.0 / 80.0;
+        assert!((report.ecc_overhead_percent - ecc_seconds / seconds * 100.0).abs() < 0.01);
+        assert_eq!(report.compression_overhead_percent, 0.0);
+        assert!(report.framing_overhead_percent > 0.0);
+        assert!((report.payload_percent() - report.net_bps / report.gross_bps * 100.0).abs() < 0.01);
+
+        // Framing weighs less on a bigger payload; ECC stays about the same.
+        let large = config.throughput_report_for(1000);
+        assert!(large.framing_overhead_percent < report.framing_overhead_percent / 3.0);
+        assert!(large.net_bps > report.net_bps);
+        let compressed = Config::default().throughput_report();
+        assert!(compressed.compression_overhead_percent > 0.0 && compressed.net_bps <= report.net_bps);
+    }
+
+    #[test]
+    fn test_send_pipeline_limits_input_to_frame() {
+        let fast = Config {
+            symbol_duration_ms: 10,
+            ..Default::default()
+        };
+        let noise = |len: usize| -> Vec<u8> {
+            let mut state = len as u64;
+            (0..len)
+                .map(|_| {
+                    state = crate::modulation::splitmix64(state);
+                    state as u8
+                })
+                .collect()
+        };
+
+        for config in [
+            fast.clone(),
+            Config { compress: false, ..fast.clone() },
+            Config { ecc_scheme: EccScheme::Repetition, ..fast.clone() },
+            Config { raw_frame: true, ..fast.clone() },
+        ] {
+            // The most that's guaranteed to fit does, and its packet is in bounds.
+            let max = config.max_payload_len();
+            let data = noise(max);
+            let samples = encode_to_samples(&data, &config).unwrap();
+            let raw = MFSKDemodulator::new(config.clone()).demodulate(&samples).unwrap();
+            assert!(Packet::deserialize_unchecked(&raw).unwrap().payload.len() <= MAX_PAYLOAD_SIZE);
+            assert_eq!(decode_from_samples(&samples, &config).unwrap().0, data);
+
+            // Incompressible input a few bytes past it doesn't, and the
+            // error says so rather than blaming the packet.
+            // Raw frames have no ECC, so their packet is the input.
+            let result = encode_to_samples(&noise(max + 16), &config);
+            if config.raw_frame {
+                assert!(matches!(result, Err(SonicPipeError::InvalidPacket(_))), "{:?}", result);
+            } else {
+                assert!(matches!(result, Err(SonicPipeError::Encoding(_))), "{:?}", result);
+            }
+        }
+
+        assert_eq!(Config { raw_frame: true, ..fast.clone() }.max_payload_len(), MAX_PAYLOAD_SIZE);
+        let repeated = Config {
+            compress: false,
+            ecc_scheme: EccScheme::Repetition,
+            ..fast.clone()
+        };
+        assert_eq!(repeated.max_payload_len(), MAX_PAYLOAD_SIZE / 3);
+
+        // Compressible input can go well past the limit and still fit.
+        let zeros = vec![0u8; 4 * MAX_PAYLOAD_SIZE];
+        assert_eq!(
+            decode_from_samples(&encode_to_samples(&zeros, &fast).unwrap(), &fast)
+                .unwrap()
+                .0,
+            zeros
+        );
+    }
+
+    struct XorCompressor;
+
+    impl Compressor for XorCompressor {
+        fn compress(&self, data: &[u8]) -> Vec<u8> {
+            data.iter().map(|b| b ^ 0x5A).collect()
+        }
+
+        fn decompress(&self, data: &[u8]) -> Result<Vec<u8>> {
+            Ok(data.iter().map(|b| b ^ 0x5A).collect())
+        }
+    }
+
+    #[test]
+    fn test_pluggable_compressors() {
+        let config = Config::default();
+        let message = b"pluggable compression";
+
+        let samples = encode_to_samples_with(message, &config, &XorCompressor).unwrap();
+        assert_eq!(
+            decode_from_samples_with(&samples, &config, &XorCompressor)
+                .unwrap()
+                .0,
+            message
+        );
+        assert!(decode_from_samples(&samples, &config).is_err());
+
+        let samples = encode_to_samples_with(message, &config, &NoCompression).unwrap();
+        assert_eq!(decode_from_samples(&samples, &config).unwrap().0, message);
+        assert_eq!(
+            decode_from_samples_with(&samples, &config, &XorCompressor)
+                .unwrap()
+                .0,
+            message
+        );
+    }
+
+    #[test]
+    fn test_decode_with_mismatched_config_fails() {
+        let samples = encode_to_samples(b"mismatch", &Config::default()).unwrap();
+        let ultrasonic = Config {
+            mode: TransmissionMode::Ultrasonic,
+            ..Default::default()
+        };
+        assert!(decode_from_samples(&samples, &ultrasonic).is_err());
+    }
+
+    #[test]
+    fn test_clipped_input_failure_is_reported() {
+        let config = Config::default();
+        let samples = encode_to_samples(b"overdriven microphone input", &config).unwrap();
+        let clipped: Vec<f32> = samples[..samples.len() / 2]
+            .iter()
+            .map(|s| (s * 20.0).clamp(-1.0, 1.0))
+            .collect();
+
+        match decode_from_samples(&clipped, &config) {
+            Err(SonicPipeError::InputClipped(fraction)) => assert!(fraction > CLIP_FRACTION_THRESHOLD),
+            other => panic!("expected InputClipped, got {:?}", other),
+        }
+
+        // The same failure on a clean signal is an ordinary decode error.
+        assert!(!matches!(
+            decode_from_samples(&samples[..samples.len() / 2], &config),
+            Err(SonicPipeError::InputClipped(_))
+        ));
+    }
+
+    #[test]
+    fn test_roundtrip_without_compression() {
+        let config = Config {
+            compress: false,
+            ..Default::default()
+        };
+        let message = b"already compressed bytes";
+
+        let samples = encode_to_samples(message, &config).unwrap();
+        let raw = MFSKDemodulator::new(config.clone()).demodulate(&samples).unwrap();
+        assert_eq!(Packet::deserialize_unchecked(&raw).unwrap().compression_id(), COMPRESSION_NONE);
+
+        assert_eq!(decode_from_samples(&samples, &config).unwrap().0, message);
+        // Without compression the size of every stage is known up front.
+        let actual_ms = (samples.len() as u64 * 1000 / config.sample_rate as u64) as u32;
+        assert_eq!(config.estimate_duration_ms(message.len()), actual_ms);
+    }
+
+    #[test]
+    fn test_fragmented_roundtrip_out_of_order() {
+        let config = Config::default();
+        let data: Vec<u8> = b"fragmented transfer ".iter().cycle().take(200).copied().collect();
+
+        let mut frames = encode_fragments_to_samples(&data, &config, 80)
+            .unwrap()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(frames.len(), 3);
+
+        frames.swap(0, 2);
+        assert_eq!(decode_fragments_from_samples(&frames, &config).unwrap(), data);
+        assert!(decode_fragments_from_samples(&frames[1..], &config).is_err());
+
+        // A fragment alone isn't mistaken for the whole message, nor a whole
+        // message for a fragment.
+        assert!(decode_from_samples(&frames[0], &config).is_err());
+        let whole = encode_to_samples(&data, &config).unwrap();
+        assert!(decode_fragments_from_samples(&[whole], &config).is_err());
+
+        let single = encode_fragments_to_samples(&data, &config, data.len())
+            .unwrap()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(decode_from_samples(&single[0], &config).unwrap().0, data);
+    }
+
+    #[test]
+    fn test_decode_partial_reports_missing_fragment() {
+        let config = Config::default();
+        let data: Vec<u8> = (0..200u8).collect();
+
+        let mut frames = encode_fragments_to_samples(&data, &config, 80)
+            .unwrap()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        let last = frames.len() - 1;
+        let cut = frames[last].len() / 2;
+        frames[last].truncate(cut);
+
+        let (fragments, missing) = decode_partial(&frames, &config);
+        assert_eq!(missing, vec![2]);
+        assert_eq!(fragments.len(), 2);
+        assert_eq!(fragments[0], (0, data[..80].to_vec()));
+        assert_eq!(fragments[1], (1, data[80..160].to_vec()));
+
+        let (fragments, missing) = decode_partial(&frames[2..], &config);
+        assert!(fragments.is_empty() && missing.is_empty());
+    }
+
+    #[test]
+    fn test_noisy_reception_scores_lower() {
+        let config = Config::default();
+        let message = b"how good was that".to_vec();
+        let mut samples = vec![0.0f32; 5000];
+        samples.extend(encode_to_samples(&message, &config).unwrap());
+        samples.extend(vec![0.0f32; 5000]);
+
+        let (data, clean) = decode_from_samples(&samples, &config).unwrap();
+        assert_eq!(data, message);
+        assert!(clean > 0.9, "clean quality {}", clean);
+
+        // Found by its onset, with the wake-up tone cut off, it scores the same.
+        let wake = MFSKModulator::new(config.clone())
+            .generate_wake_up_tone()
+            .len();
+        let mut trimmed = vec![0.0f32; 5000];
+        trimmed.extend_from_slice(&samples[5000 + wake..]);
+        let (data, onset) = decode_from_samples(&trimmed, &config).unwrap();
+        assert_eq!(data, message);
+        assert!(
+            (onset - clean).abs() < 0.05,
+            "onset quality {} vs {}",
+            onset,
+            clean
+        );
+
+        let mut noisy = samples.clone();
+        add_awgn(&mut noisy, -14.0, 4);
+        let (data, marginal) = decode_from_samples(&noisy, &config).unwrap();
+        assert_eq!(data, message);
+        assert!(marginal < clean - 0.15, "noisy quality {} vs clean {}", marginal, clean);
+
+        assert_eq!(reception_quality(1.0, 0.0, true), 1.0);
+        assert_eq!(reception_quality(1.0, 1.0, true), 1.0 - ECC_LOAD_WEIGHT);
+        assert_eq!(reception_quality(1.0, 0.0, false), 0.0);
+    }
+
+    #[test]
+    fn test_failures_say_what_was_heard() {
+        let config = Config::default();
+        let message = b"hello there".to_vec();
+        let padded = |frame: Vec<f32>| {
+            let mut samples = vec![0.0f32; 5000];
+            samples.extend(frame);
+            samples.extend(vec![0.0f32; 5000]);
+            samples
+        };
+
+        let silence = vec![0.0f32; 48000];
+        assert!(matches!(decode_from_samples(&silence, &config), Err(SonicPipeError::NoSignal)));
+        // White noise around zero, at about 0.1 RMS.
+        let mut noise = vec![0.1f32; 48000];
+        add_awgn(&mut noise, 0.0, 1);
+        noise.iter_mut().for_each(|s| *s -= 0.1);
+        assert!(matches!(decode_from_samples(&noise, &config), Err(SonicPipeError::NoSignal)));
+
+        // The wake-up tone still stands out of the noise, the data doesn't.
+        // Sent raw, as ECC would repair this much damage.
+        let raw = Config { raw_frame: true, ..config.clone() };
+        let mut faint = padded(encode_to_samples(&message, &raw).unwrap());
+        add_awgn(&mut faint, -21.0, 6);
+        match decode_from_samples(&faint, &config) {
+            Err(SonicPipeError::SignalTooWeak { snr_db }) => assert!(snr_db < MIN_DECODE_SNR_DB),
+            other => panic!("expected SignalTooWeak, got {:?}", other),
+        }
+
+        // A clean frame whose checksum doesn't match.
+        let mut packet = Packet::with_flags(message.clone(), FLAG_RAW).unwrap().serialize();
+        *packet.last_mut().unwrap() ^= 0xFF;
+        let corrupt = padded(MFSKModulator::new(config.clone()).modulate(&packet).unwrap());
+        assert!(matches!(decode_from_samples(&corrupt, &config), Err(SonicPipeError::FrameCorrupt(_))));
+
+        // Another sender's frame starting over this one at full strength.
+        let mut collided = padded(encode_to_samples(&message, &config).unwrap());
+        let other = encode_to_samples(b"talking over you", &config).unwrap();
+        collided.extend(vec![0.0f32; other.len()]);
+        for (sample, s) in collided[5000 + config.duration_samples(700)..].iter_mut().zip(&other) {
+            *sample += s;
+        }
+        match decode_from_samples(&collided, &config) {
+            Err(SonicPipeError::Collision { share }) => assert!(share >= COLLISION_MIN_SHARE),
+            other => panic!("expected Collision, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_unsupported_version_is_reported() {
+        let config = Config::default();
+        let mut packet = Packet::with_flags(b"v99 frame".to_vec(), FLAG_RAW).unwrap();
+        packet.version = 99;
+        let samples = MFSKModulator::new(config.clone()).modulate(&packet.serialize()).unwrap();
+
+        assert!(matches!(
+            decode_from_samples(&samples, &config),
+            Err(SonicPipeError::UnsupportedVersion(99))
+        ));
+    }
+
+    #[test]
+    fn test_raw_debug_frame_roundtrip() {
+        let config = Config {
+            raw_frame: true,
+            ..Default::default()
+        };
+        let message = b"bring-up test pattern \x00\xff";
+
+        let samples = encode_to_samples(message, &config).unwrap();
+        let raw = MFSKDemodulator::new(config.clone()).demodulate(&samples).unwrap();
+        let packet = Packet::deserialize(&raw).unwrap();
+        assert!(packet.is_raw());
+        assert_eq!(packet.payload, message);
+
+        // The receiver dispatches on the flag, whatever its own config says.
+        assert_eq!(
+            decode_from_samples(&samples, &Config::default()).unwrap().0,
+            message
+        );
+
+        let actual_ms = (samples.len() as u64 * 1000 / config.sample_rate as u64) as u32;
+        assert_eq!(config.estimate_duration_ms(message.len()), actual_ms);
+    }
+
+    #[test]
+    fn test_crc64_roundtrip() {
+        let config = Config {
+            checksum: ChecksumKind::Crc64,
+            compress: false,
+            ..Default::default()
+        };
+        let message = b"firmware image chunk";
+
+        let samples = encode_to_samples(message, &config).unwrap();
+        let raw = MFSKDemodulator::new(config.clone()).demodulate(&samples).unwrap();
+        assert_eq!(Packet::deserialize_unchecked(&raw).unwrap().checksum_kind(), ChecksumKind::Crc64);
+
+        assert_eq!(
+            decode_from_samples(&samples, &Config::default()).unwrap().0,
+            message
+        );
+
+        let actual_ms = (samples.len() as u64 * 1000 / config.sample_rate as u64) as u32;
+        assert_eq!(config.estimate_duration_ms(message.len()), actual_ms);
+    }
+
+    #[test]
+    fn test_repetition_ecc_roundtrip() {
+        let config = Config {
+            ecc_scheme: EccScheme::Repetition,
+            compress: false,
+            ..Default::default()
+        };
+        let message = b"on!";
+
+        let samples = encode_to_samples(message, &config).unwrap();
+        let raw = MFSKDemodulator::new(config.clone()).demodulate(&samples).unwrap();
+        let packet = Packet::deserialize_unchecked(&raw).unwrap();
+        assert_eq!(EccScheme::from_flags(packet.flags), EccScheme::Repetition);
+        assert_eq!(packet.payload.len(), 9);
+
+        // The receiver follows the flag, whatever its own config says.
+        assert_eq!(
+            decode_from_samples(&samples, &Config::default()).unwrap().0,
+            message
+        );
+
+        let actual_ms = (samples.len() as u64 * 1000 / config.sample_rate as u64) as u32;
+        assert_eq!(config.estimate_duration_ms(message.len()), actual_ms);
+        let reed_solomon = Config {
+            compress: false,
+            ..Default::default()
+        };
+        assert!(actual_ms < reed_solomon.estimate_duration_ms(message.len()));
+    }
+
+    #[test]
+    fn test_protected_geometry_roundtrip() {
+        let config = Config {
+            protect_ecc_geometry: true,
+            compress: false,
+            ..Default::default()
+        };
+        let message = b"geometry three times over";
+
+        let samples = encode_to_samples(message, &config).unwrap();
+        let raw = MFSKDemodulator::new(config.clone()).demodulate(&samples).unwrap();
+        let packet = Packet::deserialize_unchecked(&raw).unwrap();
+        assert_ne!(packet.flags & FLAG_PROTECTED_GEOMETRY, 0);
+
+        // The receiver follows the flag, whatever its own config says.
+        assert_eq!(
+            decode_from_samples(&samples, &Config::default()).unwrap().0,
+            message
+        );
+
+        let actual_ms = (samples.len() as u64 * 1000 / config.sample_rate as u64) as u32;
+        assert_eq!(config.estimate_duration_ms(message.len()), actual_ms);
+    }
+
+    #[test]
+    fn test_geometry_bit_error_is_outvoted_end_to_end() {
+        let message = b"geometry three times over";
+        // The frame as sent, with one bit of the second copy of the shard
+        // size flipped on the way.
+        let damaged = |protect: bool| {
+            let config = Config {
+                protect_ecc_geometry: protect,
+                compress: false,
+                ..Default::default()
+            };
+            let raw = MFSKDemodulator::new(config.clone())
+                .demodulate(&encode_to_samples(message, &config).unwrap())
+                .unwrap();
+            let offset = if protect { ECC_GEOMETRY_LEN } else { 0 };
+            let mut packet = Packet::deserialize_unchecked(&raw).unwrap();
+            packet.payload[offset + 6] ^= 0x10;
+            MFSKModulator::new(config).modulate(&packet.serialize()).unwrap()
+        };
+
+        assert_eq!(
+            decode_from_samples(&damaged(true), &Config::default())
+                .unwrap()
+                .0,
+            message
+        );
+        assert!(decode_from_samples(&damaged(false), &Config::default()).is_err());
+    }
+
+    #[test]
+    fn test_geometry_is_sent_at_header_rate() {
+        let message = b"geometry read at the slow rate";
+        // Overwrites the middle 6 ms of the first geometry symbol, the top
+        // of the payload length, with the highest tone. Nothing repairs the
+        // geometry, so the frame lives or dies by how long that symbol is.
+        let corrupt_geometry = |factor: u32| {
+            let config = Config {
+                symbol_duration_ms: 10,
+                wake_gap_ms: 0,
+                header_symbol_factor: factor,
+                compress: false,
+                ..Default::default()
+            };
+            let mut samples = encode_to_samples(message, &config).unwrap();
+            let wake = MFSKModulator::new(config.clone())
+                .generate_wake_up_tone()
+                .len();
+            let symbol = config.symbols_per_bytes(HEADER_SIZE);
+            let start = wake + config.symbol_offset(symbol);
+            let end = wake + config.symbol_offset(symbol + 1);
+            let burst = config.duration_samples(6);
+            let from = (start + end - burst) / 2;
+
+            let amplitude = peak_amplitude(&samples[start..end]);
+            let frequency = *config.frequencies().last().unwrap();
+            for (i, s) in samples[from..from + burst].iter_mut().enumerate() {
+                let t = i as f32 / config.sample_rate as f32;
+                *s = amplitude * (2.0 * std::f32::consts::PI * frequency * t).sin();
+            }
+            decode_from_samples(&samples, &config)
+        };
+
+        assert_eq!(corrupt_geometry(2).unwrap().0, message);
+        assert!(corrupt_geometry(1).is_err());
+    }
+
+    #[test]
+    fn test_metadata_roundtrip() {
+        let metadata = Metadata {
+            filename: Some("Grüße_日本語.txt".into()),
+            mime: Some("text/plain; charset=utf-8".into()),
+            original_len: Some(11),
+        };
+        let message = b"hello there";
+
+        for config in [Config::default(), Config { raw_frame: true, ..Default::default() }] {
+            let samples = encode_with_metadata(message, Some(&metadata), &config).unwrap();
+            let (data, received) = decode_with_metadata(&samples, &config).unwrap();
+            assert_eq!(data, message);
+            assert_eq!(received.as_ref(), Some(&metadata));
+
+            // Callers that don't ask for metadata still get just the data.
+            assert_eq!(decode_from_samples(&samples, &config).unwrap().0, message);
+        }
+
+        let plain = encode_to_samples(message, &Config::default()).unwrap();
+        assert_eq!(decode_with_metadata(&plain, &Config::default()).unwrap(), (message.to_vec(), None));
+    }
+
+    #[test]
+    fn test_decode_stats_effective_rate() {
+        let config = Config {
+            compress: false,
+            ..Default::default()
+        };
+        let message = [0x5Au8; 40];
+
+        let mut samples = vec![0.0f32; 24000];
+        let frame = encode_to_samples(&message, &config).unwrap();
+        samples.extend_from_slice(&frame);
+
+        let (data, _, stats) = decode_verbose(&samples, &config).unwrap();
+        assert_eq!(data, message);
+        assert_eq!(stats.payload_bytes, 40);
+        assert_eq!(stats.compressed_bytes, 40);
+        assert_eq!(stats.ecc_bytes, EccScheme::ReedSolomon.encoded_len(40, 1) - 40);
+
+        // The leading half second of silence isn't part of the frame; the
+        // span may start up to one wake window early.
+        let expected_bps = 40.0 * 8.0 * config.sample_rate as f32 / frame.len() as f32;
+        assert!((stats.effective_bps - expected_bps).abs() < expected_bps * 0.02, "{:?}", stats);
+        assert!(stats.duration_ms.abs_diff(frame.len() as u32 / 48) <= WAKE_UP_DURATION_MS / 2);
+    }
+
+    #[test]
+    fn test_sample_rate_mismatch_is_reported() {
+        let config = Config::default();
+        let samples = encode_to_samples(b"rate check", &config).unwrap();
+
+        let demodulator = MFSKDemodulator::new(config.clone());
+        let estimated = demodulator.estimate_sample_rate(&samples).unwrap();
+        assert!((estimated - 48000.0).abs() < 100.0, "{}", estimated);
+        assert!(demodulator.sample_rate_mismatch(&samples).is_none());
+
+        // Captured at 44.1 kHz but read as 48 kHz.
+        let slow = crate::audio::resample_to(&samples, 48000, 44100);
+        match decode_from_samples(&slow, &config) {
+            Err(SonicPipeError::SampleRateMismatch { expected, estimated }) => {
+                assert_eq!(expected, 48000);
+                assert!((estimated - 44100.0).abs() < 200.0, "{}", estimated);
+            }
+            other => panic!("expected a sample rate mismatch, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_symbol_duration_mismatch_is_reported() {
+        let sender = Config {
+            symbol_duration_ms: 30,
+            ..Config::default()
+        };
+        let samples = encode_to_samples(b"sent at 30 ms per symbol, read at 50", &sender).unwrap();
+
+        match decode_from_samples(&samples, &Config::default()) {
+            Err(SonicPipeError::SymbolDurationMismatch { expected, estimated }) => {
+                assert_eq!(expected, 50);
+                assert!((estimated - 30.0).abs() < 1.0, "{}", estimated);
+            }
+            other => panic!("expected a symbol duration mismatch, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_scrambled_frame_roundtrip() {
+        let config = Config::builder()
+            .scrambler_seed(Some(7))
+            .compress(false)
+            .build()
+            .unwrap();
+        let message = vec![0u8; 80];
+
+        let samples = encode_to_samples(&message, &config).unwrap();
+        assert_eq!(decode_from_samples(&samples, &config).unwrap().0, message);
+        assert!(decode_from_samples(
+            &samples,
+            &Config {
+                scrambler_seed: None,
+                ..config.clone()
+            }
+        )
+        .is_err());
+
+        let raw_config = Config { raw_frame: true, ..config.clone() };
+        let samples = encode_to_samples(&message, &raw_config).unwrap();
+        assert_eq!(
+            decode_from_samples(&samples, &raw_config).unwrap().0,
+            message
+        );
+    }
+
+    #[test]
+    fn test_inverted_and_phase_shifted_signal_decodes() {
+        let config = Config::default();
+        let message = b"polarity should not matter to MFSK".to_vec();
+        let samples = encode_to_samples(&message, &config).unwrap();
+
+        let inverted: Vec<f32> = samples.iter().map(|s| -s).collect();
+        assert_eq!(decode_from_samples(&inverted, &config).unwrap().0, message);
+
+        // A first-order all-pass keeps every tone's level but delays each
+        // by a different phase, as a crossover or interface filter might.
+        let a = 0.6f32;
+        let (mut x1, mut y1) = (0.0f32, 0.0f32);
+        let shifted: Vec<f32> = inverted
+            .iter()
+            .map(|&x| {
+                let y = -a * x + x1 + a * y1;
+                (x1, y1) = (x, y);
+                y
+            })
+            .collect();
+        assert_eq!(decode_from_samples(&shifted, &config).unwrap().0, message);
+    }
+
+    #[test]
+    fn test_best_effort_returns_ecc_decoded_bytes_when_decompression_fails() {
+        let config = Config::default();
+        let message = b"intact message";
+        let (data, errors) = decode_best_effort(&encode_to_samples(message, &config).unwrap(), &config);
+        assert_eq!(data.as_deref(), Some(&message[..]));
+        assert!(errors.is_empty(), "{:?}", errors);
+
+        // Flagged as LZ4, but the size prefix claims far more than follows.
+        let body = b"\xff\xff\xff\x7fnot lz4 at all";
+        let samples = encode_frame(body, COMPRESSION_LZ4, &config).unwrap();
+        assert!(decode_from_samples(&samples, &config).is_err());
+
+        let (data, errors) = decode_best_effort(&samples, &config);
+        assert_eq!(data.as_deref(), Some(&body[..]));
+        assert!(matches!(errors[..], [SonicPipeError::Compression(_)]), "{:?}", errors);
+
+        let (data, errors) = decode_best_effort(&vec![0.0; 48000], &config);
+        assert!(data.is_none());
+        assert!(matches!(errors[..], [SonicPipeError::NoSignal]), "{:?}", errors);
+    }
+}
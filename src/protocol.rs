@@ -1,10 +1,148 @@
 use crate::error::{Result, SonicPipeError};
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use serde::{Deserialize, Serialize};
 use std::io::Cursor;
 
 pub const PROTOCOL_VERSION: u8 = 1;
 pub const MAX_PAYLOAD_SIZE: usize = 1024;
 pub const HEADER_SIZE: usize = 4;
+pub const CHECKSUM_SIZE: usize = 4;
+
+pub const FLAG_COMPRESSION_MASK: u8 = 0x03;
+// Debug frame: the payload is the user's bytes as-is, with no compression
+// or ECC, so the acoustic layer can be checked in isolation.
+pub const FLAG_RAW: u8 = 0x04;
+// The trailer is a CRC64 rather than a CRC32.
+pub const FLAG_CRC64: u8 = 0x08;
+
+// The payload starts with a `Metadata` block describing what it carries.
+pub const FLAG_METADATA: u8 = 0x10;
+// The payload is protected by the repetition code rather than Reed-Solomon.
+pub const FLAG_ECC_REPETITION: u8 = 0x20;
+// The Reed-Solomon geometry header is sent three times over, for the
+// receiver to take a majority vote on.
+pub const FLAG_PROTECTED_GEOMETRY: u8 = 0x40;
+// The payload is one fragment of a larger transfer, led by its index and
+// the fragment count; see `compress_fragments`.
+pub const FLAG_FRAGMENT: u8 = 0x80;
+
+const METADATA_FILENAME: u8 = 1;
+const METADATA_MIME: u8 = 2;
+const METADATA_ORIGINAL_LEN: u8 = 3;
+
+const CRC64: crc::Crc<u64> = crc::Crc::<u64>::new(&crc::CRC_64_XZ);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum ChecksumKind {
+    #[default]
+    Crc32,
+    Crc64,
+}
+
+impl ChecksumKind {
+    pub fn from_flags(flags: u8) -> Self {
+        if flags & FLAG_CRC64 != 0 {
+            ChecksumKind::Crc64
+        } else {
+            ChecksumKind::Crc32
+        }
+    }
+
+    pub fn flag(&self) -> u8 {
+        match self {
+            ChecksumKind::Crc32 => 0,
+            ChecksumKind::Crc64 => FLAG_CRC64,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        match self {
+            ChecksumKind::Crc32 => CHECKSUM_SIZE,
+            ChecksumKind::Crc64 => 8,
+        }
+    }
+
+    pub fn compute(&self, data: &[u8]) -> u64 {
+        match self {
+            ChecksumKind::Crc32 => crc32fast::hash(data) as u64,
+            ChecksumKind::Crc64 => CRC64.checksum(data),
+        }
+    }
+}
+
+// Optional description of a payload, sent ahead of it as a u16 block length
+// followed by TLV entries (u8 tag, u16 length, value). Unknown tags are
+// skipped so newer senders can add fields.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Metadata {
+    pub filename: Option<String>,
+    pub mime: Option<String>,
+    pub original_len: Option<u64>,
+}
+
+impl Metadata {
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        let mut entries = Vec::new();
+        let mut push = |tag: u8, value: &[u8]| -> Result<()> {
+            let len = u16::try_from(value.len())
+                .map_err(|_| SonicPipeError::InvalidPacket(format!("Metadata field {} too long", tag)))?;
+            entries.push(tag);
+            entries.write_u16::<BigEndian>(len).unwrap();
+            entries.extend_from_slice(value);
+            Ok(())
+        };
+
+        if let Some(filename) = &self.filename {
+            push(METADATA_FILENAME, filename.as_bytes())?;
+        }
+        if let Some(mime) = &self.mime {
+            push(METADATA_MIME, mime.as_bytes())?;
+        }
+        if let Some(original_len) = self.original_len {
+            push(METADATA_ORIGINAL_LEN, &original_len.to_be_bytes())?;
+        }
+
+        let block_len = u16::try_from(entries.len())
+            .map_err(|_| SonicPipeError::InvalidPacket("Metadata too long".into()))?;
+        let mut data = Vec::with_capacity(2 + entries.len());
+        data.write_u16::<BigEndian>(block_len).unwrap();
+        data.extend_from_slice(&entries);
+        Ok(data)
+    }
+
+    // Parses the block at the start of `data`, returning it and the rest.
+    pub fn deserialize(data: &[u8]) -> Result<(Self, &[u8])> {
+        let truncated = || SonicPipeError::InvalidPacket("Truncated metadata".into());
+
+        let (block_len, rest) = data.split_at_checked(2).ok_or_else(truncated)?;
+        let block_len = u16::from_be_bytes([block_len[0], block_len[1]]) as usize;
+        let (mut entries, rest) = rest.split_at_checked(block_len).ok_or_else(truncated)?;
+
+        let text = |value: &[u8]| {
+            String::from_utf8(value.to_vec()).map_err(|e| SonicPipeError::InvalidPacket(e.to_string()))
+        };
+
+        let mut metadata = Metadata::default();
+        while !entries.is_empty() {
+            let (header, tail) = entries.split_at_checked(3).ok_or_else(truncated)?;
+            let len = u16::from_be_bytes([header[1], header[2]]) as usize;
+            let (value, tail) = tail.split_at_checked(len).ok_or_else(truncated)?;
+
+            match header[0] {
+                METADATA_FILENAME => metadata.filename = Some(text(value)?),
+                METADATA_MIME => metadata.mime = Some(text(value)?),
+                METADATA_ORIGINAL_LEN => {
+                    let bytes: [u8; 8] = value.try_into().map_err(|_| truncated())?;
+                    metadata.original_len = Some(u64::from_be_bytes(bytes));
+                }
+                _ => {}
+            }
+            entries = tail;
+        }
+
+        Ok((metadata, rest))
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct Packet {
@@ -12,11 +150,15 @@ pub struct Packet {
     pub payload_len: u16,
     pub flags: u8,
     pub payload: Vec<u8>,
-    pub checksum: u32,
+    pub checksum: u64,
 }
 
 impl Packet {
     pub fn new(payload: Vec<u8>) -> Result<Self> {
+        Self::with_flags(payload, 0)
+    }
+
+    pub fn with_flags(payload: Vec<u8>, flags: u8) -> Result<Self> {
         if payload.len() > MAX_PAYLOAD_SIZE {
             return Err(SonicPipeError::InvalidPacket(format!(
                 "Payload too large: {} > {}",
@@ -25,30 +167,69 @@ impl Packet {
             )));
         }
 
-        let checksum = crc32fast::hash(&payload);
+        let checksum = ChecksumKind::from_flags(flags).compute(&payload);
 
         Ok(Self {
             version: PROTOCOL_VERSION,
             payload_len: payload.len() as u16,
-            flags: 0,
+            flags,
             payload,
             checksum,
         })
     }
 
+    // A packet whose checksum covers `data` rather than the payload itself.
+    // An ECC-protected frame is checked on the bytes its payload decodes to,
+    // so a bit error ECC can repair doesn't fail the checksum first.
+    pub fn with_checksum_over(payload: Vec<u8>, flags: u8, data: &[u8]) -> Result<Self> {
+        let mut packet = Self::with_flags(payload, flags)?;
+        packet.checksum = packet.checksum_kind().compute(data);
+        Ok(packet)
+    }
+
+    pub fn compression_id(&self) -> u8 {
+        self.flags & FLAG_COMPRESSION_MASK
+    }
+
+    pub fn is_raw(&self) -> bool {
+        self.flags & FLAG_RAW != 0
+    }
+
+    pub fn checksum_kind(&self) -> ChecksumKind {
+        ChecksumKind::from_flags(self.flags)
+    }
+
+    pub fn serialized_len(payload_len: usize, checksum: ChecksumKind) -> usize {
+        HEADER_SIZE + payload_len + checksum.size()
+    }
+
     pub fn serialize(&self) -> Vec<u8> {
-        let mut data = Vec::with_capacity(HEADER_SIZE + self.payload.len() + 4);
+        let checksum_kind = self.checksum_kind();
+        let mut data = Vec::with_capacity(Self::serialized_len(self.payload.len(), checksum_kind));
 
         data.push(self.version);
         data.write_u16::<BigEndian>(self.payload_len).unwrap();
         data.push(self.flags);
         data.extend_from_slice(&self.payload);
-        data.write_u32::<BigEndian>(self.checksum).unwrap();
+        match checksum_kind {
+            ChecksumKind::Crc32 => data.write_u32::<BigEndian>(self.checksum as u32).unwrap(),
+            ChecksumKind::Crc64 => data.write_u64::<BigEndian>(self.checksum).unwrap(),
+        }
 
         data
     }
 
     pub fn deserialize(data: &[u8]) -> Result<Self> {
+        let packet = Self::deserialize_unchecked(data)?;
+        if !packet.checksum_matches() {
+            return Err(SonicPipeError::ChecksumMismatch);
+        }
+        Ok(packet)
+    }
+
+    // Parses the packet without checking its payload against the checksum,
+    // for when a damaged payload is still worth looking at.
+    pub fn deserialize_unchecked(data: &[u8]) -> Result<Self> {
         if data.len() < HEADER_SIZE + 4 {
             return Err(SonicPipeError::InvalidPacket("Data too short".into()));
         }
@@ -56,25 +237,37 @@ impl Packet {
         let mut cursor = Cursor::new(data);
 
         let version = cursor.read_u8().map_err(|e| SonicPipeError::Decoding(e.to_string()))?;
+        // Later versions may lay the rest out differently, so don't guess.
+        if version != PROTOCOL_VERSION {
+            return Err(SonicPipeError::UnsupportedVersion(version));
+        }
         let payload_len = cursor.read_u16::<BigEndian>().map_err(|e| SonicPipeError::Decoding(e.to_string()))?;
         let flags = cursor.read_u8().map_err(|e| SonicPipeError::Decoding(e.to_string()))?;
 
+        // Nothing this large is ever sent; a header claiming so is damaged.
+        if payload_len as usize > MAX_PAYLOAD_SIZE {
+            return Err(SonicPipeError::InvalidPacket(format!(
+                "Payload length {} > {}",
+                payload_len, MAX_PAYLOAD_SIZE
+            )));
+        }
+
+        let checksum_kind = ChecksumKind::from_flags(flags);
         let payload_start = HEADER_SIZE;
         let payload_end = payload_start + payload_len as usize;
 
-        if data.len() < payload_end + 4 {
+        if data.len() < payload_end + checksum_kind.size() {
             return Err(SonicPipeError::InvalidPacket("Incomplete packet".into()));
         }
 
         let payload = data[payload_start..payload_end].to_vec();
 
         let mut checksum_cursor = Cursor::new(&data[payload_end..]);
-        let checksum = checksum_cursor.read_u32::<BigEndian>().map_err(|e| SonicPipeError::Decoding(e.to_string()))?;
-
-        let computed_checksum = crc32fast::hash(&payload);
-        if checksum != computed_checksum {
-            return Err(SonicPipeError::ChecksumMismatch);
+        let checksum = match checksum_kind {
+            ChecksumKind::Crc32 => checksum_cursor.read_u32::<BigEndian>().map(u64::from),
+            ChecksumKind::Crc64 => checksum_cursor.read_u64::<BigEndian>(),
         }
+        .map_err(|e| SonicPipeError::Decoding(e.to_string()))?;
 
         Ok(Self {
             version,
@@ -84,6 +277,15 @@ impl Packet {
             checksum,
         })
     }
+
+    pub fn checksum_matches(&self) -> bool {
+        self.checksum_covers(&self.payload)
+    }
+
+    // Whether the checksum is the one of `data`; see `with_checksum_over`.
+    pub fn checksum_covers(&self, data: &[u8]) -> bool {
+        self.checksum_kind().compute(data) == self.checksum
+    }
 }
 
 #[cfg(test)]
@@ -100,4 +302,121 @@ mod tests {
         assert_eq!(deserialized.version, PROTOCOL_VERSION);
         assert_eq!(deserialized.payload, payload);
     }
+
+    #[test]
+    fn test_payload_size_boundary() {
+        for len in [MAX_PAYLOAD_SIZE - 1, MAX_PAYLOAD_SIZE] {
+            let payload: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let serialized = Packet::new(payload.clone()).unwrap().serialize();
+            assert_eq!(serialized.len(), Packet::serialized_len(len, ChecksumKind::Crc32));
+
+            let packet = Packet::deserialize(&serialized).unwrap();
+            assert_eq!((packet.payload_len as usize, packet.payload), (len, payload));
+        }
+        assert!(matches!(
+            Packet::new(vec![0; MAX_PAYLOAD_SIZE + 1]),
+            Err(SonicPipeError::InvalidPacket(_))
+        ));
+
+        // A header claiming more than the limit is turned away even when
+        // that many bytes follow it.
+        let mut oversized = Packet::new(vec![0; MAX_PAYLOAD_SIZE]).unwrap().serialize();
+        oversized[1..3].copy_from_slice(&(MAX_PAYLOAD_SIZE as u16 + 1).to_be_bytes());
+        oversized.extend([0; 4]);
+        assert!(matches!(Packet::deserialize(&oversized), Err(SonicPipeError::InvalidPacket(_))));
+    }
+
+    // Pins the wire format: every field is big-endian, and previously
+    // captured frames depend on this exact layout.
+    #[test]
+    fn test_packet_golden_bytes() {
+        let packet = Packet::new(b"ABC".to_vec()).unwrap();
+        assert_eq!(
+            packet.serialize(),
+            [
+                0x01, // version
+                0x00, 0x03, // payload length
+                0x00, // flags
+                0x41, 0x42, 0x43, // payload
+                0xA3, 0x83, 0x03, 0x48, // CRC32 (ISO-HDLC) of the payload
+            ]
+        );
+
+        let packet = Packet::with_flags(b"ABC".to_vec(), FLAG_CRC64).unwrap();
+        assert_eq!(
+            packet.serialize(),
+            [
+                0x01, 0x00, 0x03, 0x08, 0x41, 0x42, 0x43,
+                0xAF, 0xA1, 0x86, 0x55, 0xD8, 0x6C, 0xC8, 0xD8, // CRC64 (XZ) of the payload
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unknown_version_is_rejected() {
+        let mut data = Packet::new(b"from the future".to_vec()).unwrap().serialize();
+        data[0] = 99;
+        assert!(matches!(Packet::deserialize(&data), Err(SonicPipeError::UnsupportedVersion(99))));
+    }
+
+    #[test]
+    fn test_packet_flags_roundtrip() {
+        let packet = Packet::with_flags(b"flags".to_vec(), 0x02).unwrap();
+        let deserialized = Packet::deserialize(&packet.serialize()).unwrap();
+        assert_eq!(deserialized.flags, 0x02);
+        assert_eq!(deserialized.compression_id(), 0x02);
+    }
+
+    #[test]
+    fn test_crc64_catches_every_single_bit_flip() {
+        let payload: Vec<u8> = (0..64u8).map(|i| i.wrapping_mul(73)).collect();
+        let packet = Packet::with_flags(payload.clone(), FLAG_CRC64).unwrap();
+        let serialized = packet.serialize();
+        assert_eq!(serialized.len(), Packet::serialized_len(payload.len(), ChecksumKind::Crc64));
+
+        let deserialized = Packet::deserialize(&serialized).unwrap();
+        assert_eq!(deserialized.checksum_kind(), ChecksumKind::Crc64);
+        assert_eq!(deserialized.payload, payload);
+
+        for bit in 0..payload.len() * 8 {
+            let mut corrupted = serialized.clone();
+            corrupted[HEADER_SIZE + bit / 8] ^= 1 << (bit % 8);
+            assert!(
+                matches!(Packet::deserialize(&corrupted), Err(SonicPipeError::ChecksumMismatch)),
+                "bit {} flip went unnoticed",
+                bit
+            );
+        }
+    }
+
+    #[test]
+    fn test_metadata_roundtrip() {
+        let metadata = Metadata {
+            filename: Some("résumé 履歴書.pdf".into()),
+            mime: Some("application/pdf".into()),
+            original_len: Some(123_456),
+        };
+
+        let mut data = metadata.serialize().unwrap();
+        data.extend_from_slice(b"body");
+        let (parsed, rest) = Metadata::deserialize(&data).unwrap();
+        assert_eq!(parsed, metadata);
+        assert_eq!(rest, b"body");
+
+        let empty_block = Metadata::default().serialize().unwrap();
+        let (empty, rest) = Metadata::deserialize(&empty_block).unwrap();
+        assert_eq!(empty, Metadata::default());
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn test_metadata_skips_unknown_tags_and_rejects_truncation() {
+        // An unknown tag 9 followed by a filename.
+        let data = [0x00, 0x09, 0x09, 0x00, 0x01, 0xAA, 0x01, 0x00, 0x02, b'h', b'i'];
+        let (parsed, _) = Metadata::deserialize(&data).unwrap();
+        assert_eq!(parsed.filename.as_deref(), Some("hi"));
+
+        assert!(Metadata::deserialize(&data[..data.len() - 1]).is_err());
+        assert!(Metadata::deserialize(&[0x00]).is_err());
+    }
 }
@@ -0,0 +1,218 @@
+use crate::error::{Result, SonicPipeError};
+use crate::modulation::{MFSKDemodulator, WakeUpTracker};
+use crate::pipeline::decode_from_samples;
+use crate::{Config, LOG_TARGET, WAKE_REPEAT_GAP_MS, WAKE_UP_DURATION_MS};
+use std::collections::VecDeque;
+
+// Listens to an endless stream of audio in bounded memory, for a receiver
+// left running for hours. Only the last `buffer_ms` of audio is kept, so
+// the buffer has to span the longest frame expected: a frame still arriving
+// when its wake-up tone falls out of the buffer is given up on and reported
+// as an error.
+pub struct RingReceiver {
+    config: Config,
+    demodulator: MFSKDemodulator,
+    tracker: WakeUpTracker,
+    buffer: VecDeque<f32>,
+    buffer_ms: u32,
+    capacity: usize,
+    // Stream positions of `buffer[0]`, of the tracker's first sample since
+    // its last reset, and of the end of everything pushed so far.
+    buffer_start: u64,
+    tracker_origin: u64,
+    received: u64,
+}
+
+impl RingReceiver {
+    pub fn new(config: Config, buffer_ms: u32) -> Self {
+        let capacity = config.duration_samples(buffer_ms);
+        Self {
+            demodulator: MFSKDemodulator::new(config.clone()),
+            tracker: WakeUpTracker::new(config.clone()),
+            config,
+            buffer: VecDeque::with_capacity(capacity),
+            buffer_ms,
+            capacity,
+            buffer_start: 0,
+            tracker_origin: 0,
+            received: 0,
+        }
+    }
+
+    // Adds the next stretch of audio and returns the outcome of every frame
+    // it completed, in order: the decoded data, or why it didn't decode.
+    pub fn push(&mut self, samples: &[f32]) -> Vec<Result<Vec<u8>>> {
+        let mut frames = Vec::new();
+
+        self.buffer.extend(samples);
+        self.received += samples.len() as u64;
+        self.tracker.feed(samples);
+
+        self.take_frames(&mut frames);
+        self.trim(&mut frames);
+        frames
+    }
+
+    pub fn buffered_samples(&self) -> usize {
+        self.buffer.len()
+    }
+
+    // Samples from the first wake window of a group to where `WakeUpTracker`
+    // puts its end, with a tone's worth to spare.
+    fn lead_samples(&self) -> usize {
+        let wake_samples = self.config.duration_samples(WAKE_UP_DURATION_MS);
+        let repeat_gap = self.config.duration_samples(WAKE_REPEAT_GAP_MS);
+        let repeats = self.config.wake_up_repeats.max(1) as usize;
+        repeats * (wake_samples + repeat_gap) + wake_samples + self.config.sync_word_samples()
+    }
+
+    fn take_frames(&mut self, frames: &mut Vec<Result<Vec<u8>>>) {
+        while let Some(wake_end) = self.tracker.wake_end() {
+            // A buffer shorter than the tracker takes to confirm a wake-up
+            // tone has already let the tone's end go by the time it's found.
+            let Some(wake_end) =
+                (self.tracker_origin + wake_end as u64).checked_sub(self.buffer_start)
+            else {
+                self.give_up_frame(frames);
+                return;
+            };
+            let wake_end = wake_end as usize;
+            let lead_samples = self.lead_samples();
+            let buffer = self.buffer.make_contiguous();
+            if !self.demodulator.detect_end_of_frame_from(buffer, wake_end) {
+                return;
+            }
+
+            let from = wake_end.saturating_sub(lead_samples);
+            let (start, end) = self
+                .demodulator
+                .find_frames(&buffer[from..])
+                .first()
+                .map_or((from, buffer.len()), |&(start, end)| (from + start, from + end));
+            log::debug!(
+                target: LOG_TARGET,
+                "Frame at stream samples {} to {}",
+                self.buffer_start + start as u64,
+                self.buffer_start + end as u64
+            );
+            frames
+                .push(decode_from_samples(&buffer[start..end], &self.config).map(|(data, _)| data));
+
+            // Whatever followed the frame is scanned afresh for the next
+            // wake-up tone; a trailing tone still sounding isn't mistaken for
+            // one, since the reset tracker waits for it to stop first.
+            self.buffer.drain(..end);
+            self.buffer_start += end as u64;
+            self.tracker.reset();
+            self.tracker_origin = self.buffer_start;
+            let rest: Vec<f32> = self.buffer.iter().copied().collect();
+            self.tracker.feed(&rest);
+        }
+    }
+
+    fn trim(&mut self, frames: &mut Vec<Result<Vec<u8>>>) {
+        let excess = self.buffer.len().saturating_sub(self.capacity);
+        if excess == 0 {
+            return;
+        }
+
+        let new_start = self.buffer_start + excess as u64;
+        if let Some(wake_end) = self.tracker.wake_end() {
+            let frame_start = (self.tracker_origin + wake_end as u64).saturating_sub(self.lead_samples() as u64);
+            if frame_start < new_start {
+                self.give_up_frame(frames);
+            }
+        }
+
+        self.buffer.drain(..excess);
+        self.buffer_start = new_start;
+    }
+
+    // Reports the frame under way as lost to the buffer's length and starts
+    // listening for the next one from the end of the stream.
+    fn give_up_frame(&mut self, frames: &mut Vec<Result<Vec<u8>>>) {
+        log::warn!(
+            target: LOG_TARGET,
+            "Frame outgrew the {} ms receive buffer",
+            self.buffer_ms
+        );
+        frames.push(Err(SonicPipeError::Decoding(format!(
+            "frame ran past the {} ms receive buffer",
+            self.buffer_ms
+        ))));
+        self.tracker.reset();
+        self.tracker_origin = self.received;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::encode_to_samples;
+    use crate::testing::add_awgn;
+
+    #[test]
+    fn test_ring_receiver_decodes_sparse_stream_in_bounded_memory() {
+        let config = Config::default();
+        let buffer_ms = 8000;
+        let mut receiver = RingReceiver::new(config.clone(), buffer_ms);
+
+        let messages: Vec<Vec<u8>> = (0..4)
+            .map(|i| format!("ping {}", i).into_bytes())
+            .collect();
+        let mut stream = Vec::new();
+        for (i, message) in messages.iter().enumerate() {
+            // Frames land several buffer lengths apart, at odd offsets.
+            stream.extend(vec![0.0f32; config.duration_samples(20_000 + 700 * i as u32)]);
+            stream.extend(encode_to_samples(message, &config).unwrap());
+        }
+        stream.extend(vec![0.0f32; config.duration_samples(2000)]);
+        add_awgn(&mut stream, 30.0, 9);
+
+        let chunk = 1024;
+        let mut decoded = Vec::new();
+        for samples in stream.chunks(chunk) {
+            decoded.extend(receiver.push(samples));
+            assert!(receiver.buffered_samples() <= config.duration_samples(buffer_ms) + chunk);
+        }
+
+        let decoded: Vec<Vec<u8>> = decoded.into_iter().map(|frame| frame.unwrap()).collect();
+        assert_eq!(decoded, messages);
+    }
+
+    #[test]
+    fn test_frame_longer_than_buffer_is_reported() {
+        let config = Config::default();
+        let mut receiver = RingReceiver::new(config.clone(), 500);
+
+        let message: Vec<u8> = (0..=255u8).collect();
+        let mut stream = encode_to_samples(&message, &config).unwrap();
+        assert!(stream.len() > config.duration_samples(500));
+        stream.extend(vec![0.0f32; config.duration_samples(1000)]);
+
+        let frames: Vec<_> = stream.chunks(1024).flat_map(|samples| receiver.push(samples)).collect();
+        assert!(!frames.is_empty() && frames.iter().all(|frame| frame.is_err()), "{:?}", frames);
+    }
+
+    #[test]
+    fn test_buffer_shorter_than_wake_lookahead_reports_frame() {
+        let config = Config::default();
+        let mut stream = vec![0.0f32; config.duration_samples(500)];
+        stream.extend(encode_to_samples(b"short buffer", &config).unwrap());
+        stream.extend(vec![0.0f32; config.duration_samples(1000)]);
+
+        for buffer_ms in [0, 20, 50] {
+            let mut receiver = RingReceiver::new(config.clone(), buffer_ms);
+            let frames: Vec<_> = stream
+                .chunks(1024)
+                .flat_map(|samples| receiver.push(samples))
+                .collect();
+            assert!(
+                !frames.is_empty() && frames.iter().all(|frame| frame.is_err()),
+                "{} ms: {:?}",
+                buffer_ms,
+                frames
+            );
+        }
+    }
+}
@@ -0,0 +1,561 @@
+use crate::audio::{AudioInput, AudioOutput, RecordLimits, RecordStatus};
+use crate::error::{Result, SonicPipeError};
+use crate::handshake::{decode_rate_reply, encode_rate_probe, encode_rate_reply, evaluate_rate_probe, rate_probe_samples};
+use crate::modulation::{AckKind, MFSKDemodulator, MFSKModulator};
+use crate::pipeline::{decode_from_samples, encode_to_samples};
+use crate::{Config, LOG_TARGET};
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_TURNAROUND_MS: u32 = 250;
+pub const DEFAULT_RECEIVE_TIMEOUT_MS: u64 = 30_000;
+pub const DEFAULT_MAX_RECORD_MS: u64 = 300_000;
+pub const DEFAULT_ACK_TIMEOUT_MS: u32 = 3000;
+pub const DEFAULT_RETRY_BACKOFF_MS: u32 = 500;
+// Backoff doubles with each retry up to this.
+const MAX_RETRY_BACKOFF_MS: u32 = 8000;
+
+// Silence queued ahead of a ping's tone, so the input is already recording
+// by the time the tone plays.
+const PING_LEAD_MS: u32 = 300;
+const PING_LISTEN_MS: u32 = 1500;
+
+// Volumes `auto_calibrate_volume` tries, quietest first.
+const CALIBRATION_VOLUMES: [f32; 7] = [0.05, 0.1, 0.2, 0.3, 0.5, 0.7, 1.0];
+const CALIBRATION_TONE_MS: u32 = 300;
+const CALIBRATION_WINDOW_MS: u32 = 20;
+// The target leaves headroom for a receiver further from the speaker than
+// our own microphone; below the minimum the tone wasn't heard at all.
+pub const CALIBRATION_TARGET_SNR_DB: f32 = 30.0;
+const CALIBRATION_MIN_SNR_DB: f32 = 10.0;
+
+// Half-duplex link over one speaker and one microphone. The two sides take
+// turns, so echo is avoided by timing rather than cancellation: the input is
+// only opened while receiving, and never until `turnaround_ms` has passed
+// since our own transmission finished, which lets the room's reverb tail
+// and the output device's buffered audio die away first. The guard has to
+// be at least as long as both, and the other side must wait at least as
+// long before replying or the start of its frame will be missed.
+pub struct Session {
+    config: Config,
+    input: AudioInput,
+    output: AudioOutput,
+    turnaround_ms: u32,
+    limits: RecordLimits,
+    sent_at: Option<Instant>,
+}
+
+impl Session {
+    pub fn new(config: Config) -> Result<Self> {
+        Ok(Self {
+            config,
+            input: AudioInput::new()?,
+            output: AudioOutput::new()?,
+            turnaround_ms: DEFAULT_TURNAROUND_MS,
+            limits: RecordLimits::new(DEFAULT_RECEIVE_TIMEOUT_MS, DEFAULT_MAX_RECORD_MS),
+            sent_at: None,
+        })
+    }
+
+    pub fn with_turnaround_ms(mut self, turnaround_ms: u32) -> Self {
+        self.turnaround_ms = turnaround_ms;
+        self
+    }
+
+    pub fn with_receive_timeout_ms(mut self, timeout_ms: u64) -> Self {
+        self.limits.wake_timeout_ms = timeout_ms;
+        self
+    }
+
+    pub fn with_max_record_ms(mut self, max_record_ms: u64) -> Self {
+        self.limits.max_record_ms = max_record_ms;
+        self
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    // Plays `data` and hands the turn to the other side; the next `receive`
+    // waits out the turnaround guard before listening.
+    pub fn send(&mut self, data: &[u8]) -> Result<()> {
+        let samples = encode_to_samples(data, &self.config)?;
+        self.output.play_samples(samples)?;
+        self.sent_at = Some(Instant::now());
+        Ok(())
+    }
+
+    pub fn receive(&mut self) -> Result<Vec<u8>> {
+        self.wait_turnaround();
+        let samples = self.input.record_frame(&self.config, self.limits)?;
+        decode_from_samples(&samples, &self.config).map(|(data, _)| data)
+    }
+
+    // Receives a frame and acknowledges it with `ack_seq` of its data, for a
+    // sender using `reliable_send`. Nothing is sent back for a frame that
+    // didn't decode, so the sender retransmits it.
+    pub fn receive_and_ack(&mut self) -> Result<Vec<u8>> {
+        let data = self.receive()?;
+        std::thread::sleep(Duration::from_millis(self.turnaround_ms as u64));
+        let ack = MFSKModulator::new(self.config.clone()).generate_ack_tone(ack_seq(&data));
+        self.transmit(ack)?;
+        Ok(data)
+    }
+
+    // `reliable_send` over this session.
+    pub fn send_reliably(&mut self, data: &[u8], policy: RetryPolicy) -> Result<()> {
+        let config = self.config.clone();
+        send_until_acked(self, data, &config, policy)
+    }
+
+    // Sends `data` and waits for the other side's reply.
+    pub fn request(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        self.send(data)?;
+        self.receive()
+    }
+
+    // Sender half of the rate handshake: plays the probe, then adopts the
+    // symbol duration the receiver picked from it.
+    pub fn negotiate_rate(&mut self) -> Result<u32> {
+        self.output.play_samples(encode_rate_probe(&self.config)?)?;
+        self.sent_at = Some(Instant::now());
+
+        let demodulator = MFSKDemodulator::new(self.config.clone());
+        self.wait_turnaround();
+        let reply = self.input.record_until_complete(
+            |samples| match demodulator.detect_ack_signal(samples) {
+                Some(_) => RecordStatus::Complete,
+                None => RecordStatus::Waiting,
+            },
+            self.limits,
+        )?;
+
+        let duration = decode_rate_reply(&reply, &self.config)
+            .ok_or_else(|| SonicPipeError::Decoding("receiver could not decode any probe rate".into()))?;
+        self.config.symbol_duration_ms = duration;
+        Ok(duration)
+    }
+
+    // Receiver half: listens to the whole probe, answers with the fastest
+    // rate that came through and switches to it.
+    pub fn accept_rate(&mut self) -> Result<u32> {
+        let probe_len = rate_probe_samples(&self.config)?;
+        let demodulator = MFSKDemodulator::new(self.config.clone());
+        self.wait_turnaround();
+        let samples = self.input.record_until_complete(
+            |samples| match demodulator.detect_wake_up(samples) {
+                Some(wake) if samples.len() >= wake + probe_len => RecordStatus::Complete,
+                Some(_) => RecordStatus::Receiving,
+                None => RecordStatus::Waiting,
+            },
+            self.limits,
+        )?;
+
+        let chosen = evaluate_rate_probe(&samples, &self.config)?;
+        std::thread::sleep(Duration::from_millis(self.turnaround_ms as u64));
+        self.output
+            .play_samples(encode_rate_reply(&self.config, chosen))?;
+        self.sent_at = Some(Instant::now());
+
+        let duration = chosen.ok_or_else(|| SonicPipeError::Decoding("no probe rate decoded".into()))?;
+        self.config.symbol_duration_ms = duration;
+        Ok(duration)
+    }
+
+    // Plays a wake-up tone and times how long it takes to come back in
+    // through the microphone, in milliseconds: input buffering plus the
+    // acoustic path. The output device's reported latency is taken off, as
+    // the tone only leaves the speaker that long after it is handed over.
+    // The input stream's own start-up delay is not seen, so the figure is a
+    // lower bound on a real turnaround.
+    pub fn ping(&mut self) -> Result<f32> {
+        let modulator = MFSKModulator::new(self.config.clone());
+        let mut signal = vec![0.0f32; self.config.duration_samples(PING_LEAD_MS)];
+        signal.extend(modulator.generate_wake_up_tone());
+
+        let queued_at = Instant::now();
+        self.output.enqueue(signal)?;
+        let recording_from = queued_at.elapsed();
+        let recording = self.input.record_samples(PING_LISTEN_MS)?;
+        self.output.wait_drained()?;
+        self.sent_at = Some(Instant::now());
+
+        let emitted_at = self.config.duration_samples(PING_LEAD_MS) as f64
+            + (self.output.output_latency().as_secs_f64() - recording_from.as_secs_f64())
+                * self.config.sample_rate as f64;
+        echo_delay_ms(&recording, emitted_at, &self.config).ok_or(SonicPipeError::NoWakeUpTone)
+    }
+
+    // Waits out whatever is left of the turnaround guard since this side
+    // last played, so its own tail and echo aren't heard as the reply.
+    fn wait_turnaround(&mut self) {
+        if let Some(sent_at) = self.sent_at.take() {
+            std::thread::sleep(guard_remaining(sent_at, Instant::now(), self.turnaround_ms));
+        }
+    }
+}
+
+impl AckLink for Session {
+    fn transmit(&mut self, samples: Vec<f32>) -> Result<()> {
+        self.output.play_samples(samples)?;
+        self.sent_at = Some(Instant::now());
+        Ok(())
+    }
+
+    // Stops as soon as any ack or nack burst is heard; `send_until_acked`
+    // checks whether it was the right one.
+    fn listen(&mut self, timeout_ms: u32) -> Result<Vec<f32>> {
+        self.wait_turnaround();
+        let demodulator = MFSKDemodulator::new(self.config.clone());
+        self.input.record_until_complete(
+            |samples| match demodulator.detect_ack_signal(samples) {
+                Some(_) => RecordStatus::Complete,
+                None => RecordStatus::Waiting,
+            },
+            RecordLimits::new(timeout_ms.into(), timeout_ms.into()),
+        )
+    }
+}
+
+// The half-duplex link `send_until_acked` drives: play some audio, then
+// record for up to `timeout_ms` while the other side answers. A link may
+// stop listening early once it has heard a reply, or give up with
+// `SonicPipeError::Timeout` when it hears nothing.
+pub trait AckLink {
+    fn transmit(&mut self, samples: Vec<f32>) -> Result<()>;
+    fn listen(&mut self, timeout_ms: u32) -> Result<Vec<f32>>;
+}
+
+// How hard `send_until_acked` tries: the first transmission plus up to
+// `max_retries` more, each waiting `ack_timeout_ms` for the ack. Before a
+// retry it backs off for `backoff_ms`, doubled on every further retry, so a
+// receiver still busy or a channel still noisy gets time to clear.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub ack_timeout_ms: u32,
+    pub backoff_ms: u32,
+}
+
+impl RetryPolicy {
+    pub fn new(max_retries: u32) -> Self {
+        Self {
+            max_retries,
+            ack_timeout_ms: DEFAULT_ACK_TIMEOUT_MS,
+            backoff_ms: DEFAULT_RETRY_BACKOFF_MS,
+        }
+    }
+
+    pub fn with_ack_timeout_ms(mut self, timeout_ms: u32) -> Self {
+        self.ack_timeout_ms = timeout_ms;
+        self
+    }
+
+    pub fn with_backoff_ms(mut self, backoff_ms: u32) -> Self {
+        self.backoff_ms = backoff_ms;
+        self
+    }
+
+    // Wait before retry number `retry`, counting from 1.
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let factor = 1u32 << retry.saturating_sub(1).min(16);
+        Duration::from_millis(self.backoff_ms.saturating_mul(factor).min(MAX_RETRY_BACKOFF_MS) as u64)
+    }
+}
+
+// The sequence number a frame carrying `data` is acknowledged with. Frames
+// don't carry one of their own, so both sides derive it from the payload:
+// the low half of its CRC-32. A stale ack for an earlier frame with the same
+// data is taken as acknowledging this one, which is harmless as it was the
+// same data.
+pub fn ack_seq(data: &[u8]) -> u16 {
+    crc32fast::hash(data) as u16
+}
+
+// Sends `data` over a fresh session and waits for the receiver's ack,
+// retransmitting up to `max_retries` times; see `send_until_acked`.
+pub fn reliable_send(data: &[u8], config: &Config, max_retries: u32) -> Result<()> {
+    Session::new(config.clone())?.send_reliably(data, RetryPolicy::new(max_retries))
+}
+
+// Transmits `data` and listens for an ack of `ack_seq(data)`, retransmitting
+// with backoff until it comes or the policy's retries run out. A nack, an
+// ack for another frame or silence all count as not acknowledged.
+pub fn send_until_acked(link: &mut impl AckLink, data: &[u8], config: &Config, policy: RetryPolicy) -> Result<()> {
+    let samples = encode_to_samples(data, config)?;
+    let seq = ack_seq(data);
+    let demodulator = MFSKDemodulator::new(config.clone());
+
+    for attempt in 0..=policy.max_retries {
+        if attempt > 0 {
+            let backoff = policy.backoff(attempt);
+            log::info!(target: LOG_TARGET, "No ack for {:04x}, retrying in {} ms", seq, backoff.as_millis());
+            std::thread::sleep(backoff);
+        }
+
+        link.transmit(samples.clone())?;
+        let reply = match link.listen(policy.ack_timeout_ms) {
+            Ok(reply) => reply,
+            Err(SonicPipeError::Timeout) => continue,
+            Err(e) => return Err(e),
+        };
+        match demodulator.detect_ack_signal(&reply) {
+            Some((AckKind::Ack, acked)) if acked == seq => return Ok(()),
+            other => log::debug!(target: LOG_TARGET, "Heard {:?} while waiting for ack {:04x}", other, seq),
+        }
+    }
+
+    Err(SonicPipeError::NotAcknowledged {
+        attempts: policy.max_retries + 1,
+    })
+}
+
+// How one calibration tone came back through the microphone.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CalibrationLevel {
+    pub volume: f32,
+    pub snr_db: f32,
+    pub clipped: bool,
+}
+
+// Picks a send volume by playing a data tone at rising volumes and listening
+// for it on the microphone, so it needs a working microphone within earshot
+// of the speaker. Stops at the first volume that clears
+// `CALIBRATION_TARGET_SNR_DB`, or at the last one before the input clips.
+// The receiver's level also depends on its distance, so this is a starting
+// point rather than a guarantee.
+pub fn auto_calibrate_volume(config: &Config) -> Result<f32> {
+    let mut output = AudioOutput::new()?;
+    let input = AudioInput::new()?;
+    let demodulator = MFSKDemodulator::new(config.clone());
+    let frequency = config.tone_frequency(config.num_tones() / 2);
+
+    let noise = input.record_samples(CALIBRATION_TONE_MS)?;
+    let noise_levels = window_levels(&demodulator, &noise, frequency, config);
+    let noise_level = noise_levels.iter().sum::<f32>() / noise_levels.len().max(1) as f32;
+
+    let mut levels = Vec::new();
+    for volume in CALIBRATION_VOLUMES {
+        let tone_config = Config {
+            volume,
+            ..config.clone()
+        };
+        let mut signal = vec![0.0f32; config.duration_samples(PING_LEAD_MS)];
+        signal.extend(MFSKModulator::new(tone_config).generate_tone(frequency, CALIBRATION_TONE_MS));
+
+        output.enqueue(signal)?;
+        let recording = input.record_samples(PING_LISTEN_MS + CALIBRATION_TONE_MS)?;
+        output.wait_drained()?;
+
+        let level = measure_level(&demodulator, &recording, frequency, noise_level, volume, config);
+        log::debug!(target: LOG_TARGET, "calibration at {:.2}: {:.1} dB SNR, clipped: {}", volume, level.snr_db, level.clipped);
+        levels.push(level);
+        if level.clipped || level.snr_db >= CALIBRATION_TARGET_SNR_DB {
+            break;
+        }
+    }
+
+    choose_volume(&levels).ok_or_else(|| match levels.first() {
+        Some(level) if level.clipped => SonicPipeError::AudioDevice(
+            "microphone clips even at the lowest calibration volume; lower the input gain".into(),
+        ),
+        _ => SonicPipeError::AudioDevice(
+            "calibration tone was not heard; check that the microphone can hear the speaker".into(),
+        ),
+    })
+}
+
+// Tone magnitude in each calibration window of `samples`.
+fn window_levels(demodulator: &MFSKDemodulator, samples: &[f32], frequency: f32, config: &Config) -> Vec<f32> {
+    samples
+        .chunks_exact(config.duration_samples(CALIBRATION_WINDOW_MS).max(1))
+        .map(|window| demodulator.goertzel_normalized(window, frequency))
+        .collect()
+}
+
+fn measure_level(
+    demodulator: &MFSKDemodulator,
+    recording: &[f32],
+    frequency: f32,
+    noise_level: f32,
+    volume: f32,
+    config: &Config,
+) -> CalibrationLevel {
+    let peak = window_levels(demodulator, recording, frequency, config)
+        .into_iter()
+        .fold(0.0f32, f32::max);
+
+    CalibrationLevel {
+        volume,
+        snr_db: 20.0 * (peak / noise_level.max(1e-6)).log10(),
+        clipped: demodulator.is_clipped(recording),
+    }
+}
+
+// The quietest volume that reaches the target SNR without clipping, else the
+// loudest that didn't clip as long as it was heard at all.
+pub fn choose_volume(levels: &[CalibrationLevel]) -> Option<f32> {
+    let unclipped = || levels.iter().take_while(|level| !level.clipped);
+
+    unclipped()
+        .find(|level| level.snr_db >= CALIBRATION_TARGET_SNR_DB)
+        .or_else(|| unclipped().last().filter(|level| level.snr_db >= CALIBRATION_MIN_SNR_DB))
+        .map(|level| level.volume)
+}
+
+// Delay between a wake-up tone starting `emitted_at` samples into
+// `recording` and where it was actually heard.
+pub fn echo_delay_ms(recording: &[f32], emitted_at: f64, config: &Config) -> Option<f32> {
+    let (wake_start, _) = MFSKDemodulator::new(config.clone()).detect_wake_bounds(recording)?;
+    let delay = wake_start as f64 - emitted_at;
+    Some((delay * 1000.0 / config.sample_rate as f64) as f32)
+}
+
+fn guard_remaining(sent_at: Instant, now: Instant, turnaround_ms: u32) -> Duration {
+    (sent_at + Duration::from_millis(turnaround_ms as u64)).saturating_duration_since(now)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::add_awgn;
+
+    // Both ends in memory: every transmission is decoded on the spot and
+    // answered with an ack, except for the first `drop_acks`, whose ack is
+    // lost and leaves only noise to hear.
+    struct Loopback {
+        config: Config,
+        drop_acks: usize,
+        received: Vec<Vec<u8>>,
+        reply: Vec<f32>,
+    }
+
+    impl AckLink for Loopback {
+        fn transmit(&mut self, samples: Vec<f32>) -> Result<()> {
+            let (data, _) = decode_from_samples(&samples, &self.config)?;
+            self.reply = vec![0.0f32; self.config.duration_samples(200)];
+            if self.drop_acks > 0 {
+                self.drop_acks -= 1;
+            } else {
+                let ack = MFSKModulator::new(self.config.clone()).generate_ack_tone(ack_seq(&data));
+                self.reply.extend(ack.iter().map(|s| s * 0.3));
+            }
+            self.reply.extend(vec![0.0f32; self.config.duration_samples(200)]);
+            add_awgn(&mut self.reply, 10.0, self.received.len() as u64);
+            self.received.push(data);
+            Ok(())
+        }
+
+        fn listen(&mut self, _timeout_ms: u32) -> Result<Vec<f32>> {
+            Ok(std::mem::take(&mut self.reply))
+        }
+    }
+
+    #[test]
+    fn test_reliable_send_retransmits_on_dropped_ack() {
+        let config = Config::default();
+        let policy = RetryPolicy::new(3).with_backoff_ms(5);
+        let data = b"acknowledge me";
+        let mut link = Loopback {
+            config: config.clone(),
+            drop_acks: 1,
+            received: Vec::new(),
+            reply: Vec::new(),
+        };
+
+        send_until_acked(&mut link, data, &config, policy).unwrap();
+        assert_eq!(link.received, vec![data.to_vec(); 2]);
+
+        // Never acked: every retry is spent, then it gives up.
+        link.drop_acks = usize::MAX;
+        link.received.clear();
+        match send_until_acked(&mut link, data, &config, policy) {
+            Err(SonicPipeError::NotAcknowledged { attempts: 4 }) => {}
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(link.received.len(), 4);
+    }
+
+    #[test]
+    fn test_retry_backoff_doubles_up_to_cap() {
+        let policy = RetryPolicy::new(10).with_backoff_ms(500);
+        let backoffs: Vec<u128> = (1..=6).map(|retry| policy.backoff(retry).as_millis()).collect();
+        assert_eq!(backoffs, vec![500, 1000, 2000, 4000, 8000, 8000]);
+    }
+
+    #[test]
+    fn test_turnaround_guard() {
+        let sent_at = Instant::now();
+
+        assert_eq!(guard_remaining(sent_at, sent_at, 250), Duration::from_millis(250));
+        assert_eq!(
+            guard_remaining(sent_at, sent_at + Duration::from_millis(100), 250),
+            Duration::from_millis(150)
+        );
+        assert_eq!(guard_remaining(sent_at, sent_at + Duration::from_secs(1), 250), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_echo_delay() {
+        let config = Config::default();
+        let tone = MFSKModulator::new(config.clone()).generate_wake_up_tone();
+
+        // Emitted 100 ms into the recording, heard 37 ms later at a tenth of
+        // the level.
+        let mut recording = vec![0.0f32; config.duration_samples(137)];
+        recording.extend(tone.iter().map(|s| s * 0.1));
+        recording.extend(vec![0.0f32; config.duration_samples(500)]);
+        add_awgn(&mut recording, 0.0, 5);
+
+        let emitted_at = config.duration_samples(100) as f64;
+        let delay = echo_delay_ms(&recording, emitted_at, &config).unwrap();
+        assert!((delay - 37.0).abs() < 3.0, "measured {} ms", delay);
+
+        assert_eq!(echo_delay_ms(&vec![0.0f32; 48000], emitted_at, &config), None);
+    }
+
+    #[test]
+    fn test_calibration_level() {
+        let config = Config::default();
+        let demodulator = MFSKDemodulator::new(config.clone());
+        let frequency = config.tone_frequency(8);
+        let tone = MFSKModulator::new(Config {
+            volume: 0.1,
+            ..config.clone()
+        })
+        .generate_tone(frequency, CALIBRATION_TONE_MS);
+
+        let lead = config.duration_samples(250);
+        let mut recording = vec![0.0f32; lead];
+        recording.extend(&tone);
+        recording.extend(vec![0.0f32; lead]);
+        add_awgn(&mut recording, 20.0, 3);
+
+        let noise = &recording[..lead];
+        let noise_levels = window_levels(&demodulator, noise, frequency, &config);
+        let noise_level = noise_levels.iter().sum::<f32>() / noise_levels.len() as f32;
+
+        let level = measure_level(&demodulator, &recording, frequency, noise_level, 0.1, &config);
+        assert!(level.snr_db > CALIBRATION_TARGET_SNR_DB, "{:?}", level);
+        assert!(!level.clipped);
+
+        // Noise alone doesn't count as hearing the tone.
+        let unheard = measure_level(&demodulator, noise, frequency, noise_level, 0.1, &config);
+        assert!(unheard.snr_db < CALIBRATION_MIN_SNR_DB, "{:?}", unheard);
+    }
+
+    #[test]
+    fn test_choose_volume() {
+        let level = |volume, snr_db, clipped| CalibrationLevel { volume, snr_db, clipped };
+
+        // The quietest volume that reaches the target wins.
+        assert_eq!(choose_volume(&[level(0.05, 12.0, false), level(0.1, 31.0, false)]), Some(0.1));
+        // Clipping before the target settles for the step below it.
+        assert_eq!(
+            choose_volume(&[level(0.05, 12.0, false), level(0.1, 18.0, false), level(0.2, 24.0, true)]),
+            Some(0.1)
+        );
+        // Never heard, or clipping from the start.
+        assert_eq!(choose_volume(&[level(0.05, 2.0, false), level(1.0, 4.0, false)]), None);
+        assert_eq!(choose_volume(&[level(0.05, 40.0, true)]), None);
+    }
+}
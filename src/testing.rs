@@ -0,0 +1,180 @@
+use crate::modulation::splitmix64;
+use std::f32::consts::PI;
+
+// Helpers for reproducible robustness tests: seeded white noise at a given
+// SNR, a rough voice codec, and a bit error rate to measure what got
+// through.
+
+// Adds white Gaussian noise at `snr_db` relative to the mean power of
+// `samples`. The same seed always adds the same noise.
+pub fn add_awgn(samples: &mut [f32], snr_db: f32, seed: u64) {
+    if samples.is_empty() {
+        return;
+    }
+
+    let signal_power = samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32;
+    let sigma = (signal_power / 10f32.powf(snr_db / 10.0)).sqrt();
+
+    let mut state = seed;
+    let mut uniform = || {
+        state = splitmix64(state);
+        // 24 bits, shifted off zero so the log below stays finite.
+        ((state >> 40) as f32 + 0.5) / (1u64 << 24) as f32
+    };
+
+    for pair in samples.chunks_mut(2) {
+        // Box-Muller: two uniforms give two independent normals.
+        let radius = (-2.0 * uniform().ln()).sqrt() * sigma;
+        let angle = 2.0 * PI * uniform();
+        pair[0] += radius * angle.cos();
+        if let Some(second) = pair.get_mut(1) {
+            *second += radius * angle.sin();
+        }
+    }
+}
+
+// Second-order Butterworth section, in the RBJ cookbook's form.
+fn biquad(samples: &mut [f32], sample_rate: u32, cutoff_hz: f32, high_pass: bool) {
+    let omega = 2.0 * PI * cutoff_hz / sample_rate as f32;
+    let alpha = omega.sin() / 2.0f32.sqrt();
+    let cos = omega.cos();
+    let (b0, b1) = if high_pass { ((1.0 + cos) / 2.0, -(1.0 + cos)) } else { ((1.0 - cos) / 2.0, 1.0 - cos) };
+    let (a0, a1, a2) = (1.0 + alpha, -2.0 * cos, 1.0 - alpha);
+
+    let (mut x1, mut x2, mut y1, mut y2) = (0.0f32, 0.0f32, 0.0f32, 0.0f32);
+    for sample in samples.iter_mut() {
+        let x = *sample;
+        let y = (b0 * x + b1 * x1 + b0 * x2 - a1 * y1 - a2 * y2) / a0;
+        (x2, x1, y2, y1) = (x1, x, y1, y);
+        *sample = y;
+    }
+}
+
+// A narrowband voice channel, roughly: 300-3400 Hz band-pass at 24 dB per
+// octave either side, then G.711 mu-law's 8-bit companded quantization.
+// Every `drop_every`th 20 ms codec frame, if any, is lost and replaced by
+// silence; `Some(0)` drops nothing, like `None`.
+pub fn voice_channel(samples: &mut [f32], sample_rate: u32, drop_every: Option<usize>) {
+    for _ in 0..2 {
+        biquad(samples, sample_rate, 300.0, true);
+        biquad(samples, sample_rate, 3400.0, false);
+    }
+
+    const MU: f32 = 255.0;
+    for sample in samples.iter_mut() {
+        let x = sample.clamp(-1.0, 1.0);
+        let companded = x.signum() * (1.0 + MU * x.abs()).ln() / (1.0 + MU).ln();
+        let quantized = (companded * 127.0).round() / 127.0;
+        *sample = quantized.signum() * ((1.0 + MU).powf(quantized.abs()) - 1.0) / MU;
+    }
+
+    if let Some(every) = drop_every.filter(|&every| every > 0) {
+        let frame = sample_rate as usize / 50;
+        for lost in samples.chunks_mut(frame).skip(every - 1).step_by(every) {
+            lost.fill(0.0);
+        }
+    }
+}
+
+// Fraction of `original`'s bits that `decoded` got wrong. Missing or extra
+// bytes count as eight errors each.
+pub fn bit_error_rate(original: &[u8], decoded: &[u8]) -> f64 {
+    let total_bits = original.len().max(decoded.len()) * 8;
+    if total_bits == 0 {
+        return 0.0;
+    }
+
+    let flipped: u32 = original.iter().zip(decoded).map(|(a, b)| (a ^ b).count_ones()).sum();
+    let length_mismatch = original.len().abs_diff(decoded.len()) * 8;
+
+    (flipped as usize + length_mismatch) as f64 / total_bits as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{decode_from_samples, encode_to_samples, Config, MFSKDemodulator, Packet};
+
+    #[test]
+    fn test_awgn_is_seeded_and_hits_snr() {
+        let signal: Vec<f32> = (0..48000).map(|i| (i as f32 * 0.1).sin() * 0.5).collect();
+
+        let mut a = signal.clone();
+        let mut b = signal.clone();
+        add_awgn(&mut a, 10.0, 1);
+        add_awgn(&mut b, 10.0, 1);
+        assert_eq!(a, b);
+
+        let noise_power = a.iter().zip(&signal).map(|(n, s)| (n - s) * (n - s)).sum::<f32>() / a.len() as f32;
+        let signal_power = signal.iter().map(|s| s * s).sum::<f32>() / signal.len() as f32;
+        let snr_db = 10.0 * (signal_power / noise_power).log10();
+        assert!((snr_db - 10.0).abs() < 0.2, "measured {} dB", snr_db);
+    }
+
+    #[test]
+    fn test_telephony_survives_voice_channel() {
+        let config = Config::builder().telephony().sample_rate(8000).build().unwrap();
+        let message = b"data over a voice call".to_vec();
+        let clean = encode_to_samples(&message, &config).unwrap();
+
+        let mut received = clean.clone();
+        voice_channel(&mut received, config.sample_rate, None);
+        assert_eq!(decode_from_samples(&received, &config).unwrap().0, message);
+
+        let mut no_drops = clean.clone();
+        voice_channel(&mut no_drops, config.sample_rate, Some(0));
+        assert_eq!(no_drops, received);
+
+        // A codec frame lost every 200 ms still leaves most of each 80 ms
+        // symbol it lands in.
+        let mut received = clean.clone();
+        voice_channel(&mut received, config.sample_rate, Some(10));
+        assert_eq!(decode_from_samples(&received, &config).unwrap().0, message);
+    }
+
+    #[test]
+    fn test_bit_error_rate() {
+        assert_eq!(bit_error_rate(b"abcd", b"abcd"), 0.0);
+        assert_eq!(bit_error_rate(&[0x00, 0x00], &[0x01, 0x00]), 1.0 / 16.0);
+        assert_eq!(bit_error_rate(&[0x00, 0x00], &[0x00]), 0.5);
+    }
+
+    #[test]
+    fn test_snr_sweep() {
+        let config = Config {
+            symbol_duration_ms: 10,
+            ..Default::default()
+        };
+        let message: Vec<u8> = (0..64u8).map(|i| i.wrapping_mul(151)).collect();
+        let clean = encode_to_samples(&message, &config).unwrap();
+        let clean_bytes = MFSKDemodulator::new(config.clone()).demodulate(&clean).unwrap();
+        Packet::deserialize_unchecked(&clean_bytes).unwrap();
+
+        // SNR is measured over the full band, but each Goertzel bin only sees a
+        // sliver of the noise, so the sweep has to go well below 0 dB to fail.
+        let mut threshold = None;
+        for snr_db in (-30..=30).rev().step_by(3) {
+            let mut noisy = clean.clone();
+            add_awgn(&mut noisy, snr_db as f32, (snr_db + 30) as u64);
+
+            let raw = MFSKDemodulator::new(config.clone()).demodulate(&noisy).unwrap_or_default();
+            let ber = bit_error_rate(&clean_bytes, &raw);
+            let decoded = decode_from_samples(&noisy, &config).is_ok_and(|(d, _)| d == message);
+
+            if decoded {
+                // Success must not come back once decoding has started failing.
+                assert!(
+                    threshold.is_none(),
+                    "decoded at {} dB (symbol BER {:.4}) after failing above it",
+                    snr_db,
+                    ber
+                );
+            } else if threshold.is_none() {
+                threshold = Some(snr_db + 3);
+            }
+        }
+
+        let threshold = threshold.expect("decoded all the way down to -30 dB");
+        assert!(threshold <= 0, "threshold {} dB", threshold);
+    }
+}
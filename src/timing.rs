@@ -0,0 +1,100 @@
+use std::time::Duration;
+
+// A source of timestamps for profiling. `std::time::Instant` panics on
+// wasm, where the browser's `performance.now()` stands in for it.
+pub trait Clock {
+    // Milliseconds since some fixed point of the clock's choosing.
+    fn now_ms(&self) -> f64;
+}
+
+pub struct SystemClock {
+    #[cfg(not(target_arch = "wasm32"))]
+    origin: std::time::Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> Self {
+        Self {
+            #[cfg(not(target_arch = "wasm32"))]
+            origin: std::time::Instant::now(),
+        }
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for SystemClock {
+    #[cfg(not(target_arch = "wasm32"))]
+    fn now_ms(&self) -> f64 {
+        self.origin.elapsed().as_secs_f64() * 1000.0
+    }
+
+    // Falls back to the wall clock's millisecond ticks outside a window,
+    // e.g. in a worker without `performance`.
+    #[cfg(target_arch = "wasm32")]
+    fn now_ms(&self) -> f64 {
+        web_sys::window()
+            .and_then(|window| window.performance())
+            .map_or_else(js_sys::Date::now, |performance| performance.now())
+    }
+}
+
+// Elapsed time between two `Clock` readings.
+pub fn elapsed_between(from_ms: f64, to_ms: f64) -> Duration {
+    Duration::from_secs_f64((to_ms - from_ms).max(0.0) / 1000.0)
+}
+
+// Where `MFSKDemodulator::demodulate_timed` spent its time. The phases
+// don't quite add up to `total`, which also covers the bookkeeping between
+// them.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct DemodTiming {
+    // Finding the frame: the wake-up tone, sync word and frame type, or the
+    // energy onset when there's no tone.
+    pub wake_detection: Duration,
+    // Reading each symbol window's tones.
+    pub symbol_detection: Duration,
+    // Turning symbols into bytes, descrambling included.
+    pub packing: Duration,
+    pub total: Duration,
+    pub symbols: usize,
+}
+
+impl DemodTiming {
+    pub fn per_symbol(&self) -> Duration {
+        self.symbol_detection / self.symbols.max(1) as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Config, MFSKDemodulator, MFSKModulator};
+
+    #[test]
+    fn test_demod_phases_account_for_total() {
+        let config = Config::default();
+        let data: Vec<u8> = (0..120u8).collect();
+        let samples = MFSKModulator::new(config.clone()).modulate(&data).unwrap();
+        let mut demodulator = MFSKDemodulator::new(config);
+
+        let (decoded, timing) = demodulator.demodulate_timed(&samples);
+        assert_eq!(decoded.unwrap(), data);
+        assert_eq!(timing.symbols, 2 * data.len());
+        assert!(timing.wake_detection > Duration::ZERO && timing.symbol_detection > Duration::ZERO);
+
+        let phases = timing.wake_detection + timing.symbol_detection + timing.packing;
+        assert!(phases <= timing.total, "{:?}", timing);
+        // Wall-clock time between the phases varies with the machine's load,
+        // so this only checks that the phases are most of the total.
+        assert!(phases * 2 > timing.total, "{:?}", timing);
+
+        let (decoded, timing) = demodulator.demodulate_timed(&vec![0.0f32; samples.len()]);
+        assert!(decoded.is_none());
+        assert_eq!((timing.symbols, timing.total), (0, timing.wake_detection));
+    }
+}
@@ -0,0 +1,139 @@
+use crate::error::{Result, SonicPipeError};
+use crate::pipeline::{decode_from_samples, encode_to_samples};
+use crate::Config;
+use serde::{Deserialize, Serialize};
+
+// Silence before, between and after frames so each one can be sliced out
+// without catching its neighbours' tones.
+const VECTOR_GAP_MS: u32 = 250;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TestVector {
+    pub offset: usize,
+    pub length: usize,
+    pub payload: Vec<u8>,
+    pub config: Config,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Manifest {
+    pub sample_rate: u32,
+    pub vectors: Vec<TestVector>,
+}
+
+// Encodes each case back to back into one buffer, recording where each frame
+// sits and the config needed to decode it. All cases must share a sample rate
+// so the buffer can be written out as a single WAV.
+pub fn generate_test_vectors(cases: &[(Vec<u8>, Config)]) -> Result<(Vec<f32>, Manifest)> {
+    let sample_rate = cases.first().map(|(_, config)| config.sample_rate).unwrap_or(crate::SAMPLE_RATE);
+    let gap = vec![0.0f32; (sample_rate as u64 * VECTOR_GAP_MS as u64 / 1000) as usize];
+
+    let mut samples = gap.clone();
+    let mut vectors = Vec::with_capacity(cases.len());
+
+    for (payload, config) in cases {
+        if config.sample_rate != sample_rate {
+            return Err(SonicPipeError::InvalidConfig(format!(
+                "test vectors mix sample rates {} and {}",
+                sample_rate, config.sample_rate
+            )));
+        }
+
+        let frame = encode_to_samples(payload, config)?;
+        vectors.push(TestVector {
+            offset: samples.len(),
+            length: frame.len(),
+            payload: payload.clone(),
+            config: config.clone(),
+        });
+        samples.extend(frame);
+        samples.extend_from_slice(&gap);
+    }
+
+    Ok((samples, Manifest { sample_rate, vectors }))
+}
+
+// Decodes every vector in the manifest from its recorded span, one result per
+// vector in manifest order.
+pub fn verify_test_vectors(samples: &[f32], manifest: &Manifest) -> Vec<Result<()>> {
+    manifest.vectors.iter().map(|vector| verify_vector(samples, vector)).collect()
+}
+
+fn verify_vector(samples: &[f32], vector: &TestVector) -> Result<()> {
+    let end = vector.offset + vector.length;
+    if end > samples.len() {
+        return Err(SonicPipeError::Decoding(format!(
+            "vector at {} runs past the end of the buffer ({} samples)",
+            vector.offset,
+            samples.len()
+        )));
+    }
+
+    let (decoded, _) = decode_from_samples(&samples[vector.offset..end], &vector.config)?;
+    if decoded != vector.payload {
+        return Err(SonicPipeError::Decoding(format!(
+            "vector at {} decoded to {} bytes that don't match the expected {}",
+            vector.offset,
+            decoded.len(),
+            vector.payload.len()
+        )));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{read_wav_from, write_wav_to, TransmissionMode};
+    use std::io::Cursor;
+
+    fn cases() -> Vec<(Vec<u8>, Config)> {
+        vec![
+            (b"hello".to_vec(), Config::default()),
+            ((0..120u8).collect(), Config { compress: false, ..Default::default() }),
+            (
+                b"ultrasonic".to_vec(),
+                Config {
+                    mode: TransmissionMode::Ultrasonic,
+                    ..Default::default()
+                },
+            ),
+        ]
+    }
+
+    #[test]
+    fn test_vectors_verify_through_wav_and_json() {
+        let (samples, manifest) = generate_test_vectors(&cases()).unwrap();
+        assert_eq!(manifest.vectors.len(), 3);
+
+        let mut wav = Vec::new();
+        write_wav_to(&mut wav, &samples, manifest.sample_rate).unwrap();
+        let (read_back, sample_rate) = read_wav_from(&mut Cursor::new(wav)).unwrap();
+        assert_eq!(sample_rate, manifest.sample_rate);
+
+        let json = serde_json::to_string(&manifest).unwrap();
+        let manifest: Manifest = serde_json::from_str(&json).unwrap();
+
+        for result in verify_test_vectors(&read_back, &manifest) {
+            result.unwrap();
+        }
+    }
+
+    #[test]
+    fn test_vectors_report_mismatch() {
+        let (samples, mut manifest) = generate_test_vectors(&cases()).unwrap();
+        manifest.vectors[0].payload = b"world".to_vec();
+
+        let results = verify_test_vectors(&samples, &manifest);
+        assert!(results[0].is_err());
+        assert!(results[1].is_ok() && results[2].is_ok());
+    }
+
+    #[test]
+    fn test_vectors_reject_mixed_sample_rates() {
+        let mut cases = cases();
+        cases[1].1.sample_rate = 44100;
+        assert!(matches!(generate_test_vectors(&cases), Err(SonicPipeError::InvalidConfig(_))));
+    }
+}
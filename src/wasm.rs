@@ -3,10 +3,8 @@ use wasm_bindgen::prelude::*;
 
 #[cfg(target_arch = "wasm32")]
 use crate::{
-    codec::{compress, decompress, ReedSolomonCodec},
-    modulation::{MFSKDemodulator, MFSKModulator},
-    protocol::Packet,
-    Config, TransmissionMode,
+    pipeline::{decode_from_samples, encode_to_samples},
+    Config, ToneBand, TransmissionMode,
 };
 
 #[cfg(target_arch = "wasm32")]
@@ -19,49 +17,68 @@ pub struct SonicPipeWasm {
 #[wasm_bindgen]
 impl SonicPipeWasm {
     #[wasm_bindgen(constructor)]
-    pub fn new(ultrasonic: bool) -> Self {
+    pub fn new(ultrasonic: bool) -> Result<SonicPipeWasm, JsValue> {
         console_error_panic_hook::set_once();
 
-        Self {
-            config: Config {
-                mode: if ultrasonic {
-                    TransmissionMode::Ultrasonic
-                } else {
-                    TransmissionMode::Audible
-                },
-                ..Default::default()
-            },
-        }
+        let mode = if ultrasonic {
+            TransmissionMode::Ultrasonic
+        } else {
+            TransmissionMode::Audible
+        };
+        let config = Config::builder()
+            .mode(mode)
+            .build()
+            .map_err(|e| JsValue::from_str(&e.to_string()))?;
+
+        Ok(Self { config })
     }
 
     #[wasm_bindgen]
-    pub fn set_symbol_duration(&mut self, duration_ms: u32) {
-        self.config.symbol_duration_ms = duration_ms;
+    pub fn set_mode(&mut self, ultrasonic: bool) {
+        self.set_transmission_mode(if ultrasonic {
+            TransmissionMode::Ultrasonic
+        } else {
+            TransmissionMode::Audible
+        });
     }
 
+    // Any mode, where `set_mode` only picks between audible and ultrasonic.
+    // Switching modes drops any custom band so the mode's own tones apply.
     #[wasm_bindgen]
-    pub fn set_volume(&mut self, volume: f32) {
-        self.config.volume = volume.clamp(0.0, 1.0);
+    pub fn set_transmission_mode(&mut self, mode: TransmissionMode) {
+        self.config.mode = mode;
+        self.config.custom_band = None;
     }
 
     #[wasm_bindgen]
-    pub fn encode(&self, data: &[u8]) -> Result<Vec<f32>, JsValue> {
-        let compressed = compress(data);
+    pub fn set_custom_band(&mut self, base_frequency: f32, frequency_step: f32) -> Result<(), JsValue> {
+        self.config
+            .set_custom_band(ToneBand {
+                base_frequency,
+                frequency_step,
+            })
+            .map_err(|e| JsValue::from_str(&e.to_string()))
+    }
 
-        let ecc = ReedSolomonCodec::new()
-            .map_err(|e| JsValue::from_str(&e.to_string()))?;
-        let encoded = ecc
-            .encode(&compressed)
-            .map_err(|e| JsValue::from_str(&e.to_string()))?;
+    #[wasm_bindgen]
+    pub fn clear_custom_band(&mut self) {
+        self.config.custom_band = None;
+    }
 
-        let packet = Packet::new(encoded)
-            .map_err(|e| JsValue::from_str(&e.to_string()))?;
-        let packet_data = packet.serialize();
+    #[wasm_bindgen]
+    pub fn set_symbol_duration(&mut self, duration_ms: u32) {
+        self.config.symbol_duration_ms = duration_ms;
+    }
 
-        let modulator = MFSKModulator::new(self.config.clone());
-        let samples = modulator.modulate(&packet_data);
+    #[wasm_bindgen]
+    pub fn set_volume(&mut self, volume: f32) {
+        self.config.set_volume(volume);
+    }
 
-        Ok(samples)
+    #[wasm_bindgen]
+    pub fn encode(&self, data: &[u8]) -> Result<Vec<f32>, JsValue> {
+        encode_to_samples(data, &self.config)
+            .map_err(|e| JsValue::from_str(&e.to_string()))
     }
 
     #[wasm_bindgen]
@@ -71,25 +88,9 @@ impl SonicPipeWasm {
 
     #[wasm_bindgen]
     pub fn decode(&self, samples: &[f32]) -> Result<Vec<u8>, JsValue> {
-        let mut demodulator = MFSKDemodulator::new(self.config.clone());
-
-        let raw_data = demodulator
-            .demodulate(samples)
-            .ok_or_else(|| JsValue::from_str("Failed to demodulate signal"))?;
-
-        let packet = Packet::deserialize(&raw_data)
-            .map_err(|e| JsValue::from_str(&e.to_string()))?;
-
-        let ecc = ReedSolomonCodec::new()
-            .map_err(|e| JsValue::from_str(&e.to_string()))?;
-        let decoded = ecc
-            .decode(&packet.payload)
-            .map_err(|e| JsValue::from_str(&e.to_string()))?;
-
-        let decompressed = decompress(&decoded)
-            .map_err(|e| JsValue::from_str(&e.to_string()))?;
-
-        Ok(decompressed)
+        decode_from_samples(samples, &self.config)
+            .map(|(data, _)| data)
+            .map_err(|e| JsValue::from_str(&e.to_string()))
     }
 
     #[wasm_bindgen]
@@ -101,9 +102,7 @@ impl SonicPipeWasm {
 
     #[wasm_bindgen]
     pub fn get_frequencies(&self) -> Vec<f32> {
-        let base_freq = self.config.mode.base_frequency();
-        let step = self.config.mode.frequency_step();
-        (0..16).map(|i| base_freq + (i as f32) * step).collect()
+        self.config.frequencies()
     }
 
     #[wasm_bindgen]
@@ -111,6 +110,11 @@ impl SonicPipeWasm {
         self.config.sample_rate
     }
 
+    #[wasm_bindgen]
+    pub fn estimate_duration_ms(&self, payload_len: usize) -> u32 {
+        self.config.estimate_duration_ms(payload_len)
+    }
+
     #[wasm_bindgen]
     pub fn get_symbol_duration_samples(&self) -> u32 {
         (self.config.sample_rate as f32 * self.config.symbol_duration_ms as f32 / 1000.0) as u32
@@ -0,0 +1,201 @@
+use crate::error::{Result, SonicPipeError};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+const WAVE_FORMAT_PCM: u16 = 1;
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+
+pub fn write_wav<P: AsRef<Path>>(path: P, samples: &[f32], sample_rate: u32) -> Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    write_wav_to(&mut writer, samples, sample_rate)?;
+    writer.flush()?;
+    Ok(())
+}
+
+pub fn write_wav_to<W: Write>(writer: &mut W, samples: &[f32], sample_rate: u32) -> Result<()> {
+    let data_len = (samples.len() * 4) as u32;
+
+    writer.write_all(b"RIFF")?;
+    writer.write_u32::<LittleEndian>(36 + data_len)?;
+    writer.write_all(b"WAVE")?;
+
+    writer.write_all(b"fmt ")?;
+    writer.write_u32::<LittleEndian>(16)?;
+    writer.write_u16::<LittleEndian>(WAVE_FORMAT_IEEE_FLOAT)?;
+    writer.write_u16::<LittleEndian>(1)?;
+    writer.write_u32::<LittleEndian>(sample_rate)?;
+    writer.write_u32::<LittleEndian>(sample_rate * 4)?;
+    writer.write_u16::<LittleEndian>(4)?;
+    writer.write_u16::<LittleEndian>(32)?;
+
+    writer.write_all(b"data")?;
+    writer.write_u32::<LittleEndian>(data_len)?;
+    for &sample in samples {
+        writer.write_f32::<LittleEndian>(sample)?;
+    }
+
+    Ok(())
+}
+
+pub fn read_wav<P: AsRef<Path>>(path: P) -> Result<(Vec<f32>, u32)> {
+    let mut reader = BufReader::new(File::open(path)?);
+    read_wav_from(&mut reader)
+}
+
+pub fn read_wav_from<R: Read>(reader: &mut R) -> Result<(Vec<f32>, u32)> {
+    let mut tag = [0u8; 4];
+
+    reader.read_exact(&mut tag)?;
+    if &tag != b"RIFF" {
+        return Err(SonicPipeError::Wav("Missing RIFF header".into()));
+    }
+    reader.read_u32::<LittleEndian>()?;
+    reader.read_exact(&mut tag)?;
+    if &tag != b"WAVE" {
+        return Err(SonicPipeError::Wav("Missing WAVE tag".into()));
+    }
+
+    let mut format = None;
+
+    loop {
+        reader.read_exact(&mut tag)?;
+        let chunk_len = reader.read_u32::<LittleEndian>()? as usize;
+        // Read rather than allocated up front, so a header claiming a huge
+        // chunk costs no more than the bytes actually there.
+        let padded_len = chunk_len + chunk_len % 2;
+        let mut chunk = Vec::new();
+        reader
+            .by_ref()
+            .take(padded_len as u64)
+            .read_to_end(&mut chunk)?;
+        if chunk.len() < padded_len {
+            return Err(SonicPipeError::Wav(format!(
+                "Truncated {} chunk",
+                String::from_utf8_lossy(&tag)
+            )));
+        }
+        chunk.truncate(chunk_len);
+
+        match &tag {
+            b"fmt " => {
+                if chunk.len() < 16 {
+                    return Err(SonicPipeError::Wav("Truncated fmt chunk".into()));
+                }
+                let mut fmt = &chunk[..];
+                let mut format_tag = fmt.read_u16::<LittleEndian>()?;
+                let channels = fmt.read_u16::<LittleEndian>()?;
+                let sample_rate = fmt.read_u32::<LittleEndian>()?;
+                fmt.read_u32::<LittleEndian>()?;
+                fmt.read_u16::<LittleEndian>()?;
+                let bits_per_sample = fmt.read_u16::<LittleEndian>()?;
+
+                if format_tag == WAVE_FORMAT_EXTENSIBLE && chunk.len() >= 26 {
+                    format_tag = u16::from_le_bytes([chunk[24], chunk[25]]);
+                }
+
+                format = Some((format_tag, channels.max(1) as usize, sample_rate, bits_per_sample));
+            }
+            b"data" => {
+                let (format_tag, channels, sample_rate, bits_per_sample) =
+                    format.ok_or_else(|| SonicPipeError::Wav("data chunk before fmt chunk".into()))?;
+                let frames = decode_samples(&chunk, format_tag, bits_per_sample)?;
+
+                let samples = frames
+                    .chunks_exact(channels)
+                    .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+                    .collect();
+
+                return Ok((samples, sample_rate));
+            }
+            _ => {}
+        }
+    }
+}
+
+fn decode_samples(data: &[u8], format_tag: u16, bits_per_sample: u16) -> Result<Vec<f32>> {
+    let mut cursor = data;
+
+    match (format_tag, bits_per_sample) {
+        (WAVE_FORMAT_IEEE_FLOAT, 32) => (0..data.len() / 4)
+            .map(|_| Ok(cursor.read_f32::<LittleEndian>()?))
+            .collect(),
+        (WAVE_FORMAT_PCM, 16) => (0..data.len() / 2)
+            .map(|_| Ok(cursor.read_i16::<LittleEndian>()? as f32 / 32768.0))
+            .collect(),
+        (WAVE_FORMAT_PCM, 24) => (0..data.len() / 3)
+            .map(|_| Ok(cursor.read_i24::<LittleEndian>()? as f32 / 8_388_608.0))
+            .collect(),
+        (WAVE_FORMAT_PCM, 32) => (0..data.len() / 4)
+            .map(|_| Ok(cursor.read_i32::<LittleEndian>()? as f32 / 2_147_483_648.0))
+            .collect(),
+        _ => Err(SonicPipeError::Wav(format!(
+            "Unsupported sample format {} with {} bits per sample",
+            format_tag, bits_per_sample
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_wav_roundtrip() {
+        let samples: Vec<f32> = (0..1000).map(|i| (i as f32 * 0.05).sin() * 0.8).collect();
+
+        let mut buffer = Vec::new();
+        write_wav_to(&mut buffer, &samples, 44100).unwrap();
+        assert_eq!(buffer.len(), 44 + samples.len() * 4);
+
+        let (decoded, sample_rate) = read_wav_from(&mut Cursor::new(buffer)).unwrap();
+        assert_eq!(sample_rate, 44100);
+        assert_eq!(decoded, samples);
+    }
+
+    #[test]
+    fn test_read_stereo_pcm16() {
+        let mut buffer = Vec::new();
+        buffer.extend_from_slice(b"RIFF");
+        buffer.write_u32::<LittleEndian>(36 + 8).unwrap();
+        buffer.extend_from_slice(b"WAVEfmt ");
+        buffer.write_u32::<LittleEndian>(16).unwrap();
+        buffer.write_u16::<LittleEndian>(WAVE_FORMAT_PCM).unwrap();
+        buffer.write_u16::<LittleEndian>(2).unwrap();
+        buffer.write_u32::<LittleEndian>(48000).unwrap();
+        buffer.write_u32::<LittleEndian>(48000 * 4).unwrap();
+        buffer.write_u16::<LittleEndian>(4).unwrap();
+        buffer.write_u16::<LittleEndian>(16).unwrap();
+        buffer.extend_from_slice(b"data");
+        buffer.write_u32::<LittleEndian>(8).unwrap();
+        for sample in [16384i16, 0, -32768, -32768] {
+            buffer.write_i16::<LittleEndian>(sample).unwrap();
+        }
+
+        let (decoded, sample_rate) = read_wav_from(&mut Cursor::new(buffer)).unwrap();
+        assert_eq!(sample_rate, 48000);
+        assert_eq!(decoded, vec![0.25, -1.0]);
+    }
+
+    #[test]
+    fn test_read_rejects_non_wav() {
+        assert!(read_wav_from(&mut Cursor::new(b"not a wav file at all".to_vec())).is_err());
+    }
+
+    #[test]
+    fn test_oversized_chunk_length_is_rejected() {
+        let mut buffer = Vec::new();
+        write_wav_to(&mut buffer, &[0.5; 4], 44100).unwrap();
+        // The data chunk's length sits just before its four samples.
+        let at = buffer.len() - 16 - 4;
+        buffer[at..at + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+
+        assert!(matches!(
+            read_wav_from(&mut Cursor::new(buffer)),
+            Err(SonicPipeError::Wav(_))
+        ));
+    }
+}
@@ -0,0 +1,61 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use sonic_pipe_core::{
+    modulation::{MFSKDemodulator, MFSKModulator},
+    Config, SymbolDetector,
+};
+
+// Counts every allocation in this test binary, which holds a single test so
+// nothing else runs alongside it.
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+fn allocations_during(f: impl FnOnce()) -> usize {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    f();
+    ALLOCATIONS.load(Ordering::Relaxed) - before
+}
+
+#[test]
+fn test_repeated_demodulate_reuses_buffers() {
+    for detector in [SymbolDetector::Goertzel, SymbolDetector::Fft] {
+        let config = Config {
+            symbol_detector: detector,
+            ..Default::default()
+        };
+        let modulator = MFSKModulator::new(config.clone());
+        let mut demodulator = MFSKDemodulator::new(config);
+
+        for data in [(0..200u8).collect::<Vec<u8>>(), vec![1, 2, 3, 4]] {
+            let samples = modulator.modulate(&data).unwrap();
+            let expected = Some(data);
+
+            let first = allocations_during(|| assert_eq!(demodulator.demodulate(&samples), expected));
+            let repeat = allocations_during(|| assert_eq!(demodulator.demodulate(&samples), expected));
+
+            // Once the buffers are warm, only the returned payload is new.
+            assert_eq!(repeat, 1, "{:?}: {} allocations on the first call", detector, first);
+        }
+    }
+}
@@ -0,0 +1,42 @@
+use sonic_pipe_core::{decode_from_samples, encode_to_samples, read_wav, testing::add_awgn, write_wav, Config};
+
+fn payloads() -> Vec<Vec<u8>> {
+    vec![
+        b"Hello, Sonic-Pipe!".to_vec(),
+        // Not valid UTF-8, and long enough for a full frame rather than a short one.
+        (0..=255u8).rev().step_by(3).chain([0xFF, 0xFE, 0x00, 0xC3]).collect(),
+    ]
+}
+
+#[test]
+fn test_wav_roundtrip_decodes() {
+    let config = Config::default();
+    let dir = tempfile::tempdir().unwrap();
+
+    for (i, payload) in payloads().iter().enumerate() {
+        let path = dir.path().join(format!("message-{}.wav", i));
+        let samples = encode_to_samples(payload, &config).unwrap();
+        write_wav(&path, &samples, config.sample_rate).unwrap();
+
+        let (read, sample_rate) = read_wav(&path).unwrap();
+        assert_eq!(sample_rate, config.sample_rate);
+        assert_eq!(read.len(), samples.len());
+        assert_eq!(decode_from_samples(&read, &config).unwrap().0, *payload);
+    }
+}
+
+#[test]
+fn test_noisy_wav_still_decodes() {
+    let config = Config::default();
+    let dir = tempfile::tempdir().unwrap();
+
+    for (i, payload) in payloads().iter().enumerate() {
+        let path = dir.path().join(format!("noisy-{}.wav", i));
+        let mut samples = encode_to_samples(payload, &config).unwrap();
+        add_awgn(&mut samples, 3.0, i as u64 + 1);
+        write_wav(&path, &samples, config.sample_rate).unwrap();
+
+        let (read, _) = read_wav(&path).unwrap();
+        assert_eq!(decode_from_samples(&read, &config).unwrap().0, *payload);
+    }
+}